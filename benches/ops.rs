@@ -4,6 +4,7 @@ extern crate test;
 
 use merk::owner::Owner;
 use merk::test_utils::*;
+use merk::tree::{NoopCommit, Tree};
 use test::Bencher;
 
 #[bench]
@@ -65,3 +66,106 @@ fn update_1m_10k_rand_memonly(b: &mut Bencher) {
         i = (i + 1) % (initial_size / batch_size);
     });
 }
+
+// the keys are looked up in the 1m-node tree built from batches with seeds
+// 0..100, so every lookup finds its key
+
+#[bench]
+fn get_1m_10k_rand_memonly(b: &mut Bencher) {
+    let initial_size = 1_000_000;
+    let batch_size = 10_000;
+
+    let tree = make_tree_rand(initial_size, batch_size, 0);
+
+    let mut i = 0;
+    b.iter(|| {
+        let batch = make_batch_rand(batch_size, i % 100);
+        for (key, _) in batch.iter() {
+            tree.get(key).expect("get failed");
+        }
+        i += 1;
+    });
+}
+
+#[bench]
+fn get_many_1m_10k_rand_memonly(b: &mut Bencher) {
+    let initial_size = 1_000_000;
+    let batch_size = 10_000;
+
+    let tree = make_tree_rand(initial_size, batch_size, 0);
+
+    let mut i = 0;
+    b.iter(|| {
+        let batch = make_batch_rand(batch_size, i % 100);
+        let keys: Vec<&[u8]> = batch.iter().map(|(key, _)| key.as_slice()).collect();
+        tree.get_many(&keys).expect("get_many failed");
+        i += 1;
+    });
+}
+
+/// Builds a fully-modified (uncommitted) tree with `node_count` nodes.
+fn make_modified_tree_seq(node_count: u64) -> Tree {
+    let entries = (0..node_count).map(|n| (seq_key(n), vec![123; 60]));
+    Tree::from_sorted(entries).expect("expected tree")
+}
+
+// building the tree is included in the timings of both of these benches, so
+// compare their difference rather than their ratio
+
+#[bench]
+fn commit_1m_seq_memonly(b: &mut Bencher) {
+    b.iter(|| {
+        let mut tree = make_modified_tree_seq(1_000_000);
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        tree
+    });
+}
+
+#[cfg(feature = "rayon")]
+#[bench]
+fn commit_parallel_1m_seq_memonly(b: &mut Bencher) {
+    b.iter(|| {
+        let mut tree = make_modified_tree_seq(1_000_000);
+        tree.commit_parallel(&mut NoopCommit {})
+            .expect("commit failed");
+        tree
+    });
+}
+
+// both of these benches append 1M increasing keys one at a time, starting
+// from a single node
+
+#[bench]
+fn push_max_1m_seq_memonly(b: &mut Bencher) {
+    b.iter(|| {
+        let mut tree = Tree::new(seq_key(0), vec![123; 60]);
+        for n in 1..1_000_000 {
+            tree = tree.push_max(seq_key(n), vec![123; 60]);
+        }
+        tree
+    });
+}
+
+#[bench]
+fn insert_1m_seq_one_by_one_memonly(b: &mut Bencher) {
+    b.iter(|| {
+        let mut tree = Tree::new(seq_key(0), vec![123; 60]);
+        for n in 1..1_000_000 {
+            tree = tree
+                .insert(seq_key(n), vec![123; 60])
+                .ok()
+                .expect("insert failed");
+        }
+        tree
+    });
+}
+
+#[bench]
+fn memory_footprint_1m_seq_memonly(b: &mut Bencher) {
+    let tree = make_tree_seq(1_000_000);
+
+    let footprint = tree.memory_footprint();
+    assert!(footprint >= tree.count() as usize * Tree::node_size());
+
+    b.iter(|| tree.memory_footprint());
+}
@@ -0,0 +1,165 @@
+#![feature(test)]
+
+extern crate test;
+
+use merk::proofs::{verify_range, BorrowedDecoder, BorrowedNode, BorrowedOp, Decoder, Node, Op};
+use merk::test_utils::*;
+use merk::tree::{NoopCommit, Tree};
+use std::ops::Bound;
+use test::Bencher;
+
+// every tree is built from a fixed seed, so each run of a bench measures the
+// same tree and the same keys
+
+/// The number of entries in each proven range.
+const RANGE_SIZE: usize = 100;
+
+fn bench_insert_rand(b: &mut Bencher, node_count: u64) {
+    b.iter(|| make_modified_tree_rand(node_count, 0));
+}
+
+// building the tree is included in the timings of the commit benches, so
+// compare them to the insert benches of the same size
+
+fn bench_commit_rand(b: &mut Bencher, node_count: u64) {
+    b.iter(|| {
+        let mut tree = make_modified_tree_rand(node_count, 0);
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        tree
+    });
+}
+
+/// Builds a committed tree of random keys, returning it with its keys in order.
+fn make_committed_tree_rand(node_count: u64) -> (Tree, Vec<Vec<u8>>) {
+    let mut tree = make_modified_tree_rand(node_count, 0);
+    tree.commit(&mut NoopCommit {}).expect("commit failed");
+    let keys = tree.keys().map(|key| key.to_vec()).collect();
+    (tree, keys)
+}
+
+fn bench_prove_key_rand(b: &mut Bencher, node_count: u64) {
+    let (mut tree, keys) = make_committed_tree_rand(node_count);
+
+    let mut i = 0;
+    b.iter(|| {
+        let bytes = prove_key(&mut tree, &keys[i]);
+        i = (i + 7919) % keys.len();
+        bytes
+    });
+}
+
+fn bench_verify_range_rand(b: &mut Bencher, node_count: u64) {
+    let (mut tree, keys) = make_committed_tree_rand(node_count);
+    let hash = tree.hash();
+
+    let proofs: Vec<_> = keys
+        .chunks_exact(RANGE_SIZE)
+        .take(100)
+        .map(|chunk| {
+            let (start, end) = (&chunk[0], &chunk[RANGE_SIZE - 1]);
+            let bytes = prove_range(&mut tree, start, end);
+            (start.clone(), end.clone(), bytes)
+        })
+        .collect();
+
+    let mut i = 0;
+    b.iter(|| {
+        let (start, end, bytes) = &proofs[i];
+        let entries = verify_range(
+            bytes,
+            Bound::Included(start.clone()),
+            Bound::Excluded(end.clone()),
+            None,
+            None,
+            hash,
+        )
+        .expect("verify failed");
+        assert_eq!(entries.len(), RANGE_SIZE - 1);
+        i = (i + 1) % proofs.len();
+        entries
+    });
+}
+
+// the decode benches sum the lengths of the proof's values, so compare them to
+// see the cost of allocating each key and value
+
+fn make_range_proof_rand(node_count: u64) -> Vec<u8> {
+    let (mut tree, keys) = make_committed_tree_rand(node_count);
+    prove_range(&mut tree, &keys[0], &keys[RANGE_SIZE])
+}
+
+fn bench_decode_range_rand(b: &mut Bencher, node_count: u64) {
+    let bytes = make_range_proof_rand(node_count);
+
+    b.iter(|| {
+        Decoder::new(&bytes)
+            .map(|op| match op.expect("decode failed") {
+                Op::Push(Node::KV(_, value)) => value.len(),
+                _ => 0,
+            })
+            .sum::<usize>()
+    });
+}
+
+fn bench_decode_borrowed_range_rand(b: &mut Bencher, node_count: u64) {
+    let bytes = make_range_proof_rand(node_count);
+
+    b.iter(|| {
+        BorrowedDecoder::new(&bytes)
+            .map(|op| match op.expect("decode failed") {
+                BorrowedOp::Push(BorrowedNode::KV(_, value)) => value.len(),
+                _ => 0,
+            })
+            .sum::<usize>()
+    });
+}
+
+#[bench]
+fn insert_10k_rand_memonly(b: &mut Bencher) {
+    bench_insert_rand(b, 10_000);
+}
+
+#[bench]
+fn insert_100k_rand_memonly(b: &mut Bencher) {
+    bench_insert_rand(b, 100_000);
+}
+
+#[bench]
+fn commit_10k_rand_memonly(b: &mut Bencher) {
+    bench_commit_rand(b, 10_000);
+}
+
+#[bench]
+fn commit_100k_rand_memonly(b: &mut Bencher) {
+    bench_commit_rand(b, 100_000);
+}
+
+#[bench]
+fn prove_key_10k_rand_memonly(b: &mut Bencher) {
+    bench_prove_key_rand(b, 10_000);
+}
+
+#[bench]
+fn prove_key_1m_rand_memonly(b: &mut Bencher) {
+    bench_prove_key_rand(b, 1_000_000);
+}
+
+#[bench]
+fn verify_range_10k_rand_memonly(b: &mut Bencher) {
+    bench_verify_range_rand(b, 10_000);
+}
+
+#[bench]
+fn verify_range_1m_rand_memonly(b: &mut Bencher) {
+    bench_verify_range_rand(b, 1_000_000);
+}
+
+#[bench]
+fn decode_range_10k_rand_memonly(b: &mut Bencher) {
+    bench_decode_range_rand(b, 10_000);
+}
+
+#[bench]
+fn decode_borrowed_range_10k_rand_memonly(b: &mut Bencher) {
+    bench_decode_borrowed_range_rand(b, 10_000);
+}
@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use merk::proofs::{decode_proof, encode_proof};
+
+fuzz_target!(|data: &[u8]| {
+    // decoding arbitrary bytes must not panic, and the encoding is canonical,
+    // so any accepted input must re-encode to the same bytes
+    if let Ok(ops) = decode_proof(data) {
+        assert_eq!(encode_proof(ops.as_slice()), data);
+    }
+});
@@ -0,0 +1,122 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use merk::proofs::{encode_into, verify_query_items, QueryItem};
+use merk::tree::{NoopCommit, PanicSource, RefWalker, Tree, Walker};
+use merk::Op;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+/// Keys are hashed with a 1-byte length prefix.
+const MAX_KEY_LENGTH: usize = 255;
+/// Values may be up to 65,535 bytes, but shorter values keep cases fast.
+const MAX_VALUE_LENGTH: usize = 1_024;
+
+#[derive(Arbitrary, Debug)]
+enum Query {
+    Key(Vec<u8>),
+    Range(Option<(bool, Vec<u8>)>, Option<(bool, Vec<u8>)>),
+    RangeInclusive(Vec<u8>, Vec<u8>),
+    RangeFrom(Vec<u8>),
+    RangeTo(Vec<u8>),
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    queries: Vec<Query>,
+    mutations: Vec<(u16, u8)>,
+}
+
+fn to_bound(bound: Option<(bool, Vec<u8>)>) -> Bound<Vec<u8>> {
+    match bound {
+        None => Bound::Unbounded,
+        Some((true, key)) => Bound::Included(key),
+        Some((false, key)) => Bound::Excluded(key),
+    }
+}
+
+fn to_query_item(query: Query) -> QueryItem {
+    match query {
+        Query::Key(key) => QueryItem::Key(key),
+        Query::Range(start, end) => QueryItem::Range(to_bound(start), to_bound(end)),
+        Query::RangeInclusive(start, end) => QueryItem::RangeInclusive(start, end),
+        Query::RangeFrom(start) => QueryItem::RangeFrom(start),
+        Query::RangeTo(end) => QueryItem::RangeTo(end),
+    }
+}
+
+/// Builds a committed tree from the given entries. Entries are collected into
+/// a map first, so keys are unique and sorted as `apply_to` requires (later
+/// entries for a duplicated key take precedence). Empty keys are skipped,
+/// since `apply_to` rejects them.
+fn build_tree(entries: Vec<(Vec<u8>, Vec<u8>)>) -> Option<Tree> {
+    let map: BTreeMap<_, _> = entries
+        .into_iter()
+        .filter(|(key, _)| !key.is_empty())
+        .map(|(mut key, mut value)| {
+            key.truncate(MAX_KEY_LENGTH);
+            value.truncate(MAX_VALUE_LENGTH);
+            (key, value)
+        })
+        .collect();
+    let batch: Vec<_> = map
+        .into_iter()
+        .map(|(key, value)| (key, Op::Put(value)))
+        .collect();
+
+    let mut tree = Walker::<PanicSource>::apply_to(None, &batch)
+        .expect("apply failed")
+        .0?;
+    tree.commit(&mut NoopCommit {}).expect("commit failed");
+    Some(tree)
+}
+
+fuzz_target!(|input: Input| {
+    let mut tree = match build_tree(input.entries) {
+        Some(tree) => tree,
+        None => return,
+    };
+    let root_hash = tree.hash();
+    let queries: Vec<_> = input.queries.into_iter().map(to_query_item).collect();
+
+    let proof = RefWalker::new(&mut tree, PanicSource {})
+        .create_query_proof(&queries)
+        .expect("failed to create proof");
+    let mut bytes = vec![];
+    encode_into(proof.iter(), &mut bytes);
+
+    let result = verify_query_items(&bytes, &queries, root_hash).expect("verify failed");
+
+    // every proven entry must be in the tree, and every entry matched by a
+    // query must be proven
+    for (key, value) in result.iter() {
+        assert_eq!(tree.get(key).expect("get failed"), Some(value.as_slice()));
+    }
+    for query in queries.iter() {
+        for (key, value) in tree.iter() {
+            if query.contains(key) {
+                assert_eq!(result.get(key).map(|value| value.as_slice()), Some(value));
+            }
+        }
+    }
+
+    // a tampered proof must fail to verify (without panicking), unless the
+    // mutations happened to cancel out
+    if bytes.is_empty() || input.mutations.is_empty() {
+        return;
+    }
+    let mut tampered = bytes.clone();
+    for (index, byte) in input.mutations {
+        let index = index as usize % tampered.len();
+        tampered[index] ^= byte;
+    }
+    if tampered == bytes {
+        return;
+    }
+    if let Ok(tampered_result) = verify_query_items(&tampered, &queries, root_hash) {
+        // still matching the root hash means the same entries were proven
+        assert_eq!(tampered_result, result);
+    }
+});
@@ -1,3 +1,59 @@
+use crate::tree::{Hash, HexHash};
+use std::fmt;
+
 pub use failure::Error;
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+/// The kinds of errors which callers may want to handle programmatically.
+///
+/// Errors of these kinds are returned wrapped in an `Error`, like all other
+/// errors, and can be matched by downcasting, e.g. with
+/// `err.downcast_ref::<ErrorKind>()`. Their messages are given by `Display`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// A proof does not contain the value of a queried key, e.g. because it
+    /// only contains its value's hash.
+    MissingProofData,
+
+    /// The keys of the nodes in a proof are not in strictly increasing order.
+    UnsortedNodes,
+
+    /// The given key is not in the tree.
+    KeyNotFound(Vec<u8>),
+
+    /// A node's encoding does not match its checksum.
+    Corrupt,
+
+    /// A proof does not hash to the expected root hash.
+    HashMismatch { expected: Hash, actual: Hash },
+
+    /// A node needed to be fetched, but the tree has no source to fetch it
+    /// from (see `ErrSource`).
+    NoSource,
+
+    /// The node with the given hash is not in the store it was fetched from.
+    NodeNotFound(Hash),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::MissingProofData => {
+                write!(f, "Proof does not contain value for queried key")
+            }
+            ErrorKind::UnsortedNodes => write!(f, "Incorrect key ordering"),
+            ErrorKind::KeyNotFound(key) => write!(f, "Key {:?} not found", key),
+            ErrorKind::Corrupt => write!(f, "Corrupt node encoding, checksum does not match"),
+            ErrorKind::HashMismatch { expected, actual } => write!(
+                f,
+                "Proof did not match expected hash\n\tExpected: {:?}\n\tActual: {:?}",
+                expected, actual
+            ),
+            ErrorKind::NoSource => write!(f, "Cannot fetch node from tree without a source"),
+            ErrorKind::NodeNotFound(hash) => write!(f, "Node {} not found", HexHash(hash)),
+        }
+    }
+}
+
+impl std::error::Error for ErrorKind {}
@@ -0,0 +1,108 @@
+use std::convert::TryInto;
+
+use failure::bail;
+
+use crate::error::Result;
+
+/// The bit which is flipped in the big-endian encoding of signed integers, so
+/// that negative numbers sort before positive ones.
+const SIGN_BIT: u64 = 1 << 63;
+
+/// Encodes `n` as an 8-byte big-endian key, so that keys sort in the same order
+/// as the numbers they encode.
+///
+/// # Example
+/// ```
+/// # use merk::keys::{decode_u64, encode_u64};
+/// assert!(encode_u64(255) < encode_u64(256));
+/// assert_eq!(decode_u64(&encode_u64(256)).unwrap(), 256);
+/// ```
+pub fn encode_u64(n: u64) -> Vec<u8> {
+    n.to_be_bytes().to_vec()
+}
+
+/// Decodes a key encoded with `encode_u64`.
+///
+/// Returns an error if the key is not 8 bytes long.
+pub fn decode_u64(key: &[u8]) -> Result<u64> {
+    let bytes: [u8; 8] = match key.try_into() {
+        Ok(bytes) => bytes,
+        Err(_) => bail!("Expected 8-byte key, got {} bytes", key.len()),
+    };
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Encodes `n` as an 8-byte big-endian key with its sign bit flipped, so that
+/// keys sort in the same order as the numbers they encode (negative numbers
+/// first).
+///
+/// # Example
+/// ```
+/// # use merk::keys::{decode_i64, encode_i64};
+/// assert!(encode_i64(-1) < encode_i64(0));
+/// assert_eq!(decode_i64(&encode_i64(-1)).unwrap(), -1);
+/// ```
+pub fn encode_i64(n: i64) -> Vec<u8> {
+    encode_u64(n as u64 ^ SIGN_BIT)
+}
+
+/// Decodes a key encoded with `encode_i64`.
+///
+/// Returns an error if the key is not 8 bytes long.
+pub fn decode_i64(key: &[u8]) -> Result<i64> {
+    Ok((decode_u64(key)? ^ SIGN_BIT) as i64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tree::Tree;
+
+    #[test]
+    fn signed_keys_in_numeric_order() {
+        let numbers = [
+            0,
+            -1,
+            1,
+            i64::MIN,
+            i64::MAX,
+            -256,
+            255,
+            256,
+            -255,
+            i64::MIN + 1,
+            i64::MAX - 1,
+        ];
+
+        let mut tree = Tree::new(encode_i64(numbers[0]), vec![]);
+        for &n in numbers[1..].iter() {
+            tree = tree
+                .insert(encode_i64(n), vec![])
+                .ok()
+                .expect("insert failed");
+        }
+
+        let iterated: Vec<i64> = tree
+            .iter()
+            .map(|(key, _)| decode_i64(key).expect("decode failed"))
+            .collect();
+        let mut sorted = numbers.to_vec();
+        sorted.sort();
+        assert_eq!(iterated, sorted);
+    }
+
+    #[test]
+    fn decode_roundtrip() {
+        for &n in [0, 1, 255, 256, u64::MAX].iter() {
+            assert_eq!(decode_u64(&encode_u64(n)).unwrap(), n);
+        }
+        assert!(encode_u64(1) < encode_u64(256));
+
+        for &n in [0, 1, -1, i64::MIN, i64::MAX].iter() {
+            assert_eq!(decode_i64(&encode_i64(n)).unwrap(), n);
+        }
+
+        assert!(decode_u64(&[0; 7]).is_err());
+        assert!(decode_i64(&[0; 9]).is_err());
+    }
+}
@@ -7,6 +7,8 @@ pub use rocksdb;
 mod error;
 /// The top-level store API.
 mod merk;
+/// Helpers for encoding integers as keys which sort in numeric order.
+pub mod keys;
 /// Provides a container type that allows temporarily taking ownership of a value.
 // TODO: move this into its own crate
 pub mod owner;
@@ -17,8 +19,8 @@ pub mod test_utils;
 /// The core tree data structure.
 pub mod tree;
 
-pub use crate::merk::{chunks, Merk, restore};
+pub use crate::merk::{chunks, Merk, MerkSnapshotSource, restore};
 
-pub use error::{Error, Result};
+pub use error::{Error, ErrorKind, Result};
 pub use proofs::verify_query;
-pub use tree::{Batch, BatchEntry, Hash, Op, PanicSource, HASH_LENGTH};
+pub use tree::{Batch, BatchEntry, EntryLimits, ErrSource, Hash, Op, PanicSource, HASH_LENGTH};
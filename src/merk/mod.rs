@@ -4,13 +4,17 @@ pub mod restore;
 use std::cell::Cell;
 use std::collections::LinkedList;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use failure::bail;
 use rocksdb::{checkpoint::Checkpoint, ColumnFamilyDescriptor, WriteBatch};
 
-use crate::error::Result;
+use crate::error::{ErrorKind, Result};
 use crate::proofs::encode_into;
-use crate::tree::{Batch, Commit, Fetch, Hash, Link, Op, RefWalker, Tree, Walker, NULL_HASH};
+use crate::tree::{
+    check_batch, check_keys, merge_values, Batch, Commit, EntryLimits, Fetch, Hash, Link, Op,
+    RefWalker, Snapshot, Tree, Walker, NULL_HASH,
+};
 
 const ROOT_KEY_KEY: &[u8] = b"root";
 const AUX_CF_NAME: &str = "aux";
@@ -27,7 +31,7 @@ fn column_families() -> Vec<ColumnFamilyDescriptor> {
 /// A handle to a Merkle key/value store backed by RocksDB.
 pub struct Merk {
     pub(crate) tree: Cell<Option<Tree>>,
-    pub(crate) db: rocksdb::DB,
+    pub(crate) db: Arc<rocksdb::DB>,
     pub(crate) path: PathBuf,
 }
 
@@ -51,7 +55,7 @@ impl Merk {
 
         let mut merk = Merk {
             tree: Cell::new(None),
-            db,
+            db: Arc::new(db),
             path: path_buf,
         };
         merk.load_root()?;
@@ -120,6 +124,12 @@ impl Merk {
         self.use_tree(|tree| tree.map_or(NULL_HASH, |tree| tree.hash()))
     }
 
+    /// Returns `true` if the store has no entries, in which case its root hash
+    /// is the null hash.
+    pub fn is_empty(&self) -> bool {
+        self.use_tree(|tree| tree.is_none())
+    }
+
     /// Applies a batch of operations (puts and deletes) to the tree.
     ///
     /// This will fail if the keys in `batch` are not sorted and unique. This
@@ -142,19 +152,70 @@ impl Merk {
     /// ```
     pub fn apply(&mut self, batch: &Batch, aux: &Batch) -> Result<()> {
         // ensure keys in batch are sorted and unique
-        let mut maybe_prev_key = None;
-        for (key, _) in batch.iter() {
-            if let Some(prev_key) = maybe_prev_key {
-                if prev_key > *key {
-                    bail!("Keys in batch must be sorted");
-                } else if prev_key == *key {
-                    bail!("Keys in batch must be unique");
+        check_batch(batch)?;
+
+        unsafe { self.apply_unchecked(batch, aux) }
+    }
+
+    /// Like `apply`, but first checks the lengths of the keys and values in
+    /// `batch` against `limits`, returning an error without modifying the
+    /// store if any entry is too large (see `EntryLimits::check`).
+    pub fn apply_with_limits(
+        &mut self,
+        batch: &Batch,
+        aux: &Batch,
+        limits: &EntryLimits,
+    ) -> Result<()> {
+        limits.check(batch)?;
+        self.apply(batch, aux)
+    }
+
+    /// Like `apply`, but first calls `validate` on each key in `batch`,
+    /// returning an error naming the index of the first key it rejects without
+    /// modifying the store (see `check_keys`).
+    pub fn apply_with_validator<F>(&mut self, batch: &Batch, aux: &Batch, validate: F) -> Result<()>
+    where
+        F: Fn(&[u8]) -> Result<()>,
+    {
+        check_keys(batch, validate)?;
+        self.apply(batch, aux)
+    }
+
+    /// Like `apply`, but also applies `Op::Merge` entries, combining the
+    /// existing value of each merged key with the operands of its merges (in
+    /// batch order) by calling `merge` (see `Walker::apply_to_with_merge`).
+    /// Several consecutive merges for the same key may be in the batch.
+    ///
+    /// The merged values are computed before the tree is modified, so if
+    /// `merge` returns an error the store is left untouched.
+    pub fn apply_with_merge<F>(&mut self, batch: &Batch, aux: &Batch, merge: F) -> Result<()>
+    where
+        F: Fn(&[u8], &[u8], &[u8]) -> Result<Vec<u8>>,
+    {
+        check_batch(batch)?;
+
+        let mut resolved = Vec::with_capacity(batch.len());
+        let mut start = 0;
+        while start < batch.len() {
+            let (key, op) = &batch[start];
+            let end = start
+                + batch[start..]
+                    .iter()
+                    .take_while(|(other, _)| other == key)
+                    .count();
+            let op = match op {
+                Op::Merge(_) => {
+                    let existing = self.get(key)?;
+                    let value = merge_values(key, existing.as_deref(), &batch[start..end], &merge)?;
+                    Op::Put(value)
                 }
-            }
-            maybe_prev_key = Some(key.to_vec());
+                op => op.clone(),
+            };
+            resolved.push((key.clone(), op));
+            start = end;
         }
 
-        unsafe { self.apply_unchecked(batch, aux) }
+        unsafe { self.apply_unchecked(&resolved, aux) }
     }
 
     /// Applies a batch of operations (puts and deletes) to the tree.
@@ -178,6 +239,31 @@ impl Merk {
     /// unsafe { store.apply_unchecked(batch, &[]).unwrap() };
     /// ```
     pub unsafe fn apply_unchecked(&mut self, batch: &Batch, aux: &Batch) -> Result<()> {
+        // aux data is written without reading existing values, so conditional
+        // operations can't be checked
+        for (key, op) in aux.iter() {
+            match op {
+                Op::Put(_) | Op::Delete => {}
+                _ => bail!("Aux key {:?} has unsupported operation {:?}", key, op),
+            }
+        }
+
+        // check updated keys exist before taking the tree, so a failed update
+        // leaves the store untouched
+        for (key, op) in batch.iter() {
+            if let Op::Update(_) = op {
+                if self.get(key)?.is_none() {
+                    bail!("Cannot update key {:?} which does not exist", key);
+                }
+            }
+            if let Op::Merge(_) = op {
+                bail!(
+                    "Cannot apply Merge for key {:?} without a merge function",
+                    key
+                );
+            }
+        }
+
         let maybe_walker = self
             .tree
             .take()
@@ -235,7 +321,8 @@ impl Merk {
     /// tree, its absence will be proven by including boundary keys.
     ///
     /// The proof returned is in an encoded format which can be verified with
-    /// `merk::verify`.
+    /// `merk::verify`. If the store is empty, the proof is empty, and proves the
+    /// absence of every key against the null hash.
     ///
     /// This is unsafe because the keys in `query` must be sorted and unique -
     /// if they are not, there will be undefined behavior. For a safe version of
@@ -244,7 +331,7 @@ impl Merk {
     pub unsafe fn prove_unchecked(&self, query: &[Vec<u8>]) -> Result<Vec<u8>> {
         self.use_tree_mut(|maybe_tree| {
             let tree = match maybe_tree {
-                None => bail!("Cannot create proof for empty tree"),
+                None => return Ok(vec![]),
                 Some(tree) => tree,
             };
 
@@ -303,6 +390,7 @@ impl Merk {
             match value {
                 Op::Put(value) => batch.put_cf(aux_cf, key, value),
                 Op::Delete => batch.delete_cf(aux_cf, key),
+                _ => unreachable!("conditional aux operations are rejected by apply"),
             };
         }
 
@@ -331,6 +419,23 @@ impl Merk {
         Merk::open(path)
     }
 
+    /// Takes a snapshot of the store's tree (see `Tree::snapshot`) which reads
+    /// its nodes from a RocksDB snapshot of the database, so it can still be
+    /// read after further batches are applied to the store. Returns `None` if
+    /// the store is empty.
+    ///
+    /// The snapshot keeps the database open, so `destroy` will fail while any
+    /// snapshot of the store is still alive.
+    pub fn snapshot(&self) -> Result<Option<Snapshot<MerkSnapshotSource>>> {
+        let source = MerkSnapshotSource::new(self.db.clone());
+        let internal_cf = self.db.cf_handle(INTERNAL_CF_NAME).unwrap();
+        let root = match source.snapshot.get_cf(internal_cf, ROOT_KEY_KEY)? {
+            None => return Ok(None),
+            Some(root_key) => source.fetch_key(&root_key)?,
+        };
+        Ok(Some(root.snapshot(source)?))
+    }
+
     fn source(&self) -> MerkSource {
         MerkSource { db: &self.db }
     }
@@ -391,6 +496,40 @@ impl<'a> Fetch for MerkSource<'a> {
     }
 }
 
+/// A source which fetches nodes from a RocksDB snapshot of a store's database,
+/// so nodes overwritten by later commits remain readable (see
+/// `Merk::snapshot`).
+pub struct MerkSnapshotSource {
+    // declared before `_db` so it is dropped before the database it reads
+    snapshot: rocksdb::Snapshot<'static>,
+    _db: Arc<rocksdb::DB>,
+}
+
+impl MerkSnapshotSource {
+    fn new(db: Arc<rocksdb::DB>) -> Self {
+        let snapshot = db.snapshot();
+        // the snapshot only borrows the database, which is kept alive (and not
+        // moved, since it is behind the `Arc`) until the snapshot is dropped
+        let snapshot = unsafe {
+            std::mem::transmute::<rocksdb::Snapshot<'_>, rocksdb::Snapshot<'static>>(snapshot)
+        };
+        MerkSnapshotSource { snapshot, _db: db }
+    }
+
+    fn fetch_key(&self, key: &[u8]) -> Result<Tree> {
+        match self.snapshot.get(key)? {
+            Some(bytes) => Ok(Tree::decode(key.to_vec(), &bytes)),
+            None => Err(ErrorKind::KeyNotFound(key.to_vec()).into()),
+        }
+    }
+}
+
+impl Fetch for MerkSnapshotSource {
+    fn fetch(&self, link: &Link) -> Result<Tree> {
+        self.fetch_key(link.key())
+    }
+}
+
 struct MerkCommitter {
     batch: Vec<(Vec<u8>, Option<Vec<u8>>)>,
     height: u8,
@@ -410,7 +549,7 @@ impl MerkCommitter {
 impl Commit for MerkCommitter {
     fn write(&mut self, tree: &Tree) -> Result<()> {
         let mut buf = Vec::with_capacity(tree.encoding_length());
-        tree.encode_into(&mut buf);
+        tree.encode_into(&mut buf)?;
         self.batch.push((tree.key().to_vec(), Some(buf)));
         Ok(())
     }
@@ -433,16 +572,19 @@ fn fetch_node(db: &rocksdb::DB, key: &[u8]) -> Result<Option<Tree>> {
 
 fn fetch_existing_node(db: &rocksdb::DB, key: &[u8]) -> Result<Tree> {
     match fetch_node(db, key)? {
-        None => bail!("key not found: {:?}", key),
+        None => Err(ErrorKind::KeyNotFound(key.to_vec()).into()),
         Some(node) => Ok(node),
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{RefWalker, Merk, MerkSource};
+    use super::{EntryLimits, RefWalker, Merk, MerkSource, Result, NULL_HASH};
+    use failure::bail;
+    use crate::proofs::verify_query;
     use crate::test_utils::*;
     use crate::Op;
+    use std::ops::Bound;
     use std::thread;
 
     // TODO: Close and then reopen test
@@ -468,8 +610,8 @@ mod test {
         assert_eq!(
             merk.root_hash(),
             [
-                217, 218, 163, 74, 119, 133, 165, 247, 140, 194, 85, 70, 28, 33, 61, 148, 118, 231,
-                134, 111
+                248, 61, 185, 130, 200, 30, 114, 109, 251, 4, 207, 49, 61, 33, 57, 192, 74, 10,
+                181, 215
             ]
         );
     }
@@ -505,6 +647,107 @@ mod test {
         }
     }
 
+    #[test]
+    fn apply_with_limits() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = TempMerk::open(path).expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..10), &[]).expect("apply failed");
+        let root_hash = merk.root_hash();
+
+        let limits = EntryLimits {
+            max_key_length: 8,
+            max_value_length: 59,
+        };
+        assert!(merk
+            .apply_with_limits(&make_batch_seq(10..20), &[], &limits)
+            .is_err());
+        assert_eq!(merk.root_hash(), root_hash);
+        assert!(merk.get(&seq_key(10)).unwrap().is_none());
+
+        let limits = EntryLimits {
+            max_key_length: 8,
+            max_value_length: 60,
+        };
+        merk.apply_with_limits(&make_batch_seq(10..20), &[], &limits)
+            .expect("apply failed");
+        assert_invariants(&merk);
+        assert!(merk.get(&seq_key(10)).unwrap().is_some());
+    }
+
+    #[test]
+    fn apply_with_validator() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = TempMerk::open(path).expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..10), &[]).expect("apply failed");
+        let root_hash = merk.root_hash();
+
+        // keys must be 8 bytes long
+        let validate = |key: &[u8]| -> Result<()> {
+            if key.len() != 8 {
+                bail!("expected 8-byte key");
+            }
+            Ok(())
+        };
+
+        let mut batch = make_batch_seq(10..20);
+        batch[5].0.push(0);
+        assert!(merk.apply_with_validator(&batch, &[], validate).is_err());
+        assert_eq!(merk.root_hash(), root_hash);
+        assert!(merk.get(&seq_key(10)).unwrap().is_none());
+
+        merk.apply_with_validator(&make_batch_seq(10..20), &[], validate)
+            .expect("apply failed");
+        assert_invariants(&merk);
+        assert!(merk.get(&seq_key(10)).unwrap().is_some());
+    }
+
+    #[test]
+    fn apply_with_merge() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = TempMerk::open(path).expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..10), &[]).expect("apply failed");
+        let root_hash = merk.root_hash();
+
+        // values are 8-byte counters, and merges add to them
+        let add = |_key: &[u8], existing: &[u8], operand: &[u8]| -> Result<Vec<u8>> {
+            if existing.len() != 8 || operand.len() != 8 {
+                bail!("expected 8-byte counter");
+            }
+            let read = |bytes: &[u8]| {
+                let mut array = [0; 8];
+                array.copy_from_slice(bytes);
+                u64::from_be_bytes(array)
+            };
+            Ok((read(existing) + read(operand)).to_be_bytes().to_vec())
+        };
+        let counter = |n: u64| n.to_be_bytes().to_vec();
+
+        // existing values are not counters, so merging into them fails
+        let batch = [(seq_key(5), Op::Merge(counter(1)))];
+        assert!(merk.apply_with_merge(&batch, &[], add).is_err());
+        assert!(merk.apply(&batch, &[]).is_err());
+        assert_eq!(merk.root_hash(), root_hash);
+
+        let batch = [
+            (seq_key(5), Op::Put(counter(10))),
+            (seq_key(20), Op::Merge(counter(1))),
+            (seq_key(20), Op::Merge(counter(2))),
+            (seq_key(20), Op::Merge(counter(3))),
+        ];
+        merk.apply_with_merge(&batch, &[], add).expect("apply failed");
+        assert_eq!(merk.get(&seq_key(20)).unwrap(), Some(counter(6)));
+
+        let batch = [
+            (seq_key(5), Op::Merge(counter(1))),
+            (seq_key(5), Op::Merge(counter(1))),
+            (seq_key(20), Op::Merge(counter(4))),
+        ];
+        merk.apply_with_merge(&batch, &[], add).expect("apply failed");
+        assert_invariants(&merk);
+        assert_eq!(merk.get(&seq_key(5)).unwrap(), Some(counter(12)));
+        assert_eq!(merk.get(&seq_key(20)).unwrap(), Some(counter(10)));
+    }
+
     #[test]
     fn actual_deletes() {
         let path = thread::current().name().unwrap().to_owned();
@@ -520,6 +763,36 @@ mod test {
         assert!(value.is_none());
     }
 
+    #[test]
+    fn delete_all_keys() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = TempMerk::open(path).expect("failed to open merk");
+        assert!(merk.is_empty());
+        assert_eq!(merk.root_hash(), NULL_HASH);
+
+        let batch = make_batch_seq(0..10);
+        merk.apply(&batch, &[]).expect("apply failed");
+        assert!(!merk.is_empty());
+        assert_ne!(merk.root_hash(), NULL_HASH);
+
+        let deletes: Vec<_> = batch.into_iter().map(|(key, _)| (key, Op::Delete)).collect();
+        merk.apply(&deletes, &[]).expect("apply failed");
+        assert!(merk.is_empty());
+        assert_eq!(merk.root_hash(), NULL_HASH);
+
+        let keys = vec![seq_key(0), seq_key(5)];
+        let proof = merk.prove(&keys).expect("prove failed");
+        let values = verify_query(&proof, &keys, merk.root_hash()).expect("verify failed");
+        assert_eq!(values, vec![None, None]);
+        assert!(verify_query(&proof, &keys, [1; 20]).is_err());
+
+        // the store can become non-empty again
+        merk.apply(&[(vec![1], Op::Put(vec![1]))], &[])
+            .expect("apply failed");
+        assert!(!merk.is_empty());
+        assert_eq!(merk.get(&[1]).unwrap(), Some(vec![1]));
+    }
+
     #[test]
     fn aux_data() {
         let path = thread::current().name().unwrap().to_owned();
@@ -530,6 +803,24 @@ mod test {
         assert_eq!(val, Some(vec![4, 5, 6]));
     }
 
+    #[test]
+    fn update_missing_key() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = TempMerk::open(path).expect("failed to open merk");
+        merk.apply(&[(vec![1], Op::Put(vec![1]))], &[])
+            .expect("apply failed");
+        let hash = merk.root_hash();
+
+        let batch = [(vec![1], Op::Update(vec![2])), (vec![2], Op::Update(vec![2]))];
+        assert!(merk.apply(&batch, &[]).is_err());
+        assert_eq!(merk.root_hash(), hash);
+        assert_eq!(merk.get(&[1]).unwrap(), Some(vec![1]));
+
+        merk.apply(&[(vec![1], Op::Update(vec![2]))], &[])
+            .expect("apply failed");
+        assert_eq!(merk.get(&[1]).unwrap(), Some(vec![2]));
+    }
+
     #[test]
     fn simulated_crash() {
         let path = thread::current().name().unwrap().to_owned();
@@ -722,4 +1013,46 @@ mod test {
 
         std::fs::remove_dir_all(&path).unwrap();
     }
+
+    #[test]
+    fn snapshot() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = TempMerk::open(path).expect("failed to open merk");
+        assert!(merk.snapshot().expect("snapshot failed").is_none());
+
+        merk.apply(&make_batch_seq(0..100), &[])
+            .expect("apply failed");
+        let hash = merk.root_hash();
+        let snapshot = merk
+            .snapshot()
+            .expect("snapshot failed")
+            .expect("expected snapshot");
+        assert_eq!(snapshot.hash(), hash);
+
+        // rewrite every node, which overwrites them in the database by key
+        let batch: Vec<_> = (0..100).map(|i| (seq_key(i), Op::Put(vec![1]))).collect();
+        merk.apply(&batch, &[]).expect("apply failed");
+        let batch: Vec<_> = (0..50).map(|i| (seq_key(i), Op::Delete)).collect();
+        merk.apply(&batch, &[]).expect("apply failed");
+        assert_ne!(merk.root_hash(), hash);
+        assert_eq!(merk.get(&seq_key(5)).unwrap(), None);
+
+        assert_eq!(snapshot.hash(), hash);
+        for i in 0..100 {
+            assert_eq!(snapshot.get(&seq_key(i)).unwrap(), Some(vec![123; 60]));
+        }
+        let entries = snapshot
+            .range(Bound::Included(seq_key(90)), Bound::Unbounded)
+            .unwrap();
+        let keys: Vec<_> = entries.into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, (90..100).map(seq_key).collect::<Vec<_>>());
+
+        let latest = merk
+            .snapshot()
+            .expect("snapshot failed")
+            .expect("expected snapshot");
+        assert_eq!(latest.hash(), merk.root_hash());
+        assert_eq!(latest.get(&seq_key(5)).unwrap(), None);
+        assert_eq!(latest.get(&seq_key(50)).unwrap(), Some(vec![1]));
+    }
 }
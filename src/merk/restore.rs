@@ -3,13 +3,14 @@
 
 use super::Merk;
 use crate::{
+    error::ErrorKind,
     merk::MerkSource,
     proofs::{
         chunk::{verify_leaf, verify_trunk, MIN_TRUNK_HEIGHT},
         verify::{Child, Tree as ProofTree},
         Decoder, Node,
     },
-    tree::{Link, RefWalker, Tree},
+    tree::{CtEq, Link, NoAggregate, RefWalker, Tree},
     Hash, Result,
 };
 use failure::bail;
@@ -84,7 +85,7 @@ impl Restorer {
         }
 
         if self.trunk_height.unwrap() >= MIN_TRUNK_HEIGHT {
-            self.rewrite_trunk_child_heights()?;
+            self.rewrite_trunk_link_metadata()?;
         }
 
         self.merk.flush()?;
@@ -131,12 +132,12 @@ impl Restorer {
     fn process_trunk(&mut self, ops: Decoder) -> Result<usize> {
         let (trunk, height) = verify_trunk(ops)?;
 
-        if trunk.hash() != self.expected_root_hash {
-            bail!(
-                "Proof did not match expected hash\n\tExpected: {:?}\n\tActual: {:?}",
-                self.expected_root_hash,
-                trunk.hash()
-            );
+        if !trunk.hash().ct_eq(&self.expected_root_hash) {
+            return Err(ErrorKind::HashMismatch {
+                expected: self.expected_root_hash,
+                actual: trunk.hash(),
+            }
+            .into());
         }
 
         let root_key = trunk.key().to_vec();
@@ -234,33 +235,40 @@ impl Restorer {
         Ok(())
     }
 
-    fn rewrite_trunk_child_heights(&mut self) -> Result<()> {
+    /// Rewrites the child heights and descendant counts of the links in the
+    /// trunk, which could not be known until the leaf chunks were processed.
+    fn rewrite_trunk_link_metadata(&mut self) -> Result<()> {
         fn recurse(
             mut node: RefWalker<MerkSource>,
             remaining_depth: usize,
             batch: &mut WriteBatch,
-        ) -> Result<(u8, u8)> {
+        ) -> Result<((u8, u8), u64)> {
             if remaining_depth == 0 {
-                return Ok(node.tree().child_heights());
+                return Ok((node.tree().child_heights(), node.tree().count()));
             }
 
             let mut cloned_node =
                 Tree::decode(node.tree().key().to_vec(), node.tree().encode().as_slice());
 
             let left_child = node.walk(true)?.unwrap();
-            let left_child_heights = recurse(left_child, remaining_depth - 1, batch)?;
+            let (left_child_heights, left_count) = recurse(left_child, remaining_depth - 1, batch)?;
             let left_height = left_child_heights.0.max(left_child_heights.1) + 1;
-            *cloned_node.link_mut(true).unwrap().child_heights_mut() = left_child_heights;
+            let left_link = cloned_node.link_mut(true).unwrap();
+            *left_link.child_heights_mut() = left_child_heights;
+            *left_link.descendant_count_mut() = left_count;
 
             let right_child = node.walk(false)?.unwrap();
-            let right_child_heights = recurse(right_child, remaining_depth - 1, batch)?;
+            let (right_child_heights, right_count) =
+                recurse(right_child, remaining_depth - 1, batch)?;
             let right_height = right_child_heights.0.max(right_child_heights.1) + 1;
-            *cloned_node.link_mut(false).unwrap().child_heights_mut() = right_child_heights;
+            let right_link = cloned_node.link_mut(false).unwrap();
+            *right_link.child_heights_mut() = right_child_heights;
+            *right_link.descendant_count_mut() = right_count;
 
             let bytes = cloned_node.encode();
             batch.put(node.tree().key(), bytes);
 
-            Ok((left_height, right_height))
+            Ok(((left_height, right_height), 1 + left_count + right_count))
         }
 
         self.merk.flush()?;
@@ -328,6 +336,8 @@ impl Child {
         Link::Reference {
             hash: self.hash,
             child_heights: self.tree.child_heights(),
+            descendant_count: self.tree.count(),
+            aggregate: NoAggregate,
             key: key.to_vec(),
         }
     }
@@ -6,13 +6,53 @@ use super::{
     Node, Op,
 };
 use crate::error::Result;
-use crate::tree::{Fetch, Hash, RefWalker, Tree};
+use crate::tree::{Aggregate, CtEq, Fetch, Hash, Hasher, RefWalker, Tree};
 
 /// The minimum number of layers the trunk will be guaranteed to have before
 /// splitting into multiple chunks. If the tree's height is less than double
 /// this value, the trunk should be verified as a leaf chunk.
 pub const MIN_TRUNK_HEIGHT: usize = 5;
 
+impl<'a, S, H, A> RefWalker<'a, S, H, A>
+where
+    S: Fetch<H, A> + Sized + Send + Clone,
+    H: Hasher<Digest = Hash>,
+    A: Aggregate,
+{
+    /// Generates a proof of the number of nodes in the tree, made of the hash
+    /// of the root node's key/value pair and a `Node::CountedHash` for each of
+    /// its children. Hashers of version 3 and later hash the children's counts
+    /// into the root hash, so the proof has constant size however large the
+    /// tree is, and nothing needs to be fetched from the source.
+    ///
+    /// Returns an error if `H` is earlier than version 3.
+    ///
+    /// The resulting proof can be checked with `verify_count`.
+    pub fn create_count_proof(&mut self) -> Result<Vec<Op>> {
+        if H::VERSION < 3 {
+            bail!("Hashers before version 3 cannot create count proofs");
+        }
+
+        let mut proof = Vec::with_capacity(5);
+        if let Some(left) = self.tree().link(true) {
+            proof.push(Op::Push(left.to_hash_node()));
+        }
+
+        proof.push(Op::Push(self.to_kvhash_node()));
+
+        if self.tree().link(true).is_some() {
+            proof.push(Op::Parent);
+        }
+
+        if let Some(right) = self.tree().link(false) {
+            proof.push(Op::Push(right.to_hash_node()));
+            proof.push(Op::Child);
+        }
+
+        Ok(proof)
+    }
+}
+
 impl<'a, S> RefWalker<'a, S>
 where
     S: Fetch + Sized + Send + Clone,
@@ -39,6 +79,17 @@ where
         }
     }
 
+    /// Generates a proof of the entire tree, in which every node is included as
+    /// a `Node::KV`, fetching nodes from the source as needed. This serializes
+    /// the tree in a form the verifier can check against its root hash.
+    ///
+    /// The resulting proof can be checked with `verify_full`.
+    pub fn create_full_proof(&mut self) -> Result<Vec<Op>> {
+        let mut proof = Vec::with_capacity(self.tree().count() as usize * 2);
+        self.traverse_for_trunk(&mut proof, usize::MAX, false)?;
+        Ok(proof)
+    }
+
     /// Traverses down the left edge of the tree and pushes ops to the proof, to
     /// act as a proof of the height of the tree. This is the first step in
     /// generating a trunk proof.
@@ -172,12 +223,12 @@ pub(crate) fn verify_leaf<I: Iterator<Item = Result<Op>>>(
     ops: I,
     expected_hash: Hash,
 ) -> Result<ProofTree> {
-    let tree = execute(ops, false, |node| match node {
+    let tree: ProofTree = execute(ops, false, |node| match node {
         Node::KV(_, _) => Ok(()),
         _ => bail!("Leaf chunks must contain full subtree"),
     })?;
 
-    if tree.hash() != expected_hash {
+    if !tree.hash().ct_eq(&expected_hash) {
         bail!(
             "Leaf chunk proof did not match expected hash\n\tExpected: {:?}\n\tActual: {:?}",
             expected_hash,
@@ -234,7 +285,7 @@ pub(crate) fn verify_trunk<I: Iterator<Item = Result<Op>>>(ops: I) -> Result<(Pr
     }
 
     let mut kv_only = true;
-    let tree = execute(ops, false, |node| {
+    let tree: ProofTree = execute(ops, false, |node| {
         kv_only &= match node {
             Node::KV(_, _) => true,
             _ => false,
@@ -277,9 +328,9 @@ mod tests {
 
         tree.visit_nodes(&mut |node| {
             match node {
-                Node::Hash(_) => counts.hash += 1,
+                Node::Hash(_) | Node::CountedHash(_, _) => counts.hash += 1,
                 Node::KVHash(_) => counts.kvhash += 1,
-                Node::KV(_, _) => counts.kv += 1,
+                Node::KV(_, _) | Node::KVDigest(_, _) => counts.kv += 1,
             };
         });
 
@@ -425,8 +476,8 @@ mod tests {
         let chunk = verify_leaf(
             ops,
             [
-                10, 147, 175, 167, 145, 38, 181, 73, 116, 253, 95, 138, 110, 222, 254, 197, 189,
-                68, 11, 151,
+                95, 66, 105, 121, 188, 148, 189, 169, 240, 171, 132, 12, 105, 12, 70, 101, 222,
+                12, 162, 53,
             ],
         )
         .unwrap();
@@ -441,8 +492,8 @@ mod tests {
         let chunk = verify_leaf(
             ops,
             [
-                128, 166, 214, 176, 167, 251, 11, 84, 228, 2, 97, 239, 253, 75, 184, 16, 137, 134,
-                72, 154,
+                81, 231, 78, 35, 59, 25, 234, 113, 45, 108, 175, 207, 99, 153, 212, 95, 9, 11,
+                241, 181,
             ],
         )
         .unwrap();
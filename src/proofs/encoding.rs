@@ -3,9 +3,15 @@ use std::io::{Read, Write};
 use ed::{Decode, Encode, Terminated};
 use failure::bail;
 
-use super::{Node, Op};
+use super::{BorrowedNode, BorrowedOp, Node, Op};
 use crate::error::Result;
-use crate::tree::HASH_LENGTH;
+use crate::tree::{read_varint, varint_length, write_varint, Hash, HASH_LENGTH};
+
+/// The version byte written at the start of proofs encoded by `encode_proof`.
+pub const PROOF_VERSION: u8 = 1;
+
+/// The length of the trailer of proofs encoded by `encode_proof`.
+const TRAILER_LENGTH: usize = 4;
 
 impl Encode for Op {
     fn encode_into<W: Write>(&self, dest: &mut W) -> ed::Result<()> {
@@ -27,6 +33,18 @@ impl Encode for Op {
                 (value.len() as u16).encode_into(dest)?;
                 dest.write_all(value)?;
             }
+            Op::Push(Node::KVDigest(key, value_hash)) => {
+                debug_assert!(key.len() < 256);
+
+                dest.write_all(&[0x04, key.len() as u8])?;
+                dest.write_all(key)?;
+                dest.write_all(value_hash)?;
+            }
+            Op::Push(Node::CountedHash(hash, count)) => {
+                dest.write_all(&[0x05])?;
+                dest.write_all(hash)?;
+                count.encode_into(dest)?;
+            }
             Op::Parent => dest.write_all(&[0x10])?,
             Op::Child => dest.write_all(&[0x11])?,
         };
@@ -38,6 +56,8 @@ impl Encode for Op {
             Op::Push(Node::Hash(_)) => 1 + HASH_LENGTH,
             Op::Push(Node::KVHash(_)) => 1 + HASH_LENGTH,
             Op::Push(Node::KV(key, value)) => 4 + key.len() + value.len(),
+            Op::Push(Node::KVDigest(key, _)) => 2 + key.len() + HASH_LENGTH,
+            Op::Push(Node::CountedHash(_, _)) => 1 + HASH_LENGTH + 8,
             Op::Parent => 1,
             Op::Child => 1,
         })
@@ -72,6 +92,23 @@ impl Decode for Op {
 
                 Op::Push(Node::KV(key, value))
             }
+            0x04 => {
+                let key_len: u8 = Decode::decode(&mut input)?;
+                let mut key = Vec::with_capacity(key_len as usize);
+                key.resize(key_len as usize, 0);
+                input.read_exact(key.as_mut_slice())?;
+
+                let mut value_hash = [0; HASH_LENGTH];
+                input.read_exact(&mut value_hash)?;
+
+                Op::Push(Node::KVDigest(key, value_hash))
+            }
+            0x05 => {
+                let mut hash = [0; HASH_LENGTH];
+                input.read_exact(&mut hash)?;
+                let count: u64 = Decode::decode(&mut input)?;
+                Op::Push(Node::CountedHash(hash, count))
+            }
             0x10 => Op::Parent,
             0x11 => Op::Child,
             _ => bail!("Proof has unexpected value"),
@@ -93,6 +130,79 @@ impl Op {
     pub fn decode(bytes: &[u8]) -> Result<Self> {
         Decode::decode(bytes)
     }
+
+    /// Decodes an operator from the start of `bytes` like `Op::decode`, but
+    /// without allocating: the key and value of the pushed node (if any) are
+    /// borrowed from `bytes`. Use `BorrowedOp::to_op` to get an owned `Op`.
+    pub fn decode_borrowed(bytes: &[u8]) -> Result<BorrowedOp> {
+        let mut input = bytes;
+        let variant = take(&mut input, 1)?[0];
+
+        Ok(match variant {
+            0x01 => BorrowedOp::Push(BorrowedNode::Hash(take_hash(&mut input)?)),
+            0x02 => BorrowedOp::Push(BorrowedNode::KVHash(take_hash(&mut input)?)),
+            0x03 => {
+                let key_len = take(&mut input, 1)?[0] as usize;
+                let key = take(&mut input, key_len)?;
+                let value_len = take(&mut input, 2)?;
+                let value_len = u16::from_be_bytes([value_len[0], value_len[1]]) as usize;
+                let value = take(&mut input, value_len)?;
+                BorrowedOp::Push(BorrowedNode::KV(key, value))
+            }
+            0x04 => {
+                let key_len = take(&mut input, 1)?[0] as usize;
+                let key = take(&mut input, key_len)?;
+                BorrowedOp::Push(BorrowedNode::KVDigest(key, take_hash(&mut input)?))
+            }
+            0x05 => {
+                let hash = take_hash(&mut input)?;
+                let count = take(&mut input, 8)?;
+                let mut count_bytes = [0; 8];
+                count_bytes.copy_from_slice(count);
+                BorrowedOp::Push(BorrowedNode::CountedHash(
+                    hash,
+                    u64::from_be_bytes(count_bytes),
+                ))
+            }
+            0x10 => BorrowedOp::Parent,
+            0x11 => BorrowedOp::Child,
+            _ => bail!("Proof has unexpected value"),
+        })
+    }
+}
+
+impl<'a> BorrowedOp<'a> {
+    /// Returns the length of the operator's encoding (like
+    /// `Op::encoding_length`).
+    pub fn encoding_length(&self) -> usize {
+        match self {
+            BorrowedOp::Push(BorrowedNode::Hash(_)) => 1 + HASH_LENGTH,
+            BorrowedOp::Push(BorrowedNode::KVHash(_)) => 1 + HASH_LENGTH,
+            BorrowedOp::Push(BorrowedNode::KV(key, value)) => 4 + key.len() + value.len(),
+            BorrowedOp::Push(BorrowedNode::KVDigest(key, _)) => 2 + key.len() + HASH_LENGTH,
+            BorrowedOp::Push(BorrowedNode::CountedHash(_, _)) => 1 + HASH_LENGTH + 8,
+            BorrowedOp::Parent => 1,
+            BorrowedOp::Child => 1,
+        }
+    }
+}
+
+/// Splits the first `length` bytes off of `input`, returning an error if there
+/// are not enough bytes.
+fn take<'a>(input: &mut &'a [u8], length: usize) -> Result<&'a [u8]> {
+    if input.len() < length {
+        bail!("Unexpected end of proof");
+    }
+    let (taken, rest) = input.split_at(length);
+    *input = rest;
+    Ok(taken)
+}
+
+/// Splits a hash off of the start of `input`.
+fn take_hash(input: &mut &[u8]) -> Result<Hash> {
+    let mut hash = [0; HASH_LENGTH];
+    hash.copy_from_slice(take(input, HASH_LENGTH)?);
+    Ok(hash)
 }
 
 pub fn encode_into<'a, T: Iterator<Item = &'a Op>>(ops: T, output: &mut Vec<u8>) {
@@ -101,6 +211,151 @@ pub fn encode_into<'a, T: Iterator<Item = &'a Op>>(ops: T, output: &mut Vec<u8>)
     }
 }
 
+/// Encodes the proof in its framed wire format: a version byte
+/// (`PROOF_VERSION`), then each operator prefixed with the varint length of its
+/// encoding, then the length of everything before the trailer as a 4-byte
+/// big-endian integer. The trailer lets a receiver check that it has the
+/// complete proof before parsing it.
+///
+/// Panics if a key is longer than 255 bytes or a value is longer than 65,535
+/// bytes.
+pub fn encode_proof(ops: &[Op]) -> Vec<u8> {
+    // the lengths are truncated when encoded, so check them in release builds
+    // too rather than relying on the debug assertions in `encode_into`
+    for op in ops {
+        match op {
+            Op::Push(Node::KV(key, value)) => {
+                assert!(key.len() < 256, "key must be less than 256 bytes");
+                assert!(value.len() < 65536, "value must be less than 65,536 bytes");
+            }
+            Op::Push(Node::KVDigest(key, _)) => {
+                assert!(key.len() < 256, "key must be less than 256 bytes");
+            }
+            _ => {}
+        }
+    }
+
+    let length = 1 + ops
+        .iter()
+        .map(|op| {
+            let op_length = op.encoding_length();
+            varint_length(op_length as u64) + op_length
+        })
+        .sum::<usize>();
+
+    let mut bytes = Vec::with_capacity(length + TRAILER_LENGTH);
+    bytes.push(PROOF_VERSION);
+    for op in ops {
+        write_varint(&mut bytes, op.encoding_length() as u64).unwrap();
+        op.encode_into(&mut bytes).unwrap();
+    }
+    bytes.extend_from_slice(&(length as u32).to_be_bytes());
+    bytes
+}
+
+/// Decodes a proof encoded by `encode_proof`.
+///
+/// Returns an error, without panicking, if the input is truncated or has
+/// extra bytes, has an unknown version, or contains a malformed or
+/// non-minimally framed operator. Every length is checked against the
+/// remaining input before it is used, so arbitrary input can be decoded
+/// safely.
+pub fn decode_proof(bytes: &[u8]) -> Result<Vec<Op>> {
+    if bytes.len() < 1 + TRAILER_LENGTH {
+        bail!(
+            "Invalid proof: expected at least 5 bytes, got {}",
+            bytes.len()
+        );
+    }
+
+    let (body, trailer) = bytes.split_at(bytes.len() - TRAILER_LENGTH);
+    let mut length = [0; TRAILER_LENGTH];
+    length.copy_from_slice(trailer);
+    let length = u32::from_be_bytes(length) as usize;
+    if length != body.len() {
+        bail!(
+            "Invalid proof: trailer specifies length {}, got {}",
+            length,
+            body.len()
+        );
+    }
+
+    if body[0] != PROOF_VERSION {
+        bail!("Invalid proof: unknown version {}", body[0]);
+    }
+
+    let mut ops = vec![];
+    let mut input = &body[1..];
+    while !input.is_empty() {
+        let remaining = input.len();
+        let op_length = match read_varint(&mut input) {
+            Ok(op_length) => op_length,
+            Err(_) => bail!("Invalid proof: malformed operator length"),
+        };
+        if remaining - input.len() != varint_length(op_length) {
+            bail!("Invalid proof: operator length is not minimally encoded");
+        }
+        if op_length > input.len() as u64 {
+            bail!(
+                "Invalid proof: operator length {} exceeds remaining {} bytes",
+                op_length,
+                input.len()
+            );
+        }
+
+        let (op_bytes, rest) = input.split_at(op_length as usize);
+        let op = match Op::decode(op_bytes) {
+            Ok(op) => op,
+            Err(_) => bail!("Invalid proof: malformed operator"),
+        };
+        if op.encoding_length() != op_bytes.len() {
+            bail!("Invalid proof: operator length does not match its encoding");
+        }
+
+        ops.push(op);
+        input = rest;
+    }
+
+    Ok(ops)
+}
+
+/// Statistics about a proof, as returned by `proof_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProofStats {
+    /// The length of the encoded proof, in bytes.
+    pub byte_size: usize,
+
+    /// The number of `Node::KV` nodes in the proof.
+    pub kv_count: usize,
+
+    /// The number of `Node::KVDigest` nodes in the proof.
+    pub kvdigest_count: usize,
+
+    /// The number of `Node::Hash` and `Node::CountedHash` nodes in the proof.
+    pub hash_count: usize,
+
+    /// The number of `Node::KVHash` nodes in the proof.
+    pub kvhash_count: usize,
+}
+
+/// Computes the encoded size of the proof and counts its nodes of each type,
+/// without encoding it. Useful for rejecting oversized proofs before sending
+/// them.
+pub fn proof_stats(ops: &[Op]) -> ProofStats {
+    let mut stats = ProofStats::default();
+    for op in ops {
+        stats.byte_size += op.encoding_length();
+        match op {
+            Op::Push(Node::KV(_, _)) => stats.kv_count += 1,
+            Op::Push(Node::KVDigest(_, _)) => stats.kvdigest_count += 1,
+            Op::Push(Node::Hash(_)) | Op::Push(Node::CountedHash(_, _)) => stats.hash_count += 1,
+            Op::Push(Node::KVHash(_)) => stats.kvhash_count += 1,
+            Op::Parent | Op::Child => {}
+        }
+    }
+    stats
+}
+
 pub struct Decoder<'a> {
     offset: usize,
     bytes: &'a [u8],
@@ -132,10 +387,46 @@ impl<'a> Iterator for Decoder<'a> {
     }
 }
 
+/// Like `Decoder`, but decodes each operator with `Op::decode_borrowed`, so
+/// no allocations are made for the keys and values of the proof's nodes.
+pub struct BorrowedDecoder<'a> {
+    offset: usize,
+    bytes: &'a [u8],
+}
+
+impl<'a> BorrowedDecoder<'a> {
+    pub fn new(proof_bytes: &'a [u8]) -> Self {
+        BorrowedDecoder {
+            offset: 0,
+            bytes: proof_bytes,
+        }
+    }
+}
+
+impl<'a> Iterator for BorrowedDecoder<'a> {
+    type Item = Result<BorrowedOp<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.bytes.len() {
+            return None;
+        }
+
+        let result = Op::decode_borrowed(&self.bytes[self.offset..]);
+        if let Ok(op) = &result {
+            self.offset += op.encoding_length();
+        }
+        Some(result)
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::super::{Node, Op};
+    use super::super::{BorrowedNode, BorrowedOp, Node, Op};
+    use super::{
+        decode_proof, encode_into, encode_proof, proof_stats, BorrowedDecoder, Decoder, ProofStats,
+    };
     use crate::tree::HASH_LENGTH;
+    use rand::prelude::*;
 
     #[test]
     fn encode_push_hash() {
@@ -179,6 +470,38 @@ mod test {
         assert_eq!(bytes, vec![0x03, 3, 1, 2, 3, 0, 3, 4, 5, 6]);
     }
 
+    #[test]
+    fn encode_push_kvdigest() {
+        let op = Op::Push(Node::KVDigest(vec![1, 2, 3], [123; HASH_LENGTH]));
+        assert_eq!(op.encoding_length(), 5 + HASH_LENGTH);
+
+        let mut bytes = vec![];
+        op.encode_into(&mut bytes).unwrap();
+        assert_eq!(
+            bytes,
+            vec![
+                0x04, 3, 1, 2, 3, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123,
+                123, 123, 123, 123, 123, 123, 123
+            ]
+        );
+    }
+
+    #[test]
+    fn encode_push_counted_hash() {
+        let op = Op::Push(Node::CountedHash([123; HASH_LENGTH], 258));
+        assert_eq!(op.encoding_length(), 9 + HASH_LENGTH);
+
+        let mut bytes = vec![];
+        op.encode_into(&mut bytes).unwrap();
+        assert_eq!(
+            bytes,
+            vec![
+                0x05, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123,
+                123, 123, 123, 123, 123, 0, 0, 0, 0, 0, 0, 1, 2
+            ]
+        );
+    }
+
     #[test]
     fn encode_parent() {
         let op = Op::Parent;
@@ -207,6 +530,130 @@ mod test {
         op.encode_into(&mut bytes).unwrap();
     }
 
+    #[test]
+    fn proof_stats_counts() {
+        let ops = vec![
+            Op::Push(Node::Hash([1; HASH_LENGTH])),
+            Op::Push(Node::KV(vec![1, 2, 3], vec![4, 5, 6])),
+            Op::Parent,
+            Op::Push(Node::KVHash([2; HASH_LENGTH])),
+            Op::Child,
+            Op::Push(Node::KV(vec![7], vec![])),
+            Op::Push(Node::KVDigest(vec![8], [3; HASH_LENGTH])),
+            Op::Child,
+            Op::Parent,
+        ];
+
+        let mut bytes = vec![];
+        encode_into(ops.iter(), &mut bytes);
+
+        let stats = proof_stats(ops.as_slice());
+        assert_eq!(
+            stats,
+            ProofStats {
+                byte_size: bytes.len(),
+                kv_count: 2,
+                kvdigest_count: 1,
+                hash_count: 1,
+                kvhash_count: 1,
+            }
+        );
+        assert_eq!(stats.byte_size, 84);
+        assert_eq!(proof_stats(&[]), ProofStats::default());
+    }
+
+    fn make_ops() -> Vec<Op> {
+        vec![
+            Op::Push(Node::Hash([1; HASH_LENGTH])),
+            Op::Push(Node::KV(vec![1, 2, 3], vec![4; 200])),
+            Op::Parent,
+            Op::Push(Node::KVHash([2; HASH_LENGTH])),
+            Op::Child,
+            Op::Push(Node::KVDigest(vec![8], [3; HASH_LENGTH])),
+            Op::Child,
+        ]
+    }
+
+    #[test]
+    fn proof_round_trip() {
+        let ops = make_ops();
+        let bytes = encode_proof(ops.as_slice());
+        assert_eq!(bytes[0], 1);
+        assert_eq!(
+            &bytes[bytes.len() - 4..],
+            &(bytes.len() as u32 - 4).to_be_bytes()
+        );
+        assert_eq!(decode_proof(bytes.as_slice()).expect("decode failed"), ops);
+
+        let bytes = encode_proof(&[]);
+        assert_eq!(bytes, vec![1, 0, 0, 0, 1]);
+        assert!(decode_proof(bytes.as_slice())
+            .expect("decode failed")
+            .is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "key must be less than 256 bytes")]
+    fn encode_proof_long_key() {
+        encode_proof(&[Op::Push(Node::KVDigest(vec![123; 256], [1; HASH_LENGTH]))]);
+    }
+
+    #[test]
+    #[should_panic(expected = "value must be less than 65,536 bytes")]
+    fn encode_proof_long_value() {
+        encode_proof(&[Op::Push(Node::KV(vec![1], vec![123; 65536]))]);
+    }
+
+    #[test]
+    fn proof_truncated() {
+        let bytes = encode_proof(make_ops().as_slice());
+        for length in 0..bytes.len() {
+            assert!(decode_proof(&bytes[..length]).is_err());
+        }
+
+        // extra bytes after the trailer
+        let mut extended = bytes.clone();
+        extended.push(0);
+        assert!(decode_proof(extended.as_slice()).is_err());
+    }
+
+    #[test]
+    fn proof_malformed() {
+        // wraps a body in a valid trailer
+        let frame = |body: &[u8]| {
+            let mut bytes = body.to_vec();
+            bytes.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            bytes
+        };
+
+        // unknown version
+        assert!(decode_proof(&frame(&[2])).is_err());
+        // unknown operator
+        assert!(decode_proof(&frame(&[1, 1, 0x88])).is_err());
+        // operator length exceeds the input
+        assert!(decode_proof(&frame(&[1, 2, 0x10])).is_err());
+        // operator length does not match the operator
+        assert!(decode_proof(&frame(&[1, 2, 0x10, 0x10])).is_err());
+        // operator length is not minimally encoded
+        assert!(decode_proof(&frame(&[1, 0x81, 0x00, 0x10])).is_err());
+        // operator length is a truncated varint
+        assert!(decode_proof(&frame(&[1, 0x80])).is_err());
+        // well-framed
+        assert_eq!(
+            decode_proof(&frame(&[1, 1, 0x10])).expect("decode failed"),
+            vec![Op::Parent]
+        );
+
+        // garbage
+        let mut rng: SmallRng = SeedableRng::seed_from_u64(0);
+        for _ in 0..1_000 {
+            let length = rng.gen_range(0..100);
+            let body: Vec<u8> = (0..length).map(|_| rng.gen()).collect();
+            let _ = decode_proof(body.as_slice());
+            let _ = decode_proof(&frame(body.as_slice()));
+        }
+    }
+
     #[test]
     fn decode_push_hash() {
         let bytes = [
@@ -234,6 +681,34 @@ mod test {
         assert_eq!(op, Op::Push(Node::KV(vec![1, 2, 3], vec![4, 5, 6])));
     }
 
+    #[test]
+    fn decode_push_kvdigest() {
+        let bytes = [
+            0x04, 3, 1, 2, 3, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123,
+            123, 123, 123, 123, 123, 123,
+        ];
+        let op = Op::decode(&bytes[..]).expect("decode failed");
+        assert_eq!(
+            op,
+            Op::Push(Node::KVDigest(vec![1, 2, 3], [123; HASH_LENGTH]))
+        );
+    }
+
+    #[test]
+    fn decode_push_counted_hash() {
+        let bytes = [
+            0x05, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123,
+            123, 123, 123, 123, 0, 0, 0, 0, 0, 0, 1, 2,
+        ];
+        let op = Op::decode(&bytes[..]).expect("decode failed");
+        assert_eq!(op, Op::Push(Node::CountedHash([123; HASH_LENGTH], 258)));
+        let op = Op::decode_borrowed(&bytes[..]).expect("decode failed");
+        assert_eq!(
+            op.to_op(),
+            Op::Push(Node::CountedHash([123; HASH_LENGTH], 258))
+        );
+    }
+
     #[test]
     fn decode_parent() {
         let bytes = [0x10];
@@ -252,5 +727,52 @@ mod test {
     fn decode_unknown() {
         let bytes = [0x88];
         assert!(Op::decode(&bytes[..]).is_err());
+        assert!(Op::decode_borrowed(&bytes[..]).is_err());
+    }
+
+    #[test]
+    fn decode_borrowed() {
+        let ops = make_ops();
+        let mut bytes = vec![];
+        encode_into(ops.iter(), &mut bytes);
+
+        let owned: Vec<_> = Decoder::new(&bytes)
+            .collect::<Result<_, _>>()
+            .expect("decode failed");
+        let borrowed: Vec<_> = BorrowedDecoder::new(&bytes)
+            .collect::<Result<_, _>>()
+            .expect("decode failed");
+        assert_eq!(owned, ops);
+        assert_eq!(
+            borrowed.iter().map(BorrowedOp::to_op).collect::<Vec<_>>(),
+            ops
+        );
+
+        // the key and value are slices of the encoded proof rather than copies
+        let bytes_range = bytes.as_ptr_range();
+        match borrowed[1] {
+            BorrowedOp::Push(BorrowedNode::KV(key, value)) => {
+                assert_eq!(key, &[1, 2, 3]);
+                assert_eq!(value, &[4; 200][..]);
+                assert!(bytes_range.contains(&key.as_ptr()));
+                assert!(bytes_range.contains(&value.as_ptr()));
+            }
+            _ => panic!("Expected KV node"),
+        }
+    }
+
+    #[test]
+    fn decode_borrowed_truncated() {
+        let mut bytes = vec![];
+        encode_into(make_ops().iter(), &mut bytes);
+
+        // every prefix which ends inside an operator fails to decode, without
+        // panicking
+        for length in 0..bytes.len() {
+            let prefix = &bytes[..length];
+            let owned = Decoder::new(prefix).collect::<Result<Vec<_>, _>>();
+            let borrowed = BorrowedDecoder::new(prefix).collect::<Result<Vec<_>, _>>();
+            assert_eq!(owned.is_ok(), borrowed.is_ok());
+        }
     }
 }
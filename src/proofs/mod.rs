@@ -1,15 +1,25 @@
 pub mod chunk;
 mod encoding;
-mod query;
+pub(crate) mod query;
 pub mod verify;
 
 use crate::tree::Hash;
+use std::fmt;
 
-pub use encoding::{encode_into, Decoder};
-pub use verify::verify_query;
+pub use encoding::{
+    decode_proof, encode_into, encode_proof, proof_stats, BorrowedDecoder, Decoder, ProofStats,
+    PROOF_VERSION,
+};
+pub use query::{create_historical_range_proof, prefix_bounds, QueryItem};
+pub use verify::{
+    verify, verify_count, verify_count_with_hasher, verify_full, verify_neighbors, verify_prefix,
+    verify_query, verify_query_digests, verify_query_digests_with_hasher, verify_query_items,
+    verify_query_with_hasher, verify_range, verify_range_prefix, verify_range_rev, Neighbors,
+    RangeVerifier, VerifyCache,
+};
 
 /// A proof operator, executed to verify the data in a Merkle proof.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Op {
     /// Pushes a node on the stack.
     Push(Node),
@@ -37,4 +47,173 @@ pub enum Node {
 
     /// Represents the key and value of a tree node.
     KV(Vec<u8>, Vec<u8>),
+
+    /// Represents the key and the hash of the value of a tree node, proving
+    /// the key is present without revealing its value.
+    KVDigest(Vec<u8>, Hash),
+
+    /// Represents the hash of a tree node and the number of nodes in its
+    /// subtree, which proofs for hashers of version 3 and later give in place
+    /// of `Node::Hash` since node hashes include their children's counts.
+    CountedHash(Hash, u64),
+}
+
+/// A proof operator decoded without copying, whose node borrows its key and
+/// value from the encoded proof (see `Op::decode_borrowed`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BorrowedOp<'a> {
+    /// Pushes a node on the stack (see `Op::Push`).
+    Push(BorrowedNode<'a>),
+
+    /// Attaches a left child (see `Op::Parent`).
+    Parent,
+
+    /// Attaches a right child (see `Op::Child`).
+    Child,
+}
+
+impl<'a> BorrowedOp<'a> {
+    /// Copies the borrowed data into an owned `Op`.
+    pub fn to_op(&self) -> Op {
+        match self {
+            BorrowedOp::Push(node) => Op::Push(node.to_node()),
+            BorrowedOp::Parent => Op::Parent,
+            BorrowedOp::Child => Op::Child,
+        }
+    }
+}
+
+/// A proof node whose key and value are borrowed from the encoded proof, for
+/// nodes which are only inspected rather than kept, e.g. when scanning the
+/// entries of a large range proof. Hashes are fixed-size, so they are copied.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BorrowedNode<'a> {
+    /// Represents the hash of a tree node (see `Node::Hash`).
+    Hash(Hash),
+
+    /// Represents the hash of the key/value pair of a tree node (see
+    /// `Node::KVHash`).
+    KVHash(Hash),
+
+    /// Represents the key and value of a tree node (see `Node::KV`).
+    KV(&'a [u8], &'a [u8]),
+
+    /// Represents the key and the hash of the value of a tree node (see
+    /// `Node::KVDigest`).
+    KVDigest(&'a [u8], Hash),
+
+    /// Represents the hash of a tree node and the number of nodes in its
+    /// subtree (see `Node::CountedHash`).
+    CountedHash(Hash, u64),
+}
+
+impl<'a> BorrowedNode<'a> {
+    /// Copies the borrowed key and value into an owned `Node`.
+    pub fn to_node(&self) -> Node {
+        match *self {
+            BorrowedNode::Hash(hash) => Node::Hash(hash),
+            BorrowedNode::KVHash(kv_hash) => Node::KVHash(kv_hash),
+            BorrowedNode::KV(key, value) => Node::KV(key.to_vec(), value.to_vec()),
+            BorrowedNode::KVDigest(key, value_hash) => Node::KVDigest(key.to_vec(), value_hash),
+            BorrowedNode::CountedHash(hash, count) => Node::CountedHash(hash, count),
+        }
+    }
+}
+
+/// The number of leading bytes of keys and hashes shown when formatting nodes
+/// with `Display`.
+const DISPLAY_BYTES: usize = 4;
+
+/// Writes the first `DISPLAY_BYTES` bytes of `bytes` as hex, followed by `..`
+/// if any bytes were left out.
+fn fmt_hex_prefix(f: &mut fmt::Formatter, bytes: &[u8]) -> fmt::Result {
+    if bytes.len() > DISPLAY_BYTES {
+        write!(f, "{}..", hex::encode(&bytes[..DISPLAY_BYTES]))
+    } else {
+        f.write_str(&hex::encode(bytes))
+    }
+}
+
+impl fmt::Display for Node {
+    /// Formats the node concisely for logs and test output, with keys and
+    /// hashes as truncated hex and values by their length, e.g.
+    /// `KV(6b6579, val_len=12)` or `Hash(a1b2c3d4..)`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Node::Hash(hash) => {
+                f.write_str("Hash(")?;
+                fmt_hex_prefix(f, hash)?;
+            }
+            Node::KVHash(hash) => {
+                f.write_str("KVHash(")?;
+                fmt_hex_prefix(f, hash)?;
+            }
+            Node::KV(key, value) => {
+                f.write_str("KV(")?;
+                fmt_hex_prefix(f, key)?;
+                write!(f, ", val_len={}", value.len())?;
+            }
+            Node::KVDigest(key, value_hash) => {
+                f.write_str("KVDigest(")?;
+                fmt_hex_prefix(f, key)?;
+                f.write_str(", ")?;
+                fmt_hex_prefix(f, value_hash)?;
+            }
+            Node::CountedHash(hash, count) => {
+                f.write_str("CountedHash(")?;
+                fmt_hex_prefix(f, hash)?;
+                write!(f, ", count={}", count)?;
+            }
+        }
+        f.write_str(")")
+    }
+}
+
+impl fmt::Display for Op {
+    /// Formats the operator concisely, with pushed nodes formatted as by
+    /// `Node`'s `Display` implementation.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Op::Push(node) => write!(f, "Push({})", node),
+            Op::Parent => f.write_str("Parent"),
+            Op::Child => f.write_str("Child"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn display_node() {
+        let cases = vec![
+            (Node::Hash([0xa1; 20]), "Hash(a1a1a1a1..)"),
+            (Node::KVHash([0x0f; 20]), "KVHash(0f0f0f0f..)"),
+            (
+                Node::KV(b"key".to_vec(), vec![0; 12]),
+                "KV(6b6579, val_len=12)",
+            ),
+            (
+                Node::KV(b"longkey".to_vec(), vec![]),
+                "KV(6c6f6e67.., val_len=0)",
+            ),
+            (
+                Node::KVDigest(b"key".to_vec(), [0xb2; 20]),
+                "KVDigest(6b6579, b2b2b2b2..)",
+            ),
+            (
+                Node::CountedHash([0xc3; 20], 7),
+                "CountedHash(c3c3c3c3.., count=7)",
+            ),
+        ];
+        for (node, expected) in cases {
+            assert_eq!(node.to_string(), expected);
+        }
+
+        let op = Op::Push(Node::KV(vec![1, 2], vec![3]));
+        assert_eq!(op.to_string(), "Push(KV(0102, val_len=1))");
+        assert_eq!(Op::Parent.to_string(), "Parent");
+        assert_eq!(Op::Child.to_string(), "Child");
+    }
 }
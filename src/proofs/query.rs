@@ -1,12 +1,16 @@
-use super::{Node, Op};
+use super::{encode_into, Node, Op};
 use crate::error::Result;
-use crate::tree::{Fetch, Link, RefWalker};
+use crate::tree::{Aggregate, Fetch, Hash, Hasher, Link, NoAggregate, RefWalker};
+use failure::bail;
+use std::cmp::Ordering;
 use std::collections::LinkedList;
+use std::ops::Bound;
 
-impl Link {
-    /// Creates a `Node::Hash` from this link. Panics if the link is of variant
+impl<H: Hasher<Digest = Hash>, A: Aggregate> Link<H, A> {
+    /// Creates a `Node::Hash` from this link, or a `Node::CountedHash` for
+    /// hashers of version 3 and later. Panics if the link is of variant
     /// `Link::Modified` since its hash has not yet been computed.
-    fn to_hash_node(&self) -> Node {
+    pub(crate) fn to_hash_node(&self) -> Node {
         let hash = match self {
             Link::Reference { hash, .. } => hash,
             Link::Modified { .. } => {
@@ -15,13 +19,19 @@ impl Link {
             Link::Uncommitted { hash, .. } => hash,
             Link::Loaded { hash, .. } => hash,
         };
-        Node::Hash(*hash)
+        if H::VERSION >= 3 {
+            Node::CountedHash(*hash, self.descendant_count())
+        } else {
+            Node::Hash(*hash)
+        }
     }
 }
 
-impl<'a, S> RefWalker<'a, S>
+impl<'a, S, H, A> RefWalker<'a, S, H, A>
 where
-    S: Fetch + Sized + Send + Clone,
+    S: Fetch<H, A> + Sized + Send + Clone,
+    H: Hasher<Digest = Hash>,
+    A: Aggregate,
 {
     /// Creates a `Node::KV` from the key/value pair of the root node.
     pub(crate) fn to_kv_node(&self) -> Node {
@@ -34,9 +44,14 @@ where
         Node::KVHash(*self.tree().kv_hash())
     }
 
-    /// Creates a `Node::Hash` from the hash of the node.
+    /// Creates a `Node::Hash` from the hash of the node, or a
+    /// `Node::CountedHash` for hashers of version 3 and later.
     pub(crate) fn to_hash_node(&self) -> Node {
-        Node::Hash(self.tree().hash())
+        if H::VERSION >= 3 {
+            Node::CountedHash(self.tree().hash(), self.tree().count())
+        } else {
+            Node::Hash(self.tree().hash())
+        }
     }
 
     /// Generates a proof for the list of queried keys. Returns a tuple
@@ -103,6 +118,607 @@ where
             (LinkedList::new(), (false, false))
         })
     }
+
+    /// Generates a proof for the entries with keys in the range between `start`
+    /// and `end`. Nodes outside of the range are only included when they are
+    /// needed to show that no other entries exist in the range (the nearest
+    /// node before and after the range are included as `Node::KV`).
+    ///
+    /// If `offset` is given, the proof still includes the first `offset`
+    /// entries of the range (so the verifier can check that exactly `offset`
+    /// entries were skipped), but they will not be returned by `verify_range`.
+    /// If `limit` is given, the proof ends after `limit` entries following the
+    /// offset, and the remainder of the range is left abridged.
+    ///
+    /// The resulting proof can be checked with `verify_range`.
+    pub fn create_range_proof(
+        &mut self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: Option<usize>,
+        offset: Option<usize>,
+    ) -> Result<Vec<Op>> {
+        let ranges = [QueryRange { start, end }];
+        let mut remaining = limit.map(|limit| limit.saturating_add(offset.unwrap_or(0)));
+        let (proof, _) = self.create_range_proof_inner(&ranges, &mut remaining, false)?;
+        Ok(proof.into_iter().collect())
+    }
+
+    /// Like `create_range_proof`, but limits the size of the encoded proof (as
+    /// written by `encode_into`) to `max_bytes`, for clients with limited
+    /// bandwidth. If the proof of the whole range is too large, the proof ends
+    /// after as many entries as fit in the budget, and the remainder of the
+    /// range is left abridged.
+    ///
+    /// The resulting proof can be checked with `verify_range_prefix`, which
+    /// returns the key to resume from if the proof was truncated. Returns an
+    /// error if the range is not empty and a proof of its first entry alone is
+    /// larger than `max_bytes`.
+    pub fn create_range_proof_max_bytes(
+        &mut self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        max_bytes: usize,
+    ) -> Result<Vec<Op>> {
+        // returns the proof, whether it fits, and whether it contains every
+        // entry in the range (which is the case when the limit isn't reached)
+        let mut create_proof = |limit: Option<usize>| -> Result<(Vec<Op>, bool, bool)> {
+            let ranges = [QueryRange {
+                start: start.clone(),
+                end: end.clone(),
+            }];
+            let mut remaining = limit;
+            let (proof, _) = self.create_range_proof_inner(&ranges, &mut remaining, false)?;
+            let proof: Vec<Op> = proof.into_iter().collect();
+            let mut bytes = vec![];
+            encode_into(proof.iter(), &mut bytes);
+            let complete = remaining != Some(0);
+            Ok((proof, bytes.len() <= max_bytes, complete))
+        };
+
+        let (proof, fits, _) = create_proof(None)?;
+        if fits {
+            return Ok(proof);
+        }
+
+        // find the greatest limit whose proof fits, by doubling the limit
+        // until it no longer fits then bisecting. A proof whose limit is
+        // reached leaves the rest of the range abridged, so it may fit even
+        // when the limit is the size of the whole range; the doubling stops
+        // once the limit exceeds the range's size, since larger limits give
+        // the same proof.
+        let (mut proof, fits, _) = create_proof(Some(1))?;
+        if !fits {
+            bail!(
+                "Proof of the first entry of the range exceeds {} bytes",
+                max_bytes
+            );
+        }
+        let (mut low, mut high) = (1, 2);
+        loop {
+            match create_proof(Some(high))? {
+                (next_proof, true, true) => return Ok(next_proof),
+                (next_proof, true, false) => {
+                    proof = next_proof;
+                    low = high;
+                    high = high.saturating_mul(2);
+                }
+                (_, false, _) => break,
+            }
+        }
+        while high - low > 1 {
+            let mid = low + (high - low) / 2;
+            match create_proof(Some(mid))? {
+                (next_proof, true, _) => {
+                    proof = next_proof;
+                    low = mid;
+                }
+                (_, false, _) => high = mid,
+            }
+        }
+
+        Ok(proof)
+    }
+
+    /// Like `create_range_proof`, but `offset` and `limit` count entries in
+    /// descending key order from the end of the range, e.g. to prove the last
+    /// `limit` entries of the range.
+    ///
+    /// The resulting proof can be checked with `verify_range_rev`.
+    pub fn create_range_proof_rev(
+        &mut self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: Option<usize>,
+        offset: Option<usize>,
+    ) -> Result<Vec<Op>> {
+        let ranges = [QueryRange { start, end }];
+        let mut remaining = limit.map(|limit| limit.saturating_add(offset.unwrap_or(0)));
+        let (proof, _) = self.create_range_proof_inner(&ranges, &mut remaining, true)?;
+        Ok(proof.into_iter().collect())
+    }
+
+    /// Generates a single proof for a batch of queries, each of which is either
+    /// a single key or a range of keys. The queries may be given in any order
+    /// and may overlap, they are sorted and merged before the proof is
+    /// generated so that nodes on paths shared by several queries are only
+    /// included once.
+    ///
+    /// The resulting proof can be checked with `verify_query_items`.
+    pub fn create_query_proof(&mut self, queries: &[QueryItem]) -> Result<Vec<Op>> {
+        let ranges = merge_query_items(queries);
+        let (proof, _) = self.create_range_proof_inner(&ranges, &mut None, false)?;
+        Ok(proof.into_iter().collect())
+    }
+
+    /// Like `create_range_proof`, but proves the entries whose keys start with
+    /// `prefix` (see `prefix_bounds`).
+    ///
+    /// The resulting proof can be checked with `verify_prefix`.
+    pub fn create_prefix_proof(
+        &mut self,
+        prefix: &[u8],
+        limit: Option<usize>,
+        offset: Option<usize>,
+    ) -> Result<Vec<Op>> {
+        let (start, end) = prefix_bounds(prefix);
+        self.create_range_proof(start, end, limit, offset)
+    }
+
+    /// Like `create_proof`, but every key/value pair in the proof is replaced
+    /// by a `Node::KVDigest` containing the hash of the value, proving which of
+    /// the queried keys are present without revealing their values.
+    ///
+    /// The resulting proof can be checked with `verify_query_digests`.
+    pub fn create_digest_proof(&mut self, keys: &[Vec<u8>]) -> Result<Vec<Op>> {
+        let (proof, _) = self.create_proof(keys)?;
+        Ok(proof
+            .into_iter()
+            .map(|op| match op {
+                Op::Push(Node::KV(key, value)) => {
+                    Op::Push(Node::KVDigest(key, H::hash_value(&value)))
+                }
+                op => op,
+            })
+            .collect())
+    }
+
+    /// Generates a proof of the entry with key `key` (or of its absence) along
+    /// with its in-order neighbors: the entry with the greatest key less than
+    /// `key`, and the entry with the least key greater than `key`, if they
+    /// exist. The neighbors are included as `Node::KV`, adjacent to the queried
+    /// entry, so the verifier can check that no keys lie between them.
+    ///
+    /// The resulting proof can be checked with `verify_neighbors`.
+    pub fn create_neighbor_proof(&mut self, key: &[u8]) -> Result<Vec<Op>> {
+        let (maybe_predecessor, maybe_successor) = self.neighbor_keys(key)?;
+
+        let mut keys = Vec::with_capacity(3);
+        keys.extend(maybe_predecessor);
+        keys.push(key.to_vec());
+        keys.extend(maybe_successor);
+
+        let (proof, _) = self.create_proof(keys.as_slice())?;
+        Ok(proof.into_iter().collect())
+    }
+
+    /// Finds the keys of the entries immediately before and after `key` in the
+    /// subtree, fetching nodes from the source as needed.
+    fn neighbor_keys(&mut self, key: &[u8]) -> Result<(Option<Vec<u8>>, Option<Vec<u8>>)> {
+        let node_key = self.tree().key().to_vec();
+
+        Ok(match key.cmp(node_key.as_slice()) {
+            Ordering::Less => {
+                let (predecessor, successor) = match self.walk(true)? {
+                    Some(mut child) => child.neighbor_keys(key)?,
+                    None => (None, None),
+                };
+                (predecessor, successor.or(Some(node_key)))
+            }
+            Ordering::Greater => {
+                let (predecessor, successor) = match self.walk(false)? {
+                    Some(mut child) => child.neighbor_keys(key)?,
+                    None => (None, None),
+                };
+                (predecessor.or(Some(node_key)), successor)
+            }
+            Ordering::Equal => {
+                let predecessor = match self.walk(true)? {
+                    Some(mut child) => Some(child.edge_key(false)?),
+                    None => None,
+                };
+                let successor = match self.walk(false)? {
+                    Some(mut child) => Some(child.edge_key(true)?),
+                    None => None,
+                };
+                (predecessor, successor)
+            }
+        })
+    }
+
+    /// Returns the least key in the subtree if `left` is `true`, otherwise the
+    /// greatest key, fetching nodes from the source as needed.
+    fn edge_key(&mut self, left: bool) -> Result<Vec<u8>> {
+        if let Some(mut child) = self.walk(left)? {
+            return child.edge_key(left);
+        }
+        Ok(self.tree().key().to_vec())
+    }
+
+    /// Recursive implementation of `create_range_proof`,
+    /// `create_range_proof_rev`, and `create_query_proof`. `ranges` must be
+    /// sorted and non-overlapping. `remaining` is the number of entries left to
+    /// include before the proof is cut off, or `None` if there is no limit.
+    /// Entries are counted in descending key order if `descending` is `true`.
+    ///
+    /// Returns the proof operators, and a tuple representing if the queried
+    /// ranges extend past the left edge or the right edge of this subtree,
+    /// respectively (as in `create_proof`).
+    fn create_range_proof_inner(
+        &mut self,
+        ranges: &[QueryRange],
+        remaining: &mut Option<usize>,
+        descending: bool,
+    ) -> Result<(LinkedList<Op>, (bool, bool))> {
+        let key = self.tree().key();
+        let contains_key = ranges.iter().any(|range| range.contains(key));
+
+        // since the ranges are sorted and don't overlap, the ranges which
+        // extend below the key form a prefix of the slice and the ranges which
+        // extend above the key form a suffix
+        let left_len = ranges
+            .iter()
+            .take_while(|range| range.extends_below(key))
+            .count();
+        let right_start = ranges
+            .iter()
+            .position(|range| range.extends_above(key))
+            .unwrap_or_else(|| ranges.len());
+        let (left_ranges, right_ranges) = (&ranges[..left_len], &ranges[right_start..]);
+
+        // entries count against the limit in the order they are visited, so
+        // when descending the right child is visited before the left
+        let (first_ranges, second_ranges) = if descending {
+            (right_ranges, left_ranges)
+        } else {
+            (left_ranges, right_ranges)
+        };
+
+        let walk_first = !first_ranges.is_empty() && *remaining != Some(0);
+        let first = self.create_child_range_proof(
+            !descending,
+            walk_first,
+            first_ranges,
+            remaining,
+            descending,
+        )?;
+
+        let in_range = contains_key && *remaining != Some(0);
+        if in_range {
+            if let Some(remaining) = remaining.as_mut() {
+                *remaining -= 1;
+            }
+        }
+
+        let walk_second = !second_ranges.is_empty() && *remaining != Some(0);
+        let second = self.create_child_range_proof(
+            descending,
+            walk_second,
+            second_ranges,
+            remaining,
+            descending,
+        )?;
+
+        let ((mut proof, left_absence), (mut right_proof, right_absence)) = if descending {
+            (second, first)
+        } else {
+            (first, second)
+        };
+
+        let (has_left, has_right) = (!proof.is_empty(), !right_proof.is_empty());
+
+        proof.push_back(if in_range || left_absence.1 || right_absence.0 {
+            Op::Push(self.to_kv_node())
+        } else {
+            Op::Push(self.to_kvhash_node())
+        });
+
+        if has_left {
+            proof.push_back(Op::Parent);
+        }
+
+        if has_right {
+            proof.append(&mut right_proof);
+            proof.push_back(Op::Child);
+        }
+
+        Ok((proof, (left_absence.0, right_absence.1)))
+    }
+
+    /// Similar to `create_child_proof`. Recurses into the child on the given
+    /// side if `walk` is `true`, otherwise includes the child as a
+    /// `Node::Hash`.
+    fn create_child_range_proof(
+        &mut self,
+        left: bool,
+        walk: bool,
+        ranges: &[QueryRange],
+        remaining: &mut Option<usize>,
+        descending: bool,
+    ) -> Result<(LinkedList<Op>, (bool, bool))> {
+        Ok(if walk {
+            if let Some(mut child) = self.walk(left)? {
+                child.create_range_proof_inner(ranges, remaining, descending)?
+            } else {
+                (LinkedList::new(), (true, true))
+            }
+        } else if let Some(link) = self.tree().link(left) {
+            let mut proof = LinkedList::new();
+            proof.push_back(Op::Push(link.to_hash_node()));
+            (proof, (false, false))
+        } else {
+            (LinkedList::new(), (false, false))
+        })
+    }
+}
+
+/// An item in a batched query, passed to `create_query_proof` and
+/// `verify_query_items`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum QueryItem {
+    /// Queries a single key, proving either its value or its absence.
+    Key(Vec<u8>),
+
+    /// Queries all entries with keys between the given bounds.
+    Range(Bound<Vec<u8>>, Bound<Vec<u8>>),
+
+    /// Queries all entries with keys from the first key up to and including
+    /// the second key. The range is empty if the second key is less than the
+    /// first.
+    RangeInclusive(Vec<u8>, Vec<u8>),
+
+    /// Queries all entries with keys greater than or equal to the given key.
+    RangeFrom(Vec<u8>),
+
+    /// Queries all entries with keys less than the given key.
+    RangeTo(Vec<u8>),
+}
+
+impl QueryItem {
+    /// Returns `true` if `key` is matched by the query.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        QueryRange::from(self).contains(key)
+    }
+}
+
+/// A range of keys being queried while a proof is generated or verified.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct QueryRange {
+    pub(crate) start: Bound<Vec<u8>>,
+    pub(crate) end: Bound<Vec<u8>>,
+}
+
+impl QueryRange {
+    /// Returns `true` if no key can be within the range, because its upper
+    /// bound is below its lower bound (or equal to it, if either is
+    /// exclusive).
+    fn is_empty(&self) -> bool {
+        match (&self.start, &self.end) {
+            (Bound::Unbounded, _) | (_, Bound::Unbounded) => false,
+            (Bound::Included(start), Bound::Included(end)) => end < start,
+            (Bound::Included(start), Bound::Excluded(end))
+            | (Bound::Excluded(start), Bound::Included(end))
+            | (Bound::Excluded(start), Bound::Excluded(end)) => end <= start,
+        }
+    }
+
+    /// Returns `true` if `key` is within the range.
+    pub(crate) fn contains(&self, key: &[u8]) -> bool {
+        !is_below_start(&self.start, key) && !is_above_end(&self.end, key)
+    }
+
+    /// Returns `true` if the range may contain keys less than `key`, or if
+    /// `key` is the first key in the range (so the entry before it is needed
+    /// as a boundary).
+    fn extends_below(&self, key: &[u8]) -> bool {
+        match &self.start {
+            Bound::Included(start) => start.as_slice() <= key,
+            Bound::Excluded(start) => start.as_slice() < key,
+            Bound::Unbounded => true,
+        }
+    }
+
+    /// Returns `true` if the range may contain keys greater than `key`, or if
+    /// `key` is the last key in the range (so the entry after it is needed as
+    /// a boundary).
+    fn extends_above(&self, key: &[u8]) -> bool {
+        match &self.end {
+            Bound::Included(end) => end.as_slice() >= key,
+            Bound::Excluded(end) => end.as_slice() > key,
+            Bound::Unbounded => true,
+        }
+    }
+
+    /// Returns `true` if this range ends before `other` starts, with at least
+    /// one key in between (so the two ranges can not be merged).
+    fn ends_before(&self, other: &QueryRange) -> bool {
+        match (&self.end, &other.start) {
+            (Bound::Unbounded, _) | (_, Bound::Unbounded) => false,
+            (Bound::Excluded(end), Bound::Excluded(start)) => end <= start,
+            (Bound::Included(end), Bound::Included(start))
+            | (Bound::Included(end), Bound::Excluded(start))
+            | (Bound::Excluded(end), Bound::Included(start)) => end < start,
+        }
+    }
+}
+
+impl From<&QueryItem> for QueryRange {
+    fn from(item: &QueryItem) -> QueryRange {
+        match item {
+            QueryItem::Key(key) => QueryRange {
+                start: Bound::Included(key.clone()),
+                end: Bound::Included(key.clone()),
+            },
+            QueryItem::Range(start, end) => QueryRange {
+                start: start.clone(),
+                end: end.clone(),
+            },
+            QueryItem::RangeInclusive(start, end) => QueryRange {
+                start: Bound::Included(start.clone()),
+                end: Bound::Included(end.clone()),
+            },
+            QueryItem::RangeFrom(start) => QueryRange {
+                start: Bound::Included(start.clone()),
+                end: Bound::Unbounded,
+            },
+            QueryItem::RangeTo(end) => QueryRange {
+                start: Bound::Unbounded,
+                end: Bound::Excluded(end.clone()),
+            },
+        }
+    }
+}
+
+/// Converts the query items to ranges, sorted by their lower bounds, merging
+/// any ranges which overlap or touch. Empty ranges are dropped, since they
+/// don't need to be proven.
+pub(crate) fn merge_query_items(items: &[QueryItem]) -> Vec<QueryRange> {
+    let mut ranges: Vec<QueryRange> = items
+        .iter()
+        .map(QueryRange::from)
+        .filter(|range| !range.is_empty())
+        .collect();
+    ranges.sort_by(|a, b| cmp_start(&a.start, &b.start));
+
+    let mut merged: Vec<QueryRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if !last.ends_before(&range) => {
+                if cmp_end(&range.end, &last.end) == Ordering::Greater {
+                    last.end = range.end;
+                }
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Compares two lower bounds, where the lower of the two includes more keys.
+fn cmp_start(a: &Bound<Vec<u8>>, b: &Bound<Vec<u8>>) -> Ordering {
+    match (a, b) {
+        (Bound::Unbounded, Bound::Unbounded) => Ordering::Equal,
+        (Bound::Unbounded, _) => Ordering::Less,
+        (_, Bound::Unbounded) => Ordering::Greater,
+        (Bound::Included(a), Bound::Excluded(b)) if a == b => Ordering::Less,
+        (Bound::Excluded(a), Bound::Included(b)) if a == b => Ordering::Greater,
+        (Bound::Included(a), Bound::Included(b))
+        | (Bound::Included(a), Bound::Excluded(b))
+        | (Bound::Excluded(a), Bound::Included(b))
+        | (Bound::Excluded(a), Bound::Excluded(b)) => a.cmp(b),
+    }
+}
+
+/// Compares two upper bounds, where the greater of the two includes more keys.
+fn cmp_end(a: &Bound<Vec<u8>>, b: &Bound<Vec<u8>>) -> Ordering {
+    match (a, b) {
+        (Bound::Unbounded, Bound::Unbounded) => Ordering::Equal,
+        (Bound::Unbounded, _) => Ordering::Greater,
+        (_, Bound::Unbounded) => Ordering::Less,
+        (Bound::Included(a), Bound::Excluded(b)) if a == b => Ordering::Greater,
+        (Bound::Excluded(a), Bound::Included(b)) if a == b => Ordering::Less,
+        (Bound::Included(a), Bound::Included(b))
+        | (Bound::Included(a), Bound::Excluded(b))
+        | (Bound::Excluded(a), Bound::Included(b))
+        | (Bound::Excluded(a), Bound::Excluded(b)) => a.cmp(b),
+    }
+}
+
+/// Returns the bounds of the range containing exactly the keys which start with
+/// `prefix`: from `prefix` (inclusive) up to the prefix's successor, the
+/// smallest key greater than all keys starting with `prefix` (exclusive).
+///
+/// The successor is computed by dropping any trailing `0xff` bytes and
+/// incrementing the last remaining byte. If there is no remaining byte (the
+/// prefix is empty or made up only of `0xff` bytes), the range is unbounded on
+/// the right.
+pub fn prefix_bounds(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let start = if prefix.is_empty() {
+        Bound::Unbounded
+    } else {
+        Bound::Included(prefix.to_vec())
+    };
+
+    let mut successor = prefix.to_vec();
+    while let Some(&0xff) = successor.last() {
+        successor.pop();
+    }
+    let end = match successor.last_mut() {
+        Some(byte) => {
+            *byte += 1;
+            Bound::Excluded(successor)
+        }
+        None => Bound::Unbounded,
+    };
+
+    (start, end)
+}
+
+/// Generates a proof for the entries with keys in the range between `start`
+/// and `end` as of an earlier version of the tree, given the key and hash of
+/// that version's root node. The proof is created the same way as in
+/// `RefWalker::create_range_proof`, but every node is fetched from `source`,
+/// which must still contain the nodes of that version (e.g. a `MemStore`, which
+/// keeps every node it is written, keyed by hash).
+///
+/// Returns `Err` if the root node can't be fetched or does not match
+/// `root_hash`. The resulting proof can be checked with `verify_range` against
+/// `root_hash`, regardless of later changes to the tree.
+pub fn create_historical_range_proof<S>(
+    source: S,
+    root_key: &[u8],
+    root_hash: Hash,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+) -> Result<Vec<Op>>
+where
+    S: Fetch + Sized + Send + Clone,
+{
+    // only the key and hash are needed to fetch the node
+    let root = Link::Reference {
+        hash: root_hash,
+        child_heights: (0, 0),
+        descendant_count: 0,
+        aggregate: NoAggregate,
+        key: root_key.to_vec(),
+    };
+
+    let mut tree = source.fetch(&root)?;
+    if tree.hash() != root_hash {
+        bail!("Fetched root node did not match the historical root hash");
+    }
+
+    RefWalker::new(&mut tree, source).create_range_proof(start, end, limit, offset)
+}
+
+/// Returns `true` if `key` is less than the range's lower bound.
+pub(crate) fn is_below_start(start: &Bound<Vec<u8>>, key: &[u8]) -> bool {
+    match start {
+        Bound::Included(start) => key < start.as_slice(),
+        Bound::Excluded(start) => key <= start.as_slice(),
+        Bound::Unbounded => false,
+    }
+}
+
+/// Returns `true` if `key` is greater than the range's upper bound.
+pub(crate) fn is_above_end(end: &Bound<Vec<u8>>, key: &[u8]) -> bool {
+    match end {
+        Bound::Included(end) => key > end.as_slice(),
+        Bound::Excluded(end) => key >= end.as_slice(),
+        Bound::Unbounded => false,
+    }
 }
 
 #[cfg(test)]
@@ -118,11 +734,15 @@ mod test {
             [105; 20],
             Some(Link::Loaded {
                 child_heights: (0, 0),
+                descendant_count: 1,
+                aggregate: NoAggregate,
                 hash: [3; 20],
                 tree: Tree::from_fields(vec![3], vec![3], [103; 20], None, None),
             }),
             Some(Link::Loaded {
                 child_heights: (0, 0),
+                descendant_count: 1,
+                aggregate: NoAggregate,
                 hash: [7; 20],
                 tree: Tree::from_fields(vec![7], vec![7], [107; 20], None, None),
             }),
@@ -270,6 +890,8 @@ mod test {
             [105; 20],
             Some(Link::Loaded {
                 child_heights: (0, 0),
+                descendant_count: 4,
+                aggregate: NoAggregate,
                 hash: [2; 20],
                 tree: Tree::from_fields(
                     vec![2],
@@ -277,11 +899,15 @@ mod test {
                     [102; 20],
                     Some(Link::Loaded {
                         child_heights: (0, 0),
+                        descendant_count: 1,
+                        aggregate: NoAggregate,
                         hash: [1; 20],
                         tree: Tree::from_fields(vec![1], vec![1], [101; 20], None, None),
                     }),
                     Some(Link::Loaded {
                         child_heights: (0, 0),
+                        descendant_count: 2,
+                        aggregate: NoAggregate,
                         hash: [4; 20],
                         tree: Tree::from_fields(
                             vec![4],
@@ -289,6 +915,8 @@ mod test {
                             [104; 20],
                             Some(Link::Loaded {
                                 child_heights: (0, 0),
+                                descendant_count: 1,
+                                aggregate: NoAggregate,
                                 hash: [3; 20],
                                 tree: Tree::from_fields(vec![3], vec![3], [103; 20], None, None),
                             }),
@@ -299,6 +927,8 @@ mod test {
             }),
             Some(Link::Loaded {
                 child_heights: (0, 0),
+                descendant_count: 6,
+                aggregate: NoAggregate,
                 hash: [9; 20],
                 tree: Tree::from_fields(
                     vec![9],
@@ -306,6 +936,8 @@ mod test {
                     [109; 20],
                     Some(Link::Loaded {
                         child_heights: (0, 0),
+                        descendant_count: 3,
+                        aggregate: NoAggregate,
                         hash: [7; 20],
                         tree: Tree::from_fields(
                             vec![7],
@@ -313,11 +945,15 @@ mod test {
                             [107; 20],
                             Some(Link::Loaded {
                                 child_heights: (0, 0),
+                                descendant_count: 1,
+                                aggregate: NoAggregate,
                                 hash: [6; 20],
                                 tree: Tree::from_fields(vec![6], vec![6], [106; 20], None, None),
                             }),
                             Some(Link::Loaded {
                                 child_heights: (0, 0),
+                                descendant_count: 1,
+                                aggregate: NoAggregate,
                                 hash: [8; 20],
                                 tree: Tree::from_fields(vec![8], vec![8], [108; 20], None, None),
                             }),
@@ -325,6 +961,8 @@ mod test {
                     }),
                     Some(Link::Loaded {
                         child_heights: (0, 0),
+                        descendant_count: 2,
+                        aggregate: NoAggregate,
                         hash: [11; 20],
                         tree: Tree::from_fields(
                             vec![11],
@@ -332,6 +970,8 @@ mod test {
                             [111; 20],
                             Some(Link::Loaded {
                                 child_heights: (0, 0),
+                                descendant_count: 1,
+                                aggregate: NoAggregate,
                                 hash: [10; 20],
                                 tree: Tree::from_fields(vec![10], vec![10], [110; 20], None, None),
                             }),
@@ -374,4 +1014,48 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn prefix_bounds_successor() {
+        assert_eq!(
+            prefix_bounds(&[1, 2, 3]),
+            (
+                Bound::Included(vec![1, 2, 3]),
+                Bound::Excluded(vec![1, 2, 4])
+            )
+        );
+        assert_eq!(
+            prefix_bounds(&[1, 0xff, 0xff]),
+            (
+                Bound::Included(vec![1, 0xff, 0xff]),
+                Bound::Excluded(vec![2])
+            )
+        );
+        assert_eq!(
+            prefix_bounds(&[0xff, 0xff]),
+            (Bound::Included(vec![0xff, 0xff]), Bound::Unbounded)
+        );
+        assert_eq!(prefix_bounds(&[]), (Bound::Unbounded, Bound::Unbounded));
+    }
+
+    #[test]
+    fn merge_query_items_sorts_and_merges() {
+        let range = |start, end| QueryRange { start, end };
+        let items = vec![
+            QueryItem::Range(Bound::Included(vec![5]), Bound::Excluded(vec![8])),
+            QueryItem::Key(vec![2]),
+            QueryItem::Key(vec![8]),
+            QueryItem::Range(Bound::Excluded(vec![9]), Bound::Unbounded),
+            QueryItem::Key(vec![6]),
+            QueryItem::Key(vec![2]),
+        ];
+        assert_eq!(
+            merge_query_items(items.as_slice()),
+            vec![
+                range(Bound::Included(vec![2]), Bound::Included(vec![2])),
+                range(Bound::Included(vec![5]), Bound::Included(vec![8])),
+                range(Bound::Excluded(vec![9]), Bound::Unbounded),
+            ]
+        );
+    }
 }
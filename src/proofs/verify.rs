@@ -1,27 +1,34 @@
+use super::query::{is_above_end, is_below_start, merge_query_items, prefix_bounds, QueryItem};
 use super::{Decoder, Node, Op};
-use crate::error::Result;
-use crate::tree::{kv_hash, node_hash, Hash, NULL_HASH};
+use crate::error::{ErrorKind, Result};
+use crate::tree::{Blake2bCountedHasher, Blake2bHasher, CtEq, Hash, Hasher, NoopCommit, NULL_HASH};
 use failure::bail;
+use std::collections::{BTreeMap, HashMap};
+use std::marker::PhantomData;
+use std::ops::Bound;
 
-/// Contains a tree's child node and its hash. The hash can always be assumed to
-/// be up-to-date.
+/// Contains a tree's child node, its hash, and the number of nodes in its
+/// subtree (see `Tree::count`). The hash and count can always be assumed to be
+/// up-to-date.
 #[derive(Debug)]
-pub(crate) struct Child {
-    pub(crate) tree: Box<Tree>,
+pub(crate) struct Child<H = Blake2bHasher> {
+    pub(crate) tree: Box<Tree<H>>,
     pub(crate) hash: Hash,
+    pub(crate) count: u64,
 }
 
 /// A binary tree data structure used to represent a select subset of a tree
-/// when verifying Merkle proofs.
+/// when verifying Merkle proofs. Node hashes are computed with the hasher `H`.
 #[derive(Debug)]
-pub(crate) struct Tree {
+pub(crate) struct Tree<H = Blake2bHasher> {
     pub(crate) node: Node,
-    pub(crate) left: Option<Child>,
-    pub(crate) right: Option<Child>,
+    pub(crate) left: Option<Child<H>>,
+    pub(crate) right: Option<Child<H>>,
     pub(crate) height: usize,
+    hasher: PhantomData<H>,
 }
 
-impl From<Node> for Tree {
+impl<H> From<Node> for Tree<H> {
     /// Creates a childless tree with the target node as the `node` field.
     fn from(node: Node) -> Self {
         Tree {
@@ -29,20 +36,21 @@ impl From<Node> for Tree {
             left: None,
             right: None,
             height: 1,
+            hasher: PhantomData,
         }
     }
 }
 
-impl PartialEq for Tree {
+impl<H: Hasher<Digest = Hash>> PartialEq for Tree<H> {
     /// Checks equality for the hashes of the two trees.
     fn eq(&self, other: &Self) -> bool {
         self.hash() == other.hash()
     }
 }
 
-impl Tree {
+impl<H: Hasher<Digest = Hash>> Tree<H> {
     /// Returns an immutable reference to the child on the given side, if any.
-    pub(crate) fn child(&self, left: bool) -> Option<&Child> {
+    pub(crate) fn child(&self, left: bool) -> Option<&Child<H>> {
         if left {
             self.left.as_ref()
         } else {
@@ -51,7 +59,7 @@ impl Tree {
     }
 
     /// Returns a mutable reference to the child on the given side, if any.
-    fn child_mut(&mut self, left: bool) -> &mut Option<Child> {
+    fn child_mut(&mut self, left: bool) -> &mut Option<Child<H>> {
         if left {
             &mut self.left
         } else {
@@ -59,18 +67,25 @@ impl Tree {
         }
     }
 
-    /// Attaches the child to the `Tree`'s given side. Panics if there is
-    /// already a child attached to this side.
-    fn attach(&mut self, left: bool, child: Tree) -> Result<()> {
+    /// Attaches the child to the `Tree`'s given side, computing its hash with
+    /// `pending` if given. Panics if there is already a child attached to this
+    /// side.
+    fn attach(
+        &mut self,
+        left: bool,
+        child: Self,
+        pending: Option<&mut PendingHashes>,
+    ) -> Result<()> {
         if self.child(left).is_some() {
             bail!("Tried to attach to left child, but it is already Some");
         }
 
         self.height = self.height.max(child.height + 1);
 
-        let hash = child.hash();
+        let hash = child.hash_with(pending);
+        let count = child.count();
         let tree = Box::new(child);
-        *self.child_mut(left) = Some(Child { tree, hash });
+        *self.child_mut(left) = Some(Child { tree, hash, count });
 
         Ok(())
     }
@@ -83,34 +98,63 @@ impl Tree {
         self.child(left).map_or(NULL_HASH, |c| c.hash)
     }
 
-    /// Consumes the tree node, calculates its hash, and returns a `Node::Hash`
-    /// variant.
-    fn into_hash(self) -> Tree {
-        Node::Hash(self.hash()).into()
+    /// Returns the already-computed number of nodes in the subtree of this tree
+    /// node's child on the given side, or 0 if there is no child.
+    #[inline]
+    fn child_count(&self, left: bool) -> u64 {
+        self.child(left).map_or(0, |c| c.count)
+    }
+
+    /// Returns the number of nodes in the subtree of this tree node. A
+    /// `Node::CountedHash` counts as the nodes of the subtree it abridges, and a
+    /// `Node::Hash` as 0 nodes since its count is unknown.
+    pub(crate) fn count(&self) -> u64 {
+        match self.node {
+            Node::Hash(_) => 0,
+            Node::CountedHash(_, count) => count,
+            _ => 1 + self.child_count(true) + self.child_count(false),
+        }
+    }
+
+    /// Consumes the tree node, calculates its hash (with `pending` if given),
+    /// and returns a `Node::Hash` variant, or a `Node::CountedHash` variant
+    /// for hashers of version 3 and later.
+    fn into_hash(self, pending: Option<&mut PendingHashes>) -> Self {
+        let hash = self.hash_with(pending);
+        if H::VERSION >= 3 {
+            Node::CountedHash(hash, self.count()).into()
+        } else {
+            Node::Hash(hash).into()
+        }
     }
 
     /// Gets or computes the hash for this tree node.
     pub(crate) fn hash(&self) -> Hash {
-        fn compute_hash(tree: &Tree, kv_hash: Hash) -> Hash {
-            node_hash(&kv_hash, &tree.child_hash(true), &tree.child_hash(false))
-        }
+        self.hash_with(None)
+    }
+
+    /// Gets or computes the hash for this tree node, looking up and recording
+    /// the computed node hash in `pending` if given.
+    fn hash_with(&self, pending: Option<&mut PendingHashes>) -> Hash {
+        let compute_hash = |kv_hash: Hash| {
+            let (left, right) = (self.child_hash(true), self.child_hash(false));
+            let counts = (self.child_count(true), self.child_count(false));
+            match pending {
+                Some(pending) => pending.hash_node::<H>(kv_hash, left, right, counts),
+                None => H::hash_counted_node(&kv_hash, &left, &right, counts),
+            }
+        };
 
         match &self.node {
-            Node::Hash(hash) => *hash,
-            Node::KVHash(kv_hash) => compute_hash(&self, *kv_hash),
-            Node::KV(key, value) => {
-                let kv_hash = kv_hash(key.as_slice(), value.as_slice());
-                compute_hash(&self, kv_hash)
+            Node::Hash(hash) | Node::CountedHash(hash, _) => *hash,
+            Node::KVHash(kv_hash) => compute_hash(*kv_hash),
+            Node::KV(key, value) => compute_hash(H::hash_kv(key.as_slice(), value.as_slice())),
+            Node::KVDigest(key, value_hash) => {
+                compute_hash(H::hash_kv_digest(key.as_slice(), value_hash))
             }
         }
     }
 
-    /// Creates an iterator that yields the in-order traversal of the nodes at
-    /// the given depth.
-    pub(crate) fn layer(&self, depth: usize) -> LayerIter {
-        LayerIter::new(self, depth)
-    }
-
     /// Consumes the `Tree` and does an in-order traversal over all the nodes in
     /// the tree, calling `visit_node` for each.
     #[allow(dead_code)] // (only used in tests for now)
@@ -129,7 +173,7 @@ impl Tree {
 
     /// Does an in-order traversal over references to all the nodes in the tree,
     /// calling `visit_node` for each.
-    pub(crate) fn visit_refs<F: FnMut(&Tree)>(&self, visit_node: &mut F) {
+    pub(crate) fn visit_refs<F: FnMut(&Self)>(&self, visit_node: &mut F) {
         if let Some(child) = &self.left {
             child.tree.visit_refs(visit_node);
         }
@@ -143,12 +187,20 @@ impl Tree {
 
     pub(crate) fn key(&self) -> &[u8] {
         match self.node {
-            Node::KV(ref key, _) => key,
-            _ => panic!("Expected node to be type KV"),
+            Node::KV(ref key, _) | Node::KVDigest(ref key, _) => key,
+            _ => panic!("Expected node to be type KV or KVDigest"),
         }
     }
 }
 
+impl Tree {
+    /// Creates an iterator that yields the in-order traversal of the nodes at
+    /// the given depth.
+    pub(crate) fn layer(&self, depth: usize) -> LayerIter {
+        LayerIter::new(self, depth)
+    }
+}
+
 /// `LayerIter` iterates over the nodes in a `Tree` at a given depth. Nodes are
 /// visited in order.
 pub(crate) struct LayerIter<'a> {
@@ -223,64 +275,137 @@ impl<'a> Iterator for LayerIter<'a> {
 /// If the `collapse` option is set to `true`, nodes will be hashed and pruned
 /// from memory during execution. This results in the minimum amount of memory
 /// usage, and the returned `Tree` will only contain a single node of type
-/// `Node::Hash`. If `false`, the returned `Tree` will contain the entire
+/// `Node::Hash` (or `Node::CountedHash`, for hashers of version 3 and later). If `false`, the returned `Tree` will contain the entire
 /// subtree contained in the proof.
 ///
 /// `visit_node` will be called once for every push operation in the proof, in
 /// key-order. If `visit_node` returns an `Err` result, it will halt the
 /// execution and `execute` will return the error.
-pub(crate) fn execute<I, F>(ops: I, collapse: bool, mut visit_node: F) -> Result<Tree>
+pub(crate) fn execute<H, I, F>(ops: I, collapse: bool, visit_node: F) -> Result<Tree<H>>
+where
+    H: Hasher<Digest = Hash>,
+    I: IntoIterator<Item = Result<Op>>,
+    F: FnMut(&Node) -> Result<()>,
+{
+    execute_with(ops, collapse, visit_node, None)
+}
+
+/// Like `execute`, but computes node hashes with `pending` if given (see
+/// `VerifyCache`).
+fn execute_with<H, I, F>(
+    ops: I,
+    collapse: bool,
+    mut visit_node: F,
+    mut pending: Option<&mut PendingHashes>,
+) -> Result<Tree<H>>
 where
+    H: Hasher<Digest = Hash>,
     I: IntoIterator<Item = Result<Op>>,
     F: FnMut(&Node) -> Result<()>,
 {
-    let mut stack: Vec<Tree> = Vec::with_capacity(32);
+    let mut stack: Vec<Tree<H>> = Vec::with_capacity(32);
     let mut maybe_last_key = None;
 
-    fn try_pop(stack: &mut Vec<Tree>) -> Result<Tree> {
+    for op in ops {
+        execute_op(
+            &mut stack,
+            &mut maybe_last_key,
+            op?,
+            collapse,
+            &mut visit_node,
+            pending.as_deref_mut(),
+        )?;
+    }
+
+    if stack.len() != 1 {
+        bail!("Expected proof to result in exactly one stack item");
+    }
+
+    Ok(stack.pop().unwrap())
+}
+
+/// Executes a single proof operator, modifying the verification stack (see
+/// `execute`). `maybe_last_key` holds the key of the last key/value node pushed,
+/// so keys can be checked to be in increasing order. `visit_node` is called if
+/// the operator pushes a node. Node hashes are computed with `pending` if given.
+fn execute_op<H, F>(
+    stack: &mut Vec<Tree<H>>,
+    maybe_last_key: &mut Option<Vec<u8>>,
+    op: Op,
+    collapse: bool,
+    visit_node: F,
+    mut pending: Option<&mut PendingHashes>,
+) -> Result<()>
+where
+    H: Hasher<Digest = Hash>,
+    F: FnOnce(&Node) -> Result<()>,
+{
+    fn try_pop<H>(stack: &mut Vec<Tree<H>>) -> Result<Tree<H>> {
         match stack.pop() {
             None => bail!("Stack underflow"),
             Some(tree) => Ok(tree),
         }
     }
 
-    for op in ops {
-        match op? {
-            Op::Parent => {
-                let (mut parent, child) = (try_pop(&mut stack)?, try_pop(&mut stack)?);
-                parent.attach(true, if collapse { child.into_hash() } else { child })?;
-                stack.push(parent);
-            }
-            Op::Child => {
-                let (child, mut parent) = (try_pop(&mut stack)?, try_pop(&mut stack)?);
-                parent.attach(false, if collapse { child.into_hash() } else { child })?;
-                stack.push(parent);
+    match op {
+        Op::Parent => {
+            let (mut parent, child) = (try_pop(stack)?, try_pop(stack)?);
+            let child = if collapse {
+                child.into_hash(pending.as_deref_mut())
+            } else {
+                child
+            };
+            parent.attach(true, child, pending)?;
+            stack.push(parent);
+        }
+        Op::Child => {
+            let (child, mut parent) = (try_pop(stack)?, try_pop(stack)?);
+            let child = if collapse {
+                child.into_hash(pending.as_deref_mut())
+            } else {
+                child
+            };
+            parent.attach(false, child, pending)?;
+            stack.push(parent);
+        }
+        Op::Push(node) => {
+            if let Node::KVDigest(_, _) = &node {
+                if H::VERSION < 2 {
+                    bail!("Hashers before version 2 cannot verify KVDigest nodes");
+                }
             }
-            Op::Push(node) => {
-                if let Node::KV(key, _) = &node {
-                    // keys should always increase
-                    if let Some(last_key) = &maybe_last_key {
-                        if key <= last_key {
-                            bail!("Incorrect key ordering");
-                        }
-                    }
 
-                    maybe_last_key = Some(key.clone());
+            // node hashes of version 3 include their children's counts, so
+            // abridged subtrees must give theirs
+            match &node {
+                Node::Hash(_) if H::VERSION >= 3 => {
+                    bail!("Hashers of version 3 and later cannot verify Hash nodes")
                 }
+                Node::CountedHash(_, _) if H::VERSION < 3 => {
+                    bail!("Hashers before version 3 cannot verify CountedHash nodes")
+                }
+                _ => {}
+            }
 
-                visit_node(&node)?;
+            if let Node::KV(key, _) | Node::KVDigest(key, _) = &node {
+                // keys should always increase
+                if let Some(last_key) = maybe_last_key {
+                    if key <= last_key {
+                        return Err(ErrorKind::UnsortedNodes.into());
+                    }
+                }
 
-                let tree: Tree = node.into();
-                stack.push(tree);
+                *maybe_last_key = Some(key.clone());
             }
-        }
-    }
 
-    if stack.len() != 1 {
-        bail!("Expected proof to result in exactly one stack item");
+            visit_node(&node)?;
+
+            let tree: Tree<H> = node.into();
+            stack.push(tree);
+        }
     }
 
-    Ok(stack.pop().unwrap())
+    Ok(())
 }
 
 /// Verifies the encoded proof with the given query and expected hash.
@@ -293,29 +418,112 @@ where
 /// list will contain 2 elements, the value of `A` and the value of `B`. Keys
 /// proven to be absent in the tree will have an entry of `None`, keys that have
 /// a proven value will have an entry of `Some(value)`.
+///
+/// An empty proof is a proof of the empty tree, which proves the absence of
+/// every key, and is valid only when `expected_hash` is the null hash.
 pub fn verify_query(
     bytes: &[u8],
     keys: &[Vec<u8>],
     expected_hash: Hash,
 ) -> Result<Vec<Option<Vec<u8>>>> {
+    verify_query_with_hasher::<Blake2bHasher>(bytes, keys, expected_hash)
+}
+
+/// Like `verify_query`, but computes the proof's hashes with the given hasher
+/// rather than the default (`Blake2bHasher`). Proofs will only verify when `H`
+/// is the same hasher used to hash the tree which generated them (see
+/// `Tree::with_hasher`).
+pub fn verify_query_with_hasher<H: Hasher<Digest = Hash>>(
+    bytes: &[u8],
+    keys: &[Vec<u8>],
+    expected_hash: Hash,
+) -> Result<Vec<Option<Vec<u8>>>> {
+    verify_query_nodes::<H>(bytes, keys, expected_hash, None)?
+        .into_iter()
+        .map(|maybe_node| match maybe_node {
+            None => Ok(None),
+            Some(Node::KV(_, value)) => Ok(Some(value)),
+            Some(_) => Err(ErrorKind::MissingProofData.into()),
+        })
+        .collect()
+}
+
+/// Verifies the encoded proof with the given query and expected hash, like
+/// `verify_query`, but accepts proofs where the queried entries are
+/// `Node::KVDigest` nodes (see `RefWalker::create_digest_proof`).
+///
+/// Returns `Err` if the proof is invalid, or a list of the value hashes (see
+/// `value_hash`) associated with `keys`. Keys proven to be absent in the tree
+/// will have an entry of `None`.
+pub fn verify_query_digests(
+    bytes: &[u8],
+    keys: &[Vec<u8>],
+    expected_hash: Hash,
+) -> Result<Vec<Option<Hash>>> {
+    verify_query_digests_with_hasher::<Blake2bHasher>(bytes, keys, expected_hash)
+}
+
+/// Verifies the encoded proof like `verify_query_digests`, but computes hashes
+/// with `H` rather than the default `Blake2bHasher`. Value hashes of
+/// `Node::KV` entries are computed with `H::hash_value`.
+///
+/// Returns `Err` if the proof contains `Node::KVDigest` nodes and `H` is
+/// earlier than version 2, since those hashers cannot hash key/value digests.
+pub fn verify_query_digests_with_hasher<H: Hasher<Digest = Hash>>(
+    bytes: &[u8],
+    keys: &[Vec<u8>],
+    expected_hash: Hash,
+) -> Result<Vec<Option<Hash>>> {
+    Ok(verify_query_nodes::<H>(bytes, keys, expected_hash, None)?
+        .into_iter()
+        .map(|maybe_node| match maybe_node {
+            Some(Node::KV(_, value)) => Some(H::hash_value(value.as_slice())),
+            Some(Node::KVDigest(_, value_hash)) => Some(value_hash),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Verifies the encoded proof with the given query and expected hash, returning
+/// the `Node::KV` or `Node::KVDigest` node for each key in `keys` which is
+/// present in the tree. Node hashes are computed with `pending` if given.
+fn verify_query_nodes<H: Hasher<Digest = Hash>>(
+    bytes: &[u8],
+    keys: &[Vec<u8>],
+    expected_hash: Hash,
+    mut pending: Option<&mut PendingHashes>,
+) -> Result<Vec<Option<Node>>> {
+    // the empty tree has the null hash and no nodes to prove, so every key is
+    // absent
+    if bytes.is_empty() {
+        if expected_hash != NULL_HASH {
+            return Err(ErrorKind::HashMismatch {
+                expected: expected_hash,
+                actual: NULL_HASH,
+            }
+            .into());
+        }
+        return Ok(vec![None; keys.len()]);
+    }
+
     let mut key_index = 0;
     let mut last_push = None;
     let mut output = Vec::with_capacity(keys.len());
 
     let ops = Decoder::new(bytes);
 
-    let root = execute(ops, true, |node| {
-        if let Node::KV(key, value) = node {
+    let visit_node = |node: &Node| {
+        if let Node::KV(key, _) | Node::KVDigest(key, _) = node {
             loop {
                 if key_index >= keys.len() || *key < keys[key_index] {
                     // TODO: should we error if proof includes unused keys?
                     break;
                 } else if key == &keys[key_index] {
                     // KV for queried key
-                    output.push(Some(value.clone()));
+                    output.push(Some(node.clone()));
                 } else if *key > keys[key_index] {
                     match &last_push {
-                        None | Some(Node::KV(_, _)) => {
+                        None | Some(Node::KV(_, _)) | Some(Node::KVDigest(_, _)) => {
                             // previous push was a boundary (global edge or lower key),
                             // so this is a valid absence proof
                             output.push(None);
@@ -332,11 +540,12 @@ pub fn verify_query(
         last_push = Some(node.clone());
 
         Ok(())
-    })?;
+    };
+    let root: Tree<H> = execute_with(ops, true, visit_node, pending.as_deref_mut())?;
 
     // absence proofs for right edge
     if key_index < keys.len() {
-        if let Some(Node::KV(_, _)) = last_push {
+        if let Some(Node::KV(_, _)) | Some(Node::KVDigest(_, _)) = last_push {
             for _ in 0..(keys.len() - key_index) {
                 output.push(None);
             }
@@ -347,172 +556,2099 @@ pub fn verify_query(
         debug_assert_eq!(keys.len(), output.len());
     }
 
-    if root.hash() != expected_hash {
-        bail!(
-            "Proof did not match expected hash\n\tExpected: {:?}\n\tActual: {:?}",
-            expected_hash,
-            root.hash()
-        );
+    let root_hash = root.hash_with(pending);
+    if !root_hash.ct_eq(&expected_hash) {
+        return Err(ErrorKind::HashMismatch {
+            expected: expected_hash,
+            actual: root_hash,
+        }
+        .into());
     }
 
     Ok(output)
 }
 
-#[cfg(test)]
-mod test {
-    use super::super::*;
-    use super::*;
-    use crate::tree;
-    use crate::tree::{NoopCommit, PanicSource, RefWalker};
-
-    fn make_3_node_tree() -> tree::Tree {
-        let mut tree = tree::Tree::new(vec![5], vec![5])
-            .attach(true, Some(tree::Tree::new(vec![3], vec![3])))
-            .attach(false, Some(tree::Tree::new(vec![7], vec![7])));
-        tree.commit(&mut NoopCommit {}).expect("commit failed");
-        tree
-    }
-
-    fn make_7_node_prooftree() -> super::Tree {
-        let make_node = |i| -> super::Tree { Node::KV(vec![i], vec![]).into() };
+/// The inputs of a node hash: the hash of the node's key/value pair, the
+/// hashes of its left and right children, and the number of nodes in their
+/// subtrees.
+type NodeHashInputs = (Hash, Hash, Hash, (u64, u64));
+
+/// The node hashes computed while verifying a single proof with a
+/// `VerifyCache`. Hashes are looked up in the cache's confirmed hashes, and
+/// those which had to be computed are recorded so they can be added to the
+/// cache once the proof has verified.
+struct PendingHashes<'a> {
+    confirmed: &'a HashMap<NodeHashInputs, Hash>,
+    computed: Vec<(NodeHashInputs, Hash)>,
+}
 
-        let mut tree = make_node(3);
-        let mut left = make_node(1);
-        left.attach(true, make_node(0)).unwrap();
-        left.attach(false, make_node(2)).unwrap();
-        let mut right = make_node(5);
-        right.attach(true, make_node(4)).unwrap();
-        right.attach(false, make_node(6)).unwrap();
-        tree.attach(true, left).unwrap();
-        tree.attach(false, right).unwrap();
+impl<'a> PendingHashes<'a> {
+    /// Returns the confirmed hash for the given inputs, or computes it with
+    /// `H` and records it.
+    fn hash_node<H: Hasher<Digest = Hash>>(
+        &mut self,
+        kv: Hash,
+        left: Hash,
+        right: Hash,
+        child_counts: (u64, u64),
+    ) -> Hash {
+        let inputs = (kv, left, right, child_counts);
+        if let Some(hash) = self.confirmed.get(&inputs) {
+            return *hash;
+        }
 
-        tree
+        let hash = H::hash_counted_node(&kv, &left, &right, child_counts);
+        self.computed.push((inputs, hash));
+        hash
     }
+}
 
-    fn verify_test(keys: Vec<Vec<u8>>, expected_result: Vec<Option<Vec<u8>>>) {
-        let mut tree = make_3_node_tree();
-        let mut walker = RefWalker::new(&mut tree, PanicSource {});
-
-        let (proof, _) = walker
-            .create_proof(keys.as_slice())
-            .expect("failed to create proof");
-        let mut bytes = vec![];
-        encode_into(proof.iter(), &mut bytes);
+/// Verifies proofs against a single root hash, remembering the node hashes
+/// computed along the way so later proofs which share nodes with earlier ones
+/// (e.g. the nodes near the root) do not recompute them. Useful for light
+/// clients which verify many proofs against the same state.
+///
+/// Node hashes are only added to the cache once the proof they were computed
+/// for has verified against the root hash, so the cache never holds hashes
+/// from an invalid proof. Since every cached hash belongs to the tree with the
+/// root hash, the cache grows to at most the number of nodes in that tree.
+pub struct VerifyCache<H = Blake2bHasher> {
+    root_hash: Hash,
+    node_hashes: HashMap<NodeHashInputs, Hash>,
+    hasher: PhantomData<H>,
+}
 
-        let expected_hash = [
-            65, 23, 96, 10, 165, 42, 240, 100, 206, 125, 192, 81, 44, 89, 119, 39, 35, 215, 211, 24,
-        ];
-        let result =
-            verify_query(bytes.as_slice(), keys.as_slice(), expected_hash).expect("verify failed");
-        assert_eq!(result, expected_result);
+impl VerifyCache {
+    /// Creates an empty cache for verifying proofs against `root_hash`.
+    pub fn new(root_hash: Hash) -> Self {
+        VerifyCache::with_hasher(root_hash)
     }
+}
 
-    #[test]
-    fn root_verify() {
-        verify_test(vec![vec![5]], vec![Some(vec![5])]);
+impl<H: Hasher<Digest = Hash>> VerifyCache<H> {
+    /// Like `VerifyCache::new`, but computes the proofs' hashes with the given
+    /// hasher rather than the default (`Blake2bHasher`).
+    pub fn with_hasher(root_hash: Hash) -> Self {
+        VerifyCache {
+            root_hash,
+            node_hashes: HashMap::new(),
+            hasher: PhantomData,
+        }
     }
 
-    #[test]
-    fn single_verify() {
-        verify_test(vec![vec![3]], vec![Some(vec![3])]);
+    /// Returns the root hash which proofs are verified against.
+    pub fn root_hash(&self) -> Hash {
+        self.root_hash
     }
 
-    #[test]
-    fn double_verify() {
-        verify_test(vec![vec![3], vec![5]], vec![Some(vec![3]), Some(vec![5])]);
+    /// Returns the number of node hashes in the cache.
+    pub fn len(&self) -> usize {
+        self.node_hashes.len()
     }
 
-    #[test]
-    fn double_verify_2() {
-        verify_test(vec![vec![3], vec![7]], vec![Some(vec![3]), Some(vec![7])]);
+    /// Returns `true` if there are no node hashes in the cache.
+    pub fn is_empty(&self) -> bool {
+        self.node_hashes.is_empty()
     }
 
-    #[test]
-    fn triple_verify() {
-        verify_test(
-            vec![vec![3], vec![5], vec![7]],
-            vec![Some(vec![3]), Some(vec![5]), Some(vec![7])],
-        );
+    /// Verifies the encoded proof with the given query against the cache's
+    /// root hash, like `verify_query`. If the proof verifies, the node hashes
+    /// computed for it are added to the cache.
+    pub fn verify_query(&mut self, bytes: &[u8], keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut pending = PendingHashes {
+            confirmed: &self.node_hashes,
+            computed: vec![],
+        };
+        let nodes = verify_query_nodes::<H>(bytes, keys, self.root_hash, Some(&mut pending))?;
+
+        let computed = pending.computed;
+        self.node_hashes.extend(computed);
+
+        nodes
+            .into_iter()
+            .map(|maybe_node| match maybe_node {
+                None => Ok(None),
+                Some(Node::KV(_, value)) => Ok(Some(value)),
+                Some(_) => Err(ErrorKind::MissingProofData.into()),
+            })
+            .collect()
     }
+}
 
-    #[test]
-    fn left_edge_absence_verify() {
-        verify_test(vec![vec![2]], vec![None]);
-    }
+/// Verifies the encoded proof with the given range, limit, offset, and
+/// expected hash (see `RefWalker::create_range_proof`).
+///
+/// The proof must contain every entry with a key in the range between `start`
+/// and `end` (or the first `offset + limit` of them, if `limit` is given), with
+/// no gaps: the entries must be bounded on each side by either a key/value pair
+/// outside of the range, or by the edge of the tree. The right side does not
+/// need to be bounded if the limit was reached.
+///
+/// Returns `Err` if the proof is invalid, or the key/value pairs in the range
+/// which come after the first `offset` entries, in key order.
+pub fn verify_range(
+    bytes: &[u8],
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+    expected_hash: Hash,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let ops = Decoder::new(bytes);
+    RangeVerifier::new(ops, start, end, limit, offset, expected_hash).collect()
+}
 
-    #[test]
-    fn right_edge_absence_verify() {
-        verify_test(vec![vec![8]], vec![None]);
-    }
+/// Verifies the encoded proof of a prefix of the entries in the range between
+/// `start` and `end`, as generated by `RefWalker::create_range_proof_max_bytes`,
+/// with the given expected hash.
+///
+/// The checks are the same as in `verify_range` with no limit, except that
+/// the right side of the entries does not need to be bounded. If it is not,
+/// the proof only shows that the returned entries are the first entries of the
+/// range, and the key of the last one is returned, so the rest of the range can
+/// be requested starting after it (with `Bound::Excluded`).
+///
+/// Returns `Err` if the proof is invalid or is truncated before the first entry
+/// of the range, or the key/value pairs it proves along with the key to resume
+/// from, which is `None` if the proof covers the whole range.
+pub fn verify_range_prefix(
+    bytes: &[u8],
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    expected_hash: Hash,
+) -> Result<(Vec<(Vec<u8>, Vec<u8>)>, Option<Vec<u8>>)> {
+    let ops = Decoder::new(bytes);
+    let mut verifier = RangeVerifier::new(ops, start, end, None, None, expected_hash);
+    verifier.allow_truncation = true;
+
+    let entries = verifier.by_ref().collect::<Result<Vec<_>>>()?;
+    let continuation = if verifier.truncated {
+        match entries.last() {
+            Some((key, _)) => Some(key.clone()),
+            None => bail!("Proof truncated before the first entry of the range"),
+        }
+    } else {
+        None
+    };
 
-    #[test]
-    fn inner_absence_verify() {
-        verify_test(vec![vec![6]], vec![None]);
-    }
+    Ok((entries, continuation))
+}
 
-    #[test]
-    fn absent_and_present_verify() {
-        verify_test(vec![vec![5], vec![6]], vec![Some(vec![5]), None]);
-    }
+/// An iterator which verifies a range proof as its operators are read, yielding
+/// the entries in the range as soon as their nodes are pushed rather than
+/// collecting them all first. The checks are the same as in `verify_range`.
+///
+/// Errors in the proof's structure are yielded as soon as they are found, and
+/// the checks which need the whole proof (that the right edge of the range is
+/// bounded, and that the computed root hash matches `expected_hash`) are made
+/// once the operators are exhausted, yielding an error after the last entry.
+/// Iteration stops after the first error. Entries are only known to be valid
+/// once the iterator has been exhausted without yielding an error.
+pub struct RangeVerifier<I> {
+    ops: I,
+    stack: Vec<Tree>,
+    maybe_last_key: Option<Vec<u8>>,
+    range: RangeState,
+    expected_hash: Hash,
+    done: bool,
+    allow_truncation: bool,
+    truncated: bool,
+}
 
-    #[test]
-    fn height_counting() {
-        fn recurse(tree: &super::Tree, expected_height: usize) {
-            assert_eq!(tree.height, expected_height);
-            tree.left
-                .as_ref()
-                .map(|l| recurse(&l.tree, expected_height - 1));
-            tree.right
-                .as_ref()
-                .map(|r| recurse(&r.tree, expected_height - 1));
-        }
+/// The state of the contiguity checks made by `RangeVerifier`.
+struct RangeState {
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    offset: usize,
+    remaining: Option<usize>,
+    index: usize,
+    passed_end: bool,
+    last_push: Option<Node>,
+}
 
-        let tree = make_7_node_prooftree();
-        recurse(&tree, 3);
+impl<I: Iterator<Item = Result<Op>>> RangeVerifier<I> {
+    /// Creates a `RangeVerifier` which reads proof operators from `ops` (e.g. a
+    /// `Decoder`), with the given range, limit, offset, and expected hash.
+    pub fn new(
+        ops: I,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: Option<usize>,
+        offset: Option<usize>,
+        expected_hash: Hash,
+    ) -> Self {
+        let offset = offset.unwrap_or(0);
+        let remaining = limit.map(|limit| limit.saturating_add(offset));
+        RangeVerifier {
+            ops,
+            stack: Vec::with_capacity(32),
+            maybe_last_key: None,
+            range: RangeState {
+                start,
+                end,
+                offset,
+                remaining,
+                index: 0,
+                passed_end: remaining == Some(0),
+                last_push: None,
+            },
+            expected_hash,
+            done: false,
+            allow_truncation: false,
+            truncated: false,
+        }
     }
 
-    #[test]
-    fn layer_iter() {
-        let tree = make_7_node_prooftree();
+    /// Executes operators until the next entry in the range is pushed, or
+    /// makes the final checks if there are no operators left.
+    fn next_entry(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        for op in &mut self.ops {
+            let range = &mut self.range;
+            let mut maybe_entry = None;
+            execute_op(
+                &mut self.stack,
+                &mut self.maybe_last_key,
+                op?,
+                true,
+                |node| {
+                    maybe_entry = range.visit_node(node)?;
+                    Ok(())
+                },
+                None,
+            )?;
+
+            if maybe_entry.is_some() {
+                return Ok(maybe_entry);
+            }
+        }
 
-        let assert_node = |node: &Tree, i| match node.node {
-            Node::KV(ref key, _) => assert_eq!(key[0], i),
-            _ => unreachable!(),
-        };
+        if self.stack.len() != 1 {
+            bail!("Expected proof to result in exactly one stack item");
+        }
 
-        let mut iter = tree.layer(0);
-        assert_node(iter.next().unwrap(), 3);
-        assert!(iter.next().is_none());
+        // absence proof for right edge (unless the limit was reached)
+        if !self.range.passed_end {
+            match self.range.last_push {
+                None | Some(Node::KV(_, _)) => {}
+                // the rest of the range is abridged (see `verify_range_prefix`)
+                _ if self.allow_truncation => self.truncated = true,
+                _ => bail!("Proof incorrectly formed"),
+            }
+        }
 
-        let mut iter = tree.layer(1);
-        assert_node(iter.next().unwrap(), 1);
-        assert_node(iter.next().unwrap(), 5);
-        assert!(iter.next().is_none());
+        let root_hash = self.stack[0].hash();
+        if !root_hash.ct_eq(&self.expected_hash) {
+            return Err(ErrorKind::HashMismatch {
+                expected: self.expected_hash,
+                actual: root_hash,
+            }
+            .into());
+        }
 
-        let mut iter = tree.layer(2);
-        assert_node(iter.next().unwrap(), 0);
-        assert_node(iter.next().unwrap(), 2);
-        assert_node(iter.next().unwrap(), 4);
-        assert_node(iter.next().unwrap(), 6);
-        assert!(iter.next().is_none());
+        Ok(None)
     }
+}
 
-    #[test]
-    fn visit_nodes() {
-        let tree = make_7_node_prooftree();
-
-        let assert_node = |node: Node, i| match node {
-            Node::KV(ref key, _) => assert_eq!(key[0], i),
-            _ => unreachable!(),
-        };
+impl<I: Iterator<Item = Result<Op>>> Iterator for RangeVerifier<I> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
 
-        let mut visited = vec![];
-        tree.visit_nodes(&mut |node| visited.push(node));
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
 
-        let mut iter = visited.into_iter();
-        for i in 0..7 {
-            assert_node(iter.next().unwrap(), i);
+        match self.next_entry() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
         }
-        assert!(iter.next().is_none());
+    }
+}
+
+impl RangeState {
+    /// Checks a pushed node against the range, returning its key/value pair if
+    /// it is an entry in the range which comes after the offset.
+    fn visit_node(&mut self, node: &Node) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let mut maybe_entry = None;
+
+        if let Node::KV(key, value) = node {
+            if !self.passed_end && !is_below_start(&self.start, key) {
+                match &self.last_push {
+                    None | Some(Node::KV(_, _)) => {}
+                    // proof is incorrect since it may have skipped entries
+                    _ => bail!("Proof incorrectly formed"),
+                }
+
+                if is_above_end(&self.end, key) {
+                    self.passed_end = true;
+                } else {
+                    if self.index >= self.offset {
+                        maybe_entry = Some((key.clone(), value.clone()));
+                    }
+                    self.index += 1;
+
+                    if let Some(remaining) = self.remaining.as_mut() {
+                        *remaining -= 1;
+                        self.passed_end = *remaining == 0;
+                    }
+                }
+            }
+        }
+
+        self.last_push = Some(node.clone());
+
+        Ok(maybe_entry)
+    }
+}
+
+/// Verifies the encoded proof of the entries whose keys start with `prefix`,
+/// with the given limit, offset, and expected hash (see
+/// `RefWalker::create_prefix_proof`). The same checks are made as in
+/// `verify_range`, over the range given by `prefix_bounds`.
+pub fn verify_prefix(
+    bytes: &[u8],
+    prefix: &[u8],
+    limit: Option<usize>,
+    offset: Option<usize>,
+    expected_hash: Hash,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let (start, end) = prefix_bounds(prefix);
+    verify_range(bytes, start, end, limit, offset, expected_hash)
+}
+
+/// Verifies the encoded proof with the given range, limit, offset, and
+/// expected hash, where the limit and offset count entries in descending key
+/// order (see `RefWalker::create_range_proof_rev`).
+///
+/// The same checks are made as in `verify_range`, mirrored: the entries must be
+/// contiguous from the end of the range, and the left side does not need to be
+/// bounded if the limit was reached.
+///
+/// Returns `Err` if the proof is invalid, or the key/value pairs in the range
+/// which come after the last `offset` entries, in descending key order.
+pub fn verify_range_rev(
+    bytes: &[u8],
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+    expected_hash: Hash,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    // entries in the range, each with a flag for whether it directly follows
+    // a key/value pair (or the left edge of the tree) in the proof
+    let mut entries = vec![];
+    // the same flag for the first key/value pair after the range, if any
+    let mut end_boundary = None;
+    let mut last_push = None;
+
+    let ops = Decoder::new(bytes);
+
+    let root: Tree = execute(ops, true, |node| {
+        if let Node::KV(key, value) = node {
+            let follows_kv = match &last_push {
+                None | Some(Node::KV(_, _)) => true,
+                _ => false,
+            };
+
+            if end_boundary.is_none() && !is_below_start(&start, key) {
+                if is_above_end(&end, key) {
+                    end_boundary = Some(follows_kv);
+                } else {
+                    entries.push((key.clone(), value.clone(), follows_kv));
+                }
+            }
+        }
+
+        last_push = Some(node.clone());
+
+        Ok(())
+    })?;
+
+    // absence proof for right edge
+    let mut contiguous = match end_boundary {
+        Some(follows_kv) => follows_kv,
+        None => match last_push {
+            None | Some(Node::KV(_, _)) => true,
+            _ => false,
+        },
+    };
+
+    let offset = offset.unwrap_or(0);
+    let mut remaining = limit.map(|limit| limit.saturating_add(offset));
+    let mut output = vec![];
+
+    for (index, (key, value, follows_kv)) in entries.into_iter().rev().enumerate() {
+        if remaining == Some(0) {
+            break;
+        }
+
+        // proof is incorrect since it may have skipped entries
+        if !contiguous {
+            bail!("Proof incorrectly formed");
+        }
+
+        if index >= offset {
+            output.push((key, value));
+        }
+
+        if let Some(remaining) = remaining.as_mut() {
+            *remaining -= 1;
+        }
+        contiguous = follows_kv;
+    }
+
+    // absence proof for left edge (unless the limit was reached)
+    if remaining != Some(0) && !contiguous {
+        bail!("Proof incorrectly formed");
+    }
+
+    if !root.hash().ct_eq(&expected_hash) {
+        return Err(ErrorKind::HashMismatch {
+            expected: expected_hash,
+            actual: root.hash(),
+        }
+        .into());
+    }
+
+    Ok(output)
+}
+
+/// An entry and its in-order neighbors, as proven by `verify_neighbors`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Neighbors {
+    /// The key/value pair with the greatest key less than the queried key, or
+    /// `None` if no keys in the tree are less than it.
+    pub predecessor: Option<(Vec<u8>, Vec<u8>)>,
+
+    /// The value of the queried key, or `None` if it is absent from the tree.
+    pub value: Option<Vec<u8>>,
+
+    /// The key/value pair with the least key greater than the queried key, or
+    /// `None` if no keys in the tree are greater than it.
+    pub successor: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Verifies the encoded proof of the entry with key `key` and its in-order
+/// neighbors (see `RefWalker::create_neighbor_proof`), with the given expected
+/// hash.
+///
+/// The predecessor, the queried entry (if present), and the successor must be
+/// pushed consecutively in the proof, so that no other keys can lie between
+/// them. A missing predecessor or successor must instead be at the edge of the
+/// proof, showing that no keys lie beyond the queried key on that side.
+///
+/// Returns `Err` if the proof is invalid, or the proven entries.
+pub fn verify_neighbors(bytes: &[u8], key: &[u8], expected_hash: Hash) -> Result<Neighbors> {
+    let mut pushes = vec![];
+
+    let ops = Decoder::new(bytes);
+    let root: Tree = execute(ops, true, |node| {
+        pushes.push(node.clone());
+        Ok(())
+    })?;
+
+    fn kv_key(node: &Node) -> Option<&[u8]> {
+        match node {
+            Node::KV(key, _) => Some(key.as_slice()),
+            _ => None,
+        }
+    }
+
+    // keys are checked to increase during execution, so the predecessor comes
+    // before the successor
+    let maybe_lower = pushes
+        .iter()
+        .rposition(|node| kv_key(node).map_or(false, |node_key| node_key < key));
+    let maybe_upper = pushes
+        .iter()
+        .position(|node| kv_key(node).map_or(false, |node_key| node_key > key));
+
+    // the nodes between the neighbors (or the edges of the proof) may only be
+    // the queried entry
+    let start = maybe_lower.map_or(0, |lower| lower + 1);
+    let end = maybe_upper.unwrap_or_else(|| pushes.len());
+    let value = match &pushes[start..end] {
+        [] => None,
+        [Node::KV(node_key, value)] if node_key.as_slice() == key => Some(value.clone()),
+        _ => bail!("Proof incorrectly formed"),
+    };
+
+    if !root.hash().ct_eq(&expected_hash) {
+        return Err(ErrorKind::HashMismatch {
+            expected: expected_hash,
+            actual: root.hash(),
+        }
+        .into());
+    }
+
+    let into_entry = |index: usize| match pushes[index].clone() {
+        Node::KV(key, value) => (key, value),
+        _ => unreachable!("Expected KV node"),
+    };
+    Ok(Neighbors {
+        predecessor: maybe_lower.map(into_entry),
+        value,
+        successor: maybe_upper.map(into_entry),
+    })
+}
+
+/// Verifies the encoded proof with the given batch of queries and expected hash
+/// (see `RefWalker::create_query_proof`).
+///
+/// The queries are sorted and merged in the same way as when the proof was
+/// generated. Every queried key or range must be covered by the proof with no
+/// gaps, as in `verify_range`, so that keys missing from the result are proven
+/// to be absent from the tree.
+///
+/// Returns `Err` if the proof is invalid, or a map containing every proven
+/// key/value pair matched by any of the queries.
+pub fn verify_query_items(
+    bytes: &[u8],
+    queries: &[QueryItem],
+    expected_hash: Hash,
+) -> Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+    let ranges = merge_query_items(queries);
+    let mut range_index = 0;
+    let mut last_push = None;
+    let mut output = BTreeMap::new();
+
+    let ops = Decoder::new(bytes);
+
+    let root: Tree = execute(ops, true, |node| {
+        if let Node::KV(key, value) = node {
+            while let Some(range) = ranges.get(range_index) {
+                if is_below_start(&range.start, key) {
+                    break;
+                }
+
+                match &last_push {
+                    None | Some(Node::KV(_, _)) => {}
+                    // proof is incorrect since it may have skipped entries
+                    _ => bail!("Proof incorrectly formed"),
+                }
+
+                if is_above_end(&range.end, key) {
+                    // this node bounds the right side of the range, it may
+                    // also be in one of the following ranges
+                    range_index += 1;
+                } else {
+                    output.insert(key.clone(), value.clone());
+                    break;
+                }
+            }
+        }
+
+        last_push = Some(node.clone());
+
+        Ok(())
+    })?;
+
+    // absence proof for right edge
+    if range_index < ranges.len() {
+        match last_push {
+            None | Some(Node::KV(_, _)) => {}
+            _ => bail!("Proof incorrectly formed"),
+        }
+    }
+
+    if !root.hash().ct_eq(&expected_hash) {
+        return Err(ErrorKind::HashMismatch {
+            expected: expected_hash,
+            actual: root.hash(),
+        }
+        .into());
+    }
+
+    Ok(output)
+}
+
+/// Executes the encoded proof without checking it against an expected root
+/// hash, for callers which receive the hash they trust separately (e.g. light
+/// clients which compare it to a signed header afterwards).
+///
+/// Returns `Err` if the proof is malformed, or the root hash computed from the
+/// proof along with every key/value pair it contains. The entries should only
+/// be trusted once the returned hash has been checked. Note that a proof does
+/// not show whether entries absent from the map are absent from the tree
+/// unless it is checked against a query, as in `verify_query`.
+pub fn verify(bytes: &[u8]) -> Result<(Hash, BTreeMap<Vec<u8>, Vec<u8>>)> {
+    let mut entries = BTreeMap::new();
+
+    let ops = Decoder::new(bytes);
+    let root: Tree = execute(ops, true, |node| {
+        if let Node::KV(key, value) = node {
+            entries.insert(key.clone(), value.clone());
+        }
+        Ok(())
+    })?;
+
+    Ok((root.hash(), entries))
+}
+
+/// Verifies a proof of an entire tree, as generated by `create_full_proof`,
+/// and reconstructs the tree from it. Every node of the proof must be a
+/// `Node::KV`, and the proof must hash to `expected_hash`.
+///
+/// The reconstructed tree has the same structure as the proven tree, so its
+/// root hash is `expected_hash`. All of its nodes are in memory and committed
+/// (with `NoopCommit`), so it can be queried or modified immediately.
+pub fn verify_full(bytes: &[u8], expected_hash: Hash) -> Result<crate::tree::Tree> {
+    /// Converts a verified proof tree into a tree with the same structure.
+    fn into_tree(proof_tree: Tree) -> crate::tree::Tree {
+        let (key, value) = match proof_tree.node {
+            Node::KV(key, value) => (key, value),
+            _ => unreachable!("Expected node to be type KV"),
+        };
+
+        let maybe_left = proof_tree.left.map(|child| into_tree(*child.tree));
+        let maybe_right = proof_tree.right.map(|child| into_tree(*child.tree));
+        crate::tree::Tree::new(key, value)
+            .attach(true, maybe_left)
+            .attach(false, maybe_right)
+    }
+
+    let ops = Decoder::new(bytes);
+    let proof_tree: Tree = execute(ops, false, |node| match node {
+        Node::KV(_, _) => Ok(()),
+        _ => bail!("Full proofs must only contain KV nodes"),
+    })?;
+
+    if !proof_tree.hash().ct_eq(&expected_hash) {
+        return Err(ErrorKind::HashMismatch {
+            expected: expected_hash,
+            actual: proof_tree.hash(),
+        }
+        .into());
+    }
+
+    let mut tree = into_tree(proof_tree);
+    tree.commit(&mut NoopCommit {})?;
+    Ok(tree)
+}
+
+/// Verifies a proof of the number of nodes in a tree, as generated by
+/// `create_count_proof` for a tree hashed with `Blake2bCountedHasher`.
+///
+/// Returns `Err` if the proof is malformed, or the total count the proof
+/// claims along with whether the proof hashes to `expected_hash`. The count
+/// can only be trusted if the proof verified.
+pub fn verify_count(bytes: &[u8], expected_hash: Hash) -> Result<(u64, bool)> {
+    verify_count_with_hasher::<Blake2bCountedHasher>(bytes, expected_hash)
+}
+
+/// Like `verify_count`, but computes the proof's hashes with the given hasher
+/// rather than the default (`Blake2bCountedHasher`).
+///
+/// Returns `Err` if `H` is earlier than version 3, since those hashers do not
+/// hash descendant counts into nodes, or if the proof is a single abridged
+/// node, whose count is not bound by its hash.
+pub fn verify_count_with_hasher<H: Hasher<Digest = Hash>>(
+    bytes: &[u8],
+    expected_hash: Hash,
+) -> Result<(u64, bool)> {
+    if H::VERSION < 3 {
+        bail!("Hashers before version 3 cannot verify count proofs");
+    }
+
+    let ops = Decoder::new(bytes);
+    let root: Tree<H> = execute(ops, true, |_| Ok(()))?;
+    if let Node::Hash(_) | Node::CountedHash(_, _) = root.node {
+        bail!("Count proofs must have a key/value hash at the root");
+    }
+
+    Ok((root.count(), root.hash().ct_eq(&expected_hash)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+    use crate::test_utils::{
+        apply_to_memonly, make_batch_seq, make_del_batch_seq, make_tree_seq, prove_key, put_entry,
+        seq_key,
+    };
+    use crate::tree;
+    use crate::tree::{
+        kv_hash, value_hash, MemStore, NoopCommit, PanicSource, RefWalker, Walker, HASH_LENGTH,
+    };
+    use std::ops::Range;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn make_3_node_tree() -> tree::Tree {
+        let mut tree = tree::Tree::new(vec![5], vec![5])
+            .attach(true, Some(tree::Tree::new(vec![3], vec![3])))
+            .attach(false, Some(tree::Tree::new(vec![7], vec![7])));
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        tree
+    }
+
+    fn make_7_node_prooftree() -> super::Tree {
+        let make_node = |i| -> super::Tree { Node::KV(vec![i], vec![]).into() };
+
+        let mut tree = make_node(3);
+        let mut left = make_node(1);
+        left.attach(true, make_node(0), None).unwrap();
+        left.attach(false, make_node(2), None).unwrap();
+        let mut right = make_node(5);
+        right.attach(true, make_node(4), None).unwrap();
+        right.attach(false, make_node(6), None).unwrap();
+        tree.attach(true, left, None).unwrap();
+        tree.attach(false, right, None).unwrap();
+
+        tree
+    }
+
+    fn verify_test(keys: Vec<Vec<u8>>, expected_result: Vec<Option<Vec<u8>>>) {
+        let mut tree = make_3_node_tree();
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+
+        let (proof, _) = walker
+            .create_proof(keys.as_slice())
+            .expect("failed to create proof");
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+
+        let expected_hash = [
+            117, 219, 253, 168, 216, 184, 36, 79, 78, 247, 230, 179, 127, 181, 196, 70, 30, 202,
+            146, 24,
+        ];
+        let result =
+            verify_query(bytes.as_slice(), keys.as_slice(), expected_hash).expect("verify failed");
+        assert_eq!(result, expected_result);
+    }
+
+    #[test]
+    fn query_missing_proof_data() {
+        let mut tree = make_3_node_tree();
+        let expected_hash = tree.hash();
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+
+        // a digest proof only contains the hash of the queried value
+        let keys = vec![vec![3]];
+        let proof = walker
+            .create_digest_proof(keys.as_slice())
+            .expect("failed to create proof");
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+
+        let err = verify_query(bytes.as_slice(), keys.as_slice(), expected_hash).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ErrorKind>(),
+            Some(&ErrorKind::MissingProofData)
+        );
+        assert_eq!(
+            err.to_string(),
+            "Proof does not contain value for queried key"
+        );
+
+        let mut wrong_hash = expected_hash;
+        wrong_hash[0] ^= 1;
+        let err = verify_query_digests(bytes.as_slice(), keys.as_slice(), wrong_hash).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ErrorKind>(),
+            Some(&ErrorKind::HashMismatch {
+                expected: wrong_hash,
+                actual: expected_hash,
+            })
+        );
+    }
+
+    #[test]
+    fn digest_verify() {
+        let mut tree = make_3_node_tree();
+        let expected_hash = tree.hash();
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+
+        let keys = vec![vec![2], vec![3], vec![6], vec![7]];
+        let proof = walker
+            .create_digest_proof(keys.as_slice())
+            .expect("failed to create proof");
+        assert!(proof.iter().all(|op| match op {
+            Op::Push(Node::KV(_, _)) => false,
+            _ => true,
+        }));
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+
+        let result = verify_query_digests(bytes.as_slice(), keys.as_slice(), expected_hash)
+            .expect("verify failed");
+        assert_eq!(
+            result,
+            vec![None, Some(value_hash(&[3])), None, Some(value_hash(&[7]))]
+        );
+
+        // the values are hidden, so the proof can't be verified as a value query
+        assert!(verify_query(bytes.as_slice(), keys.as_slice(), expected_hash).is_err());
+
+        assert_eq!(
+            verify_query_digests_with_hasher::<Blake2bHasher>(
+                bytes.as_slice(),
+                keys.as_slice(),
+                expected_hash
+            )
+            .expect("verify failed"),
+            result
+        );
+        let err = verify_query_digests_with_hasher::<tree::Blake2bV1Hasher>(
+            bytes.as_slice(),
+            keys.as_slice(),
+            expected_hash,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Hashers before version 2 cannot verify KVDigest nodes"
+        );
+    }
+
+    #[test]
+    fn verify_with_tree_hasher() {
+        let batch = make_batch_seq(0..10);
+        let mut tree = Walker::<PanicSource, tree::Blake2bV1Hasher>::apply_to(None, &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        let expected_hash = tree.hash();
+
+        let keys = vec![seq_key(3), seq_key(7)];
+        let (proof, _) = RefWalker::new(&mut tree, PanicSource {})
+            .create_proof(keys.as_slice())
+            .expect("failed to create proof");
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+
+        let result = verify_query_with_hasher::<tree::Blake2bV1Hasher>(
+            bytes.as_slice(),
+            keys.as_slice(),
+            expected_hash,
+        )
+        .expect("verify failed");
+        assert_eq!(result, vec![Some(vec![123; 60]), Some(vec![123; 60])]);
+
+        // the proof only verifies under the hasher which hashed the tree
+        assert!(verify_query(bytes.as_slice(), keys.as_slice(), expected_hash).is_err());
+        assert!(verify_query_with_hasher::<tree::LegacyBlake2bHasher>(
+            bytes.as_slice(),
+            keys.as_slice(),
+            expected_hash
+        )
+        .is_err());
+
+        // the same entries hashed with the default hasher have a different root
+        let mut tree = Walker::<PanicSource>::apply_to(None, &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        assert_ne!(tree.hash(), expected_hash);
+
+        let (proof, _) = RefWalker::new(&mut tree, PanicSource {})
+            .create_proof(keys.as_slice())
+            .expect("failed to create proof");
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+        assert!(verify_query(bytes.as_slice(), keys.as_slice(), tree.hash()).is_ok());
+        assert!(verify_query_with_hasher::<tree::Blake2bV1Hasher>(
+            bytes.as_slice(),
+            keys.as_slice(),
+            tree.hash()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn root_verify() {
+        verify_test(vec![vec![5]], vec![Some(vec![5])]);
+    }
+
+    #[test]
+    fn single_verify() {
+        verify_test(vec![vec![3]], vec![Some(vec![3])]);
+    }
+
+    #[test]
+    fn double_verify() {
+        verify_test(vec![vec![3], vec![5]], vec![Some(vec![3]), Some(vec![5])]);
+    }
+
+    #[test]
+    fn double_verify_2() {
+        verify_test(vec![vec![3], vec![7]], vec![Some(vec![3]), Some(vec![7])]);
+    }
+
+    #[test]
+    fn triple_verify() {
+        verify_test(
+            vec![vec![3], vec![5], vec![7]],
+            vec![Some(vec![3]), Some(vec![5]), Some(vec![7])],
+        );
+    }
+
+    #[test]
+    fn left_edge_absence_verify() {
+        verify_test(vec![vec![2]], vec![None]);
+    }
+
+    #[test]
+    fn right_edge_absence_verify() {
+        verify_test(vec![vec![8]], vec![None]);
+    }
+
+    #[test]
+    fn inner_absence_verify() {
+        verify_test(vec![vec![6]], vec![None]);
+    }
+
+    #[test]
+    fn empty_tree_absence_verify() {
+        let keys = vec![vec![1], vec![2]];
+        let values = verify_query(&[], &keys, NULL_HASH).expect("verify failed");
+        assert_eq!(values, vec![None, None]);
+
+        let err = verify_query(&[], &keys, [1; 20]).unwrap_err();
+        assert!(err.downcast_ref::<ErrorKind>().is_some());
+    }
+
+    #[test]
+    fn absent_and_present_verify() {
+        verify_test(vec![vec![5], vec![6]], vec![Some(vec![5]), None]);
+    }
+
+    #[test]
+    fn unproven_absence_verify() {
+        let mut tree = make_3_node_tree();
+        let expected_hash = tree.hash();
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+
+        // a proof for key 5 alone abridges its neighbors, so it can't prove
+        // the absence of key 6
+        let (proof, _) = walker
+            .create_proof(vec![vec![5]].as_slice())
+            .expect("failed to create proof");
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+
+        let keys = vec![vec![5]];
+        let result =
+            verify_query(bytes.as_slice(), keys.as_slice(), expected_hash).expect("verify failed");
+        assert_eq!(result, vec![Some(vec![5])]);
+
+        let keys = vec![vec![5], vec![6]];
+        assert!(verify_query(bytes.as_slice(), keys.as_slice(), expected_hash).is_err());
+    }
+
+    #[test]
+    fn height_counting() {
+        fn recurse(tree: &super::Tree, expected_height: usize) {
+            assert_eq!(tree.height, expected_height);
+            tree.left
+                .as_ref()
+                .map(|l| recurse(&l.tree, expected_height - 1));
+            tree.right
+                .as_ref()
+                .map(|r| recurse(&r.tree, expected_height - 1));
+        }
+
+        let tree = make_7_node_prooftree();
+        recurse(&tree, 3);
+    }
+
+    #[test]
+    fn layer_iter() {
+        let tree = make_7_node_prooftree();
+
+        let assert_node = |node: &Tree, i| match node.node {
+            Node::KV(ref key, _) => assert_eq!(key[0], i),
+            _ => unreachable!(),
+        };
+
+        let mut iter = tree.layer(0);
+        assert_node(iter.next().unwrap(), 3);
+        assert!(iter.next().is_none());
+
+        let mut iter = tree.layer(1);
+        assert_node(iter.next().unwrap(), 1);
+        assert_node(iter.next().unwrap(), 5);
+        assert!(iter.next().is_none());
+
+        let mut iter = tree.layer(2);
+        assert_node(iter.next().unwrap(), 0);
+        assert_node(iter.next().unwrap(), 2);
+        assert_node(iter.next().unwrap(), 4);
+        assert_node(iter.next().unwrap(), 6);
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn visit_nodes() {
+        let tree = make_7_node_prooftree();
+
+        let assert_node = |node: Node, i| match node {
+            Node::KV(ref key, _) => assert_eq!(key[0], i),
+            _ => unreachable!(),
+        };
+
+        let mut visited = vec![];
+        tree.visit_nodes(&mut |node| visited.push(node));
+
+        let mut iter = visited.into_iter();
+        for i in 0..7 {
+            assert_node(iter.next().unwrap(), i);
+        }
+        assert!(iter.next().is_none());
+    }
+
+    fn range_proof(tree: &mut tree::Tree, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Vec<Op> {
+        paged_range_proof(tree, start, end, None, None)
+    }
+
+    fn paged_range_proof(
+        tree: &mut tree::Tree,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: Option<usize>,
+        offset: Option<usize>,
+    ) -> Vec<Op> {
+        let mut walker = RefWalker::new(tree, PanicSource {});
+        walker
+            .create_range_proof(start, end, limit, offset)
+            .expect("failed to create proof")
+    }
+
+    #[test]
+    fn range_verify() {
+        let mut tree = apply_to_memonly(None, &make_batch_seq(0..100)).expect("expected tree");
+        let expected_hash = tree.hash();
+
+        let cases = vec![
+            (
+                Bound::Included(seq_key(10)),
+                Bound::Excluded(seq_key(20)),
+                10..20,
+            ),
+            (
+                Bound::Excluded(seq_key(10)),
+                Bound::Included(seq_key(20)),
+                11..21,
+            ),
+            (Bound::Unbounded, Bound::Included(seq_key(5)), 0..6),
+            (Bound::Included(seq_key(95)), Bound::Unbounded, 95..100),
+            (Bound::Unbounded, Bound::Unbounded, 0..100),
+            (Bound::Included(seq_key(150)), Bound::Unbounded, 0..0),
+            (
+                Bound::Excluded(seq_key(10)),
+                Bound::Excluded(seq_key(11)),
+                0..0,
+            ),
+        ];
+
+        for (start, end, expected) in cases {
+            let proof = range_proof(&mut tree, start.clone(), end.clone());
+            let mut bytes = vec![];
+            encode_into(proof.iter(), &mut bytes);
+
+            let result = verify_range(bytes.as_slice(), start, end, None, None, expected_hash)
+                .expect("verify failed");
+            let keys: Vec<_> = result.into_iter().map(|(key, _)| key).collect();
+            let expected_keys: Vec<_> = expected.map(seq_key).collect();
+            assert_eq!(keys, expected_keys);
+        }
+    }
+
+    #[test]
+    fn range_verify_tampered_boundary() {
+        let mut tree = make_tree_seq(100);
+        let expected_hash = tree.hash();
+        let start = Bound::Included(seq_key(10));
+        let end = Bound::Excluded(seq_key(20));
+
+        let proof = range_proof(&mut tree, start.clone(), end.clone());
+        let boundaries: Vec<_> = proof
+            .iter()
+            .enumerate()
+            .filter_map(|(i, op)| match op {
+                Op::Push(Node::KV(key, value)) if *key == seq_key(9) || *key == seq_key(20) => {
+                    Some((i, key.clone(), value.clone()))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(boundaries.len(), 2);
+
+        for (i, key, value) in boundaries {
+            let verify = |proof: Vec<Op>| {
+                let mut bytes = vec![];
+                encode_into(proof.iter(), &mut bytes);
+                verify_range(
+                    bytes.as_slice(),
+                    start.clone(),
+                    end.clone(),
+                    None,
+                    None,
+                    expected_hash,
+                )
+            };
+
+            // hiding the boundary's key keeps the same root hash, but no longer
+            // shows that the range is contiguous
+            let mut tampered = range_proof(&mut tree, start.clone(), end.clone());
+            tampered[i] = Op::Push(Node::KVHash(kv_hash(key.as_slice(), value.as_slice())));
+            assert!(verify(tampered).is_err());
+
+            let mut tampered = range_proof(&mut tree, start.clone(), end.clone());
+            tampered[i] = Op::Push(Node::KV(key, vec![1, 2, 3]));
+            assert!(verify(tampered).is_err());
+        }
+    }
+
+    #[test]
+    fn range_verifier_streams() {
+        let mut tree = make_tree_seq(100);
+        let expected_hash = tree.hash();
+        let start = Bound::Included(seq_key(10));
+        let end = Bound::Excluded(seq_key(20));
+        let proof = range_proof(&mut tree, start.clone(), end.clone());
+
+        let verifier = RangeVerifier::new(
+            proof.into_iter().map(Ok),
+            start.clone(),
+            end.clone(),
+            None,
+            None,
+            expected_hash,
+        );
+        let entries: Vec<_> = verifier
+            .map(|entry| entry.expect("verify failed").0)
+            .collect();
+        assert_eq!(entries, (10..20).map(seq_key).collect::<Vec<_>>());
+
+        // entries are yielded before the root hash is checked, so the mismatch
+        // is only reported once the proof is exhausted
+        let mut wrong_hash = expected_hash;
+        wrong_hash[0] ^= 1;
+        let proof = range_proof(&mut tree, start.clone(), end.clone());
+        let mut verifier = RangeVerifier::new(
+            proof.into_iter().map(Ok),
+            start.clone(),
+            end.clone(),
+            None,
+            None,
+            wrong_hash,
+        );
+        for i in 10..15 {
+            let (key, _) = verifier.next().unwrap().expect("verify failed");
+            assert_eq!(key, seq_key(i));
+        }
+        let results: Vec<_> = verifier.collect();
+        assert_eq!(results.len(), 6);
+        assert!(results[..5].iter().all(|result| result.is_ok()));
+        let err = results[5].as_ref().err().expect("expected error");
+        assert!(err
+            .to_string()
+            .starts_with("Proof did not match expected hash"));
+
+        // a tampered value is yielded, but the root hash won't match
+        let mut proof = range_proof(&mut tree, start.clone(), end.clone());
+        let i = proof
+            .iter()
+            .position(|op| match op {
+                Op::Push(Node::KV(key, _)) => *key == seq_key(12),
+                _ => false,
+            })
+            .unwrap();
+        proof[i] = Op::Push(Node::KV(seq_key(12), vec![1, 2, 3]));
+        let verifier = RangeVerifier::new(
+            proof.into_iter().map(Ok),
+            start,
+            end,
+            None,
+            None,
+            expected_hash,
+        );
+        let results: Vec<_> = verifier.collect();
+        assert_eq!(results[2].as_ref().unwrap().1, vec![1, 2, 3]);
+        assert!(results.last().unwrap().is_err());
+    }
+
+    #[test]
+    fn neighbors_verify() {
+        // keys 0, 2, ..., 98, pruned so proving fetches from the store
+        let mut store = MemStore::new();
+        let batch: Vec<_> = (0..50).map(|n| put_entry(n * 2)).collect();
+        let mut tree = Walker::<PanicSource>::apply_to(None, &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        tree.commit(&mut store).expect("commit failed");
+        let expected_hash = tree.hash();
+
+        let mut prove = |key: &[u8]| {
+            let proof = RefWalker::new(&mut tree, &store)
+                .create_neighbor_proof(key)
+                .expect("failed to create proof");
+            let mut bytes = vec![];
+            encode_into(proof.iter(), &mut bytes);
+            bytes
+        };
+        let entry = |n| Some((seq_key(n), vec![123; 60]));
+        let check = |bytes: &[u8], key: &[u8], expected: Neighbors| {
+            let neighbors = verify_neighbors(bytes, key, expected_hash).expect("verify failed");
+            assert_eq!(neighbors, expected);
+        };
+
+        // present key
+        let bytes = prove(&seq_key(50));
+        check(
+            &bytes,
+            &seq_key(50),
+            Neighbors {
+                predecessor: entry(48),
+                value: Some(vec![123; 60]),
+                successor: entry(52),
+            },
+        );
+        // the proof does not show the neighbors of other keys
+        assert!(verify_neighbors(&bytes, &seq_key(46), expected_hash).is_err());
+        assert!(verify_neighbors(&bytes, &seq_key(54), expected_hash).is_err());
+        let mut wrong_hash = expected_hash;
+        wrong_hash[0] ^= 1;
+        assert!(verify_neighbors(&bytes, &seq_key(50), wrong_hash).is_err());
+
+        // absent key
+        check(
+            &prove(&seq_key(51)),
+            &seq_key(51),
+            Neighbors {
+                predecessor: entry(50),
+                value: None,
+                successor: entry(52),
+            },
+        );
+
+        // extremes
+        check(
+            &prove(&seq_key(0)),
+            &seq_key(0),
+            Neighbors {
+                predecessor: None,
+                value: Some(vec![123; 60]),
+                successor: entry(2),
+            },
+        );
+        check(
+            &prove(&seq_key(98)),
+            &seq_key(98),
+            Neighbors {
+                predecessor: entry(96),
+                value: Some(vec![123; 60]),
+                successor: None,
+            },
+        );
+        check(
+            &prove(&[0]),
+            &[0],
+            Neighbors {
+                predecessor: None,
+                value: None,
+                successor: entry(0),
+            },
+        );
+        check(
+            &prove(&seq_key(1000)),
+            &seq_key(1000),
+            Neighbors {
+                predecessor: entry(98),
+                value: None,
+                successor: None,
+            },
+        );
+
+        // a plain key proof does not include the neighbors
+        let proof = RefWalker::new(&mut tree, &store)
+            .create_proof(&[seq_key(50)])
+            .expect("failed to create proof")
+            .0;
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+        assert!(verify_neighbors(&bytes, &seq_key(50), expected_hash).is_err());
+    }
+
+    #[test]
+    fn historical_range_verify() {
+        let mut store = MemStore::new();
+        let mut tree = Walker::<PanicSource>::apply_to(None, &make_batch_seq(0..100))
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        tree.commit(&mut store).expect("commit failed");
+        let old_key = tree.key().to_vec();
+        let old_hash = tree.hash();
+
+        // delete the first half of the entries and add new ones
+        let mut batch = make_del_batch_seq(0..50);
+        batch.extend(make_batch_seq(100..150));
+        let walker = Walker::new(tree, &store);
+        let mut tree = Walker::apply_to(Some(walker), &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        tree.commit(&mut store).expect("commit failed");
+        assert_ne!(tree.hash(), old_hash);
+
+        let start = Bound::Included(seq_key(40));
+        let end = Bound::Excluded(seq_key(60));
+        let proof = create_historical_range_proof(
+            &store,
+            old_key.as_slice(),
+            old_hash,
+            start.clone(),
+            end.clone(),
+            None,
+            None,
+        )
+        .expect("failed to create proof");
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+
+        let result = verify_range(
+            bytes.as_slice(),
+            start.clone(),
+            end.clone(),
+            None,
+            None,
+            old_hash,
+        )
+        .expect("verify failed");
+        let keys: Vec<_> = result.into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, (40..60).map(seq_key).collect::<Vec<_>>());
+        assert!(verify_range(
+            bytes.as_slice(),
+            start.clone(),
+            end.clone(),
+            None,
+            None,
+            tree.hash()
+        )
+        .is_err());
+
+        // the root hash must match the fetched node
+        let mut wrong_hash = old_hash;
+        wrong_hash[0] ^= 1;
+        assert!(create_historical_range_proof(
+            &store,
+            old_key.as_slice(),
+            wrong_hash,
+            start,
+            end,
+            None,
+            None
+        )
+        .is_err());
+    }
+
+    fn prefix_test(tree: &mut tree::Tree, prefix: &[u8], expected_keys: Vec<Vec<u8>>) {
+        let expected_hash = tree.hash();
+        let mut walker = RefWalker::new(tree, PanicSource {});
+        let proof = walker
+            .create_prefix_proof(prefix, None, None)
+            .expect("failed to create proof");
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+
+        let result = verify_prefix(bytes.as_slice(), prefix, None, None, expected_hash)
+            .expect("verify failed");
+        let keys: Vec<_> = result.into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, expected_keys);
+    }
+
+    #[test]
+    fn prefix_verify() {
+        let keys = vec![
+            vec![1],
+            vec![1, 0xff],
+            vec![1, 0xff, 0],
+            vec![1, 0xff, 0xff],
+            vec![1, 0xff, 0xff, 5],
+            vec![2],
+            vec![2, 0],
+            vec![4],
+            vec![0xff],
+            vec![0xff, 0xff],
+            vec![0xff, 0xff, 1],
+        ];
+        let mut tree = tree::Tree::from_sorted(keys.iter().map(|key| (key.clone(), vec![7])))
+            .expect("expected tree");
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+
+        prefix_test(&mut tree, &[1], keys[0..5].to_vec());
+        prefix_test(&mut tree, &[1, 0xff], keys[1..5].to_vec());
+        prefix_test(&mut tree, &[1, 0xff, 0xff], keys[3..5].to_vec());
+        prefix_test(&mut tree, &[0xff, 0xff], keys[9..11].to_vec());
+        prefix_test(&mut tree, &[0xff], keys[8..11].to_vec());
+        prefix_test(&mut tree, &[], keys.clone());
+        prefix_test(&mut tree, &[3], vec![]);
+        prefix_test(&mut tree, &[1, 0xff, 1], vec![]);
+    }
+
+    #[test]
+    fn prefix_verify_gap() {
+        let keys = vec![vec![1], vec![1, 0xff], vec![1, 0xff, 0xff], vec![2]];
+        let mut tree = tree::Tree::from_sorted(keys.into_iter().map(|key| (key, vec![7])))
+            .expect("expected tree");
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        let expected_hash = tree.hash();
+
+        // a proof of a narrower prefix leaves out some of the entries
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let proof = walker
+            .create_prefix_proof(&[1, 0xff, 0xff], None, None)
+            .expect("failed to create proof");
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+        assert!(verify_prefix(bytes.as_slice(), &[1, 0xff], None, None, expected_hash).is_err());
+    }
+
+    fn paged_range_test(limit: Option<usize>, offset: Option<usize>, expected: Range<u64>) {
+        let mut tree = make_tree_seq(100);
+        let expected_hash = tree.hash();
+        let start = Bound::Included(seq_key(10));
+        let end = Bound::Excluded(seq_key(50));
+
+        let proof = paged_range_proof(&mut tree, start.clone(), end.clone(), limit, offset);
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+
+        let result = verify_range(bytes.as_slice(), start, end, limit, offset, expected_hash)
+            .expect("verify failed");
+        let keys: Vec<_> = result.into_iter().map(|(key, _)| key).collect();
+        let expected_keys: Vec<_> = expected.map(seq_key).collect();
+        assert_eq!(keys, expected_keys);
+    }
+
+    #[test]
+    fn range_verify_limit() {
+        paged_range_test(Some(5), None, 10..15);
+        paged_range_test(Some(100), None, 10..50);
+    }
+
+    #[test]
+    fn range_verify_offset() {
+        paged_range_test(None, Some(5), 15..50);
+        paged_range_test(Some(5), Some(30), 40..45);
+        paged_range_test(Some(5), Some(38), 48..50);
+    }
+
+    #[test]
+    fn range_verify_offset_past_end() {
+        paged_range_test(None, Some(40), 50..50);
+        paged_range_test(Some(5), Some(100), 50..50);
+    }
+
+    #[test]
+    fn range_verify_limit_zero() {
+        paged_range_test(Some(0), None, 10..10);
+        paged_range_test(Some(0), Some(5), 15..15);
+    }
+
+    #[test]
+    fn range_verify_limit_overflow() {
+        paged_range_test(Some(usize::MAX), Some(5), 15..50);
+        paged_range_test(Some(5), Some(usize::MAX), 50..50);
+    }
+
+    #[test]
+    fn range_verify_limit_gap() {
+        let mut tree = make_tree_seq(100);
+        let expected_hash = tree.hash();
+        let start = Bound::Included(seq_key(10));
+        let end = Bound::Excluded(seq_key(50));
+
+        // a verifier expecting a larger window must reject a proof which
+        // leaves entries within the window abridged
+        let proof = paged_range_proof(&mut tree, start.clone(), end.clone(), Some(5), Some(2));
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+        assert!(verify_range(
+            bytes.as_slice(),
+            start,
+            end,
+            Some(6),
+            Some(2),
+            expected_hash
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn range_verify_max_bytes() {
+        let mut tree = make_tree_seq(100);
+        let expected_hash = tree.hash();
+        let end = Bound::Excluded(seq_key(90));
+
+        // resumes after the continuation key until the whole range is proven
+        let mut start = Bound::Included(seq_key(10));
+        let mut keys = vec![];
+        let mut proofs = 0;
+        loop {
+            let mut walker = RefWalker::new(&mut tree, PanicSource {});
+            let proof = walker
+                .create_range_proof_max_bytes(start.clone(), end.clone(), 1024)
+                .expect("failed to create proof");
+            let mut bytes = vec![];
+            encode_into(proof.iter(), &mut bytes);
+            assert!(bytes.len() <= 1024);
+
+            let (entries, continuation) =
+                verify_range_prefix(bytes.as_slice(), start, end.clone(), expected_hash)
+                    .expect("verify failed");
+            proofs += 1;
+            keys.extend(entries.into_iter().map(|(key, _)| key));
+
+            match continuation {
+                Some(key) => {
+                    assert_eq!(keys.last(), Some(&key));
+                    start = Bound::Excluded(key);
+                }
+                None => break,
+            }
+        }
+        assert!(proofs > 1);
+        assert_eq!(keys, (10..90).map(seq_key).collect::<Vec<_>>());
+
+        // a truncated proof is not a proof of the whole range
+        let start = Bound::Included(seq_key(10));
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let proof = walker
+            .create_range_proof_max_bytes(start.clone(), end.clone(), 1024)
+            .expect("failed to create proof");
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+        assert!(verify_range(
+            bytes.as_slice(),
+            start.clone(),
+            end.clone(),
+            None,
+            None,
+            expected_hash
+        )
+        .is_err());
+
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        assert!(walker
+            .create_range_proof_max_bytes(start, end, 100)
+            .is_err());
+    }
+
+    #[test]
+    fn range_verify_max_bytes_limited_fits() {
+        let mut tree = make_tree_seq(100);
+        let expected_hash = tree.hash();
+        let start = Bound::Included(seq_key(10));
+        let end = Bound::Excluded(seq_key(20));
+
+        let mut proof_length = |limit| {
+            let mut walker = RefWalker::new(&mut tree, PanicSource {});
+            let proof = walker
+                .create_range_proof(start.clone(), end.clone(), limit, None)
+                .expect("failed to create proof");
+            let mut bytes = vec![];
+            encode_into(proof.iter(), &mut bytes);
+            bytes.len()
+        };
+        // limiting the proof to the size of the range abridges the entry after
+        // the range, so the proof is smaller than the unlimited proof
+        let limited_length = proof_length(Some(10));
+        let unlimited_length = proof_length(None);
+        assert!(limited_length < unlimited_length);
+
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let proof = walker
+            .create_range_proof_max_bytes(start.clone(), end.clone(), limited_length)
+            .expect("failed to create proof");
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+        assert_eq!(bytes.len(), limited_length);
+
+        let (entries, _) = verify_range_prefix(bytes.as_slice(), start, end, expected_hash)
+            .expect("verify failed");
+        let keys: Vec<_> = entries.into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, (10..20).map(seq_key).collect::<Vec<_>>());
+    }
+
+    fn paged_range_rev_test(limit: Option<usize>, offset: Option<usize>, expected: Range<u64>) {
+        let mut tree = make_tree_seq(100);
+        let expected_hash = tree.hash();
+        let start = Bound::Included(seq_key(10));
+        let end = Bound::Excluded(seq_key(50));
+
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let proof = walker
+            .create_range_proof_rev(start.clone(), end.clone(), limit, offset)
+            .expect("failed to create proof");
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+
+        let result = verify_range_rev(bytes.as_slice(), start, end, limit, offset, expected_hash)
+            .expect("verify failed");
+        let keys: Vec<_> = result.into_iter().map(|(key, _)| key).collect();
+        let expected_keys: Vec<_> = expected.rev().map(seq_key).collect();
+        assert_eq!(keys, expected_keys);
+    }
+
+    #[test]
+    fn range_rev_verify() {
+        paged_range_rev_test(None, None, 10..50);
+        paged_range_rev_test(Some(5), None, 45..50);
+        paged_range_rev_test(Some(5), Some(5), 40..45);
+        paged_range_rev_test(None, Some(38), 10..12);
+        paged_range_rev_test(Some(5), Some(100), 10..10);
+        paged_range_rev_test(Some(0), None, 10..10);
+        paged_range_rev_test(Some(usize::MAX), Some(5), 10..45);
+        paged_range_rev_test(Some(5), Some(usize::MAX), 10..10);
+    }
+
+    #[test]
+    fn range_rev_verify_gap() {
+        let mut tree = make_tree_seq(100);
+        let expected_hash = tree.hash();
+        let start = Bound::Included(seq_key(10));
+        let end = Bound::Excluded(seq_key(50));
+
+        // an ascending page leaves the end of the range abridged, so it can't
+        // prove the last entries of the range
+        let proof = paged_range_proof(&mut tree, start.clone(), end.clone(), Some(5), None);
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+        assert!(verify_range_rev(
+            bytes.as_slice(),
+            start.clone(),
+            end.clone(),
+            Some(5),
+            None,
+            expected_hash
+        )
+        .is_err());
+
+        // a descending page can't be verified with a larger window
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let proof = walker
+            .create_range_proof_rev(start.clone(), end.clone(), Some(5), None)
+            .expect("failed to create proof");
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+        assert!(
+            verify_range_rev(bytes.as_slice(), start, end, Some(6), None, expected_hash).is_err()
+        );
+    }
+
+    fn query_items_proof(tree: &mut tree::Tree, queries: &[QueryItem]) -> Vec<u8> {
+        let mut walker = RefWalker::new(tree, PanicSource {});
+        let proof = walker
+            .create_query_proof(queries)
+            .expect("failed to create proof");
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn query_items_verify() {
+        let mut tree = make_tree_seq(1000);
+        let expected_hash = tree.hash();
+
+        let mut queries: Vec<_> = (0..200)
+            .rev()
+            .map(|i| QueryItem::Key(seq_key(i * 5)))
+            .collect();
+        queries.push(QueryItem::Key(seq_key(5)));
+        queries.push(QueryItem::Key(seq_key(5000)));
+        queries.push(QueryItem::Range(
+            Bound::Included(seq_key(100)),
+            Bound::Excluded(seq_key(110)),
+        ));
+
+        let bytes = query_items_proof(&mut tree, queries.as_slice());
+        let result = verify_query_items(bytes.as_slice(), queries.as_slice(), expected_hash)
+            .expect("verify failed");
+
+        for i in 0..200 {
+            assert_eq!(result.get(&seq_key(i * 5)), Some(&vec![123; 60]));
+        }
+        for i in 100..110 {
+            assert_eq!(result.get(&seq_key(i)), Some(&vec![123; 60]));
+        }
+        assert_eq!(result.get(&seq_key(5000)), None);
+        assert_eq!(result.len(), 208);
+
+        let separate_len: usize = queries
+            .iter()
+            .map(|query| query_items_proof(&mut tree, &[query.clone()]).len())
+            .sum();
+        assert!(bytes.len() * 2 < separate_len);
+    }
+
+    #[test]
+    fn query_items_bounds() {
+        let mut tree = apply_to_memonly(None, &make_batch_seq(0..100)).expect("expected tree");
+        let expected_hash = tree.hash();
+
+        let cases = vec![
+            (QueryItem::Key(seq_key(20)), 20..21),
+            (QueryItem::Key(seq_key(200)), 0..0),
+            (
+                QueryItem::Range(Bound::Included(seq_key(10)), Bound::Excluded(seq_key(20))),
+                10..20,
+            ),
+            (
+                QueryItem::Range(Bound::Excluded(seq_key(10)), Bound::Included(seq_key(20))),
+                11..21,
+            ),
+            (
+                QueryItem::Range(Bound::Included(seq_key(20)), Bound::Excluded(seq_key(20))),
+                0..0,
+            ),
+            (QueryItem::RangeInclusive(seq_key(10), seq_key(20)), 10..21),
+            (QueryItem::RangeInclusive(seq_key(20), seq_key(20)), 20..21),
+            (QueryItem::RangeInclusive(seq_key(20), seq_key(10)), 0..0),
+            (QueryItem::RangeFrom(seq_key(95)), 95..100),
+            (QueryItem::RangeFrom(seq_key(100)), 0..0),
+            (QueryItem::RangeTo(seq_key(3)), 0..3),
+            (QueryItem::RangeTo(seq_key(0)), 0..0),
+        ];
+
+        for (query, expected) in cases.iter() {
+            let queries = [query.clone()];
+            let bytes = query_items_proof(&mut tree, &queries);
+            let result = verify_query_items(bytes.as_slice(), &queries, expected_hash)
+                .expect("verify failed");
+            let keys: Vec<_> = result.keys().cloned().collect();
+            assert_eq!(
+                keys,
+                expected.clone().map(seq_key).collect::<Vec<_>>(),
+                "{:?}",
+                query
+            );
+            assert!(keys.iter().all(|key| query.contains(key)));
+        }
+
+        // empty ranges are proven along with other queries
+        let queries: Vec<_> = cases.into_iter().map(|(query, _)| query).collect();
+        let bytes = query_items_proof(&mut tree, queries.as_slice());
+        let result = verify_query_items(bytes.as_slice(), queries.as_slice(), expected_hash)
+            .expect("verify failed");
+        assert_eq!(result.len(), 19);
+    }
+
+    #[test]
+    fn query_items_verify_missing_query() {
+        let mut tree = make_tree_seq(100);
+        let expected_hash = tree.hash();
+
+        let queries = vec![QueryItem::Key(seq_key(10)), QueryItem::Key(seq_key(20))];
+        let bytes = query_items_proof(&mut tree, queries.as_slice());
+
+        let queries = vec![QueryItem::Key(seq_key(10)), QueryItem::Key(seq_key(15))];
+        assert!(verify_query_items(bytes.as_slice(), queries.as_slice(), expected_hash).is_err());
+    }
+
+    #[test]
+    fn verify_returns_root_hash() {
+        let mut tree = make_tree_seq(10);
+        let expected_hash = tree.hash();
+        let keys = vec![seq_key(2), seq_key(5)];
+
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let (proof, _) = walker
+            .create_proof(keys.as_slice())
+            .expect("failed to create proof");
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+
+        let (hash, entries) = verify(bytes.as_slice()).expect("verify failed");
+        assert_eq!(hash, expected_hash);
+        assert_eq!(entries.get(&seq_key(2)), Some(&vec![123; 60]));
+        assert_eq!(entries.get(&seq_key(5)), Some(&vec![123; 60]));
+
+        // corrupting the data in the proof changes the resulting hash
+        let index = bytes.iter().position(|byte| *byte == 123).unwrap();
+        bytes[index] = 124;
+        let (hash, _) = verify(bytes.as_slice()).expect("verify failed");
+        assert_ne!(hash, expected_hash);
+
+        // a truncated proof is an error rather than a panic
+        assert!(verify(&bytes[..bytes.len() - 1]).is_err());
+        assert!(verify(&[]).is_err());
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn verify_with_blake3() {
+        use crate::tree::Blake3Hasher;
+
+        let mut tree = make_3_node_tree();
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+
+        let keys = vec![vec![3], vec![5], vec![7]];
+        let (proof, _) = walker
+            .create_proof(keys.as_slice())
+            .expect("failed to create proof");
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+
+        let leaf_hash = |i| {
+            let kv_hash = Blake3Hasher::hash_kv(&[i], &[i]);
+            Blake3Hasher::hash_node(&kv_hash, &NULL_HASH, &NULL_HASH)
+        };
+        let expected_hash = Blake3Hasher::hash_node(
+            &Blake3Hasher::hash_kv(&[5], &[5]),
+            &leaf_hash(3),
+            &leaf_hash(7),
+        );
+
+        let result = verify_query_with_hasher::<Blake3Hasher>(
+            bytes.as_slice(),
+            keys.as_slice(),
+            expected_hash,
+        )
+        .expect("verify failed");
+        assert_eq!(result, vec![Some(vec![3]), Some(vec![5]), Some(vec![7])]);
+
+        assert!(verify_query(bytes.as_slice(), keys.as_slice(), expected_hash).is_err());
+        assert!(verify_query_with_hasher::<Blake3Hasher>(
+            bytes.as_slice(),
+            keys.as_slice(),
+            tree.hash()
+        )
+        .is_err());
+    }
+
+    /// Counts the node hashes computed by `CountingHasher`.
+    static NODE_HASH_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    /// A `Blake2bHasher` which counts calls to `hash_node`.
+    struct CountingHasher;
+
+    impl Hasher for CountingHasher {
+        type Digest = Hash;
+        const LENGTH: usize = HASH_LENGTH;
+        const VERSION: u8 = Blake2bHasher::VERSION;
+
+        fn hash_value(value: &[u8]) -> Hash {
+            Blake2bHasher::hash_value(value)
+        }
+
+        fn hash_kv_digest(key: &[u8], value_hash: &Hash) -> Hash {
+            Blake2bHasher::hash_kv_digest(key, value_hash)
+        }
+
+        fn hash_node(kv: &Hash, left: &Hash, right: &Hash) -> Hash {
+            NODE_HASH_COUNT.fetch_add(1, Ordering::SeqCst);
+            Blake2bHasher::hash_node(kv, left, right)
+        }
+    }
+
+    #[test]
+    fn verify_cache() {
+        let mut tree = make_tree_seq(100);
+        let keys_a = vec![seq_key(10)];
+        let keys_b = vec![seq_key(20)];
+        let proof_a = prove_key(&mut tree, &keys_a[0]);
+        let proof_b = prove_key(&mut tree, &keys_b[0]);
+
+        let verify_counting = |cache: &mut VerifyCache<CountingHasher>, proof: &[u8], keys| {
+            let count_before = NODE_HASH_COUNT.load(Ordering::SeqCst);
+            let result = cache.verify_query(proof, keys);
+            (
+                result,
+                NODE_HASH_COUNT.load(Ordering::SeqCst) - count_before,
+            )
+        };
+
+        let mut uncached = VerifyCache::with_hasher(tree.hash());
+        let (_, uncached_count) = verify_counting(&mut uncached, &proof_b, &keys_b);
+
+        let mut cache = VerifyCache::with_hasher(tree.hash());
+        assert!(cache.is_empty());
+        let (result, _) = verify_counting(&mut cache, &proof_a, &keys_a);
+        assert_eq!(result.unwrap(), vec![Some(vec![123; 60])]);
+        assert!(!cache.is_empty());
+
+        // the proofs share the nodes near the root, so the second only
+        // computes the hashes the first did not
+        let (result, count) = verify_counting(&mut cache, &proof_b, &keys_b);
+        assert_eq!(result.unwrap(), vec![Some(vec![123; 60])]);
+        assert!(count < uncached_count);
+
+        // verifying the same proof again computes no node hashes
+        let (result, count) = verify_counting(&mut cache, &proof_a, &keys_a);
+        assert_eq!(result.unwrap(), vec![Some(vec![123; 60])]);
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn verify_cache_invalid_proof() {
+        let mut tree = make_tree_seq(100);
+        let keys = vec![seq_key(10)];
+        let proof = prove_key(&mut tree, &keys[0]);
+
+        let mut cache = VerifyCache::new([1; HASH_LENGTH]);
+        assert!(cache.verify_query(&proof, &keys).is_err());
+        assert!(cache.is_empty());
+
+        // hashes from the failed proof were not cached, so a tampered value is
+        // still rejected by a cache for the real root hash
+        let mut cache = VerifyCache::new(tree.hash());
+        cache.verify_query(&proof, &keys).expect("verify failed");
+        let len = cache.len();
+        let mut tampered = proof.clone();
+        let value_index = tampered
+            .windows(60)
+            .position(|window| window == &[123; 60][..])
+            .expect("expected value in proof");
+        tampered[value_index] ^= 1;
+        assert!(cache.verify_query(&tampered, &keys).is_err());
+        assert_eq!(cache.len(), len);
+    }
+
+    #[test]
+    fn full_verify() {
+        let mut tree = make_tree_seq(100);
+        let expected_hash = tree.hash();
+        let expected_entries: Vec<_> = tree
+            .iter()
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+            .collect();
+
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let proof = walker.create_full_proof().expect("failed to create proof");
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+
+        let verified = verify_full(bytes.as_slice(), expected_hash).expect("verify failed");
+        assert_eq!(verified.hash(), expected_hash);
+        assert!(verified.structurally_eq(&tree));
+        let entries: Vec<_> = verified
+            .iter()
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+            .collect();
+        assert_eq!(entries, expected_entries);
+
+        let mut wrong_hash = expected_hash;
+        wrong_hash[0] ^= 1;
+        assert!(verify_full(bytes.as_slice(), wrong_hash).is_err());
+
+        // a proof with abridged nodes does not prove the whole tree
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let (proof, _) = walker
+            .create_proof(&[seq_key(50)])
+            .expect("failed to create proof");
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+        assert!(verify_full(bytes.as_slice(), expected_hash).is_err());
+    }
+
+    #[test]
+    fn count_verify() {
+        let mut tree =
+            Walker::<PanicSource, Blake2bCountedHasher>::apply_to(None, &make_batch_seq(0..100))
+                .expect("apply failed")
+                .0
+                .expect("expected tree");
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        let expected_hash = tree.hash();
+        let encode = |proof: &[Op]| {
+            let mut bytes = vec![];
+            encode_into(proof.iter(), &mut bytes);
+            bytes
+        };
+
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let proof = walker.create_count_proof().expect("failed to create proof");
+        assert_eq!(proof.len(), 5);
+        let bytes = encode(&proof);
+        assert_eq!(
+            verify_count(bytes.as_slice(), expected_hash).expect("verify failed"),
+            (100, true)
+        );
+
+        let mut wrong_hash = expected_hash;
+        wrong_hash[0] ^= 1;
+        assert_eq!(
+            verify_count(bytes.as_slice(), wrong_hash).expect("verify failed"),
+            (100, false)
+        );
+
+        // claiming an extra node in a child's subtree
+        let mut tampered = proof.clone();
+        match &mut tampered[0] {
+            Op::Push(Node::CountedHash(_, count)) => *count += 1,
+            _ => panic!("expected counted hash"),
+        }
+        let bytes = encode(&tampered);
+        assert_eq!(
+            verify_count(bytes.as_slice(), expected_hash).expect("verify failed"),
+            (101, false)
+        );
+
+        // moving a node from one child's claimed count to the other's keeps
+        // the total but changes the hash
+        let mut tampered = proof.clone();
+        for (i, op) in tampered.iter_mut().enumerate() {
+            if let Op::Push(Node::CountedHash(_, count)) = op {
+                if i == 0 {
+                    *count -= 1;
+                } else {
+                    *count += 1;
+                }
+            }
+        }
+        let bytes = encode(&tampered);
+        assert_eq!(
+            verify_count(bytes.as_slice(), expected_hash).expect("verify failed"),
+            (100, false)
+        );
+
+        // a bare counted hash does not bind its count
+        let bytes = encode(&[Op::Push(Node::CountedHash(expected_hash, 100))]);
+        assert!(verify_count(bytes.as_slice(), expected_hash).is_err());
+
+        // query proofs also prove the count
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let (proof, _) = walker
+            .create_proof(&[seq_key(50)])
+            .expect("failed to create proof");
+        let proof: Vec<_> = proof.into_iter().collect();
+        let bytes = encode(&proof);
+        assert_eq!(
+            verify_count(bytes.as_slice(), expected_hash).expect("verify failed"),
+            (100, true)
+        );
+        assert_eq!(
+            verify_query_with_hasher::<Blake2bCountedHasher>(
+                bytes.as_slice(),
+                &[seq_key(50)],
+                expected_hash
+            )
+            .expect("verify failed"),
+            vec![Some(vec![123; 60])]
+        );
+
+        // proofs of one hasher version cannot be verified with the other
+        assert!(verify_query(bytes.as_slice(), &[seq_key(50)], expected_hash).is_err());
+        assert!(
+            verify_count_with_hasher::<Blake2bHasher>(bytes.as_slice(), expected_hash).is_err()
+        );
+
+        // trees hashed before version 3 cannot prove their count
+        let mut tree = make_tree_seq(100);
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        assert!(walker.create_count_proof().is_err());
     }
 }
@@ -1,11 +1,15 @@
 mod crash_merk;
 mod temp_merk;
 
-use crate::tree::{Batch, BatchEntry, NoopCommit, Op, PanicSource, Tree, Walker};
+use crate::proofs::encode_into;
+use crate::tree::{
+    Batch, BatchEntry, Fetch, Link, NoAggregate, NoopCommit, Op, PanicSource, RefWalker, Tree,
+    Walker,
+};
 use byteorder::{BigEndian, WriteBytesExt};
 use rand::prelude::*;
 use std::convert::TryInto;
-use std::ops::Range;
+use std::ops::{Bound, Range};
 
 pub use crash_merk::CrashMerk;
 pub use temp_merk::TempMerk;
@@ -26,9 +30,11 @@ pub fn assert_tree_invariants(tree: &Tree) {
     }
 
     if let Some(left) = tree.child(true) {
+        assert_eq!(tree.child_count(true), left.count());
         assert_tree_invariants(left);
     }
     if let Some(right) = tree.child(false) {
+        assert_eq!(tree.child_count(false), right.count());
         assert_tree_invariants(right);
     }
 }
@@ -153,3 +159,70 @@ pub fn make_tree_seq(node_count: u64) -> Tree {
 
     tree
 }
+
+pub fn make_modified_tree_rand(node_count: u64, seed: u64) -> Tree {
+    let batch = make_batch_rand(node_count, seed);
+    Walker::<PanicSource>::apply_to(None, &batch)
+        .expect("apply failed")
+        .0
+        .expect("expected tree")
+}
+
+/// Builds a tree with key `[5]` at the root, a pruned left child with key
+/// `[3]`, and a right child with key `[7]` which is held in memory.
+pub fn make_partially_pruned_tree(value_5: Vec<u8>, value_7: Vec<u8>) -> Tree {
+    Tree::from_fields(
+        vec![5],
+        value_5,
+        Default::default(),
+        Some(Link::Reference {
+            hash: Default::default(),
+            child_heights: (0, 0),
+            descendant_count: 1,
+            aggregate: NoAggregate,
+            key: vec![3],
+        }),
+        None,
+    )
+    .attach(false, Some(Tree::new(vec![7], value_7)))
+}
+
+/// Walks the whole tree in order, fetching pruned nodes from the walker's
+/// source, and pushes every key onto `keys`.
+pub fn collect_keys<S>(mut walker: RefWalker<S>, keys: &mut Vec<Vec<u8>>)
+where
+    S: Fetch + Sized + Clone + Send,
+{
+    if let Some(left) = walker.walk(true).expect("walk failed") {
+        collect_keys(left, keys);
+    }
+    keys.push(walker.tree().key().to_vec());
+    if let Some(right) = walker.walk(false).expect("walk failed") {
+        collect_keys(right, keys);
+    }
+}
+
+pub fn prove_key(tree: &mut Tree, key: &[u8]) -> Vec<u8> {
+    let mut walker = RefWalker::new(tree, PanicSource {});
+    let (proof, _) = walker
+        .create_proof(&[key.to_vec()])
+        .expect("failed to create proof");
+    let mut bytes = vec![];
+    encode_into(proof.iter(), &mut bytes);
+    bytes
+}
+
+pub fn prove_range(tree: &mut Tree, start: &[u8], end: &[u8]) -> Vec<u8> {
+    let mut walker = RefWalker::new(tree, PanicSource {});
+    let proof = walker
+        .create_range_proof(
+            Bound::Included(start.to_vec()),
+            Bound::Excluded(end.to_vec()),
+            None,
+            None,
+        )
+        .expect("failed to create proof");
+    let mut bytes = vec![];
+    encode_into(proof.iter(), &mut bytes);
+    bytes
+}
@@ -0,0 +1,362 @@
+use super::hash::Hasher;
+use super::{side_to_str, Tree};
+use crate::error::Result;
+use ed::{Decode, Encode, Terminated};
+use failure::bail;
+use std::io::{Read, Write};
+use std::ops::{Bound, RangeBounds};
+
+/// An application-defined summary of a set of key/value pairs, such as the sum
+/// of a numeric field of the values, which can be computed over a range of keys
+/// with `Tree::range_aggregate`.
+///
+/// Aggregates form a monoid: `combine` must be associative, and `identity` must
+/// leave any aggregate unchanged when combined with it on either side.
+/// `combine` is always called with aggregates of adjacent ranges in key order,
+/// so it does not need to be commutative.
+///
+/// Trees are generic over their aggregate type (defaulting to `NoAggregate`),
+/// and every link stores the aggregate of its child's subtree beside its
+/// descendant count. Like the child heights, it is recomputed whenever a child
+/// is attached, and is encoded with the link so it is kept when the child is
+/// pruned. It is not part of the node's hash. Since other fields follow it in
+/// the encoding, its encoding must be `Terminated`.
+pub trait Aggregate: Clone + Encode + Decode + Terminated + Send + Sync {
+    /// Returns the aggregate of an empty range.
+    fn identity() -> Self;
+
+    /// Combines the aggregate of a range with the aggregate of the range
+    /// immediately after it.
+    fn combine(self, other: Self) -> Self;
+
+    /// Computes the aggregate of a single key/value pair.
+    fn from_kv(key: &[u8], value: &[u8]) -> Self;
+}
+
+/// The default aggregate, for trees which don't need one. It is encoded in zero
+/// bytes, so links of trees using it are encoded the same as before aggregates
+/// were added.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NoAggregate;
+
+impl Aggregate for NoAggregate {
+    #[inline]
+    fn identity() -> Self {
+        NoAggregate
+    }
+
+    #[inline]
+    fn combine(self, _other: Self) -> Self {
+        NoAggregate
+    }
+
+    #[inline]
+    fn from_kv(_key: &[u8], _value: &[u8]) -> Self {
+        NoAggregate
+    }
+}
+
+impl Encode for NoAggregate {
+    #[inline]
+    fn encode_into<W: Write>(&self, _dest: &mut W) -> ed::Result<()> {
+        Ok(())
+    }
+
+    #[inline]
+    fn encoding_length(&self) -> ed::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl Decode for NoAggregate {
+    #[inline]
+    fn decode<R: Read>(_input: R) -> ed::Result<Self> {
+        Ok(NoAggregate)
+    }
+}
+
+impl Terminated for NoAggregate {}
+
+impl<H: Hasher, A: Aggregate> Tree<H, A> {
+    /// Returns the aggregate of the entries in the child's subtree on the given
+    /// side, as stored on its link. If there is no child, returns the identity.
+    #[inline]
+    pub fn child_aggregate(&self, left: bool) -> A {
+        self.link(left)
+            .map_or_else(A::identity, |link| link.aggregate().clone())
+    }
+
+    /// Returns the aggregate of the entries in the tree, including any pruned
+    /// descendants, computed from the aggregates stored on its links.
+    #[inline]
+    pub fn aggregate(&self) -> A {
+        self.child_aggregate(true)
+            .combine(A::from_kv(self.key(), self.value()))
+            .combine(self.child_aggregate(false))
+    }
+
+    /// Computes the aggregate of the entries with keys in `range`, combining
+    /// them in key order.
+    ///
+    /// Subtrees whose keys are all in the range contribute the aggregate stored
+    /// on their link without being traversed, so this takes time logarithmic
+    /// in the size of the tree rather than linear in the number of entries in
+    /// the range. Returns an error if a pruned subtree is only partly in the
+    /// range.
+    pub fn range_aggregate<R: RangeBounds<[u8]>>(&self, range: R) -> Result<A> {
+        let check_start = !matches!(range.start_bound(), Bound::Unbounded);
+        let check_end = !matches!(range.end_bound(), Bound::Unbounded);
+        self.bounded_aggregate(&range, check_start, check_end)
+    }
+
+    /// Computes the aggregate of the entries of the tree with keys in `range`.
+    /// The start or end bound is only checked if `check_start` or `check_end`
+    /// is `true`, otherwise it is known to hold for every key in the tree.
+    fn bounded_aggregate<R>(&self, range: &R, check_start: bool, check_end: bool) -> Result<A>
+    where
+        R: RangeBounds<[u8]>,
+    {
+        let key = self.key();
+        let (start, end) = (range.start_bound(), range.end_bound());
+        let after_start = !check_start
+            || match start {
+                Bound::Included(start) => key >= start,
+                Bound::Excluded(start) => key > start,
+                Bound::Unbounded => true,
+            };
+        let before_end = !check_end
+            || match end {
+                Bound::Included(end) => key <= end,
+                Bound::Excluded(end) => key < end,
+                Bound::Unbounded => true,
+            };
+
+        // the left subtree's keys are less than this key, so none of them are
+        // in the range if this key is before the start, and all of them are
+        // before the end if this key is not after it (and vice versa for the
+        // right subtree)
+        let left_check_end = check_end
+            && match end {
+                Bound::Included(end) | Bound::Excluded(end) => key > end,
+                Bound::Unbounded => false,
+            };
+        let right_check_start = check_start
+            && match start {
+                Bound::Included(start) | Bound::Excluded(start) => key < start,
+                Bound::Unbounded => false,
+            };
+
+        let left = if after_start {
+            self.child_bounded_aggregate(true, range, check_start, left_check_end)?
+        } else {
+            A::identity()
+        };
+        let kv = if after_start && before_end {
+            A::from_kv(key, self.value())
+        } else {
+            A::identity()
+        };
+        let right = if before_end {
+            self.child_bounded_aggregate(false, range, right_check_start, check_end)?
+        } else {
+            A::identity()
+        };
+
+        Ok(left.combine(kv).combine(right))
+    }
+
+    /// Like `bounded_aggregate`, for the child on the given side. Uses the
+    /// aggregate stored on the child's link if neither bound needs checking,
+    /// otherwise returns an error if the child is pruned.
+    fn child_bounded_aggregate<R>(
+        &self,
+        left: bool,
+        range: &R,
+        check_start: bool,
+        check_end: bool,
+    ) -> Result<A>
+    where
+        R: RangeBounds<[u8]>,
+    {
+        let link = match self.link(left) {
+            None => return Ok(A::identity()),
+            Some(link) => link,
+        };
+
+        if !check_start && !check_end {
+            return Ok(link.aggregate().clone());
+        }
+
+        match link.tree() {
+            Some(child) => child.bounded_aggregate(range, check_start, check_end),
+            None => bail!("Cannot aggregate pruned {} child", side_to_str(left)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::seq_key;
+    use crate::tree::{Blake2bHasher, DepthPrune, NoopCommit, Op, PanicSource, Walker};
+    use std::collections::BTreeMap;
+
+    /// Sums the values, which are 8-byte big-endian integers.
+    #[derive(Clone, Debug, PartialEq)]
+    struct Sum(u64);
+
+    impl Aggregate for Sum {
+        fn identity() -> Self {
+            Sum(0)
+        }
+
+        fn combine(self, other: Self) -> Self {
+            Sum(self.0 + other.0)
+        }
+
+        fn from_kv(_key: &[u8], value: &[u8]) -> Self {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(value);
+            Sum(u64::from_be_bytes(bytes))
+        }
+    }
+
+    impl Encode for Sum {
+        fn encode_into<W: Write>(&self, dest: &mut W) -> ed::Result<()> {
+            self.0.encode_into(dest)
+        }
+
+        fn encoding_length(&self) -> ed::Result<usize> {
+            self.0.encoding_length()
+        }
+    }
+
+    impl Decode for Sum {
+        fn decode<R: Read>(input: R) -> ed::Result<Self> {
+            Ok(Sum(u64::decode(input)?))
+        }
+    }
+
+    impl Terminated for Sum {}
+
+    type SumTree = Tree<Blake2bHasher, Sum>;
+
+    /// Applies puts of `n * n % 37` for each `n` in `puts`, and deletes of
+    /// each key in `deletes`, to the tree and to `values`.
+    fn apply(
+        maybe_tree: Option<SumTree>,
+        values: &mut BTreeMap<Vec<u8>, u64>,
+        puts: &[u64],
+        deletes: &[u64],
+    ) -> Option<SumTree> {
+        let mut batch = vec![];
+        for &n in puts {
+            let value = n * n % 37;
+            values.insert(seq_key(n), value);
+            batch.push((seq_key(n), Op::Put(value.to_be_bytes().to_vec())));
+        }
+        for &n in deletes {
+            values.remove(&seq_key(n));
+            batch.push((seq_key(n), Op::Delete));
+        }
+        batch.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let walker = maybe_tree.map(|tree| Walker::new(tree, PanicSource {}));
+        let (maybe_tree, _) = Walker::<PanicSource, Blake2bHasher, Sum>::apply_to(walker, &batch)
+            .expect("apply failed");
+        maybe_tree
+    }
+
+    /// Returns ranges with every combination of start and end bounds, given as
+    /// optional keys and whether they are inclusive.
+    fn bounds() -> Vec<(Option<(Vec<u8>, bool)>, Option<(Vec<u8>, bool)>)> {
+        let mut bounds = vec![];
+        for &start in [None, Some(0), Some(10), Some(37), Some(50), Some(99)].iter() {
+            for &end in [None, Some(0), Some(20), Some(38), Some(50), Some(100)].iter() {
+                for &(start_inclusive, end_inclusive) in
+                    [(true, true), (true, false), (false, true), (false, false)].iter()
+                {
+                    bounds.push((
+                        start.map(|n| (seq_key(n), start_inclusive)),
+                        end.map(|n| (seq_key(n), end_inclusive)),
+                    ));
+                }
+            }
+        }
+        bounds
+    }
+
+    fn bound(key: &Option<(Vec<u8>, bool)>) -> Bound<&[u8]> {
+        match key {
+            None => Bound::Unbounded,
+            Some((key, true)) => Bound::Included(key.as_slice()),
+            Some((key, false)) => Bound::Excluded(key.as_slice()),
+        }
+    }
+
+    fn assert_sums(tree: &SumTree, values: &BTreeMap<Vec<u8>, u64>) {
+        for (start, end) in bounds() {
+            let range = (bound(&start), bound(&end));
+
+            let expected: u64 = values
+                .iter()
+                .filter(|(key, _)| range.contains(key.as_slice()))
+                .map(|(_, value)| value)
+                .sum();
+            let sum = tree.range_aggregate(range).expect("aggregate failed");
+            assert_eq!(sum, Sum(expected));
+        }
+    }
+
+    #[test]
+    fn range_sum() {
+        let mut values = BTreeMap::new();
+        let puts: Vec<u64> = (0..100).collect();
+        let tree = apply(None, &mut values, &puts, &[]).expect("expected tree");
+        assert_eq!(tree.aggregate(), Sum(values.values().sum()));
+        assert_sums(&tree, &values);
+
+        // links are updated as nodes are detached, rotated, and reattached
+        let puts: Vec<u64> = (0..100).filter(|n| n % 7 == 0).map(|n| n + 1000).collect();
+        let deletes: Vec<u64> = (0..100).filter(|n| n % 3 == 0).collect();
+        let mut tree = apply(Some(tree), &mut values, &puts, &deletes).expect("expected tree");
+        assert_eq!(tree.aggregate(), Sum(values.values().sum()));
+        assert_sums(&tree, &values);
+
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        assert_sums(&tree, &values);
+    }
+
+    #[test]
+    fn range_sum_pruned() {
+        let mut values = BTreeMap::new();
+        let puts: Vec<u64> = (0..100).collect();
+        let mut tree = apply(None, &mut values, &puts, &[]).expect("expected tree");
+        tree.commit(&mut DepthPrune { keep_levels: 1 })
+            .expect("commit failed");
+        assert!(tree.link(true).unwrap().is_reference());
+        assert!(tree.link(false).unwrap().is_reference());
+
+        // pruned subtrees entirely in the range use their links' aggregates
+        let root_key = tree.key().to_vec();
+        let left_sum: u64 = values.range(..root_key.clone()).map(|(_, v)| v).sum();
+        assert_eq!(
+            tree.range_aggregate(..).expect("aggregate failed"),
+            Sum(values.values().sum())
+        );
+        assert_eq!(
+            tree.range_aggregate((Bound::Unbounded, Bound::Excluded(root_key.as_slice())))
+                .expect("aggregate failed"),
+            Sum(left_sum)
+        );
+
+        // but pruned subtrees only partly in the range can't be aggregated
+        let (start, end) = (seq_key(10), seq_key(20));
+        assert!(tree
+            .range_aggregate((
+                Bound::Included(start.as_slice()),
+                Bound::Excluded(end.as_slice())
+            ))
+            .is_err());
+    }
+}
@@ -1,31 +1,200 @@
-use super::Tree;
+use super::{Aggregate, Blake2bHasher, Hasher, NoAggregate, Tree};
 use crate::error::Result;
+use std::cell::Cell;
 
 /// To be used when committing a tree (writing it to a store after applying the
 /// changes).
-pub trait Commit {
+pub trait Commit<H: Hasher = Blake2bHasher, A: Aggregate = NoAggregate> {
+    /// Called once at the start of a commit, before any nodes are written.
+    /// Implementations which write to a transactional store can use this to
+    /// begin buffering writes. By default, does nothing.
+    fn begin_batch(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called once at the end of a commit, after all nodes have been written,
+    /// so that buffered writes can be flushed atomically. Not called if the
+    /// commit fails. By default, does nothing.
+    fn end_batch(&mut self) -> Result<()> {
+        Ok(())
+    }
+
     /// Called once per updated node when a finalized tree is to be written to a
     /// backing store or cache.
-    fn write(&mut self, tree: &Tree) -> Result<()>;
+    fn write(&mut self, tree: &Tree<H, A>) -> Result<()>;
 
-    /// Called once per node after writing a node and its children. The returned
-    /// tuple specifies whether or not to prune the left and right child nodes,
-    /// respectively. For example, returning `(true, true)` will prune both
-    /// nodes, removing them from memory.
-    fn prune(&self, _tree: &Tree) -> (bool, bool) {
+    /// Called once per written node, after all nodes have been written and
+    /// `end_batch` has succeeded. The returned tuple specifies whether or not to
+    /// prune the left and right child nodes, respectively. For example,
+    /// returning `(true, true)` will prune both nodes, removing them from
+    /// memory.
+    fn prune(&self, _tree: &Tree<H, A>) -> (bool, bool) {
         (true, true)
     }
+
+    /// Like `prune`, but also given the depth of the node being committed,
+    /// relative to the root of the tree being committed (the root has depth
+    /// 0). Called instead of `prune`, so implementations which prune based on
+    /// depth should override this method. By default, ignores the depth and
+    /// calls `prune`.
+    fn prune_at_depth(&self, tree: &Tree<H, A>, _depth: u8) -> (bool, bool) {
+        self.prune(tree)
+    }
 }
 
 /// A `Commit` implementation which does not write to a store and does not prune
 /// any nodes from the Tree. Useful when only keeping a tree in memory.
 pub struct NoopCommit {}
-impl Commit for NoopCommit {
-    fn write(&mut self, _tree: &Tree) -> Result<()> {
+impl<H: Hasher, A: Aggregate> Commit<H, A> for NoopCommit {
+    fn write(&mut self, _tree: &Tree<H, A>) -> Result<()> {
         Ok(())
     }
 
-    fn prune(&self, _tree: &Tree) -> (bool, bool) {
+    fn prune(&self, _tree: &Tree<H, A>) -> (bool, bool) {
         (false, false)
     }
 }
+
+/// A `Commit` implementation which does not write to a store, and keeps only
+/// the top `keep_levels` levels of the tree in memory, pruning all nodes below
+/// them. For example, `keep_levels: 1` keeps only the root node.
+pub struct DepthPrune {
+    pub keep_levels: u8,
+}
+
+impl<H: Hasher, A: Aggregate> Commit<H, A> for DepthPrune {
+    fn write(&mut self, _tree: &Tree<H, A>) -> Result<()> {
+        Ok(())
+    }
+
+    fn prune_at_depth(&self, _tree: &Tree<H, A>, depth: u8) -> (bool, bool) {
+        let prune = depth.saturating_add(1) >= self.keep_levels;
+        (prune, prune)
+    }
+}
+
+/// Statistics about the nodes written through a `StatsCommit`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommitStats {
+    /// The number of nodes written.
+    pub nodes_written: usize,
+    /// The sum of the encoding lengths of the nodes written.
+    pub bytes_written: usize,
+    /// The greatest depth of a written node, relative to the root of the tree
+    /// being committed (the root has depth 0).
+    pub max_depth: u8,
+}
+
+/// A `Commit` implementation which wraps another, forwarding every call to it
+/// while tallying how many nodes were written, their total encoded size, and
+/// the greatest depth reached (e.g. to see the effect of a pruning policy).
+///
+/// The statistics accumulate over every commit made with the `StatsCommit`.
+/// Depths are recorded when nodes are pruned, which happens only once the
+/// commit's batch has ended successfully.
+pub struct StatsCommit<C: Commit> {
+    inner: C,
+    stats: CommitStats,
+    max_depth: Cell<u8>,
+}
+
+impl<C: Commit> StatsCommit<C> {
+    /// Creates a `StatsCommit` which forwards to `inner`.
+    pub fn new(inner: C) -> Self {
+        StatsCommit {
+            inner,
+            stats: Default::default(),
+            max_depth: Cell::new(0),
+        }
+    }
+
+    /// Returns the statistics of the nodes written so far.
+    pub fn stats(&self) -> CommitStats {
+        CommitStats {
+            max_depth: self.max_depth.get(),
+            ..self.stats
+        }
+    }
+
+    /// Returns a reference to the wrapped `Commit`.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Consumes the `StatsCommit` and returns the wrapped `Commit`.
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: Commit> Commit for StatsCommit<C> {
+    fn begin_batch(&mut self) -> Result<()> {
+        self.inner.begin_batch()
+    }
+
+    fn end_batch(&mut self) -> Result<()> {
+        self.inner.end_batch()
+    }
+
+    /// Writes the node to the wrapped `Commit`, counting it once the write
+    /// succeeds.
+    fn write(&mut self, tree: &Tree) -> Result<()> {
+        self.inner.write(tree)?;
+        self.stats.nodes_written += 1;
+        self.stats.bytes_written += tree.encoding_length();
+        Ok(())
+    }
+
+    fn prune(&self, tree: &Tree) -> (bool, bool) {
+        self.inner.prune(tree)
+    }
+
+    /// Records the depth, then defers to the wrapped `Commit`.
+    fn prune_at_depth(&self, tree: &Tree, depth: u8) -> (bool, bool) {
+        self.max_depth.set(self.max_depth.get().max(depth));
+        self.inner.prune_at_depth(tree, depth)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{Op, PanicSource, Walker};
+    use super::*;
+
+    /// Sums the encoding lengths of the in-memory nodes of the tree.
+    fn encoding_length(tree: &Tree) -> usize {
+        let children: usize = [true, false]
+            .iter()
+            .filter_map(|&left| tree.child(left))
+            .map(encoding_length)
+            .sum();
+        tree.encoding_length() + children
+    }
+
+    #[test]
+    fn stats_commit() {
+        // builds a perfect tree of keys 1..=7, rooted at 4
+        let batch: Vec<_> = (1..=7).map(|i| (vec![i], Op::Put(vec![i]))).collect();
+        let mut tree = Walker::<PanicSource>::apply_to(None, &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+
+        let mut commit = StatsCommit::new(NoopCommit {});
+        tree.commit(&mut commit).expect("commit failed");
+        let stats = commit.stats();
+        assert_eq!(stats.nodes_written, 7);
+        assert_eq!(stats.max_depth, 2);
+        assert_eq!(stats.bytes_written, encoding_length(&tree));
+
+        // only the path to the updated node is written again
+        let walker = Walker::new(tree, PanicSource {});
+        let mut tree = Walker::apply_to(Some(walker), &[(vec![7], Op::Put(vec![0]))])
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        tree.commit(&mut commit).expect("commit failed");
+        assert_eq!(commit.stats().nodes_written, 10);
+        assert_eq!(commit.stats().max_depth, 2);
+    }
+}
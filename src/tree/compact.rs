@@ -0,0 +1,133 @@
+use super::kv::KV;
+use super::walk::Fetch;
+use super::{Link, Tree, TreeInner};
+use crate::error::Result;
+use std::mem;
+
+impl Tree {
+    /// Loads every pruned node of the tree from `source` (see `load_all`), then
+    /// moves every node into a fresh heap allocation, in pre-order, so each
+    /// node's allocation is made before those of its descendants rather than
+    /// being scattered by the history of loads and prunes.
+    ///
+    /// The shape of the tree, its links' states, and so its hashes are
+    /// unchanged. Returns an error if a node can't be fetched, in which case
+    /// the nodes loaded so far are kept but no nodes are moved.
+    pub fn compact<S: Fetch>(&mut self, source: &S) -> Result<()> {
+        self.load_all(source)?;
+
+        let tree = mem::replace(
+            self,
+            Tree::from_fields(vec![], vec![], Default::default(), None, None),
+        );
+        *self = relocate(tree);
+        Ok(())
+    }
+}
+
+/// Moves the root node of `tree` into a new allocation, then does the same for
+/// its descendants. The old allocation is freed once the descendants have been
+/// moved, so it is not reused for them.
+fn relocate(mut tree: Tree) -> Tree {
+    let inner = &mut *tree.inner;
+    let mut relocated = Box::new(TreeInner {
+        left: None,
+        right: None,
+        kv: mem::replace(
+            &mut inner.kv,
+            KV::from_fields(vec![], vec![], Default::default()),
+        ),
+        height: inner.height,
+    });
+    relocated.left = inner.left.take().map(relocate_link);
+    relocated.right = inner.right.take().map(relocate_link);
+
+    Tree { inner: relocated }
+}
+
+/// Relocates the tree held by `link`, if any, keeping the link's variant and
+/// fields.
+fn relocate_link(link: Link) -> Link {
+    match link {
+        Link::Modified {
+            pending_writes,
+            child_heights,
+            descendant_count,
+            aggregate,
+            tree,
+        } => Link::Modified {
+            pending_writes,
+            child_heights,
+            descendant_count,
+            aggregate,
+            tree: relocate(tree),
+        },
+        Link::Uncommitted {
+            hash,
+            child_heights,
+            descendant_count,
+            aggregate,
+            tree,
+        } => Link::Uncommitted {
+            hash,
+            child_heights,
+            descendant_count,
+            aggregate,
+            tree: relocate(tree),
+        },
+        Link::Loaded {
+            hash,
+            child_heights,
+            descendant_count,
+            aggregate,
+            tree,
+        } => Link::Loaded {
+            hash,
+            child_heights,
+            descendant_count,
+            aggregate,
+            tree: relocate(tree),
+        },
+        link @ Link::Reference { .. } => link,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{MemStore, PanicSource, Walker};
+    use crate::test_utils::make_batch_seq;
+
+    #[test]
+    fn compact() {
+        let mut store = MemStore::new();
+        let batch = make_batch_seq(0..100);
+        let mut tree = Walker::<PanicSource>::apply_to(None, &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        tree.commit(&mut store).expect("commit failed");
+        let hash = tree.hash();
+
+        // load part of the tree, leaving a mix of loaded and pruned links
+        tree.load(true, &&store).expect("load failed");
+        tree.child_mut(true)
+            .unwrap()
+            .load(false, &&store)
+            .expect("load failed");
+        assert!(tree.link(false).unwrap().is_reference());
+
+        tree.compact(&&store).expect("compact failed");
+        assert_eq!(tree.hash(), hash);
+        tree.verify_avl().expect("expected balanced tree");
+
+        let entries: Vec<_> = tree
+            .iter()
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+            .collect();
+        let expected: Vec<_> = batch
+            .into_iter()
+            .map(|(key, _)| (key, vec![123; 60]))
+            .collect();
+        assert_eq!(entries, expected);
+    }
+}
@@ -1,6 +1,6 @@
 use super::{Link, Tree};
 use colored::Colorize;
-use std::fmt::{Debug, Formatter, Result};
+use std::fmt::{Debug, Formatter, Result, Write};
 
 impl Debug for Tree {
     // TODO: unwraps should be results that bubble up
@@ -94,3 +94,113 @@ impl Debug for Tree {
         writeln!(f)
     }
 }
+
+impl Tree {
+    /// Renders the tree as a GraphViz DOT graph, for visualizing its structure
+    /// (e.g. with `dot -Tsvg`).
+    ///
+    /// Each node is labeled with its key (hex-encoded), the length of its
+    /// value, its height, its balance factor, and the variant of the link which
+    /// points to it, colored by variant. Pruned children are drawn as dashed
+    /// placeholders showing the first bytes of their hash.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        writeln!(dot, "digraph tree {{").unwrap();
+        writeln!(dot, "    node [shape=box];").unwrap();
+        write_dot_node(&mut dot, self, None);
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+}
+
+/// Writes the DOT statements for `tree`, its descendants, and the edges between
+/// them. `link` is the link pointing to `tree`, or `None` for the root.
+fn write_dot_node(dot: &mut String, tree: &Tree, link: Option<&Link>) {
+    let id = hex::encode(tree.key());
+    let (variant, color) = match link {
+        None => ("Root", "black"),
+        Some(Link::Modified { .. }) => ("Modified", "red"),
+        Some(Link::Uncommitted { .. }) => ("Uncommitted", "orange"),
+        Some(Link::Loaded { .. }) => ("Stored", "blue"),
+        Some(Link::Reference { .. }) => unreachable!("pruned nodes are not in memory"),
+    };
+    writeln!(
+        dot,
+        "    \"{}\" [label=\"key: {}\\nvalue: {} bytes\\nheight: {}\\nbalance: {}\\n{}\", color={}];",
+        id,
+        id,
+        tree.value().len(),
+        tree.height(),
+        tree.balance_factor(),
+        variant,
+        color
+    )
+    .unwrap();
+
+    for &left in [true, false].iter() {
+        let child_link = match tree.link(left) {
+            Some(child_link) => child_link,
+            None => continue,
+        };
+
+        let child_id = hex::encode(child_link.key());
+        writeln!(
+            dot,
+            "    \"{}\" -> \"{}\" [label=\"{}\"];",
+            id,
+            child_id,
+            if left { "left" } else { "right" }
+        )
+        .unwrap();
+
+        match child_link.tree() {
+            Some(child) => write_dot_node(dot, child, Some(child_link)),
+            None => writeln!(
+                dot,
+                "    \"{}\" [label=\"Pruned\\nhash: {}\", style=dashed, color=gray];",
+                child_id,
+                hex::encode(&child_link.hash()[..4])
+            )
+            .unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{Link, NoAggregate, NoopCommit, Tree};
+
+    #[test]
+    fn to_dot() {
+        let mut tree = Tree::from_fields(
+            vec![2],
+            vec![1, 2, 3],
+            Default::default(),
+            Some(Link::Reference {
+                hash: [0xab; 20],
+                child_heights: (0, 0),
+                descendant_count: 1,
+                aggregate: NoAggregate,
+                key: vec![1],
+            }),
+            None,
+        )
+        .attach(false, Some(Tree::new(vec![3], vec![])));
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+
+        let dot = tree.to_dot();
+        assert!(dot.starts_with("digraph tree {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert!(dot.contains(
+            "    \"02\" [label=\"key: 02\\nvalue: 3 bytes\\nheight: 2\\nbalance: 0\\nRoot\", color=black];\n"
+        ));
+        assert!(dot.contains("    \"02\" -> \"01\" [label=\"left\"];\n"));
+        assert!(dot.contains(
+            "    \"01\" [label=\"Pruned\\nhash: abababab\", style=dashed, color=gray];\n"
+        ));
+        assert!(dot.contains("    \"02\" -> \"03\" [label=\"right\"];\n"));
+        assert!(dot.contains(
+            "    \"03\" [label=\"key: 03\\nvalue: 0 bytes\\nheight: 1\\nbalance: 0\\nStored\", color=blue];\n"
+        ));
+    }
+}
@@ -0,0 +1,391 @@
+use super::hash::Hash;
+use super::walk::Fetch;
+use super::{Link, NoAggregate, Tree};
+use crate::error::Result;
+use failure::bail;
+use std::cmp::Ordering;
+
+/// A difference between two versions of a tree, as returned by `Tree::diff`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffEntry {
+    /// The key is in the newer tree, but not in the older tree.
+    Added(Vec<u8>),
+
+    /// The key is in the older tree, but not in the newer tree.
+    Removed(Vec<u8>),
+
+    /// The key is in both trees, with different values.
+    Changed(Vec<u8>),
+}
+
+impl Tree {
+    /// Computes the keys which were added, removed, or changed in this tree
+    /// compared to an older version of it, given the key and hash of the older
+    /// version's root node. Entries are returned in key order.
+    ///
+    /// Both trees are traversed in key order, comparing the hashes of subtrees
+    /// which start at the same position: subtrees with matching hashes hold the
+    /// same entries, so they are skipped without being loaded. Only the nodes
+    /// of differing subtrees are fetched from `source`, which must contain the
+    /// nodes of both versions (e.g. a `MemStore`).
+    ///
+    /// Returns an error if the tree has changes which have not been committed
+    /// or hashed (see `compute_hashes`), or if a node can't be fetched.
+    pub fn diff<S: Fetch>(
+        &self,
+        source: &S,
+        other_root_key: &[u8],
+        other_root: Hash,
+    ) -> Result<Vec<DiffEntry>> {
+        for &left in [true, false].iter() {
+            if let Some(link) = self.link(left) {
+                if link.is_modified() {
+                    bail!("Cannot diff tree with uncommitted changes");
+                }
+            }
+        }
+
+        if self.hash() == other_root {
+            return Ok(vec![]);
+        }
+
+        let mut new = Cursor::new(source);
+        new.push_tree(self)?;
+
+        // only the key and hash are needed to fetch the node
+        let root = Link::Reference {
+            hash: other_root,
+            child_heights: (0, 0),
+            descendant_count: 0,
+            aggregate: NoAggregate,
+            key: other_root_key.to_vec(),
+        };
+        let other = source.fetch(&root)?;
+        if other.hash() != other_root {
+            bail!("Fetched root node did not match the other root hash");
+        }
+        let mut old = Cursor::new(source);
+        old.push_fetched(other);
+
+        let mut diff = vec![];
+        loop {
+            match (new.front(), old.front()) {
+                (None, None) => break,
+                (Some(Item::Subtree(new_hash, ..)), Some(Item::Subtree(old_hash, ..)))
+                    if new_hash == old_hash =>
+                {
+                    new.stack.pop();
+                    old.stack.pop();
+                }
+                (Some(Item::Entry(new_key, new_value)), Some(Item::Entry(old_key, old_value))) => {
+                    match new_key.cmp(old_key) {
+                        Ordering::Less => diff.push(DiffEntry::Added(new.pop_key())),
+                        Ordering::Greater => diff.push(DiffEntry::Removed(old.pop_key())),
+                        Ordering::Equal => {
+                            let changed = new_value != old_value;
+                            let key = new.pop_key();
+                            old.stack.pop();
+                            if changed {
+                                diff.push(DiffEntry::Changed(key));
+                            }
+                        }
+                    }
+                }
+                (Some(Item::Entry(..)), None) => diff.push(DiffEntry::Added(new.pop_key())),
+                (None, Some(Item::Entry(..))) => diff.push(DiffEntry::Removed(old.pop_key())),
+                (new_front, old_front) => {
+                    // expand the taller subtree (or both, if they are the same
+                    // height) until the fronts line up
+                    let new_height = new_front.map_or(0, Item::height);
+                    let old_height = old_front.map_or(0, Item::height);
+                    if new_height >= old_height {
+                        new.expand()?;
+                    }
+                    if old_height >= new_height {
+                        old.expand()?;
+                    }
+                }
+            }
+        }
+
+        Ok(diff)
+    }
+}
+
+/// An element of a tree's in-order traversal, either an entry or a subtree
+/// which has not been expanded yet.
+enum Item<'a> {
+    /// A subtree, with its hash and height.
+    Subtree(Hash, u8, Node<'a>),
+
+    /// A key/value pair.
+    Entry(Vec<u8>, Vec<u8>),
+}
+
+impl<'a> Item<'a> {
+    /// Returns the height of the subtree, or 0 for an entry.
+    fn height(&self) -> u8 {
+        match self {
+            Item::Subtree(_, height, _) => *height,
+            Item::Entry(..) => 0,
+        }
+    }
+}
+
+/// The root node of an unexpanded subtree.
+enum Node<'a> {
+    /// A node of the in-memory tree.
+    InMemory(&'a Tree),
+
+    /// A pruned child of a node in the in-memory tree.
+    Pruned(&'a Link),
+
+    /// A child of a node which was fetched from the source.
+    Fetched(Link),
+}
+
+/// Traverses a tree in key order, expanding subtrees on demand. The front of
+/// the traversal is the top of the stack.
+struct Cursor<'a, S: Fetch> {
+    stack: Vec<Item<'a>>,
+    source: &'a S,
+}
+
+impl<'a, S: Fetch> Cursor<'a, S> {
+    fn new(source: &'a S) -> Self {
+        Cursor {
+            stack: vec![],
+            source,
+        }
+    }
+
+    fn front(&self) -> Option<&Item<'a>> {
+        self.stack.last()
+    }
+
+    /// Pops the entry at the front and returns its key.
+    fn pop_key(&mut self) -> Vec<u8> {
+        match self.stack.pop() {
+            Some(Item::Entry(key, _)) => key,
+            _ => unreachable!("Expected entry"),
+        }
+    }
+
+    /// Replaces the subtree at the front with its left subtree, its root entry,
+    /// and its right subtree, fetching the root node if it is not in memory.
+    fn expand(&mut self) -> Result<()> {
+        match self.stack.pop() {
+            Some(Item::Subtree(_, _, Node::InMemory(tree))) => self.push_tree(tree),
+            Some(Item::Subtree(_, _, Node::Pruned(link))) => {
+                let tree = self.source.fetch(link)?;
+                self.push_fetched(tree);
+                Ok(())
+            }
+            Some(Item::Subtree(_, _, Node::Fetched(link))) => {
+                let tree = self.source.fetch(&link)?;
+                self.push_fetched(tree);
+                Ok(())
+            }
+            _ => unreachable!("Expected subtree"),
+        }
+    }
+
+    /// Pushes the children and entry of a node in the in-memory tree.
+    fn push_tree(&mut self, tree: &'a Tree) -> Result<()> {
+        if let Some(link) = tree.link(false) {
+            self.push_link(link)?;
+        }
+        self.stack
+            .push(Item::Entry(tree.key().to_vec(), tree.value().to_vec()));
+        if let Some(link) = tree.link(true) {
+            self.push_link(link)?;
+        }
+        Ok(())
+    }
+
+    fn push_link(&mut self, link: &'a Link) -> Result<()> {
+        if link.is_modified() {
+            bail!("Cannot diff tree with uncommitted changes");
+        }
+
+        let node = match link.tree() {
+            Some(tree) => Node::InMemory(tree),
+            None => Node::Pruned(link),
+        };
+        self.stack
+            .push(Item::Subtree(*link.hash(), link.height(), node));
+        Ok(())
+    }
+
+    /// Pushes the children and entry of a node fetched from the source.
+    fn push_fetched(&mut self, mut tree: Tree) {
+        if let Some(link) = tree.take_link(false) {
+            self.stack.push(Item::Subtree(
+                *link.hash(),
+                link.height(),
+                Node::Fetched(link),
+            ));
+        }
+        self.stack
+            .push(Item::Entry(tree.key().to_vec(), tree.value().to_vec()));
+        if let Some(link) = tree.take_link(true) {
+            self.stack.push(Item::Subtree(
+                *link.hash(),
+                link.height(),
+                Node::Fetched(link),
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{MemStore, Op, PanicSource, Walker};
+    use super::*;
+    use crate::test_utils::{
+        del_entry, make_batch_rand, make_batch_seq, make_del_batch_seq, put_entry, seq_key,
+    };
+    use std::cell::Cell;
+    use std::collections::BTreeMap;
+
+    /// A `Fetch` source which counts the nodes fetched from a `MemStore`.
+    struct CountingSource<'a> {
+        store: &'a MemStore,
+        fetches: &'a Cell<usize>,
+    }
+
+    impl<'a> Fetch for CountingSource<'a> {
+        fn fetch(&self, link: &Link) -> Result<Tree> {
+            self.fetches.set(self.fetches.get() + 1);
+            (&self.store).fetch(link)
+        }
+    }
+
+    fn make_committed_tree(store: &mut MemStore) -> Tree {
+        let mut tree = Walker::<PanicSource>::apply_to(None, &make_batch_seq(0..100))
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        tree.commit(store).expect("commit failed");
+        tree
+    }
+
+    #[test]
+    fn diff_changes() {
+        let mut store = MemStore::new();
+        let tree = make_committed_tree(&mut store);
+        let old_key = tree.key().to_vec();
+        let old_hash = tree.hash();
+
+        let batch = vec![
+            del_entry(10),
+            (seq_key(50), Op::Put(vec![1, 2, 3])),
+            put_entry(200),
+        ];
+        let walker = Walker::new(tree, &store);
+        let mut tree = Walker::apply_to(Some(walker), &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        tree.commit(&mut store).expect("commit failed");
+        assert!(tree.link(true).unwrap().is_reference());
+
+        let fetches = Cell::new(0);
+        let source = CountingSource {
+            store: &store,
+            fetches: &fetches,
+        };
+        let diff = tree
+            .diff(&source, old_key.as_slice(), old_hash)
+            .expect("diff failed");
+        assert_eq!(
+            diff,
+            vec![
+                DiffEntry::Removed(seq_key(10)),
+                DiffEntry::Changed(seq_key(50)),
+                DiffEntry::Added(seq_key(200)),
+            ]
+        );
+
+        // only the nodes near the paths to the changes were loaded, out of the
+        // 200 nodes in both versions
+        assert!(fetches.get() < 70, "fetched {} nodes", fetches.get());
+
+        // diffing a version against itself loads nothing
+        fetches.set(0);
+        let diff = tree
+            .diff(&source, tree.key(), tree.hash())
+            .expect("diff failed");
+        assert!(diff.is_empty());
+        assert_eq!(fetches.get(), 0);
+    }
+
+    #[test]
+    fn diff_matches_entries() {
+        let mut store = MemStore::without_pruning();
+        let tree = make_committed_tree(&mut store);
+        let old_key = tree.key().to_vec();
+        let old_hash = tree.hash();
+        let old_entries: BTreeMap<_, _> = tree
+            .iter()
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+            .collect();
+
+        let mut batch = make_batch_rand(20, 0);
+        batch.extend(make_del_batch_seq(30..40));
+        batch.push((seq_key(60), Op::Put(vec![1])));
+        batch.sort_by(|a, b| a.0.cmp(&b.0));
+        let walker = Walker::new(tree, &store);
+        let mut tree = Walker::apply_to(Some(walker), &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        tree.commit(&mut store).expect("commit failed");
+        let new_entries: BTreeMap<_, _> = tree
+            .iter()
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+            .collect();
+
+        let mut expected: Vec<_> = new_entries
+            .iter()
+            .filter_map(|(key, value)| match old_entries.get(key) {
+                None => Some(DiffEntry::Added(key.clone())),
+                Some(old_value) if old_value != value => Some(DiffEntry::Changed(key.clone())),
+                Some(_) => None,
+            })
+            .chain(
+                old_entries
+                    .keys()
+                    .filter(|key| !new_entries.contains_key(*key))
+                    .map(|key| DiffEntry::Removed(key.clone())),
+            )
+            .collect();
+        let key = |entry: &DiffEntry| match entry {
+            DiffEntry::Added(key) | DiffEntry::Removed(key) | DiffEntry::Changed(key) => {
+                key.clone()
+            }
+        };
+        expected.sort_by_key(key);
+        assert_eq!(expected.len(), 31);
+
+        let diff = tree
+            .diff(&&store, old_key.as_slice(), old_hash)
+            .expect("diff failed");
+        assert_eq!(diff, expected);
+    }
+
+    #[test]
+    fn diff_uncommitted() {
+        let mut store = MemStore::new();
+        let tree = make_committed_tree(&mut store);
+        let old_key = tree.key().to_vec();
+        let old_hash = tree.hash();
+
+        let walker = Walker::new(tree, &store);
+        let tree = Walker::apply_to(Some(walker), &[put_entry(200)])
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        assert!(tree.diff(&&store, old_key.as_slice(), old_hash).is_err());
+    }
+}
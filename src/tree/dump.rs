@@ -0,0 +1,268 @@
+use super::hash::{Hash, HASH_LENGTH};
+use super::walk::Fetch;
+use super::{Link, Tree};
+use crate::error::{ErrorKind, Result};
+use failure::bail;
+use std::io::{Read, Write};
+
+/// The bytes which begin every dump.
+const DUMP_MAGIC: &[u8; 8] = b"merkdump";
+
+/// The version of the dump format written by `Tree::dump`.
+const DUMP_VERSION: u8 = 1;
+
+impl Tree {
+    /// Writes the entire tree to `w` as a single self-describing dump, which
+    /// can be read back with `Tree::restore` (e.g. for backups). Pruned nodes
+    /// are fetched from `source` as they are reached, and are not kept in
+    /// memory.
+    ///
+    /// The dump starts with a header of the bytes `merkdump`, a version byte,
+    /// the hash length (as a byte), the root hash, and the node count (as an
+    /// 8-byte big-endian integer). The nodes follow in pre-order, each as a
+    /// length-prefixed key (1 byte) followed by its length-prefixed encoding (4
+    /// bytes, big-endian).
+    ///
+    /// Returns an error if the tree has modified nodes, since their hashes are
+    /// not known, or if a fetched node does not match the hash of its link.
+    pub fn dump<S: Fetch, W: Write>(&self, source: &S, w: &mut W) -> Result<()> {
+        let mut stack = vec![self];
+        while let Some(tree) = stack.pop() {
+            for &left in [true, false].iter() {
+                if let Some(Link::Modified { .. }) = tree.link(left) {
+                    bail!("Cannot dump tree with modified nodes");
+                }
+                if let Some(child) = tree.child(left) {
+                    stack.push(child);
+                }
+            }
+        }
+
+        w.write_all(DUMP_MAGIC)?;
+        w.write_all(&[DUMP_VERSION, HASH_LENGTH as u8])?;
+        w.write_all(&self.hash())?;
+        w.write_all(&self.count().to_be_bytes())?;
+
+        dump_node(self, source, w)
+    }
+
+    /// Reads a dump written by `Tree::dump`, returning the tree with all of its
+    /// nodes in memory (as `Link::Loaded`).
+    ///
+    /// Returns an error if the header is invalid or of an unsupported version,
+    /// if a node is corrupted or does not match its parent's link, if the dump
+    /// has a different number of nodes than its header or has trailing bytes,
+    /// or if the tree does not match the root hash in the header.
+    pub fn restore<R: Read>(mut r: R) -> Result<Tree> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != DUMP_MAGIC {
+            bail!("Invalid dump header");
+        }
+
+        let mut version_and_hash_length = [0; 2];
+        r.read_exact(&mut version_and_hash_length)?;
+        match version_and_hash_length {
+            [DUMP_VERSION, length] if length as usize == HASH_LENGTH => {}
+            [DUMP_VERSION, length] => bail!("Unsupported dump hash length {}", length),
+            [version, _] => bail!("Unsupported dump version {}", version),
+        }
+
+        let mut expected_hash: Hash = Default::default();
+        r.read_exact(&mut expected_hash)?;
+        let mut node_count = [0; 8];
+        r.read_exact(&mut node_count)?;
+        let node_count = u64::from_be_bytes(node_count);
+
+        let mut nodes_read = 0;
+        let tree = restore_node(&mut r, &mut nodes_read)?;
+
+        if nodes_read != node_count {
+            bail!(
+                "Dump header has node count {}, but dump has {} nodes",
+                node_count,
+                nodes_read
+            );
+        }
+        if r.read(&mut [0])? != 0 {
+            bail!("Dump has trailing bytes");
+        }
+        if tree.hash() != expected_hash {
+            return Err(ErrorKind::HashMismatch {
+                expected: expected_hash,
+                actual: tree.hash(),
+            }
+            .into());
+        }
+
+        Ok(tree)
+    }
+}
+
+/// Writes `tree` and its descendants to `w` in pre-order, fetching pruned
+/// children from `source`.
+fn dump_node<S: Fetch, W: Write>(tree: &Tree, source: &S, w: &mut W) -> Result<()> {
+    let key = tree.key();
+    if key.len() > 255 {
+        bail!("Key must be less than 256 bytes");
+    }
+    let bytes = tree.encode();
+    w.write_all(&[key.len() as u8])?;
+    w.write_all(key)?;
+    w.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    w.write_all(bytes.as_slice())?;
+
+    for &left in [true, false].iter() {
+        match tree.link(left) {
+            None => {}
+            Some(link @ Link::Reference { .. }) => {
+                let child = source.fetch(link)?;
+                if child.hash() != *link.hash() {
+                    bail!("Fetched node {:?} does not match link hash", link.key());
+                }
+                dump_node(&child, source, w)?;
+            }
+            Some(link) => dump_node(link.tree().unwrap(), source, w)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a node and its descendants from `r` in pre-order, counting the nodes
+/// read in `nodes_read`.
+fn restore_node<R: Read>(r: &mut R, nodes_read: &mut u64) -> Result<Tree> {
+    let mut key_length = [0; 1];
+    r.read_exact(&mut key_length)?;
+    let mut key = vec![0; key_length[0] as usize];
+    r.read_exact(&mut key)?;
+
+    let mut length = [0; 4];
+    r.read_exact(&mut length)?;
+    let length = u32::from_be_bytes(length) as usize;
+    // the length is untrusted, so read up to it rather than allocating it all
+    // up front
+    let mut bytes = vec![];
+    r.by_ref().take(length as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != length {
+        bail!("Dump node {:?} is truncated", key);
+    }
+
+    let mut tree = Tree::decode_verified(key, bytes.as_slice())?;
+    *nodes_read += 1;
+
+    for &left in [true, false].iter() {
+        let (link_key, hash, child_heights) = match tree.link(left) {
+            None => continue,
+            Some(link) => (link.key().to_vec(), *link.hash(), link.child_heights()),
+        };
+
+        let child = restore_node(r, nodes_read)?;
+        // matching child heights also ensures heights decrease on the way
+        // down, which bounds the recursion depth
+        if child.key() != link_key.as_slice()
+            || child.hash() != hash
+            || child.child_heights() != child_heights
+        {
+            bail!(
+                "Dump node {:?} does not match its parent's link",
+                child.key()
+            );
+        }
+        *tree.slot_mut(left) = Some(Link::from_stored_tree(child, hash, child_heights));
+    }
+
+    Ok(tree)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{MemStore, PanicSource, Walker};
+    use super::*;
+    use crate::test_utils::make_batch_seq;
+
+    fn make_dump() -> (Tree, Vec<u8>) {
+        let mut store = MemStore::new();
+        let mut tree = Walker::<PanicSource>::apply_to(None, &make_batch_seq(0..100))
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        tree.commit(&mut store).expect("commit failed");
+        assert!(tree.link(true).unwrap().is_reference());
+
+        let mut bytes = vec![];
+        tree.dump(&&store, &mut bytes).expect("dump failed");
+        (tree, bytes)
+    }
+
+    #[test]
+    fn dump_restore() {
+        let (tree, bytes) = make_dump();
+        let restored = Tree::restore(bytes.as_slice()).expect("restore failed");
+        assert_eq!(restored.hash(), tree.hash());
+        assert_eq!(restored.count(), 100);
+        restored.verify_avl().expect("expected balanced tree");
+
+        let entries: Vec<_> = restored
+            .iter()
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+            .collect();
+        let expected: Vec<_> = make_batch_seq(0..100)
+            .into_iter()
+            .map(|(key, _)| (key, vec![123; 60]))
+            .collect();
+        assert_eq!(entries, expected);
+
+        // the restored tree is fully loaded, so it can be dumped without a store
+        let mut redumped = vec![];
+        restored
+            .dump(&PanicSource {}, &mut redumped)
+            .expect("dump failed");
+        assert_eq!(redumped, bytes);
+    }
+
+    #[test]
+    fn restore_corrupted_header() {
+        let (_, bytes) = make_dump();
+
+        // magic, version, hash length, root hash, and node count
+        for &index in [0, 8, 9, 10, 30, 37].iter() {
+            let mut corrupted = bytes.clone();
+            corrupted[index] ^= 1;
+            assert!(Tree::restore(corrupted.as_slice()).is_err());
+        }
+
+        let mut corrupted = bytes.clone();
+        corrupted[10] ^= 1;
+        let err = Tree::restore(corrupted.as_slice()).unwrap_err();
+        match err.downcast_ref::<ErrorKind>() {
+            Some(ErrorKind::HashMismatch { .. }) => {}
+            _ => panic!("Expected hash mismatch"),
+        }
+
+        assert!(Tree::restore(&bytes[..bytes.len() - 1]).is_err());
+
+        let mut extended = bytes.clone();
+        extended.push(0);
+        assert!(Tree::restore(extended.as_slice()).is_err());
+    }
+
+    #[test]
+    fn restore_oversized_length() {
+        let (_, mut bytes) = make_dump();
+
+        // the encoding length of the root node follows the 38-byte header and
+        // the root's length-prefixed key
+        let index = 38 + 1 + bytes[38] as usize;
+        bytes[index..index + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        let err = Tree::restore(bytes.as_slice()).unwrap_err();
+        assert!(err.to_string().ends_with("is truncated"));
+    }
+
+    #[test]
+    fn dump_modified() {
+        let tree = Tree::new(vec![1], vec![2]).attach(true, Some(Tree::new(vec![0], vec![1])));
+        let mut bytes: Vec<u8> = vec![];
+        assert!(tree.dump(&PanicSource {}, &mut bytes).is_err());
+    }
+}
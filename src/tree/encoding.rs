@@ -1,55 +1,405 @@
-use super::Tree;
+use super::hash::{Hasher, HASH_LENGTH};
+use super::kv::KV;
+use super::{Aggregate, Link, Tree, TreeInner};
+use crate::error::{ErrorKind, Result};
+use blake2_rfc::blake2b::Blake2b;
 use ed::{Decode, Encode};
+use failure::bail;
+use std::io::{Read, Write};
 
-impl Tree {
+/// The version byte which prefixes nodes in the current encoding, where
+/// lengths and descendant counts are written as LEB128 varints, and the node is
+/// followed by a `CHECKSUM_LENGTH`-byte checksum of the preceding bytes. Each
+/// link's descendant count is followed by the encoding of its `Aggregate`,
+/// which is empty for `NoAggregate`.
+///
+/// Nodes written by earlier versions have no checksum. Version 2 nodes have the
+/// same layout otherwise. Version 1 nodes have no version prefix, and use
+/// fixed-width fields. Their first byte is always 0 or 1 (the tag of the
+/// optional left link), so they can't be mistaken for later versions.
+pub const ENCODING_VERSION: u8 = 3;
+
+/// The version byte of the previous encoding, which is the same as the
+/// current encoding without the trailing checksum.
+const UNCHECKED_ENCODING_VERSION: u8 = 2;
+
+/// The length of the checksum at the end of nodes in the current encoding: a
+/// prefix of the Blake2b hash of the rest of the encoding.
+pub const CHECKSUM_LENGTH: usize = 4;
+
+impl<H: Hasher, A: Aggregate> Tree<H, A> {
     #[inline]
     pub fn encode(&self) -> Vec<u8> {
-        // operation is infallible so it's ok to unwrap
-        Encode::encode(self).unwrap()
+        let mut bytes = Vec::with_capacity(self.encoding_length());
+        // writing to a vec is infallible so it's ok to unwrap
+        self.encode_into(&mut bytes).unwrap();
+        bytes
     }
 
-    #[inline]
-    pub fn encode_into(&self, dest: &mut Vec<u8>) {
-        // operation is infallible so it's ok to unwrap
-        Encode::encode_into(self, dest).unwrap()
+    /// Writes the encoded node to `dest` as it is encoded, without buffering
+    /// the whole encoding in memory (e.g. to stream nodes to a file or socket
+    /// in `Commit::write`). The bytes written are identical to `encode`.
+    pub fn encode_into<W: Write>(&self, dest: &mut W) -> Result<()> {
+        let mut dest = ChecksumWriter::new(dest);
+        let dest = &mut dest;
+        dest.write_all(&[ENCODING_VERSION])?;
+        for &left in [true, false].iter() {
+            match self.link(left) {
+                None => dest.write_all(&[0])?,
+                Some(link) => {
+                    dest.write_all(&[1])?;
+                    encode_link_into(link, dest)?;
+                }
+            }
+        }
+        dest.write_all(self.kv_hash().as_ref())?;
+        write_varint(dest, self.value().len() as u64)?;
+        dest.write_all(self.value())?;
+        dest.finish()
     }
 
     #[inline]
     pub fn encoding_length(&self) -> usize {
-        // operation is infallible so it's ok to unwrap
-        Encode::encoding_length(self).unwrap()
+        let links_length: usize = [true, false]
+            .iter()
+            .map(|&left| 1 + self.link(left).map_or(0, link_encoding_length))
+            .sum();
+        let value_length = self.value().len();
+        1 + links_length
+            + H::LENGTH
+            + varint_length(value_length as u64)
+            + value_length
+            + CHECKSUM_LENGTH
     }
 
     #[inline]
     pub fn decode_into(&mut self, key: Vec<u8>, input: &[u8]) {
-        // operation is infallible so it's ok to unwrap
-        Decode::decode_into(self, input).unwrap();
-        self.inner.kv.key = key;
+        match input.first() {
+            Some(0) | Some(1) => {
+                // operation is infallible so it's ok to unwrap
+                Decode::decode_into(self, input).unwrap();
+                self.inner.kv.key = key;
+            }
+            _ => {
+                *self = Self::decode_any_version(input, false).unwrap();
+                self.inner.kv.key = key;
+            }
+        }
+    }
+
+    /// Decodes a node written in the current encoding or any earlier encoding,
+    /// detected by the first byte. The decoded node's key is empty. If `verify`
+    /// is `true`, the node's checksum must match, so earlier encodings are
+    /// rejected.
+    fn decode_any_version(input: &[u8], verify: bool) -> Result<Self> {
+        match input.first() {
+            Some(&ENCODING_VERSION) => {
+                if input.len() < 1 + CHECKSUM_LENGTH {
+                    bail!("Unexpected end of node encoding");
+                }
+                let (bytes, expected_checksum) = input.split_at(input.len() - CHECKSUM_LENGTH);
+                if verify && checksum(bytes) != expected_checksum {
+                    return Err(ErrorKind::Corrupt.into());
+                }
+                decode_current(&bytes[1..])
+            }
+            Some(&version) if verify => bail!(
+                "Cannot verify node encoding version {}, which has no checksum",
+                version
+            ),
+            Some(&UNCHECKED_ENCODING_VERSION) => decode_current(&input[1..]),
+            Some(0) | Some(1) => Decode::decode(input),
+            Some(version) => bail!("Unknown node encoding version {}", version),
+            None => bail!("Unexpected end of node encoding"),
+        }
     }
+}
 
+impl Tree {
+    /// Decodes a node written in any supported encoding, trusting that it is
+    /// not corrupted (the checksum of nodes in the current encoding is not
+    /// checked). Panics if the encoding is malformed.
     #[inline]
     pub fn decode(key: Vec<u8>, input: &[u8]) -> Tree {
-        // operation is infallible so it's ok to unwrap
-        let mut tree: Tree = Decode::decode(input).unwrap();
+        let mut tree = Tree::decode_any_version(input, false).unwrap();
         tree.inner.kv.key = key;
         tree
     }
+
+    /// Like `decode`, but checks the node's checksum before decoding it, so
+    /// corrupted nodes are detected immediately rather than when their hashes
+    /// are found to mismatch.
+    ///
+    /// Returns an error if the checksum does not match, if the node is
+    /// malformed, or if it was written in an earlier encoding which has no
+    /// checksum (see `migrate_encoding`).
+    pub fn decode_verified(key: Vec<u8>, input: &[u8]) -> Result<Tree> {
+        let mut tree = Tree::decode_any_version(input, true)?;
+        tree.inner.kv.key = key;
+        Ok(tree)
+    }
+
+    /// Re-encodes a node written in any supported encoding (e.g. the version 1
+    /// encoding, which had no version prefix) in the current encoding.
+    pub fn migrate_encoding(input: &[u8]) -> Result<Vec<u8>> {
+        Ok(Self::decode_any_version(input, false)?.encode())
+    }
+}
+
+/// Returns the checksum of the given bytes, appended to nodes in the current
+/// encoding.
+fn checksum(bytes: &[u8]) -> [u8; CHECKSUM_LENGTH] {
+    let mut hasher = Blake2b::new(HASH_LENGTH);
+    hasher.update(bytes);
+    let mut checksum = [0; CHECKSUM_LENGTH];
+    checksum.copy_from_slice(&hasher.finalize().as_bytes()[..CHECKSUM_LENGTH]);
+    checksum
+}
+
+/// A writer which computes the checksum of the bytes written through it, so
+/// nodes can be checksummed as they are streamed.
+struct ChecksumWriter<'a, W: Write> {
+    dest: &'a mut W,
+    hasher: Blake2b,
+}
+
+impl<'a, W: Write> ChecksumWriter<'a, W> {
+    fn new(dest: &'a mut W) -> Self {
+        ChecksumWriter {
+            dest,
+            hasher: Blake2b::new(HASH_LENGTH),
+        }
+    }
+
+    /// Writes the checksum of all bytes written so far.
+    fn finish(&mut self) -> Result<()> {
+        let hasher = std::mem::replace(&mut self.hasher, Blake2b::new(HASH_LENGTH));
+        let hash = hasher.finalize();
+        self.dest.write_all(&hash.as_bytes()[..CHECKSUM_LENGTH])?;
+        Ok(())
+    }
+}
+
+impl<'a, W: Write> Write for ChecksumWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let length = self.dest.write(buf)?;
+        self.hasher.update(&buf[..length]);
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.dest.flush()
+    }
+}
+
+/// The version 1 encoding of a node, with no version prefix (see
+/// `TreeInner`'s encoding).
+impl<H: Hasher, A: Aggregate> Encode for Tree<H, A> {
+    #[inline]
+    fn encode_into<W: Write>(&self, dest: &mut W) -> ed::Result<()> {
+        self.inner.encode_into(dest)
+    }
+
+    #[inline]
+    fn encoding_length(&self) -> ed::Result<usize> {
+        self.inner.encoding_length()
+    }
+}
+
+impl<H: Hasher, A: Aggregate> Decode for Tree<H, A> {
+    #[inline]
+    fn decode<R: Read>(input: R) -> ed::Result<Self> {
+        Ok(Tree {
+            inner: Box::new(Decode::decode(input)?),
+        })
+    }
+
+    #[inline]
+    fn decode_into<R: Read>(&mut self, input: R) -> ed::Result<()> {
+        self.inner.decode_into(input)
+    }
+}
+
+/// The version 1 encoding of a node's fields (its links, then its key/value
+/// pair), with no version prefix. The cached height is not encoded.
+impl<H: Hasher, A: Aggregate> Encode for TreeInner<H, A> {
+    #[inline]
+    fn encode_into<W: Write>(&self, dest: &mut W) -> ed::Result<()> {
+        self.left.encode_into(dest)?;
+        self.right.encode_into(dest)?;
+        self.kv.encode_into(dest)
+    }
+
+    #[inline]
+    fn encoding_length(&self) -> ed::Result<usize> {
+        Ok(self.left.encoding_length()?
+            + self.right.encoding_length()?
+            + self.kv.encoding_length()?)
+    }
+}
+
+impl<H: Hasher, A: Aggregate> Decode for TreeInner<H, A> {
+    #[inline]
+    fn decode<R: Read>(mut input: R) -> ed::Result<Self> {
+        let left = Decode::decode(&mut input)?;
+        let right = Decode::decode(&mut input)?;
+        let kv = Decode::decode(&mut input)?;
+        Ok(TreeInner::new(kv, left, right))
+    }
+
+    #[inline]
+    fn decode_into<R: Read>(&mut self, mut input: R) -> ed::Result<()> {
+        self.left = Decode::decode(&mut input)?;
+        self.right = Decode::decode(&mut input)?;
+        self.kv.decode_into(&mut input)?;
+        self.update_height();
+        Ok(())
+    }
+}
+
+/// Decodes the fields of a node in the current encoding, following the version
+/// byte.
+fn decode_current<H: Hasher, A: Aggregate>(mut input: &[u8]) -> Result<Tree<H, A>> {
+    let mut links = [None, None];
+    for link in links.iter_mut() {
+        let mut tag = [0];
+        input.read_exact(&mut tag)?;
+        *link = match tag[0] {
+            0 => None,
+            1 => Some(decode_link(&mut input)?),
+            tag => bail!("Unexpected link tag {}", tag),
+        };
+    }
+    let [left, right] = links;
+
+    let mut hash = H::Digest::default();
+    input.read_exact(hash.as_mut())?;
+
+    let value_length = read_varint(&mut input)? as usize;
+    if value_length != input.len() {
+        bail!(
+            "Expected value of length {}, got {} bytes",
+            value_length,
+            input.len()
+        );
+    }
+    let value = input.to_vec();
+
+    Ok(Tree {
+        inner: Box::new(TreeInner::new(
+            KV::from_fields(vec![], value, hash),
+            left,
+            right,
+        )),
+    })
+}
+
+/// Writes a link in the current encoding.
+fn encode_link_into<H: Hasher, A: Aggregate, W: Write>(
+    link: &Link<H, A>,
+    dest: &mut W,
+) -> Result<()> {
+    let key = link.key();
+    let (left_height, right_height) = link.child_heights();
+    write_varint(dest, key.len() as u64)?;
+    dest.write_all(key)?;
+    dest.write_all(link.hash().as_ref())?;
+    dest.write_all(&[left_height, right_height])?;
+    write_varint(dest, link.descendant_count())?;
+    link.aggregate().encode_into(dest)?;
+    Ok(())
+}
+
+/// Returns the length of a link in the current encoding.
+fn link_encoding_length<H: Hasher, A: Aggregate>(link: &Link<H, A>) -> usize {
+    let key_length = link.key().len();
+    varint_length(key_length as u64)
+        + key_length
+        + H::LENGTH
+        + 2
+        + varint_length(link.descendant_count())
+        // the aggregate must be encodable for the node to be encoded anyway
+        + link.aggregate().encoding_length().unwrap()
+}
+
+/// Reads a link in the current encoding, as a `Link::Reference`.
+fn decode_link<H: Hasher, A: Aggregate, R: Read>(mut input: R) -> Result<Link<H, A>> {
+    let key_length = read_varint(&mut input)? as usize;
+    let mut key = vec![0; key_length];
+    input.read_exact(&mut key)?;
+
+    let mut hash = H::Digest::default();
+    input.read_exact(hash.as_mut())?;
+
+    let mut child_heights = [0; 2];
+    input.read_exact(&mut child_heights)?;
+
+    let descendant_count = read_varint(&mut input)?;
+
+    let aggregate = A::decode(&mut input)?;
+
+    Ok(Link::Reference {
+        hash,
+        child_heights: (child_heights[0], child_heights[1]),
+        descendant_count,
+        aggregate,
+        key,
+    })
+}
+
+/// Writes `n` as an unsigned LEB128 varint: 7 bits per byte, least significant
+/// first, with the high bit set on every byte but the last.
+pub(crate) fn write_varint<W: Write>(dest: &mut W, mut n: u64) -> Result<()> {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            dest.write_all(&[byte])?;
+            return Ok(());
+        }
+        dest.write_all(&[byte | 0x80])?;
+    }
+}
+
+/// Reads an unsigned LEB128 varint written by `write_varint`.
+pub(crate) fn read_varint<R: Read>(mut input: R) -> Result<u64> {
+    let mut n = 0u64;
+    for i in 0..10 {
+        let mut byte = [0];
+        input.read_exact(&mut byte)?;
+        let bits = u64::from(byte[0] & 0x7f);
+        if i == 9 && bits > 1 {
+            break;
+        }
+        n |= bits << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    bail!("Varint is too long")
+}
+
+/// Returns the number of bytes used to encode `n` as a varint.
+pub(crate) fn varint_length(n: u64) -> usize {
+    let bits = 64 - n.leading_zeros() as usize;
+    std::cmp::max(1, (bits + 6) / 7)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::super::Link;
+    use super::super::hash::kv_hash;
+    use super::super::{Link, NoAggregate, NoopCommit};
     use super::*;
 
     #[test]
     fn encode_leaf_tree() {
         let tree = Tree::from_fields(vec![0], vec![1], [55; 20], None, None);
-        assert_eq!(tree.encoding_length(), 23);
+        assert_eq!(tree.encoding_length(), 29);
         assert_eq!(
             tree.encode(),
             vec![
-                0, 0, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
-                55, 1,
+                3, 0, 0, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
+                55, 55, 1, 1, 35, 84, 149, 90
             ]
         );
     }
@@ -64,6 +414,8 @@ mod tests {
             Some(Link::Modified {
                 pending_writes: 1,
                 child_heights: (123, 124),
+                descendant_count: 300,
+                aggregate: NoAggregate,
                 tree: Tree::new(vec![2], vec![3]),
             }),
             None,
@@ -80,6 +432,8 @@ mod tests {
             Some(Link::Loaded {
                 hash: [66; 20],
                 child_heights: (123, 124),
+                descendant_count: 300,
+                aggregate: NoAggregate,
                 tree: Tree::new(vec![2], vec![3]),
             }),
             None,
@@ -87,9 +441,9 @@ mod tests {
         assert_eq!(
             tree.encode(),
             vec![
-                1, 1, 2, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66,
-                66, 66, 123, 124, 0, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
-                55, 55, 55, 55, 55, 1
+                3, 1, 1, 2, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66,
+                66, 66, 123, 124, 172, 2, 0, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
+                55, 55, 55, 55, 55, 55, 55, 1, 1, 196, 88, 237, 190
             ]
         );
     }
@@ -103,6 +457,8 @@ mod tests {
             Some(Link::Uncommitted {
                 hash: [66; 20],
                 child_heights: (123, 124),
+                descendant_count: 300,
+                aggregate: NoAggregate,
                 tree: Tree::new(vec![2], vec![3]),
             }),
             None,
@@ -110,9 +466,9 @@ mod tests {
         assert_eq!(
             tree.encode(),
             vec![
-                1, 1, 2, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66,
-                66, 66, 123, 124, 0, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
-                55, 55, 55, 55, 55, 1
+                3, 1, 1, 2, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66,
+                66, 66, 123, 124, 172, 2, 0, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
+                55, 55, 55, 55, 55, 55, 55, 1, 1, 196, 88, 237, 190
             ]
         );
     }
@@ -126,17 +482,19 @@ mod tests {
             Some(Link::Reference {
                 hash: [66; 20],
                 child_heights: (123, 124),
+                descendant_count: 300,
+                aggregate: NoAggregate,
                 key: vec![2],
             }),
             None,
         );
-        assert_eq!(tree.encoding_length(), 47);
+        assert_eq!(tree.encoding_length(), 55);
         assert_eq!(
             tree.encode(),
             vec![
-                1, 1, 2, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66,
-                66, 66, 123, 124, 0, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
-                55, 55, 55, 55, 55, 1
+                3, 1, 1, 2, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66,
+                66, 66, 123, 124, 172, 2, 0, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
+                55, 55, 55, 55, 55, 55, 55, 1, 1, 196, 88, 237, 190
             ]
         );
     }
@@ -155,8 +513,8 @@ mod tests {
     fn decode_reference_tree() {
         let bytes = vec![
             1, 1, 2, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66,
-            66, 123, 124, 0, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
-            55, 55, 55, 1,
+            66, 123, 124, 0, 0, 0, 0, 0, 0, 1, 44, 0, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
+            55, 55, 55, 55, 55, 55, 55, 55, 55, 1,
         ];
         let tree = Tree::decode(vec![0], bytes.as_slice());
         assert_eq!(tree.key(), &[0]);
@@ -164,14 +522,262 @@ mod tests {
         if let Some(Link::Reference {
             key,
             child_heights,
+            descendant_count,
             hash,
+            ..
         }) = tree.link(true)
         {
             assert_eq!(*key, [2]);
             assert_eq!(*child_heights, (123 as u8, 124 as u8));
+            assert_eq!(*descendant_count, 300);
             assert_eq!(*hash, [66 as u8; 20]);
         } else {
             panic!("Expected Link::Reference");
         }
     }
+
+    /// A writer which accepts at most 3 bytes per call, to check that encoding
+    /// does not depend on writing each field in a single call.
+    struct ChunkedWriter(Vec<u8>);
+
+    impl Write for ChunkedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(3);
+            self.0.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn encode_into_matches_encode() {
+        let reference = |key| Link::Reference {
+            hash: [66; 20],
+            child_heights: (1, 2),
+            descendant_count: 5,
+            aggregate: NoAggregate,
+            key,
+        };
+        let trees = vec![
+            Tree::from_fields(vec![0], vec![], [55; 20], None, None),
+            Tree::from_fields(vec![0], vec![1], [55; 20], Some(reference(vec![2])), None),
+            Tree::from_fields(
+                vec![0],
+                vec![1; 65_535],
+                [55; 20],
+                Some(reference(vec![1; 255])),
+                Some(reference(vec![2; 255])),
+            ),
+            Tree::from_fields(
+                vec![0],
+                vec![1],
+                [55; 20],
+                None,
+                Some(Link::Loaded {
+                    hash: [66; 20],
+                    child_heights: (0, 0),
+                    descendant_count: 1,
+                    aggregate: NoAggregate,
+                    tree: Tree::new(vec![2], vec![3]),
+                }),
+            ),
+        ];
+
+        for tree in trees {
+            let mut writer = ChunkedWriter(vec![]);
+            tree.encode_into(&mut writer).expect("encode failed");
+            assert_eq!(writer.0, tree.encode());
+            assert_eq!(writer.0.len(), tree.encoding_length());
+        }
+    }
+
+    #[test]
+    fn varint_boundaries() {
+        for &(n, length) in [
+            (0, 1),
+            (127, 1),
+            (128, 2),
+            (16_383, 2),
+            (16_384, 3),
+            (u64::max_value(), 10),
+        ]
+        .iter()
+        {
+            let mut bytes = vec![];
+            write_varint(&mut bytes, n).unwrap();
+            assert_eq!(bytes.len(), length);
+            assert_eq!(varint_length(n), length);
+            assert_eq!(read_varint(bytes.as_slice()).unwrap(), n);
+        }
+
+        assert!(read_varint(&[0xff; 10][..]).is_err());
+        assert!(read_varint(&[0x80][..]).is_err());
+    }
+
+    fn make_tree(length: usize) -> Tree {
+        let link = |key| Link::Reference {
+            hash: [66; 20],
+            child_heights: (3, 4),
+            descendant_count: length as u64,
+            aggregate: NoAggregate,
+            key,
+        };
+        Tree::from_fields(
+            vec![0],
+            vec![1; length],
+            [55; 20],
+            Some(link(vec![2; length % 256])),
+            Some(link(vec![3; 255 - length % 256])),
+        )
+    }
+
+    fn assert_trees_eq(a: &Tree, b: &Tree) {
+        assert_eq!(a.key(), b.key());
+        assert_eq!(a.value(), b.value());
+        assert_eq!(a.kv_hash(), b.kv_hash());
+        for &left in [true, false].iter() {
+            let (a, b) = (a.link(left).unwrap(), b.link(left).unwrap());
+            assert_eq!(a.key(), b.key());
+            assert_eq!(a.hash(), b.hash());
+            assert_eq!(a.child_heights(), b.child_heights());
+            assert_eq!(a.descendant_count(), b.descendant_count());
+        }
+    }
+
+    #[test]
+    fn encode_empty_value() {
+        let tree = Tree::new(vec![1], vec![]);
+        let bytes = tree.encode();
+        assert_eq!(bytes.len(), tree.encoding_length());
+
+        let decoded = Tree::decode_verified(vec![1], bytes.as_slice()).unwrap();
+        assert_eq!(decoded.value(), &[] as &[u8]);
+        assert_eq!(decoded.kv_hash(), &kv_hash(&[1], &[]));
+        assert_eq!(decoded.hash(), tree.hash());
+
+        // an empty value hashes differently from any other value
+        assert_ne!(decoded.kv_hash(), Tree::new(vec![1], vec![0]).kv_hash());
+    }
+
+    #[test]
+    fn decode_round_trip() {
+        for &length in [0, 127, 128, 16_383, 16_384].iter() {
+            let tree = make_tree(length);
+
+            let bytes = tree.encode();
+            assert_eq!(bytes[0], ENCODING_VERSION);
+            assert_eq!(bytes.len(), tree.encoding_length());
+            assert_trees_eq(&Tree::decode(vec![0], bytes.as_slice()), &tree);
+            let verified = Tree::decode_verified(vec![0], bytes.as_slice()).unwrap();
+            assert_trees_eq(&verified, &tree);
+
+            // version 2 encoding, with no checksum
+            let mut unchecked_bytes = bytes[..bytes.len() - CHECKSUM_LENGTH].to_vec();
+            unchecked_bytes[0] = UNCHECKED_ENCODING_VERSION;
+            assert_trees_eq(&Tree::decode(vec![0], unchecked_bytes.as_slice()), &tree);
+            assert!(Tree::decode_verified(vec![0], unchecked_bytes.as_slice()).is_err());
+            let migrated = Tree::migrate_encoding(unchecked_bytes.as_slice()).unwrap();
+            assert_eq!(migrated, bytes);
+
+            // version 1 encoding, with no version prefix
+            let legacy_bytes = Encode::encode(&tree).unwrap();
+            assert!(legacy_bytes[0] == 0 || legacy_bytes[0] == 1);
+            assert_trees_eq(&Tree::decode(vec![0], legacy_bytes.as_slice()), &tree);
+            assert!(Tree::decode_verified(vec![0], legacy_bytes.as_slice()).is_err());
+
+            let mut decoded = Tree::new(vec![], vec![]);
+            decoded.decode_into(vec![0], legacy_bytes.as_slice());
+            assert_trees_eq(&decoded, &tree);
+            decoded.decode_into(vec![0], bytes.as_slice());
+            assert_trees_eq(&decoded, &tree);
+
+            let migrated = Tree::migrate_encoding(legacy_bytes.as_slice()).unwrap();
+            assert_eq!(migrated, bytes);
+            assert!(bytes.len() < legacy_bytes.len());
+        }
+    }
+
+    /// A `Hasher` with 32-byte digests, to check that nodes are encoded with
+    /// the digest length of their hasher.
+    struct Blake2b256Hasher;
+
+    impl Hasher for Blake2b256Hasher {
+        type Digest = [u8; 32];
+        const LENGTH: usize = 32;
+        const VERSION: u8 = 2;
+
+        fn hash_value(value: &[u8]) -> [u8; 32] {
+            blake2b_256(&[value])
+        }
+
+        fn hash_kv_digest(key: &[u8], value_hash: &[u8; 32]) -> [u8; 32] {
+            blake2b_256(&[key, value_hash])
+        }
+
+        fn hash_node(kv: &[u8; 32], left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+            blake2b_256(&[kv, left, right])
+        }
+    }
+
+    fn blake2b_256(parts: &[&[u8]]) -> [u8; 32] {
+        let mut hasher = Blake2b::new(32);
+        for part in parts {
+            hasher.update(part);
+        }
+        let mut hash = [0; 32];
+        hash.copy_from_slice(hasher.finalize().as_bytes());
+        hash
+    }
+
+    #[test]
+    fn encode_wide_digest() {
+        let mut tree = Tree::<Blake2b256Hasher>::with_hasher(vec![1], vec![1])
+            .attach(true, Some(Tree::with_hasher(vec![0], vec![0])));
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+
+        // the link and the key/value hash each hold a 32-byte digest
+        let bytes = tree.encode();
+        assert_eq!(bytes.len(), 1 + (1 + 37) + 1 + 32 + 2 + CHECKSUM_LENGTH);
+        assert_eq!(bytes.len(), tree.encoding_length());
+
+        let mut decoded = Tree::<Blake2b256Hasher>::with_hasher(vec![], vec![]);
+        decoded.decode_into(vec![1], bytes.as_slice());
+        assert_eq!(decoded.key(), &[1]);
+        assert_eq!(decoded.kv_hash(), tree.kv_hash());
+        assert_eq!(decoded.link(true).unwrap().hash(), &tree.child_hash(true));
+        assert_eq!(decoded.hash(), tree.hash());
+    }
+
+    #[test]
+    fn decode_unknown_version() {
+        assert!(Tree::migrate_encoding(&[4, 0, 0]).is_err());
+        assert!(Tree::migrate_encoding(&[]).is_err());
+
+        let mut bytes = make_tree(10).encode();
+        bytes.push(0);
+        assert!(Tree::migrate_encoding(bytes.as_slice()).is_err());
+    }
+
+    #[test]
+    fn decode_verified_corrupt() {
+        let bytes = make_tree(10).encode();
+        assert!(Tree::decode_verified(vec![0], bytes.as_slice()).is_ok());
+
+        for i in 1..bytes.len() {
+            let mut corrupted = bytes.clone();
+            corrupted[i] ^= 1;
+            let err = Tree::decode_verified(vec![0], corrupted.as_slice())
+                .err()
+                .expect("expected error");
+            assert!(err.to_string().starts_with("Corrupt node encoding"));
+        }
+
+        let mut corrupted = bytes.clone();
+        corrupted[0] ^= 1;
+        assert!(Tree::decode_verified(vec![0], corrupted.as_slice()).is_err());
+        assert!(Tree::decode_verified(vec![0], &bytes[..CHECKSUM_LENGTH]).is_err());
+    }
 }
@@ -33,7 +33,7 @@ fn fuzz_case(seed: u64) {
     let mut rng: SmallRng = SeedableRng::seed_from_u64(seed);
     let initial_size = (rng.gen::<u64>() % 10) + 1;
     let tree = make_tree_rand(initial_size, initial_size, seed);
-    let mut map = Map::from_iter(tree.iter());
+    let mut map = Map::from_iter(tree.iter().map(|(k, v)| (k.to_vec(), v.to_vec())));
     let mut maybe_tree = Some(tree);
     println!("====== MERK FUZZ ======");
     println!("SEED: {}", seed);
@@ -63,7 +63,7 @@ fn make_batch(maybe_tree: Option<&Tree>, size: u64, seed: u64) -> Vec<BatchEntry
         let tree = maybe_tree.as_ref().unwrap();
         let entries: Vec<_> = tree.iter().collect();
         let index = rng.borrow_mut().gen::<u64>() as usize % entries.len();
-        entries[index].0.clone()
+        entries[index].0.to_vec()
     };
 
     let random_value = |size| {
@@ -118,9 +118,12 @@ fn make_batch(maybe_tree: Option<&Tree>, size: u64, seed: u64) -> Vec<BatchEntry
 fn apply_to_map(map: &mut Map, batch: &Batch) {
     for entry in batch.iter() {
         match entry {
-            (key, Op::Put(value)) => {
+            (key, Op::Put(value)) | (key, Op::Update(value)) => {
                 map.insert(key.to_vec(), value.to_vec());
             }
+            (key, Op::PutIfAbsent(value)) => {
+                map.entry(key.to_vec()).or_insert_with(|| value.to_vec());
+            }
             (key, Op::Delete) => {
                 map.remove(key);
             }
@@ -139,8 +142,8 @@ fn assert_map(maybe_tree: Option<&Tree>, map: &Map) {
     let map_iter = map.iter();
     let tree_iter = tree.iter();
     for (tree_kv, map_kv) in tree_iter.zip(map_iter) {
-        assert_eq!(tree_kv.0, *map_kv.0);
-        assert_eq!(tree_kv.1, *map_kv.1);
+        assert_eq!(tree_kv.0, map_kv.0.as_slice());
+        assert_eq!(tree_kv.1, map_kv.1.as_slice());
     }
 
     assert_eq!(tree.iter().count(), map.len());
@@ -1,6 +1,10 @@
 use std::convert::TryFrom;
+use std::fmt;
 
 use blake2_rfc::blake2b::Blake2b;
+use failure::{bail, format_err};
+
+use crate::error::Result;
 
 /// The length of a `Hash` (in bytes).
 pub const HASH_LENGTH: usize = 20;
@@ -11,24 +15,226 @@ pub const NULL_HASH: Hash = [0; HASH_LENGTH];
 /// A cryptographic hash digest.
 pub type Hash = [u8; HASH_LENGTH];
 
-/// Hashes a key/value pair.
+/// Constant-time equality, for checking hashes computed from untrusted input
+/// (e.g. proofs) against expected hashes without revealing through timing how
+/// many leading bytes match. `==` is still used for other comparisons.
+pub trait CtEq {
+    /// Returns `true` if `self` and `other` are equal, taking the same time
+    /// wherever they differ.
+    fn ct_eq(&self, other: &Self) -> bool;
+}
+
+impl CtEq for Hash {
+    fn ct_eq(&self, other: &Hash) -> bool {
+        subtle::ConstantTimeEq::ct_eq(&self[..], &other[..]).into()
+    }
+}
+
+/// The domain-separation byte hashed before the input of key/value hashes (by
+/// hashers of version 1 and later).
+pub const KV_DOMAIN: u8 = 0x00;
+
+/// The domain-separation byte hashed before the input of node hashes (by
+/// hashers of version 1 and later).
+pub const NODE_DOMAIN: u8 = 0x01;
+
+/// The domain-separation byte hashed before the input of value hashes (by
+/// hashers of version 2 and later).
+pub const VALUE_DOMAIN: u8 = 0x02;
+
+/// A hash function used to compute the digests of key/value pairs and tree
+/// nodes. `Tree`, `KV`, and `Link` are generic over the hasher (defaulting to
+/// `Blake2bHasher`), and proofs can be verified under any hasher (see
+/// `verify_query_with_hasher`).
 ///
-/// **NOTE:** This will panic if the key is longer than 255 bytes, or the value
-/// is longer than 65,535 bytes.
-pub fn kv_hash(key: &[u8], value: &[u8]) -> Hash {
-    // TODO: result instead of panic
-    // TODO: make generic to allow other hashers
-    let mut hasher = Blake2b::new(HASH_LENGTH);
+/// Digests are written with a fixed width of `LENGTH` bytes in node
+/// encodings. Proofs encode hashes as `Hash`es, so only trees whose hasher's
+/// `Digest` is `Hash` can create them; implementations backed by hash functions
+/// with longer outputs can truncate them (or use an extendable output mode) to
+/// `HASH_LENGTH` bytes to support proofs.
+pub trait Hasher {
+    /// The digest produced by the hasher, e.g. `[u8; 32]`.
+    type Digest: Copy
+        + Default
+        + Eq
+        + fmt::Debug
+        + AsRef<[u8]>
+        + AsMut<[u8]>
+        + Send
+        + Sync
+        + 'static;
 
-    // panics if key is longer than 255!
-    let key_length = u8::try_from(key.len()).expect("key must be less than 256 bytes");
-    hasher.update(&key_length.to_be_bytes());
-    hasher.update(&key);
+    /// The length of a `Digest` in bytes.
+    const LENGTH: usize;
 
-    // panics if value is longer than 65535!
-    let val_length = u16::try_from(value.len()).expect("value must be less than 65,536 bytes");
-    hasher.update(&val_length.to_be_bytes());
-    hasher.update(&value);
+    /// The version of the hashing scheme. In version 0, key/value hashes and
+    /// node hashes are computed over their inputs alone, so a key/value pair
+    /// can be crafted whose hash input equals a node's. Version 1 prefixes the
+    /// inputs with `KV_DOMAIN` and `NODE_DOMAIN` respectively so the two can
+    /// never collide. Version 2 also hashes key/value pairs over the hash of
+    /// the value (prefixed with `VALUE_DOMAIN`) so a pair's hash can be
+    /// computed without knowing its value. Version 3 also hashes the number of
+    /// nodes in each child's subtree into node hashes (see
+    /// `hash_counted_node`), so the size of a tree can be proven.
+    const VERSION: u8;
+
+    /// Hashes a value, for use in `hash_kv_digest`.
+    fn hash_value(value: &[u8]) -> Self::Digest;
+
+    /// Hashes a key/value pair given the key and the hash of the value (as
+    /// returned by `hash_value`). Panics for hashers before version 2, which
+    /// hash the value directly.
+    fn hash_kv_digest(key: &[u8], value_hash: &Self::Digest) -> Self::Digest;
+
+    /// Hashes a key/value pair. By default, hashes the key along with the hash
+    /// of the value.
+    fn hash_kv(key: &[u8], value: &[u8]) -> Self::Digest {
+        Self::hash_kv_digest(key, &Self::hash_value(value))
+    }
+
+    /// Hashes a node based on the hash of its key/value pair, the hash of its
+    /// left child (if any), and the hash of its right child (if any). Missing
+    /// children are hashed as the default (zero-filled) digest.
+    fn hash_node(kv: &Self::Digest, left: &Self::Digest, right: &Self::Digest) -> Self::Digest;
+
+    /// Hashes a node like `hash_node`, also given the number of nodes in the
+    /// subtrees of its left and right children (0 for missing children). This
+    /// is how trees and proofs hash nodes. By default the counts are ignored;
+    /// hashers of version 3 and later hash them along with the other inputs.
+    fn hash_counted_node(
+        kv: &Self::Digest,
+        left: &Self::Digest,
+        right: &Self::Digest,
+        _child_counts: (u64, u64),
+    ) -> Self::Digest {
+        Self::hash_node(kv, left, right)
+    }
+}
+
+/// The default `Hasher`, using Blake2b with a `HASH_LENGTH`-byte output and
+/// domain separation, hashing key/value pairs over value hashes (version 2).
+#[derive(Debug)]
+pub struct Blake2bHasher;
+
+impl Hasher for Blake2bHasher {
+    type Digest = Hash;
+    const LENGTH: usize = HASH_LENGTH;
+    const VERSION: u8 = 2;
+
+    fn hash_value(value: &[u8]) -> Hash {
+        blake2b(&[&[VALUE_DOMAIN], value])
+    }
+
+    fn hash_kv_digest(key: &[u8], value_hash: &Hash) -> Hash {
+        blake2b(&[&[KV_DOMAIN], &key_length_prefix(key), key, value_hash])
+    }
+
+    fn hash_node(kv: &Hash, left: &Hash, right: &Hash) -> Hash {
+        blake2b(&[&[NODE_DOMAIN], kv, left, right])
+    }
+}
+
+/// A `Hasher` like `Blake2bHasher` which also hashes the number of nodes in
+/// each child's subtree into node hashes (version 3), so that the number of
+/// nodes in a tree can be proven with `RefWalker::create_count_proof`.
+#[derive(Debug)]
+pub struct Blake2bCountedHasher;
+
+impl Hasher for Blake2bCountedHasher {
+    type Digest = Hash;
+    const LENGTH: usize = HASH_LENGTH;
+    const VERSION: u8 = 3;
+
+    fn hash_value(value: &[u8]) -> Hash {
+        Blake2bHasher::hash_value(value)
+    }
+
+    fn hash_kv_digest(key: &[u8], value_hash: &Hash) -> Hash {
+        Blake2bHasher::hash_kv_digest(key, value_hash)
+    }
+
+    fn hash_node(_kv: &Hash, _left: &Hash, _right: &Hash) -> Hash {
+        panic!("Version 3 hashers must hash nodes with their child counts")
+    }
+
+    fn hash_counted_node(kv: &Hash, left: &Hash, right: &Hash, child_counts: (u64, u64)) -> Hash {
+        let (left_count, right_count) = child_counts;
+        blake2b(&[
+            &[NODE_DOMAIN],
+            kv,
+            left,
+            right,
+            &left_count.to_be_bytes(),
+            &right_count.to_be_bytes(),
+        ])
+    }
+}
+
+/// The `Hasher` used by earlier versions of merk, using Blake2b with domain
+/// separation but hashing key/value pairs over the value itself (version 1).
+/// Useful for verifying proofs against trees which were hashed before value
+/// hashes were added.
+#[derive(Debug)]
+pub struct Blake2bV1Hasher;
+
+impl Hasher for Blake2bV1Hasher {
+    type Digest = Hash;
+    const LENGTH: usize = HASH_LENGTH;
+    const VERSION: u8 = 1;
+
+    fn hash_value(value: &[u8]) -> Hash {
+        blake2b(&[value])
+    }
+
+    fn hash_kv_digest(_key: &[u8], _value_hash: &Hash) -> Hash {
+        panic!("Version 1 hashers cannot hash key/value digests")
+    }
+
+    fn hash_kv(key: &[u8], value: &[u8]) -> Hash {
+        let (key_length, val_length) = kv_length_prefixes(key, value);
+        blake2b(&[&[KV_DOMAIN], &key_length, key, &val_length, value])
+    }
+
+    fn hash_node(kv: &Hash, left: &Hash, right: &Hash) -> Hash {
+        blake2b(&[&[NODE_DOMAIN], kv, left, right])
+    }
+}
+
+/// The `Hasher` used by earlier versions of merk, using Blake2b without domain
+/// separation (version 0). Useful for verifying proofs against trees which
+/// were hashed before domain separation was added.
+#[derive(Debug)]
+pub struct LegacyBlake2bHasher;
+
+impl Hasher for LegacyBlake2bHasher {
+    type Digest = Hash;
+    const LENGTH: usize = HASH_LENGTH;
+    const VERSION: u8 = 0;
+
+    fn hash_value(value: &[u8]) -> Hash {
+        blake2b(&[value])
+    }
+
+    fn hash_kv_digest(_key: &[u8], _value_hash: &Hash) -> Hash {
+        panic!("Version 0 hashers cannot hash key/value digests")
+    }
+
+    fn hash_kv(key: &[u8], value: &[u8]) -> Hash {
+        let (key_length, val_length) = kv_length_prefixes(key, value);
+        blake2b(&[&key_length, key, &val_length, value])
+    }
+
+    fn hash_node(kv: &Hash, left: &Hash, right: &Hash) -> Hash {
+        blake2b(&[kv, left, right])
+    }
+}
+
+/// Hashes the concatenation of the given byte slices with Blake2b.
+fn blake2b(parts: &[&[u8]]) -> Hash {
+    let mut hasher = Blake2b::new(HASH_LENGTH);
+    for part in parts {
+        hasher.update(part);
+    }
 
     let res = hasher.finalize();
     let mut hash: Hash = Default::default();
@@ -37,19 +243,303 @@ pub fn kv_hash(key: &[u8], value: &[u8]) -> Hash {
     hash
 }
 
-/// Hashes a node based on the hash of its key/value pair, the hash of its left
-/// child (if any), and the hash of its right child (if any).
-pub fn node_hash(kv: &Hash, left: &Hash, right: &Hash) -> Hash {
-    // TODO: make generic to allow other hashers
-    let mut hasher = Blake2b::new(HASH_LENGTH);
+/// A `Hasher` using Blake3 with domain separation and value hashes (version
+/// 2), reading `HASH_LENGTH` bytes from its extendable output. Requires the
+/// `blake3` feature.
+#[cfg(feature = "blake3")]
+#[derive(Debug)]
+pub struct Blake3Hasher;
 
-    hasher.update(kv);
-    hasher.update(left);
-    hasher.update(right);
+#[cfg(feature = "blake3")]
+impl Hasher for Blake3Hasher {
+    type Digest = Hash;
+    const LENGTH: usize = HASH_LENGTH;
+    const VERSION: u8 = 2;
+
+    fn hash_value(value: &[u8]) -> Hash {
+        blake3(&[&[VALUE_DOMAIN], value])
+    }
+
+    fn hash_kv_digest(key: &[u8], value_hash: &Hash) -> Hash {
+        blake3(&[&[KV_DOMAIN], &key_length_prefix(key), key, value_hash])
+    }
+
+    fn hash_node(kv: &Hash, left: &Hash, right: &Hash) -> Hash {
+        blake3(&[&[NODE_DOMAIN], kv, left, right])
+    }
+}
+
+/// Hashes the concatenation of the given byte slices with Blake3.
+#[cfg(feature = "blake3")]
+fn blake3(parts: &[&[u8]]) -> Hash {
+    let mut hasher = blake3::Hasher::new();
+    for part in parts {
+        hasher.update(part);
+    }
 
-    let res = hasher.finalize();
     let mut hash: Hash = Default::default();
-    // TODO: if blake2 lib returned an array we wouldn't need this copy
-    hash.copy_from_slice(res.as_bytes());
+    hasher.finalize_xof().fill(&mut hash);
     hash
 }
+
+/// Returns the length prefix of the key, which is hashed along with the key
+/// itself.
+///
+/// **NOTE:** This will panic if the key is longer than 255 bytes.
+fn key_length_prefix(key: &[u8]) -> [u8; 1] {
+    // TODO: result instead of panic
+    let key_length = u8::try_from(key.len()).expect("key must be less than 256 bytes");
+    key_length.to_be_bytes()
+}
+
+/// Returns the big-endian length prefixes of the key and value, which are
+/// hashed along with the key and value themselves by hashers before version 2.
+///
+/// **NOTE:** This will panic if the key is longer than 255 bytes, or the value
+/// is longer than 65,535 bytes.
+fn kv_length_prefixes(key: &[u8], value: &[u8]) -> ([u8; 1], [u8; 2]) {
+    // TODO: result instead of panic
+    let val_length = u16::try_from(value.len()).expect("value must be less than 65,536 bytes");
+    (key_length_prefix(key), val_length.to_be_bytes())
+}
+
+/// Hashes a key/value pair using the default hasher (`Blake2bHasher`).
+///
+/// **NOTE:** This will panic if the key is longer than 255 bytes.
+pub fn kv_hash(key: &[u8], value: &[u8]) -> Hash {
+    Blake2bHasher::hash_kv(key, value)
+}
+
+/// Hashes a value using the default hasher (`Blake2bHasher`), for use in
+/// `kv_digest_hash`.
+pub fn value_hash(value: &[u8]) -> Hash {
+    Blake2bHasher::hash_value(value)
+}
+
+/// Hashes a key/value pair given the key and the hash of the value, using the
+/// default hasher (`Blake2bHasher`). Equal to `kv_hash(key, value)` when
+/// `value_hash` is `value_hash(value)`.
+///
+/// **NOTE:** This will panic if the key is longer than 255 bytes.
+pub fn kv_digest_hash(key: &[u8], value_hash: &Hash) -> Hash {
+    Blake2bHasher::hash_kv_digest(key, value_hash)
+}
+
+/// Hashes a node based on the hash of its key/value pair, the hash of its left
+/// child (if any), and the hash of its right child (if any), using the default
+/// hasher (`Blake2bHasher`).
+pub fn node_hash(kv: &Hash, left: &Hash, right: &Hash) -> Hash {
+    Blake2bHasher::hash_node(kv, left, right)
+}
+
+/// Wraps a `Hash` to display it as lowercase hex, e.g. for logging with
+/// `format!("{}", HexHash(&hash))`.
+pub struct HexHash<'a>(pub &'a Hash);
+
+impl<'a> fmt::Display for HexHash<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+/// Encodes the hash as a lowercase hex string.
+pub fn to_hex(hash: &Hash) -> String {
+    hex::encode(hash)
+}
+
+/// Parses a hash from a hex string (of either case), as written by `to_hex`.
+///
+/// Returns an error if the string is not exactly `2 * HASH_LENGTH` characters
+/// long, or if it contains non-hex characters.
+pub fn from_hex(hex: &str) -> Result<Hash> {
+    if hex.len() != 2 * HASH_LENGTH {
+        bail!(
+            "Expected hash of {} hex characters, got {}",
+            2 * HASH_LENGTH,
+            hex.len()
+        );
+    }
+
+    let mut hash = NULL_HASH;
+    hex::decode_to_slice(hex, &mut hash).map_err(|err| format_err!("Invalid hex hash: {}", err))?;
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Returns a key/value pair whose length-prefixed encoding is exactly the
+    /// 60-byte input of `hash_node(kv, left, right)`, as would be constructed
+    /// by an attacker passing a leaf off as an internal node.
+    fn colliding_inputs() -> (Vec<u8>, Vec<u8>, Hash, Hash, Hash) {
+        let mut input = [0u8; 3 * HASH_LENGTH];
+        input[0] = 10;
+        for (i, byte) in input.iter_mut().enumerate().skip(1) {
+            *byte = i as u8;
+        }
+        // the value length prefix follows the 10-byte key
+        let value_length = (input.len() - 13) as u16;
+        input[11..13].copy_from_slice(&value_length.to_be_bytes());
+
+        let key = input[1..11].to_vec();
+        let value = input[13..].to_vec();
+        let mut kv: Hash = Default::default();
+        let mut left: Hash = Default::default();
+        let mut right: Hash = Default::default();
+        kv.copy_from_slice(&input[..HASH_LENGTH]);
+        left.copy_from_slice(&input[HASH_LENGTH..2 * HASH_LENGTH]);
+        right.copy_from_slice(&input[2 * HASH_LENGTH..]);
+        (key, value, kv, left, right)
+    }
+
+    #[test]
+    fn ct_eq() {
+        let hash = kv_hash(b"foo", b"bar");
+        assert!(hash.ct_eq(&hash));
+        assert!(NULL_HASH.ct_eq(&[0; HASH_LENGTH]));
+        assert!(!hash.ct_eq(&NULL_HASH));
+
+        // differing in the first or last byte only
+        for &i in [0, HASH_LENGTH - 1].iter() {
+            let mut other = hash;
+            other[i] ^= 1;
+            assert!(!hash.ct_eq(&other));
+            assert!(!other.ct_eq(&hash));
+        }
+    }
+
+    #[test]
+    fn legacy_kv_node_collision() {
+        let (key, value, kv, left, right) = colliding_inputs();
+        assert_eq!(
+            LegacyBlake2bHasher::hash_kv(&key, &value),
+            LegacyBlake2bHasher::hash_node(&kv, &left, &right)
+        );
+    }
+
+    #[test]
+    fn domain_separation() {
+        let (key, value, kv, left, right) = colliding_inputs();
+        assert_ne!(
+            Blake2bHasher::hash_kv(&key, &value),
+            Blake2bHasher::hash_node(&kv, &left, &right)
+        );
+        assert_eq!(kv_hash(&key, &value), Blake2bHasher::hash_kv(&key, &value));
+        assert_ne!(
+            kv_hash(&key, &value),
+            LegacyBlake2bHasher::hash_kv(&key, &value)
+        );
+    }
+
+    #[test]
+    fn v1_domain_separation() {
+        let (key, value, kv, left, right) = colliding_inputs();
+        assert_ne!(
+            Blake2bV1Hasher::hash_kv(&key, &value),
+            Blake2bV1Hasher::hash_node(&kv, &left, &right)
+        );
+        assert_ne!(
+            Blake2bV1Hasher::hash_kv(&key, &value),
+            Blake2bHasher::hash_kv(&key, &value)
+        );
+        assert_ne!(
+            Blake2bV1Hasher::hash_kv(&key, &value),
+            LegacyBlake2bHasher::hash_kv(&key, &value)
+        );
+        assert_eq!(
+            Blake2bV1Hasher::hash_node(&kv, &left, &right),
+            Blake2bHasher::hash_node(&kv, &left, &right)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Version 1 hashers cannot hash key/value digests")]
+    fn v1_kv_digest_panics() {
+        Blake2bV1Hasher::hash_kv_digest(&[1, 2, 3], &NULL_HASH);
+    }
+
+    #[test]
+    fn counted_node_hash() {
+        let (_, _, kv, left, right) = colliding_inputs();
+        let hash = Blake2bCountedHasher::hash_counted_node(&kv, &left, &right, (3, 4));
+        assert_ne!(
+            hash,
+            Blake2bCountedHasher::hash_counted_node(&kv, &left, &right, (4, 3))
+        );
+        assert_ne!(
+            hash,
+            Blake2bCountedHasher::hash_counted_node(&kv, &left, &right, (3, 5))
+        );
+        assert_ne!(hash, Blake2bHasher::hash_node(&kv, &left, &right));
+
+        // hashers before version 3 ignore the counts
+        assert_eq!(
+            Blake2bHasher::hash_counted_node(&kv, &left, &right, (3, 4)),
+            Blake2bHasher::hash_node(&kv, &left, &right)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Version 3 hashers must hash nodes with their child counts")]
+    fn counted_hash_node_panics() {
+        Blake2bCountedHasher::hash_node(&NULL_HASH, &NULL_HASH, &NULL_HASH);
+    }
+
+    #[test]
+    fn kv_digest() {
+        let value = vec![123; 60];
+        assert_eq!(
+            kv_hash(&[1, 2, 3], &value),
+            kv_digest_hash(&[1, 2, 3], &value_hash(&value))
+        );
+        assert_ne!(
+            kv_hash(&[1, 2, 3], &value),
+            kv_digest_hash(&[1, 2, 3], &value_hash(&[123; 59]))
+        );
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn blake3_domain_separation() {
+        let (key, value, kv, left, right) = colliding_inputs();
+        assert_ne!(
+            Blake3Hasher::hash_kv(&key, &value),
+            Blake3Hasher::hash_node(&kv, &left, &right)
+        );
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        let hash = kv_hash(&[1, 2, 3], &[4, 5, 6]);
+        let hex = to_hex(&hash);
+        assert_eq!(hex.len(), 2 * HASH_LENGTH);
+        assert_eq!(hex, hex.to_lowercase());
+        assert_eq!(format!("{}", HexHash(&hash)), hex);
+        assert_eq!(from_hex(&hex).unwrap(), hash);
+        assert_eq!(from_hex(&hex.to_uppercase()).unwrap(), hash);
+
+        assert_eq!(to_hex(&NULL_HASH), "0".repeat(40));
+        assert_eq!(
+            to_hex(&[0xab; HASH_LENGTH]),
+            "abababababababababababababababababababab"
+        );
+    }
+
+    #[test]
+    fn hex_malformed() {
+        let hex = to_hex(&kv_hash(&[1], &[2]));
+
+        let err = from_hex(&hex[..39]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expected hash of 40 hex characters, got 39"
+        );
+        assert!(from_hex(&hex[..38]).is_err());
+        assert!(from_hex(&format!("{}00", hex)).is_err());
+        assert!(from_hex("").is_err());
+
+        let err = from_hex(&format!("{}zz", &hex[..38])).unwrap_err();
+        assert!(err.to_string().starts_with("Invalid hex hash"));
+    }
+}
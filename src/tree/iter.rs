@@ -1,84 +1,450 @@
 use super::Tree;
-
-/// An entry stored on an `Iter`'s stack, containing a reference to a `Tree`,
-/// and its traversal state.
-///
-/// The `traversed` field represents whether or not the left child, self, and
-/// right child have been visited, respectively (`(left, self, right)`).
-struct StackItem<'a> {
-    tree: &'a Tree,
-    traversed: (bool, bool, bool),
-}
-
-impl<'a> StackItem<'a> {
-    /// Creates a new `StackItem` for the given tree. The `traversed` state will
-    /// be `false` since the children and self have not been visited yet, but
-    /// will default to `true` for sides that do not have a child.
-    fn new(tree: &'a Tree) -> Self {
-        StackItem {
-            tree,
-            traversed: (
-                tree.child(true).is_none(),
-                false,
-                tree.child(false).is_none(),
-            ),
-        }
-    }
-
-    /// Gets a tuple to yield from an `Iter`, `(key, value)`.
-    fn to_entry(&self) -> (Vec<u8>, Vec<u8>) {
-        (self.tree.key().to_vec(), self.tree.value().to_vec())
-    }
-}
+use crate::error::Result;
+use failure::format_err;
+use std::ops::{Bound, RangeBounds};
 
 /// An iterator which yields the key/value pairs of the tree, in order, skipping
 /// any parts of the tree which are pruned (not currently retained in memory).
+///
+/// Traversal uses explicit stacks rather than recursion, so iterating over very
+/// tall trees will not overflow the call stack. The front stack holds the path
+/// to the next node to be yielded by `next`, and the back stack holds the path
+/// to the next node to be yielded by `next_back`.
 pub struct Iter<'a> {
-    stack: Vec<StackItem<'a>>,
+    front: Vec<&'a Tree>,
+    back: Vec<&'a Tree>,
+    last_front_key: Option<&'a [u8]>,
+    last_back_key: Option<&'a [u8]>,
 }
 
 impl<'a> Iter<'a> {
     /// Creates a new iterator for the given tree.
     pub fn new(tree: &'a Tree) -> Self {
-        let stack = vec![StackItem::new(tree)];
-        Iter { stack }
+        let mut iter = Iter {
+            front: vec![],
+            back: vec![],
+            last_front_key: None,
+            last_back_key: None,
+        };
+        push_edge(&mut iter.front, Some(tree), true);
+        push_edge(&mut iter.back, Some(tree), false);
+        iter
+    }
+
+    /// Empties both stacks, ending iteration in both directions. Called once
+    /// the front and back cursors meet.
+    fn finish(&mut self) -> Option<(&'a [u8], &'a [u8])> {
+        self.front.clear();
+        self.back.clear();
+        None
     }
 }
 
-impl<'a> Tree {
+/// Pushes `maybe_tree` and its chain of in-memory descendants on the given side
+/// onto `stack`. Pushing the left edge prepares a stack for ascending traversal,
+/// pushing the right edge prepares it for descending traversal.
+fn push_edge<'a>(stack: &mut Vec<&'a Tree>, maybe_tree: Option<&'a Tree>, left: bool) {
+    let mut maybe_cursor = maybe_tree;
+    while let Some(cursor) = maybe_cursor {
+        stack.push(cursor);
+        maybe_cursor = cursor.child(left);
+    }
+}
+
+impl Tree {
     /// Creates an iterator which yields `(key, value)` tuples for all of the
     /// tree's nodes which are retained in memory (skipping pruned subtrees).
-    pub fn iter(&'a self) -> Iter<'a> {
+    pub fn iter(&self) -> Iter {
         Iter::new(self)
     }
+
+    /// Creates an iterator which yields the keys of the tree's nodes which are
+    /// retained in memory, in key order (skipping pruned subtrees, as in
+    /// `iter`).
+    pub fn keys(&self) -> impl DoubleEndedIterator<Item = &[u8]> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    /// Creates an iterator which yields the values of the tree's nodes which
+    /// are retained in memory, in key order (skipping pruned subtrees, as in
+    /// `iter`).
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = &[u8]> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    /// Creates an iterator which yields `(key, value)` tuples for the tree's
+    /// nodes with keys in `range`, in key order, without allocating for each
+    /// entry. Unlike `iter`, pruned subtrees are not skipped: if a pruned
+    /// subtree may contain keys in the range, an error is yielded in its place
+    /// and iteration ends.
+    pub fn range<R: RangeBounds<[u8]>>(&self, range: R) -> Range<R> {
+        Range::new(self, range)
+    }
 }
 
 impl<'a> Iterator for Iter<'a> {
-    type Item = (Vec<u8>, Vec<u8>);
+    type Item = (&'a [u8], &'a [u8]);
 
     /// Traverses to and yields the next key/value pair, in key order.
     fn next(&mut self) -> Option<Self::Item> {
-        if self.stack.is_empty() {
+        let tree = self.front.pop()?;
+        if let Some(back_key) = self.last_back_key {
+            if tree.key() >= back_key {
+                return self.finish();
+            }
+        }
+
+        push_edge(&mut self.front, tree.child(false), true);
+        self.last_front_key = Some(tree.key());
+        Some((tree.key(), tree.value()))
+    }
+}
+
+impl<'a> DoubleEndedIterator for Iter<'a> {
+    /// Traverses to and yields the previous key/value pair, in reverse key
+    /// order.
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let tree = self.back.pop()?;
+        if let Some(front_key) = self.last_front_key {
+            if tree.key() <= front_key {
+                return self.finish();
+            }
+        }
+
+        push_edge(&mut self.back, tree.child(true), false);
+        self.last_back_key = Some(tree.key());
+        Some((tree.key(), tree.value()))
+    }
+}
+
+/// An iterator which yields the key/value pairs of the tree with keys in a
+/// range, in order (see `Tree::range`).
+///
+/// The stack holds the path to the next node to be yielded, as in `Iter`, where
+/// `None` marks a pruned subtree which would have to be traversed.
+pub struct Range<'a, R> {
+    stack: Vec<Option<&'a Tree>>,
+    range: R,
+}
+
+impl<'a, R: RangeBounds<[u8]>> Range<'a, R> {
+    /// Creates a new iterator over the entries of `tree` in `range`.
+    fn new(tree: &'a Tree, range: R) -> Self {
+        let mut iter = Range {
+            stack: vec![],
+            range,
+        };
+        iter.push_start(Some(tree));
+        iter
+    }
+
+    /// Descends from `maybe_tree` towards the range's lower bound, pushing the
+    /// nodes which are not below it onto the stack.
+    fn push_start(&mut self, maybe_tree: Option<&'a Tree>) {
+        let mut maybe_cursor = maybe_tree;
+        while let Some(cursor) = maybe_cursor {
+            let key = cursor.key();
+            let (below_start, extends_left) = match self.range.start_bound() {
+                Bound::Included(start) => (key < start, start < key),
+                Bound::Excluded(start) => (key <= start, start < key),
+                Bound::Unbounded => (false, true),
+            };
+
+            maybe_cursor = if below_start {
+                self.child(cursor, false)
+            } else {
+                self.stack.push(Some(cursor));
+                if extends_left {
+                    self.child(cursor, true)
+                } else {
+                    None
+                }
+            };
+        }
+    }
+
+    /// Returns the child of `tree` on the given side, if any. If the child is
+    /// pruned, pushes a `None` marker onto the stack and returns `None`.
+    fn child(&mut self, tree: &'a Tree, left: bool) -> Option<&'a Tree> {
+        tree.link(left)?;
+        let maybe_child = tree.child(left);
+        if maybe_child.is_none() {
+            self.stack.push(None);
+        }
+        maybe_child
+    }
+}
+
+impl<'a, R: RangeBounds<[u8]>> Iterator for Range<'a, R> {
+    type Item = Result<(&'a [u8], &'a [u8])>;
+
+    /// Traverses to and yields the next key/value pair in the range, in key
+    /// order.
+    fn next(&mut self) -> Option<Self::Item> {
+        let tree = match self.stack.pop()? {
+            Some(tree) => tree,
+            None => {
+                self.stack.clear();
+                return Some(Err(format_err!("Range contains pruned nodes")));
+            }
+        };
+
+        let key = tree.key();
+        let (above_end, extends_right) = match self.range.end_bound() {
+            Bound::Included(end) => (key > end, end > key),
+            Bound::Excluded(end) => (key >= end, end > key),
+            Bound::Unbounded => (false, true),
+        };
+        if above_end {
+            self.stack.clear();
             return None;
         }
 
-        let last = self.stack.last_mut().unwrap();
-        if !last.traversed.0 {
-            last.traversed.0 = true;
-            let tree = last.tree.child(true).unwrap();
-            self.stack.push(StackItem::new(tree));
-            self.next()
-        } else if !last.traversed.1 {
-            last.traversed.1 = true;
-            Some(last.to_entry())
-        } else if !last.traversed.2 {
-            last.traversed.2 = true;
-            let tree = last.tree.child(false).unwrap();
-            self.stack.push(StackItem::new(tree));
-            self.next()
-        } else {
-            self.stack.pop();
-            self.next()
+        if extends_right {
+            let maybe_right = self.child(tree, false);
+            self.push_start(maybe_right);
+        }
+        Some(Ok((key, tree.value())))
+    }
+}
+
+/// An iterator which consumes a tree and yields its key/value pairs, in order,
+/// without cloning them (see `Tree::into_iter`).
+///
+/// Like `Iter`, pruned subtrees are skipped, so only the entries retained in
+/// memory are yielded; the tree should be fully loaded (e.g. with
+/// `Tree::load_all`) to iterate over all of its entries. The stack holds the
+/// detached nodes on the path to the next node to be yielded, each of which has
+/// already had its left child detached.
+pub struct IntoIter {
+    stack: Vec<Tree>,
+}
+
+/// Pushes `maybe_tree` and its chain of in-memory left descendants onto
+/// `stack`, detaching each from its left child.
+fn push_owned_edge(stack: &mut Vec<Tree>, maybe_tree: Option<Tree>) {
+    let mut maybe_cursor = maybe_tree;
+    while let Some(cursor) = maybe_cursor {
+        let (cursor, maybe_left) = cursor.detach(true);
+        stack.push(cursor);
+        maybe_cursor = maybe_left;
+    }
+}
+
+impl IntoIterator for Tree {
+    type Item = (Vec<u8>, Vec<u8>);
+    type IntoIter = IntoIter;
+
+    /// Creates an iterator which consumes the tree, yielding owned `(key,
+    /// value)` tuples for all of its nodes which are retained in memory
+    /// (skipping pruned subtrees).
+    fn into_iter(self) -> IntoIter {
+        let mut stack = vec![];
+        push_owned_edge(&mut stack, Some(self));
+        IntoIter { stack }
+    }
+}
+
+impl Iterator for IntoIter {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    /// Traverses to and yields the next key/value pair, in key order, moving
+    /// them out of the consumed node.
+    fn next(&mut self) -> Option<Self::Item> {
+        let tree = self.stack.pop()?;
+        let (tree, maybe_right) = tree.detach(false);
+        push_owned_edge(&mut self.stack, maybe_right);
+
+        let kv = tree.inner.kv;
+        Some((kv.key, kv.value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_utils::{make_partially_pruned_tree, make_tree_rand};
+    use std::collections::BTreeMap;
+    use std::ops::Bound;
+
+    #[test]
+    fn iter_sorted() {
+        let tree = make_tree_rand(100, 10, 0);
+        let keys: Vec<_> = tree.iter().map(|(key, _)| key).collect();
+        assert_eq!(keys.len(), 101);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn iter_rev_sorted() {
+        let tree = make_tree_rand(100, 10, 1);
+        let keys: Vec<_> = tree.iter().rev().map(|(key, _)| key).collect();
+        assert_eq!(keys.len(), 101);
+        assert!(keys.windows(2).all(|pair| pair[0] > pair[1]));
+    }
+
+    #[test]
+    fn keys_and_values() {
+        let tree = make_tree_rand(100, 10, 5);
+        let (expected_keys, expected_values): (Vec<_>, Vec<_>) = tree.iter().unzip();
+
+        let keys: Vec<_> = tree.keys().collect();
+        assert_eq!(keys, expected_keys);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let values: Vec<_> = tree.values().collect();
+        assert_eq!(values, expected_values);
+        let rev_keys: Vec<_> = tree.keys().rev().collect();
+        assert_eq!(rev_keys, keys.into_iter().rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn iter_both_ends() {
+        let tree = make_tree_rand(100, 10, 2);
+        let expected: Vec<_> = tree.iter().collect();
+
+        let mut iter = tree.iter();
+        let mut front = vec![];
+        let mut back = vec![];
+        loop {
+            match iter.next() {
+                Some(entry) => front.push(entry),
+                None => break,
+            }
+            match iter.next_back() {
+                Some(entry) => back.push(entry),
+                None => break,
+            }
+        }
+        assert!(iter.next().is_none());
+        assert!(iter.next_back().is_none());
+
+        back.reverse();
+        front.append(&mut back);
+        assert_eq!(front, expected);
+    }
+
+    #[test]
+    fn iter_skips_pruned() {
+        let tree = make_partially_pruned_tree(vec![5], vec![7]);
+
+        let entries: Vec<_> = tree.iter().collect();
+        assert_eq!(
+            entries,
+            vec![(&[5u8][..], &[5u8][..]), (&[7u8][..], &[7u8][..])]
+        );
+
+        let entries: Vec<_> = tree.iter().rev().collect();
+        assert_eq!(
+            entries,
+            vec![(&[7u8][..], &[7u8][..]), (&[5u8][..], &[5u8][..])]
+        );
+    }
+
+    #[test]
+    fn range_bounds() {
+        let tree = make_tree_rand(100, 10, 3);
+        let map: BTreeMap<_, _> = tree.iter().collect();
+        let keys: Vec<_> = map.keys().cloned().collect();
+
+        // bounds at existing keys, and between existing keys
+        let mut points: Vec<Vec<u8>> = vec![vec![], vec![0xff; 9]];
+        for key in keys.iter().step_by(17) {
+            points.push(key.to_vec());
+            let mut between = key.to_vec();
+            between.push(0);
+            points.push(between);
+        }
+
+        fn bounds(point: &[u8]) -> [Bound<&[u8]>; 3] {
+            [
+                Bound::Included(point),
+                Bound::Excluded(point),
+                Bound::Unbounded,
+            ]
+        }
+        for start_point in points.iter() {
+            for end_point in points.iter() {
+                for &start in bounds(start_point).iter() {
+                    for &end in bounds(end_point).iter() {
+                        // BTreeMap::range panics on decreasing ranges
+                        let decreasing = match (start, end) {
+                            (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+                            (Bound::Included(start), Bound::Included(end))
+                            | (Bound::Included(start), Bound::Excluded(end))
+                            | (Bound::Excluded(start), Bound::Included(end)) => start > end,
+                            _ => false,
+                        };
+                        if decreasing {
+                            continue;
+                        }
+
+                        let entries: Vec<_> = tree
+                            .range((start, end))
+                            .collect::<Result<_, _>>()
+                            .expect("range failed");
+                        let expected: Vec<_> = map
+                            .range::<[u8], _>((start, end))
+                            .map(|(key, value)| (*key, *value))
+                            .collect();
+                        assert_eq!(entries, expected);
+                    }
+                }
+            }
         }
     }
+
+    #[test]
+    fn range_pruned() {
+        let tree = make_partially_pruned_tree(vec![5], vec![7]);
+
+        let entries: Vec<_> = tree
+            .range((Bound::Included(&[5u8][..]), Bound::<&[u8]>::Unbounded))
+            .collect::<Result<_, _>>()
+            .expect("range failed");
+        assert_eq!(
+            entries,
+            vec![(&[5u8][..], &[5u8][..]), (&[7u8][..], &[7u8][..])]
+        );
+
+        let mut iter = tree.range((Bound::Included(&[4u8][..]), Bound::<&[u8]>::Unbounded));
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn into_iter_sorted() {
+        let tree = make_tree_rand(100, 10, 3);
+        let expected: Vec<_> = tree
+            .iter()
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+            .collect();
+        assert_eq!(expected.len(), 101);
+
+        let entries: Vec<_> = tree.into_iter().collect();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn into_iter_moves_entries() {
+        let tree = make_tree_rand(100, 10, 4);
+        let pointers: Vec<_> = tree
+            .iter()
+            .map(|(key, value)| (key.as_ptr(), value.as_ptr()))
+            .collect();
+
+        // the yielded keys and values are the nodes' own buffers, not clones
+        let yielded: Vec<_> = tree
+            .into_iter()
+            .map(|(key, value)| (key.as_ptr(), value.as_ptr()))
+            .collect();
+        assert_eq!(yielded, pointers);
+    }
+
+    #[test]
+    fn into_iter_skips_pruned() {
+        let tree = make_partially_pruned_tree(vec![5], vec![7]);
+
+        let entries: Vec<_> = tree.into_iter().collect();
+        assert_eq!(entries, vec![(vec![5], vec![5]), (vec![7], vec![7])]);
+    }
 }
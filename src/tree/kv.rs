@@ -1,4 +1,4 @@
-use super::hash::{kv_hash, Hash, HASH_LENGTH, NULL_HASH};
+use super::hash::{Blake2bHasher, Hasher};
 use ed::{Decode, Encode, Result};
 use std::io::{Read, Write};
 
@@ -7,27 +7,42 @@ use std::io::{Read, Write};
 //       field to save even more. also might be possible to combine key
 //       field and value field.
 
-/// Contains a key/value pair, and the hash of the key/value pair.
-pub struct KV {
+/// Contains a key/value pair, and the hash of the key/value pair computed with
+/// the hasher `H`.
+pub struct KV<H: Hasher = Blake2bHasher> {
     pub(super) key: Vec<u8>,
     pub(super) value: Vec<u8>,
-    pub(super) hash: Hash,
+    pub(super) hash: H::Digest,
+    /// Whether the value may have been modified through `value_mut` since the
+    /// hash was computed, in which case the hash must be recomputed (see
+    /// `update_hash`) before it is used.
+    pub(super) dirty: bool,
 }
 
-impl KV {
+impl<H: Hasher> KV<H> {
     /// Creates a new `KV` with the given key and value and computes its hash.
     #[inline]
     pub fn new(key: Vec<u8>, value: Vec<u8>) -> Self {
         // TODO: length checks?
-        let hash = kv_hash(key.as_slice(), value.as_slice());
-        KV { key, value, hash }
+        let hash = H::hash_kv(key.as_slice(), value.as_slice());
+        KV {
+            key,
+            value,
+            hash,
+            dirty: false,
+        }
     }
 
     /// Creates a new `KV` with the given key, value, and hash. The hash is not
     /// checked to be correct for the given key/value.
     #[inline]
-    pub fn from_fields(key: Vec<u8>, value: Vec<u8>, hash: Hash) -> Self {
-        KV { key, value, hash }
+    pub fn from_fields(key: Vec<u8>, value: Vec<u8>, hash: H::Digest) -> Self {
+        KV {
+            key,
+            value,
+            hash,
+            dirty: false,
+        }
     }
 
     /// Replaces the `KV`'s value with the given value, updates the hash, and
@@ -36,10 +51,36 @@ impl KV {
     pub fn with_value(mut self, value: Vec<u8>) -> Self {
         // TODO: length check?
         self.value = value;
-        self.hash = kv_hash(self.key(), self.value());
+        self.hash = H::hash_kv(self.key(), self.value());
+        self.dirty = false;
         self
     }
 
+    /// Returns a mutable reference to the value, and marks the hash as stale
+    /// so it will be recomputed by `update_hash`.
+    #[inline]
+    pub fn value_mut(&mut self) -> &mut Vec<u8> {
+        self.dirty = true;
+        &mut self.value
+    }
+
+    /// Returns `true` if the value may have been modified through `value_mut`
+    /// since the hash was computed.
+    #[inline]
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Recomputes the hash if the value may have been modified through
+    /// `value_mut`, otherwise does nothing.
+    #[inline]
+    pub fn update_hash(&mut self) {
+        if self.dirty {
+            self.hash = H::hash_kv(self.key(), self.value());
+            self.dirty = false;
+        }
+    }
+
     /// Returns the key as a slice.
     #[inline]
     pub fn key(&self) -> &[u8] {
@@ -52,9 +93,11 @@ impl KV {
         self.value.as_slice()
     }
 
-    /// Returns the hash.
+    /// Returns the hash. Panics in debug builds if the hash is stale (see
+    /// `value_mut`).
     #[inline]
-    pub fn hash(&self) -> &Hash {
+    pub fn hash(&self) -> &H::Digest {
+        debug_assert!(!self.dirty, "KV hash is stale, call update_hash first");
         &self.hash
     }
 
@@ -65,10 +108,10 @@ impl KV {
     }
 }
 
-impl Encode for KV {
+impl<H: Hasher> Encode for KV<H> {
     #[inline]
     fn encode_into<W: Write>(&self, out: &mut W) -> Result<()> {
-        out.write_all(&self.hash[..])?;
+        out.write_all(self.hash.as_ref())?;
         out.write_all(&self.value.as_slice())?;
         Ok(())
     }
@@ -76,17 +119,18 @@ impl Encode for KV {
     #[inline]
     fn encoding_length(&self) -> Result<usize> {
         debug_assert!(self.key().len() < 256, "Key length must be less than 256");
-        Ok(HASH_LENGTH + self.value.len())
+        Ok(H::LENGTH + self.value.len())
     }
 }
 
-impl Decode for KV {
+impl<H: Hasher> Decode for KV<H> {
     #[inline]
     fn decode<R: Read>(input: R) -> Result<Self> {
         let mut kv = KV {
             key: Vec::with_capacity(0),
             value: Vec::with_capacity(128),
-            hash: NULL_HASH,
+            hash: Default::default(),
+            dirty: false,
         };
         KV::decode_into(&mut kv, input)?;
         Ok(kv)
@@ -96,7 +140,8 @@ impl Decode for KV {
     fn decode_into<R: Read>(&mut self, mut input: R) -> Result<()> {
         self.key.clear();
 
-        input.read_exact(&mut self.hash[..])?;
+        input.read_exact(self.hash.as_mut())?;
+        self.dirty = false;
 
         self.value.clear();
         input.read_to_end(self.value.as_mut())?;
@@ -107,23 +152,41 @@ impl Decode for KV {
 
 #[cfg(test)]
 mod test {
+    use super::super::hash::NULL_HASH;
     use super::*;
 
     #[test]
     fn new_kv() {
-        let kv = KV::new(vec![1, 2, 3], vec![4, 5, 6]);
+        let kv: KV = KV::new(vec![1, 2, 3], vec![4, 5, 6]);
 
         assert_eq!(kv.key(), &[1, 2, 3]);
         assert_eq!(kv.value(), &[4, 5, 6]);
-        assert_ne!(kv.hash(), &super::super::hash::NULL_HASH);
+        assert_ne!(kv.hash(), &NULL_HASH);
     }
 
     #[test]
     fn with_value() {
-        let kv = KV::new(vec![1, 2, 3], vec![4, 5, 6]).with_value(vec![7, 8, 9]);
+        let kv: KV = KV::new(vec![1, 2, 3], vec![4, 5, 6]).with_value(vec![7, 8, 9]);
 
         assert_eq!(kv.key(), &[1, 2, 3]);
         assert_eq!(kv.value(), &[7, 8, 9]);
-        assert_ne!(kv.hash(), &super::super::hash::NULL_HASH);
+        assert_ne!(kv.hash(), &NULL_HASH);
+    }
+
+    #[test]
+    fn value_mut() {
+        let mut kv: KV = KV::new(vec![1, 2, 3], vec![4, 5, 6]);
+        assert!(!kv.is_dirty());
+
+        kv.value_mut().push(7);
+        assert!(kv.is_dirty());
+        assert_eq!(kv.value(), &[4, 5, 6, 7]);
+
+        kv.update_hash();
+        assert!(!kv.is_dirty());
+        assert_eq!(
+            kv.hash(),
+            KV::<Blake2bHasher>::new(vec![1, 2, 3], vec![4, 5, 6, 7]).hash()
+        );
     }
 }
@@ -0,0 +1,150 @@
+use super::walk::Fetch;
+use super::Tree;
+use crate::error::Result;
+use std::cmp::Ordering;
+
+/// Wraps a `Tree` along with a `Fetch` source, so pruned nodes are loaded
+/// transparently when they are accessed rather than requiring calls to
+/// `Tree::load`.
+///
+/// Loaded nodes are kept in the tree (as `Link::Loaded`), so each node is
+/// fetched from the source at most once.
+pub struct LazyTree<S: Fetch> {
+    tree: Tree,
+    source: S,
+}
+
+impl<S: Fetch> LazyTree<S> {
+    /// Creates a `LazyTree` which loads the pruned nodes of `tree` from
+    /// `source`.
+    pub fn new(tree: Tree, source: S) -> Self {
+        LazyTree { tree, source }
+    }
+
+    /// Returns an immutable reference to the wrapped tree.
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+
+    /// Consumes the `LazyTree` and returns the wrapped tree, including all of
+    /// the nodes which have been loaded.
+    pub fn into_inner(self) -> Tree {
+        self.tree
+    }
+
+    /// Returns the root node's child on the given side, if any, fetching it
+    /// from the source if it is pruned.
+    pub fn child(&mut self, left: bool) -> Result<Option<&Tree>> {
+        load_child(&mut self.tree, left, &self.source)?;
+        Ok(self.tree.child(left))
+    }
+
+    /// Gets the value for the given key, fetching the pruned nodes on its path
+    /// from the source. Returns `None` if the key is not in the tree.
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<&[u8]>> {
+        let mut cursor = &mut self.tree;
+        loop {
+            let left = match key.cmp(cursor.key()) {
+                Ordering::Equal => return Ok(Some(cursor.value())),
+                Ordering::Less => true,
+                Ordering::Greater => false,
+            };
+
+            load_child(cursor, left, &self.source)?;
+            cursor = match cursor.child_mut(left) {
+                None => return Ok(None),
+                Some(child) => child,
+            };
+        }
+    }
+}
+
+/// Loads the child of `tree` on the given side from `source` if it is pruned.
+fn load_child<S: Fetch>(tree: &mut Tree, left: bool, source: &S) -> Result<()> {
+    match tree.link(left) {
+        Some(link) if link.is_reference() => tree.load(left, source),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{Link, MemStore, PanicSource, Walker};
+    use super::*;
+    use crate::test_utils::{make_batch_seq, seq_key};
+    use std::cell::Cell;
+
+    /// A `Fetch` source which counts the nodes fetched from a `MemStore`.
+    struct CountingSource<'a> {
+        store: &'a MemStore,
+        fetches: &'a Cell<usize>,
+    }
+
+    impl<'a> Fetch for CountingSource<'a> {
+        fn fetch(&self, link: &Link) -> Result<Tree> {
+            self.fetches.set(self.fetches.get() + 1);
+            (&self.store).fetch(link)
+        }
+    }
+
+    fn make_pruned_tree(store: &mut MemStore) -> Tree {
+        let batch = make_batch_seq(0..100);
+        let mut tree = Walker::<PanicSource>::apply_to(None, &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        tree.commit(store).expect("commit failed");
+        assert!(tree.link(true).unwrap().is_reference());
+        assert!(tree.link(false).unwrap().is_reference());
+        tree
+    }
+
+    #[test]
+    fn lazy_get() {
+        let mut store = MemStore::new();
+        let tree = make_pruned_tree(&mut store);
+        let fetches = Cell::new(0);
+        let source = CountingSource {
+            store: &store,
+            fetches: &fetches,
+        };
+        let mut lazy = LazyTree::new(tree, source);
+
+        for i in 0..100 {
+            let value = lazy.get(&seq_key(i)).expect("get failed");
+            assert_eq!(value, Some(&[123; 60][..]));
+        }
+        assert!(lazy.get(&seq_key(100)).expect("get failed").is_none());
+
+        // every node but the root was fetched exactly once
+        assert_eq!(fetches.get(), 99);
+        assert_eq!(lazy.into_inner().iter().count(), 100);
+    }
+
+    #[test]
+    fn lazy_child() {
+        let mut store = MemStore::new();
+        let tree = make_pruned_tree(&mut store);
+        let fetches = Cell::new(0);
+        let source = CountingSource {
+            store: &store,
+            fetches: &fetches,
+        };
+        let mut lazy = LazyTree::new(tree, source);
+
+        let child_key = lazy
+            .child(true)
+            .expect("child failed")
+            .expect("expected child")
+            .key()
+            .to_vec();
+        assert_eq!(fetches.get(), 1);
+
+        // the loaded child is cached
+        let child = lazy.child(true).expect("child failed").unwrap();
+        assert_eq!(child.key(), child_key.as_slice());
+        assert!(child.link(true).unwrap().is_reference());
+        assert_eq!(fetches.get(), 1);
+        assert!(lazy.tree().link(true).unwrap().is_stored());
+    }
+}
@@ -3,20 +3,28 @@ use std::io::{Read, Write};
 
 use ed::{Decode, Encode, Result, Terminated};
 
-use super::hash::Hash;
+use super::aggregate::{Aggregate, NoAggregate};
+use super::hash::{Blake2bHasher, Hasher};
 use super::Tree;
 
 // TODO: optimize memory footprint
 
 /// Represents a reference to a child tree node. Links may or may not contain
 /// the child's `Tree` instance (storing its key if not).
-pub enum Link {
+///
+/// Every variant tracks `descendant_count`, the number of nodes in the child's
+/// subtree (including the child itself), which allows rank/select queries
+/// without loading the whole subtree, and `aggregate`, the `Aggregate` of the
+/// entries in the child's subtree.
+pub enum Link<H: Hasher = Blake2bHasher, A: Aggregate = NoAggregate> {
     /// Represents a child tree node which has been pruned from memory, only
     /// retaining a reference to it (its key). The child node can always be
     /// fetched from the backing store by this key when necessary.
     Reference {
-        hash: Hash,
+        hash: H::Digest,
         child_heights: (u8, u8),
+        descendant_count: u64,
+        aggregate: A,
         key: Vec<u8>,
     },
 
@@ -27,46 +35,110 @@ pub enum Link {
     Modified {
         pending_writes: usize, // TODO: rename to `pending_hashes`
         child_heights: (u8, u8),
-        tree: Tree
+        descendant_count: u64,
+        aggregate: A,
+        tree: Tree<H, A>
     },
 
     // Represents a tree node which has been modified since the `Tree`'s last
     // commit, but which has an up-to-date hash. The child's `Tree` instance is
     // stored in the link.
     Uncommitted {
-        hash: Hash,
+        hash: H::Digest,
         child_heights: (u8, u8),
-        tree: Tree,
+        descendant_count: u64,
+        aggregate: A,
+        tree: Tree<H, A>,
     },
 
     /// Represents a tree node which has not been modified, has an up-to-date
     /// hash, and which is being retained in memory.
     Loaded {
-        hash: Hash,
+        hash: H::Digest,
         child_heights: (u8, u8),
-        tree: Tree,
+        descendant_count: u64,
+        aggregate: A,
+        tree: Tree<H, A>,
     },
 }
 
-impl Link {
+impl<H: Hasher, A: Aggregate> Link<H, A> {
     /// Creates a `Link::Modified` from the given `Tree`.
     #[inline]
-    pub fn from_modified_tree(tree: Tree) -> Self {
+    pub fn from_modified_tree(tree: Tree<H, A>) -> Self {
         let pending_writes = 1 + tree.child_pending_writes(true) + tree.child_pending_writes(false);
 
         Link::Modified {
             pending_writes,
             child_heights: tree.child_heights(),
+            descendant_count: tree.count(),
+            aggregate: tree.aggregate(),
             tree,
         }
     }
 
     /// Creates a `Link::Modified` from the given tree, if any. If `None`,
     /// returns `None`.
-    pub fn maybe_from_modified_tree(maybe_tree: Option<Tree>) -> Option<Self> {
+    pub fn maybe_from_modified_tree(maybe_tree: Option<Tree<H, A>>) -> Option<Self> {
         maybe_tree.map(Link::from_modified_tree)
     }
 
+    /// Creates a `Link::Loaded` from the given `Tree`, which must already be
+    /// written to the backing store, and its hash and child heights. This is
+    /// the one path by which `commit` and `load` construct stored links.
+    ///
+    /// In debug builds, panics if `hash` is not the hash of `tree`.
+    #[inline]
+    pub fn from_stored_tree(tree: Tree<H, A>, hash: H::Digest, child_heights: (u8, u8)) -> Self {
+        debug_assert_eq!(hash, tree.hash(), "Stored link hash does not match tree");
+
+        Link::Loaded {
+            hash,
+            child_heights,
+            descendant_count: tree.count(),
+            aggregate: tree.aggregate(),
+            tree,
+        }
+    }
+
+    /// Consumes the link and converts to variant `Link::Loaded`, once its tree
+    /// has been written to the backing store. Panics if the link is of variant
+    /// `Link::Modified` or `Link::Reference`, and in debug builds if the link's
+    /// hash does not match its tree.
+    #[inline]
+    pub fn into_stored(self) -> Self {
+        match self {
+            Link::Reference { .. } => panic!("Cannot store Reference"),
+            Link::Modified { .. } => panic!("Cannot store Modified tree"),
+            Link::Uncommitted {
+                hash,
+                child_heights,
+                tree,
+                ..
+            }
+            | Link::Loaded {
+                hash,
+                child_heights,
+                tree,
+                ..
+            } => Link::from_stored_tree(tree, hash, child_heights),
+        }
+    }
+
+    /// Consumes the link and converts to variant `Link::Modified`, discarding
+    /// its hash so it will be recomputed. Panics if the link is of variant
+    /// `Link::Reference`.
+    #[inline]
+    pub fn into_modified(self) -> Self {
+        match self {
+            Link::Reference { .. } => panic!("Cannot modify Reference"),
+            Link::Modified { .. } => self,
+            Link::Uncommitted { tree, .. } | Link::Loaded { tree, .. } => {
+                Link::from_modified_tree(tree)
+            }
+        }
+    }
+
     /// Returns `true` if the link is of the `Link::Reference` variant.
     #[inline]
     pub fn is_reference(&self) -> bool {
@@ -103,7 +175,49 @@ impl Link {
         }
     }
 
-    /// Returns the key of the tree referenced by this link, as a slice.
+    /// Returns the key of the pruned tree if the link is of the
+    /// `Link::Reference` variant, otherwise `None`.
+    #[inline]
+    pub fn as_reference(&self) -> Option<&[u8]> {
+        match self {
+            Link::Reference { key, .. } => Some(key.as_slice()),
+            _ => None,
+        }
+    }
+
+    /// Returns the `Tree` instance if the link is of the `Link::Modified`
+    /// variant, otherwise `None`.
+    #[inline]
+    pub fn as_modified(&self) -> Option<&Tree<H, A>> {
+        match self {
+            Link::Modified { tree, .. } => Some(tree),
+            _ => None,
+        }
+    }
+
+    /// Returns the `Tree` instance if the link is of the `Link::Uncommitted`
+    /// variant, otherwise `None`.
+    #[inline]
+    pub fn as_uncommitted(&self) -> Option<&Tree<H, A>> {
+        match self {
+            Link::Uncommitted { tree, .. } => Some(tree),
+            _ => None,
+        }
+    }
+
+    /// Returns the `Tree` instance if the link is of the `Link::Loaded`
+    /// variant, otherwise `None`.
+    #[inline]
+    pub fn as_stored(&self) -> Option<&Tree<H, A>> {
+        match self {
+            Link::Loaded { tree, .. } => Some(tree),
+            _ => None,
+        }
+    }
+
+    /// Returns the key of the tree referenced by this link, as a slice. Never
+    /// panics: `Link::Reference` links store the key, and the other variants
+    /// read it from their `Tree` instance.
     #[inline]
     pub fn key(&self) -> &[u8] {
         match self {
@@ -117,7 +231,7 @@ impl Link {
     /// Returns the `Tree` instance of the tree referenced by the link. If the
     /// link is of variant `Link::Reference`, the returned value will be `None`.
     #[inline]
-    pub fn tree(&self) -> Option<&Tree> {
+    pub fn tree(&self) -> Option<&Tree<H, A>> {
         match self {
             // TODO: panic for Reference, don't return Option?
             Link::Reference { .. } => None,
@@ -127,11 +241,12 @@ impl Link {
         }
     }
 
-    /// Returns the hash of the tree referenced by the link. Panics if link is
-    /// of variant `Link::Modified` since we have not yet recomputed the tree's
-    /// hash.
+    /// Returns the hash of the tree referenced by the link. `Link::Reference`,
+    /// `Link::Uncommitted`, and `Link::Loaded` links store the hash. Panics if
+    /// the link is of variant `Link::Modified` since we have not yet recomputed
+    /// the tree's hash (check with `is_modified` first).
     #[inline]
-    pub fn hash(&self) -> &Hash {
+    pub fn hash(&self) -> &H::Digest {
         match self {
             Link::Modified { .. } => panic!("Cannot get hash from modified link"),
             Link::Reference { hash, .. } => hash,
@@ -140,9 +255,9 @@ impl Link {
         }
     }
 
-    /// Returns the height of the children of the tree referenced by the link,
-    /// if any (note: not the height of the referenced tree itself). Return
-    /// value is `(left_child_height, right_child_height)`.
+    /// Returns the height of the tree referenced by the link, computed from the
+    /// child heights stored in every variant, so it never panics and does not
+    /// require the tree to be in memory.
     #[inline]
     pub fn height(&self) -> u8 {
         let (left_height, right_height) = match self {
@@ -151,19 +266,63 @@ impl Link {
             Link::Uncommitted { child_heights, .. } => *child_heights,
             Link::Loaded { child_heights, .. } => *child_heights,
         };
-        1 + max(left_height, right_height)
+        max(left_height, right_height).saturating_add(1)
+    }
+
+    /// Returns the number of nodes in the tree referenced by the link,
+    /// including the referenced node itself.
+    #[inline]
+    pub fn descendant_count(&self) -> u64 {
+        match self {
+            Link::Reference {
+                descendant_count, ..
+            } => *descendant_count,
+            Link::Modified {
+                descendant_count, ..
+            } => *descendant_count,
+            Link::Uncommitted {
+                descendant_count, ..
+            } => *descendant_count,
+            Link::Loaded {
+                descendant_count, ..
+            } => *descendant_count,
+        }
     }
 
-    /// Returns the balance factor of the tree referenced by the link.
+    /// Returns the aggregate of the entries in the tree referenced by the link.
     #[inline]
-    pub fn balance_factor(&self) -> i8 {
+    pub fn aggregate(&self) -> &A {
+        match self {
+            Link::Reference { aggregate, .. } => aggregate,
+            Link::Modified { aggregate, .. } => aggregate,
+            Link::Uncommitted { aggregate, .. } => aggregate,
+            Link::Loaded { aggregate, .. } => aggregate,
+        }
+    }
+
+    /// Returns the heights of the children of the tree referenced by the link,
+    /// as `(left_child_height, right_child_height)`.
+    #[inline]
+    pub fn child_heights(&self) -> (u8, u8) {
+        match self {
+            Link::Reference { child_heights, .. } => *child_heights,
+            Link::Modified { child_heights, .. } => *child_heights,
+            Link::Uncommitted { child_heights, .. } => *child_heights,
+            Link::Loaded { child_heights, .. } => *child_heights,
+        }
+    }
+
+    /// Returns the balance factor of the tree referenced by the link. The
+    /// heights are widened before subtracting, so this can't overflow.
+    #[inline]
+    pub fn balance_factor(&self) -> i16 {
         let (left_height, right_height) = match self {
             Link::Reference { child_heights, .. } => *child_heights,
             Link::Modified { child_heights, .. } => *child_heights,
             Link::Uncommitted { child_heights, .. } => *child_heights,
             Link::Loaded { child_heights, .. } => *child_heights,
         };
-        right_height as i8 - left_height as i8
+        i16::from(right_height) - i16::from(left_height)
     }
 
     /// Consumes the link and converts to variant `Link::Reference`. Panics if the
@@ -177,10 +336,14 @@ impl Link {
             Link::Loaded {
                 hash,
                 child_heights,
+                descendant_count,
+                aggregate,
                 tree,
             } => Link::Reference {
                 hash,
                 child_heights,
+                descendant_count,
+                aggregate,
                 key: tree.take_key(),
             },
         }
@@ -207,27 +370,61 @@ impl Link {
             } => child_heights,
         }
     }
+
+    #[inline]
+    pub(crate) fn descendant_count_mut(&mut self) -> &mut u64 {
+        match self {
+            Link::Reference {
+                ref mut descendant_count,
+                ..
+            } => descendant_count,
+            Link::Modified {
+                ref mut descendant_count,
+                ..
+            } => descendant_count,
+            Link::Uncommitted {
+                ref mut descendant_count,
+                ..
+            } => descendant_count,
+            Link::Loaded {
+                ref mut descendant_count,
+                ..
+            } => descendant_count,
+        }
+    }
 }
 
-impl Encode for Link {
+impl<H: Hasher, A: Aggregate> Encode for Link<H, A> {
     #[inline]
     fn encode_into<W: Write>(&self, out: &mut W) -> Result<()> {
-        let (hash, key, (left_height, right_height)) = match self {
+        let (hash, key, (left_height, right_height), descendant_count, aggregate) = match self {
             Link::Reference {
                 hash,
                 key,
                 child_heights,
-            } => (hash, key.as_slice(), child_heights),
+                descendant_count,
+                aggregate,
+            } => (
+                hash,
+                key.as_slice(),
+                child_heights,
+                descendant_count,
+                aggregate,
+            ),
             Link::Loaded {
                 hash,
                 tree,
                 child_heights,
-            } => (hash, tree.key(), child_heights),
+                descendant_count,
+                aggregate,
+            } => (hash, tree.key(), child_heights, descendant_count, aggregate),
             Link::Uncommitted {
                 hash,
                 tree,
                 child_heights,
-            } => (hash, tree.key(), child_heights),
+                descendant_count,
+                aggregate,
+            } => (hash, tree.key(), child_heights, descendant_count, aggregate),
 
             Link::Modified { .. } => panic!("No encoding for Link::Modified"),
         };
@@ -237,40 +434,45 @@ impl Encode for Link {
         out.write_all(&[key.len() as u8])?;
         out.write_all(key)?;
 
-        out.write_all(hash)?;
+        out.write_all(hash.as_ref())?;
 
         out.write_all(&[*left_height, *right_height])?;
 
-        Ok(())
+        out.write_all(&descendant_count.to_be_bytes())?;
+
+        aggregate.encode_into(out)
     }
 
     #[inline]
     fn encoding_length(&self) -> Result<usize> {
         debug_assert!(self.key().len() < 256, "Key length must be less than 256");
 
-        Ok(match self {
-            Link::Reference { key, .. } => 1 + key.len() + 20 + 2,
+        let key_length = match self {
+            Link::Reference { key, .. } => key.len(),
             Link::Modified { .. } => panic!("No encoding for Link::Modified"),
-            Link::Uncommitted { tree, .. } => 1 + tree.key().len() + 20 + 2,
-            Link::Loaded { tree, .. } => 1 + tree.key().len() + 20 + 2,
-        })
+            Link::Uncommitted { tree, .. } => tree.key().len(),
+            Link::Loaded { tree, .. } => tree.key().len(),
+        };
+        Ok(1 + key_length + H::LENGTH + 2 + 8 + self.aggregate().encoding_length()?)
     }
 }
 
-impl Link {
+impl<H: Hasher, A: Aggregate> Link<H, A> {
     #[inline]
     fn default_reference() -> Self {
         Link::Reference {
             key: Vec::with_capacity(64),
             hash: Default::default(),
             child_heights: (0, 0),
+            descendant_count: 0,
+            aggregate: A::identity(),
         }
     }
 }
 
-impl Decode for Link {
+impl<H: Hasher, A: Aggregate> Decode for Link<H, A> {
     #[inline]
-    fn decode<R: Read>(input: R) -> Result<Link> {
+    fn decode<R: Read>(input: R) -> Result<Self> {
         let mut link = Link::default_reference();
         Link::decode_into(&mut link, input)?;
         Ok(link)
@@ -288,6 +490,8 @@ impl Decode for Link {
             ref mut key,
             ref mut hash,
             ref mut child_heights,
+            ref mut descendant_count,
+            ref mut aggregate,
         } = self
         {
             let length = read_u8(&mut input)? as usize;
@@ -295,10 +499,16 @@ impl Decode for Link {
             key.resize(length, 0);
             input.read_exact(key.as_mut())?;
 
-            input.read_exact(&mut hash[..])?;
+            input.read_exact(hash.as_mut())?;
 
             child_heights.0 = read_u8(&mut input)?;
             child_heights.1 = read_u8(&mut input)?;
+
+            let mut count_bytes = [0; 8];
+            input.read_exact(&mut count_bytes)?;
+            *descendant_count = u64::from_be_bytes(count_bytes);
+
+            aggregate.decode_into(&mut input)?;
         } else {
             unreachable!()
         }
@@ -307,7 +517,7 @@ impl Decode for Link {
     }
 }
 
-impl Terminated for Link {}
+impl<H: Hasher, A: Aggregate> Terminated for Link<H, A> {}
 
 #[inline]
 fn read_u8<R: Read>(mut input: R) -> Result<u8> {
@@ -338,7 +548,7 @@ mod test {
 
     #[test]
     fn maybe_from_modified_tree() {
-        let link = Link::maybe_from_modified_tree(None);
+        let link: Option<Link> = Link::maybe_from_modified_tree(None);
         assert!(link.is_none());
 
         let tree = Tree::new(vec![0], vec![1]);
@@ -350,28 +560,37 @@ mod test {
     fn types() {
         let hash = NULL_HASH;
         let child_heights = (0, 0);
+        let descendant_count = 1;
         let pending_writes = 1;
         let key = vec![0];
         let tree = || Tree::new(vec![0], vec![1]);
 
-        let reference = Link::Reference {
+        let reference: Link = Link::Reference {
             hash,
             child_heights,
+            descendant_count,
+            aggregate: NoAggregate,
             key,
         };
         let modified = Link::Modified {
             pending_writes,
             child_heights,
+            descendant_count,
+            aggregate: NoAggregate,
             tree: tree(),
         };
         let uncommitted = Link::Uncommitted {
             hash,
             child_heights,
+            descendant_count,
+            aggregate: NoAggregate,
             tree: tree(),
         };
         let loaded = Link::Loaded {
             hash,
             child_heights,
+            descendant_count,
+            aggregate: NoAggregate,
             tree: tree(),
         };
 
@@ -380,8 +599,14 @@ mod test {
         assert!(!reference.is_uncommitted());
         assert!(!reference.is_stored());
         assert!(reference.tree().is_none());
+        assert_eq!(reference.as_reference(), Some(&[0][..]));
+        assert!(reference.as_modified().is_none());
+        assert!(reference.as_uncommitted().is_none());
+        assert!(reference.as_stored().is_none());
+        assert_eq!(reference.key(), &[0]);
         assert_eq!(reference.hash(), &[0; 20]);
         assert_eq!(reference.height(), 1);
+        assert_eq!(reference.descendant_count(), 1);
         assert!(reference.into_reference().is_reference());
 
         assert!(!modified.is_reference());
@@ -389,6 +614,11 @@ mod test {
         assert!(!modified.is_uncommitted());
         assert!(!modified.is_stored());
         assert!(modified.tree().is_some());
+        assert!(modified.as_reference().is_none());
+        assert_eq!(modified.as_modified().unwrap().key(), &[0]);
+        assert!(modified.as_uncommitted().is_none());
+        assert!(modified.as_stored().is_none());
+        assert_eq!(modified.key(), &[0]);
         assert_eq!(modified.height(), 1);
 
         assert!(!uncommitted.is_reference());
@@ -396,6 +626,11 @@ mod test {
         assert!(uncommitted.is_uncommitted());
         assert!(!uncommitted.is_stored());
         assert!(uncommitted.tree().is_some());
+        assert!(uncommitted.as_reference().is_none());
+        assert!(uncommitted.as_modified().is_none());
+        assert_eq!(uncommitted.as_uncommitted().unwrap().key(), &[0]);
+        assert!(uncommitted.as_stored().is_none());
+        assert_eq!(uncommitted.key(), &[0]);
         assert_eq!(uncommitted.hash(), &[0; 20]);
         assert_eq!(uncommitted.height(), 1);
 
@@ -404,6 +639,11 @@ mod test {
         assert!(!loaded.is_uncommitted());
         assert!(loaded.is_stored());
         assert!(loaded.tree().is_some());
+        assert!(loaded.as_reference().is_none());
+        assert!(loaded.as_modified().is_none());
+        assert!(loaded.as_uncommitted().is_none());
+        assert_eq!(loaded.as_stored().unwrap().key(), &[0]);
+        assert_eq!(loaded.key(), &[0]);
         assert_eq!(loaded.hash(), &[0; 20]);
         assert_eq!(loaded.height(), 1);
         assert!(loaded.into_reference().is_reference());
@@ -415,6 +655,8 @@ mod test {
         Link::Modified {
             pending_writes: 1,
             child_heights: (1, 1),
+            descendant_count: 1,
+            aggregate: NoAggregate,
             tree: Tree::new(vec![0], vec![1]),
         }
         .hash();
@@ -426,6 +668,8 @@ mod test {
         Link::Modified {
             pending_writes: 1,
             child_heights: (1, 1),
+            descendant_count: 1,
+            aggregate: NoAggregate,
             tree: Tree::new(vec![0], vec![1]),
         }
         .into_reference();
@@ -437,19 +681,63 @@ mod test {
         Link::Uncommitted {
             hash: [1; 20],
             child_heights: (1, 1),
+            descendant_count: 1,
+            aggregate: NoAggregate,
             tree: Tree::new(vec![0], vec![1]),
         }
         .into_reference();
     }
 
+    #[test]
+    fn from_stored_tree() {
+        let tree = Tree::new(vec![0], vec![1]);
+        let hash = tree.hash();
+        let link = Link::from_stored_tree(tree, hash, (0, 0));
+        assert!(link.is_stored());
+        assert_eq!(link.hash(), &hash);
+        assert_eq!(link.descendant_count(), 1);
+
+        let link = link.into_modified();
+        assert!(link.is_modified());
+        assert_eq!(link.height(), 1);
+    }
+
+    #[test]
+    fn uncommitted_into_stored() {
+        let tree = Tree::new(vec![0], vec![1]);
+        let link = Link::Uncommitted {
+            hash: tree.hash(),
+            child_heights: (0, 0),
+            descendant_count: 1,
+            aggregate: NoAggregate,
+            tree,
+        };
+        assert!(link.into_stored().is_stored());
+    }
+
+    #[test]
+    #[should_panic]
+    fn modified_into_stored() {
+        Link::from_modified_tree(Tree::new(vec![0], vec![1])).into_stored();
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "Stored link hash does not match tree")]
+    fn from_stored_tree_wrong_hash() {
+        Link::from_stored_tree(Tree::new(vec![0], vec![1]), [1; 20], (0, 0));
+    }
+
     #[test]
     fn encode_link() {
-        let link = Link::Reference {
+        let link: Link = Link::Reference {
             key: vec![1, 2, 3],
             child_heights: (123, 124),
+            descendant_count: 300,
+            aggregate: NoAggregate,
             hash: [55; 20],
         };
-        assert_eq!(link.encoding_length().unwrap(), 26);
+        assert_eq!(link.encoding_length().unwrap(), 34);
 
         let mut bytes = vec![];
         link.encode_into(&mut bytes).unwrap();
@@ -457,17 +745,32 @@ mod test {
             bytes,
             vec![
                 3, 1, 2, 3, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
-                55, 55, 123, 124
+                55, 55, 123, 124, 0, 0, 0, 0, 0, 0, 1, 44
             ]
         );
     }
 
+    #[test]
+    fn decode_link() {
+        let bytes = vec![
+            3, 1, 2, 3, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
+            55, 123, 124, 0, 0, 0, 0, 0, 0, 1, 44,
+        ];
+        let link: Link = Link::decode(bytes.as_slice()).unwrap();
+        assert_eq!(link.key(), &[1, 2, 3]);
+        assert_eq!(link.hash(), &[55; 20]);
+        assert_eq!(link.height(), 125);
+        assert_eq!(link.descendant_count(), 300);
+    }
+
     #[test]
     #[should_panic]
     fn encode_link_long_key() {
-        let link = Link::Reference {
+        let link: Link = Link::Reference {
             key: vec![123; 300],
             child_heights: (123, 124),
+            descendant_count: 1,
+            aggregate: NoAggregate,
             hash: [55; 20],
         };
         let mut bytes = vec![];
@@ -0,0 +1,271 @@
+use super::commit::Commit;
+use super::hash::{Hash, HASH_LENGTH};
+use super::walk::{Fetch, RefWalker};
+use super::{Link, Tree};
+use crate::error::Result;
+use failure::bail;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+/// The record type of a node write, followed by the node's key and encoding.
+const NODE_RECORD: u8 = 0x00;
+
+/// The record type written at the end of each commit, followed by the key and
+/// hash of the committed tree's root node.
+const ROOT_RECORD: u8 = 0x01;
+
+/// A `Commit` implementation which appends every node write to a log file, for
+/// crash recovery. Each commit is terminated by a record of the tree's root, so
+/// `LogCommit::replay` can reconstruct the latest tree whose commit completed,
+/// ignoring any writes from a commit which was interrupted.
+///
+/// Each record is written with a 4-byte big-endian length prefix, followed by
+/// a record type byte, the length-prefixed key of the node, and either the
+/// node's encoding (for node records) or its hash (for root records). Nodes
+/// are never pruned from memory.
+pub struct LogCommit {
+    file: BufWriter<File>,
+    last_write: Option<(Vec<u8>, Hash)>,
+}
+
+impl LogCommit {
+    /// Opens the log file at `path` for appending, creating it if it does not
+    /// exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(LogCommit {
+            file: BufWriter::new(file),
+            last_write: None,
+        })
+    }
+
+    /// Reads the log file at `path` and reconstructs the tree from the last
+    /// complete commit, fully loaded in memory. Records after the last root
+    /// record, including a partially-written trailing record, are ignored.
+    ///
+    /// Returns `Err` if the log does not contain a complete commit, if a node
+    /// record is corrupted (see `Tree::decode_verified`), or if the committed
+    /// nodes do not hash to the recorded root hash.
+    pub fn replay<P: AsRef<Path>>(path: P) -> Result<Tree> {
+        let mut bytes = vec![];
+        File::open(path)?.read_to_end(&mut bytes)?;
+
+        let mut nodes = LogNodes(HashMap::new());
+        let mut pending = vec![];
+        let mut maybe_root = None;
+
+        let mut offset = 0;
+        while let Some((record_type, key, data)) = read_record(&bytes, &mut offset) {
+            match record_type {
+                NODE_RECORD => {
+                    let hash = Tree::decode_verified(key.to_vec(), data)?.hash();
+                    pending.push((hash, data.to_vec()));
+                }
+                ROOT_RECORD if data.len() == HASH_LENGTH => {
+                    nodes.0.extend(pending.drain(..));
+                    let mut hash: Hash = Default::default();
+                    hash.copy_from_slice(data);
+                    maybe_root = Some((key.to_vec(), hash));
+                }
+                _ => bail!("Log contains invalid record"),
+            }
+        }
+
+        let (root_key, root_hash) = match maybe_root {
+            Some(root) => root,
+            None => bail!("Log contains no complete commit"),
+        };
+        let mut tree = match nodes.0.get(&root_hash) {
+            Some(bytes) => Tree::decode_verified(root_key, bytes.as_slice())?,
+            None => bail!("Root node not found in log"),
+        };
+        load_all(RefWalker::new(&mut tree, &nodes))?;
+
+        if tree.hash() != root_hash {
+            bail!("Replayed tree did not match root hash in log");
+        }
+
+        Ok(tree)
+    }
+
+    /// Appends a length-prefixed record to the log.
+    fn append(&mut self, record_type: u8, key: &[u8], data: &[u8]) -> Result<()> {
+        if key.len() > 255 {
+            bail!("Key must be less than 256 bytes");
+        }
+
+        let length = 2 + key.len() + data.len();
+        self.file.write_all(&(length as u32).to_be_bytes())?;
+        self.file.write_all(&[record_type, key.len() as u8])?;
+        self.file.write_all(key)?;
+        self.file.write_all(data)?;
+        Ok(())
+    }
+}
+
+impl Commit for LogCommit {
+    /// Appends a record containing the node's key and encoding.
+    fn write(&mut self, tree: &Tree) -> Result<()> {
+        self.append(NODE_RECORD, tree.key(), tree.encode().as_slice())?;
+        self.last_write = Some((tree.key().to_vec(), tree.hash()));
+        Ok(())
+    }
+
+    /// Appends the root record (the root is always the last node written in a
+    /// commit), then flushes the log and syncs it to disk.
+    fn end_batch(&mut self) -> Result<()> {
+        if let Some((key, hash)) = self.last_write.take() {
+            self.append(ROOT_RECORD, key.as_slice(), &hash)?;
+        }
+
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    fn prune(&self, _tree: &Tree) -> (bool, bool) {
+        (false, false)
+    }
+}
+
+/// The encoded nodes of the complete commits in a log, by hash.
+struct LogNodes(HashMap<Hash, Vec<u8>>);
+
+impl Fetch for &LogNodes {
+    fn fetch(&self, link: &Link) -> Result<Tree> {
+        match self.0.get(link.hash()) {
+            Some(bytes) => Tree::decode_verified(link.key().to_vec(), bytes.as_slice()),
+            None => bail!("Node not found in log"),
+        }
+    }
+}
+
+/// Reads the record at `offset`, advancing `offset` past it. Returns `None` if
+/// there are no complete records left.
+fn read_record<'a>(bytes: &'a [u8], offset: &mut usize) -> Option<(u8, &'a [u8], &'a [u8])> {
+    let rest = &bytes[*offset..];
+    if rest.len() < 4 {
+        return None;
+    }
+
+    let mut length = [0; 4];
+    length.copy_from_slice(&rest[..4]);
+    let length = u32::from_be_bytes(length) as usize;
+    if length < 2 || rest.len() < 4 + length {
+        return None;
+    }
+
+    let record = &rest[4..4 + length];
+    let key_length = record[1] as usize;
+    if record.len() < 2 + key_length {
+        return None;
+    }
+
+    *offset += 4 + length;
+    Some((
+        record[0],
+        &record[2..2 + key_length],
+        &record[2 + key_length..],
+    ))
+}
+
+/// Walks to every node in the tree, loading them all into memory.
+fn load_all(mut walker: RefWalker<&LogNodes>) -> Result<()> {
+    for left in &[true, false] {
+        if let Some(child) = walker.walk(*left)? {
+            load_all(child)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{PanicSource, Walker};
+    use super::*;
+    use crate::test_utils::make_batch_seq;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::thread;
+
+    fn log_path() -> PathBuf {
+        let name = thread::current().name().unwrap().replace("::", "-");
+        let path = std::env::temp_dir().join(format!("merk-{}.log", name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn apply_and_commit(maybe_tree: Option<Tree>, log: &mut LogCommit, start: u64) -> Tree {
+        let batch = make_batch_seq(start..start + 10);
+        let maybe_walker = maybe_tree.map(|tree| Walker::new(tree, PanicSource {}));
+        let mut tree = Walker::<PanicSource>::apply_to(maybe_walker, &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        tree.commit(log).expect("commit failed");
+        tree
+    }
+
+    #[test]
+    fn replay() {
+        let path = log_path();
+        let mut log = LogCommit::open(&path).expect("open failed");
+        let tree = apply_and_commit(None, &mut log, 0);
+        let tree = apply_and_commit(Some(tree), &mut log, 10);
+
+        let replayed = LogCommit::replay(&path).expect("replay failed");
+        assert_eq!(replayed.hash(), tree.hash());
+        assert_eq!(replayed.iter().count(), 20);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replay_truncated() {
+        let path = log_path();
+        let mut log = LogCommit::open(&path).expect("open failed");
+        let tree = apply_and_commit(None, &mut log, 0);
+        let first_hash = tree.hash();
+        let first_length = fs::metadata(&path).unwrap().len();
+        let tree = apply_and_commit(Some(tree), &mut log, 10);
+        let second_hash = tree.hash();
+        let second_length = fs::metadata(&path).unwrap().len();
+
+        // cut off part of the second commit's root record
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(second_length - 5).unwrap();
+        let replayed = LogCommit::replay(&path).expect("replay failed");
+        assert_eq!(replayed.hash(), first_hash);
+        assert_ne!(replayed.hash(), second_hash);
+        assert_eq!(replayed.iter().count(), 10);
+
+        // cut off a node record in the middle of the second commit
+        file.set_len((first_length + second_length) / 2).unwrap();
+        let replayed = LogCommit::replay(&path).expect("replay failed");
+        assert_eq!(replayed.hash(), first_hash);
+
+        // cut off the first commit's root record
+        file.set_len(first_length - 1).unwrap();
+        assert!(LogCommit::replay(&path).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replay_corrupted() {
+        let path = log_path();
+        let mut log = LogCommit::open(&path).expect("open failed");
+        apply_and_commit(None, &mut log, 0);
+
+        // overwrite the version byte of the first node record's encoding
+        let mut bytes = fs::read(&path).unwrap();
+        let key_length = bytes[5] as usize;
+        bytes[6 + key_length] = 0xff;
+        fs::write(&path, bytes).unwrap();
+        assert!(LogCommit::replay(&path).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+use super::commit::Commit;
+use super::hash::Hash;
+use super::walk::Fetch;
+use super::{Link, Tree};
+use crate::error::{ErrorKind, Result};
+use std::collections::BTreeMap;
+
+/// A backing store for trees made of a plain map of node hashes to encoded
+/// nodes, mainly useful for tests and for reproducing bugs from a dump of a
+/// store's nodes (e.g. one attached to a bug report).
+///
+/// The map can be built by hand, or populated by committing a tree into it
+/// (see `MapFetch::from_tree`). Fetching is implemented for shared
+/// references, like `MemStore`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MapFetch(pub BTreeMap<Hash, Vec<u8>>);
+
+impl MapFetch {
+    /// Commits `tree`, writing its modified nodes into a new `MapFetch` and
+    /// pruning all of the root's children from memory, so the rest of the tree
+    /// can only be walked by fetching from the map.
+    pub fn from_tree(tree: &mut Tree) -> Result<Self> {
+        let mut map = MapFetch::default();
+        tree.commit(&mut map)?;
+        Ok(map)
+    }
+}
+
+impl Commit for MapFetch {
+    /// Encodes the node and inserts it into the map by its hash.
+    fn write(&mut self, tree: &Tree) -> Result<()> {
+        self.0.insert(tree.hash(), tree.encode());
+        Ok(())
+    }
+
+    fn prune(&self, _tree: &Tree) -> (bool, bool) {
+        (true, true)
+    }
+}
+
+impl Fetch for &MapFetch {
+    /// Looks up the node by the link's hash and decodes it, checking its
+    /// checksum (see `Tree::decode_verified`). Returns an error of kind
+    /// `ErrorKind::NodeNotFound` if the map has no node with the link's hash.
+    fn fetch(&self, link: &Link) -> Result<Tree> {
+        match self.0.get(link.hash()) {
+            Some(bytes) => Tree::decode_verified(link.key().to_vec(), bytes.as_slice()),
+            None => Err(ErrorKind::NodeNotFound(*link.hash()).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{PanicSource, RefWalker, Walker};
+    use super::*;
+    use crate::test_utils::{collect_keys, make_batch_seq};
+
+    #[test]
+    fn rewalk_from_map() {
+        let mut tree = Walker::<PanicSource>::apply_to(None, &make_batch_seq(0..100))
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        let expected_keys: Vec<_> = tree.iter().map(|(key, _)| key.to_vec()).collect();
+
+        let map = MapFetch::from_tree(&mut tree).expect("commit failed");
+        assert_eq!(map.0.len(), 100);
+        assert!(tree.link(true).expect("expected link").is_reference());
+        assert!(tree.link(false).expect("expected link").is_reference());
+
+        let hash = tree.hash();
+        let mut keys = vec![];
+        collect_keys(RefWalker::new(&mut tree, &map), &mut keys);
+        assert_eq!(keys, expected_keys);
+        assert_eq!(tree.hash(), hash);
+        assert_eq!(tree.iter().count(), 100);
+    }
+
+    #[test]
+    fn fetch_missing() {
+        let mut tree = Walker::<PanicSource>::apply_to(None, &make_batch_seq(0..3))
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        let mut map = MapFetch::from_tree(&mut tree).expect("commit failed");
+        let left_hash = *tree.link(true).expect("expected link").hash();
+        map.0.remove(&left_hash);
+
+        let mut walker = RefWalker::new(&mut tree, &map);
+        let err = walker.walk(true).err().expect("expected error");
+        assert_eq!(
+            err.downcast_ref::<ErrorKind>(),
+            Some(&ErrorKind::NodeNotFound(left_hash))
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use super::commit::Commit;
+use super::hash::Hash;
+use super::walk::Fetch;
+use super::{Link, Tree};
+use crate::error::Result;
+use failure::bail;
+use std::collections::HashMap;
+
+/// An in-memory backing store for trees, mainly useful for testing or embedded
+/// use. Nodes are written to the store when committing (keyed by their hash),
+/// and can be fetched back when walking to pruned nodes.
+///
+/// Committing requires a mutable reference to the store, while fetching is
+/// implemented for shared references (e.g. `RefWalker::new(&mut tree, &store)`).
+pub struct MemStore {
+    nodes: HashMap<Hash, Vec<u8>>,
+    prune: bool,
+}
+
+impl MemStore {
+    /// Creates an empty `MemStore` which prunes all children from memory when
+    /// they are committed.
+    pub fn new() -> Self {
+        MemStore {
+            nodes: HashMap::new(),
+            prune: true,
+        }
+    }
+
+    /// Creates an empty `MemStore` which never prunes nodes from memory when
+    /// they are committed.
+    pub fn without_pruning() -> Self {
+        MemStore {
+            nodes: HashMap::new(),
+            prune: false,
+        }
+    }
+
+    /// Returns the number of nodes in the store.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Returns `true` if no nodes have been written to the store.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+impl Default for MemStore {
+    fn default() -> Self {
+        MemStore::new()
+    }
+}
+
+impl Commit for MemStore {
+    /// Encodes the node and stores it by its hash.
+    fn write(&mut self, tree: &Tree) -> Result<()> {
+        self.nodes.insert(tree.hash(), tree.encode());
+        Ok(())
+    }
+
+    fn prune(&self, _tree: &Tree) -> (bool, bool) {
+        (self.prune, self.prune)
+    }
+}
+
+impl Fetch for &MemStore {
+    /// Looks up the node by the link's hash and decodes it.
+    fn fetch(&self, link: &Link) -> Result<Tree> {
+        match self.nodes.get(link.hash()) {
+            Some(bytes) => Ok(Tree::decode(link.key().to_vec(), bytes.as_slice())),
+            None => bail!("Node not found in store"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{PanicSource, RefWalker, Tree, Walker};
+    use super::*;
+    use crate::test_utils::{collect_keys, make_batch_seq};
+
+    fn build_tree(node_count: u64) -> Tree {
+        let batch = make_batch_seq(0..node_count);
+        Walker::<PanicSource>::apply_to(None, &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree")
+    }
+
+    #[test]
+    fn commit_and_fetch() {
+        let mut tree = build_tree(100);
+        let expected_keys: Vec<_> = tree.iter().map(|(key, _)| key.to_vec()).collect();
+
+        let mut store = MemStore::new();
+        tree.commit(&mut store).expect("commit failed");
+        assert_eq!(store.len(), 100);
+        assert!(tree.link(true).expect("expected link").is_reference());
+        assert!(tree.link(false).expect("expected link").is_reference());
+
+        let hash = tree.hash();
+        let mut keys = vec![];
+        collect_keys(RefWalker::new(&mut tree, &store), &mut keys);
+        assert_eq!(keys, expected_keys);
+        assert_eq!(tree.hash(), hash);
+        assert_eq!(tree.iter().count(), 100);
+    }
+
+    #[test]
+    fn commit_without_pruning() {
+        let mut tree = build_tree(100);
+
+        let mut store = MemStore::without_pruning();
+        tree.commit(&mut store).expect("commit failed");
+        assert_eq!(store.len(), 100);
+        assert!(tree.link(true).expect("expected link").is_stored());
+        assert!(tree.link(false).expect("expected link").is_stored());
+        assert_eq!(tree.iter().count(), 100);
+    }
+
+    #[test]
+    fn fetch_missing() {
+        let mut tree = build_tree(3);
+        tree.commit(&mut MemStore::new()).expect("commit failed");
+
+        let store = MemStore::new();
+        let mut walker = RefWalker::new(&mut tree, &store);
+        assert!(walker.walk(true).is_err());
+    }
+}
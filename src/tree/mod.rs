@@ -1,59 +1,130 @@
+mod aggregate;
 mod commit;
+mod compact;
 mod debug;
+mod diff;
+mod dump;
 mod encoding;
 mod fuzz_tests;
 mod hash;
 mod iter;
 mod kv;
+mod lazy;
 mod link;
+mod log_commit;
+mod map_fetch;
+mod mem_store;
 mod ops;
+#[cfg(feature = "serde")]
+mod serialize;
+mod snapshot;
+mod stats;
+mod visit;
 mod walk;
 
-use std::cmp::max;
-
-use ed::{Decode, Encode};
-
-use super::error::Result;
-pub use commit::{Commit, NoopCommit};
-pub use hash::{kv_hash, node_hash, Hash, HASH_LENGTH, NULL_HASH};
+use std::cmp::{max, Ordering};
+use std::collections::BTreeMap;
+use std::mem::size_of;
+
+use failure::{bail, format_err};
+
+use super::error::{Error, Result};
+pub use aggregate::{Aggregate, NoAggregate};
+pub use commit::{Commit, CommitStats, DepthPrune, NoopCommit, StatsCommit};
+pub use diff::DiffEntry;
+pub(crate) use encoding::{read_varint, varint_length, write_varint};
+#[cfg(feature = "blake3")]
+pub use hash::Blake3Hasher;
+pub use hash::{
+    from_hex, kv_digest_hash, kv_hash, node_hash, to_hex, value_hash, Blake2bCountedHasher,
+    Blake2bHasher, Blake2bV1Hasher, CtEq, Hash, Hasher, HexHash, LegacyBlake2bHasher, HASH_LENGTH,
+    KV_DOMAIN, NODE_DOMAIN, NULL_HASH, VALUE_DOMAIN,
+};
 use kv::KV;
+pub use lazy::LazyTree;
 pub use link::Link;
-pub use ops::{Batch, BatchEntry, Op, PanicSource};
-pub use walk::{Fetch, RefWalker, Walker};
+pub use log_commit::LogCommit;
+pub use map_fetch::MapFetch;
+pub use mem_store::MemStore;
+pub(crate) use ops::{check_batch, merge_values};
+pub use ops::{check_keys, Batch, BatchEntry, EntryLimits, ErrSource, MergeFn, Op, PanicSource};
+pub use snapshot::Snapshot;
+pub use stats::TreeStats;
+pub use visit::{NodeVisitor, VisitOrder};
+#[cfg(feature = "async")]
+pub use walk::AsyncFetch;
+pub use walk::{Bfs, Fetch, RefWalker, Walker};
+
+/// The greatest height an AVL tree can have: the smallest AVL tree of height 92
+/// has more than `u64::MAX` nodes, more than can be counted by a link's
+/// descendant count. Nodes claiming greater heights are rejected by
+/// `Tree::verify_avl`.
+const MAX_HEIGHT: u8 = 91;
 
 // TODO: remove need for `TreeInner`, and just use `Box<Self>` receiver for
 // relevant methods
 
 /// The fields of the `Tree` type, stored on the heap.
-#[derive(Encode, Decode)]
-struct TreeInner {
-    left: Option<Link>,
-    right: Option<Link>,
-    kv: KV,
+struct TreeInner<H: Hasher = Blake2bHasher, A: Aggregate = NoAggregate> {
+    left: Option<Link<H, A>>,
+    right: Option<Link<H, A>>,
+    kv: KV<H>,
+    /// The height of the node, cached so `Tree::height` doesn't have to
+    /// recompute it from the links. Kept up to date by `attach` and `detach`.
+    height: u8,
+}
+
+impl<H: Hasher, A: Aggregate> TreeInner<H, A> {
+    /// Creates the fields of a node, computing its height from its links.
+    fn new(kv: KV<H>, left: Option<Link<H, A>>, right: Option<Link<H, A>>) -> Self {
+        let mut inner = TreeInner {
+            left,
+            right,
+            kv,
+            height: 0,
+        };
+        inner.update_height();
+        inner
+    }
+
+    /// Recomputes the cached height from the heights stored in the links.
+    #[inline]
+    fn update_height(&mut self) {
+        let link_height = |link: &Option<Link<H, A>>| link.as_ref().map_or(0, Link::height);
+        // saturates rather than overflowing for links claiming impossible
+        // heights (see `MAX_HEIGHT`)
+        self.height = max(link_height(&self.left), link_height(&self.right)).saturating_add(1);
+    }
 }
 
 /// A binary AVL tree data structure, with Merkle hashes.
 ///
+/// Trees are balanced with AVL rotations rather than by subtree weight, since
+/// rotations only touch the nodes on the path to each change, keeping the
+/// number of nodes rehashed and rewritten per batch logarithmic. As a result
+/// the shape of a tree, and so its root hash, depends on the order in which
+/// its entries were inserted, not only on the entries themselves. Use
+/// `Tree::canonical_root_hash` for a hash which depends only on the entries.
+///
 /// Trees' inner fields are stored on the heap so that nodes can recursively
 /// link to each other, and so we can detach nodes from their parents, then
 /// reattach without allocating or freeing heap memory.
-#[derive(Encode, Decode)]
-pub struct Tree {
-    inner: Box<TreeInner>,
+///
+/// Nodes are hashed with the `Hasher` `H`, which defaults to `Blake2bHasher`,
+/// and their links store the `Aggregate` `A` of their children's subtrees,
+/// which defaults to `NoAggregate`.
+pub struct Tree<H: Hasher = Blake2bHasher, A: Aggregate = NoAggregate> {
+    inner: Box<TreeInner<H, A>>,
 }
 
 impl Tree {
     /// Creates a new `Tree` with the given key and value, and no children.
     ///
-    /// Hashes the key/value pair and initializes the `kv_hash` field.
+    /// Hashes the key/value pair and initializes the `kv_hash` field. Values
+    /// may be empty, but keys must not be empty in trees built by applying
+    /// batches (see `Walker::apply_to`).
     pub fn new(key: Vec<u8>, value: Vec<u8>) -> Self {
-        Tree {
-            inner: Box::new(TreeInner {
-                kv: KV::new(key, value),
-                left: None,
-                right: None,
-            }),
-        }
+        Tree::with_hasher(key, value)
     }
 
     /// Creates a `Tree` by supplying all the raw struct fields (mainly useful
@@ -64,16 +135,183 @@ impl Tree {
         kv_hash: Hash,
         left: Option<Link>,
         right: Option<Link>,
-    ) -> Tree {
+    ) -> Self {
         Tree {
-            inner: Box::new(TreeInner {
-                kv: KV::from_fields(key, value, kv_hash),
+            inner: Box::new(TreeInner::new(
+                KV::from_fields(key, value, kv_hash),
                 left,
                 right,
+            )),
+        }
+    }
+
+    /// Builds a balanced tree from key/value pairs which are sorted by key in
+    /// strictly increasing order, in linear time. Returns `None` if there are
+    /// no entries.
+    ///
+    /// The middle entry of each range becomes the root of its subtree, so the
+    /// resulting tree has the minimum possible height. All nodes are left
+    /// modified, so the tree must be committed to compute their hashes.
+    pub fn from_sorted<I>(entries: I) -> Option<Tree>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        let mut entries: Vec<_> = entries.into_iter().map(Some).collect();
+        debug_assert!(
+            entries.windows(2).all(|pair| match pair {
+                [Some((a, _)), Some((b, _))] => a < b,
+                _ => unreachable!(),
             }),
+            "Entries must be sorted and unique"
+        );
+        build_sorted(entries.as_mut_slice())
+    }
+
+    /// Computes the root hash of the canonical tree containing the given
+    /// key/value pairs, which may be in any order. If a key is given more than
+    /// once, its last value is used. Returns `NULL_HASH` if there are no
+    /// entries.
+    ///
+    /// The canonical tree is the one built by `from_sorted` from the sorted
+    /// entries, so the hash is a function of the set of entries alone, unlike
+    /// the root hash of a tree built by applying batches, which depends on the
+    /// order in which its entries were inserted.
+    pub fn canonical_root_hash<I>(entries: I) -> Hash
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        let entries: BTreeMap<_, _> = entries.into_iter().collect();
+        match Tree::from_sorted(entries) {
+            Some(mut tree) => {
+                tree.compute_hashes();
+                tree.hash()
+            }
+            None => NULL_HASH,
+        }
+    }
+
+    /// Returns the size in bytes of the heap allocation made for each node,
+    /// not including the buffers for its key and value (which are allocated
+    /// separately, with sizes depending on their contents).
+    #[inline]
+    pub fn node_size() -> usize {
+        size_of::<TreeInner>()
+    }
+
+    /// Returns an estimate of the number of bytes of memory used by the tree,
+    /// including the `Tree` itself, the heap allocation for each node, and the
+    /// key and value buffers of every node retained in memory. Pruned subtrees
+    /// are not counted, other than the key buffer held by their
+    /// `Link::Reference`.
+    ///
+    /// Key and value buffers are counted by their capacity rather than their
+    /// length, since that is the size of their allocations.
+    pub fn memory_footprint(&self) -> usize {
+        struct Footprint(usize);
+
+        impl NodeVisitor for Footprint {
+            fn enter(&mut self, tree: &Tree) {
+                self.0 += Tree::node_size();
+                self.0 += tree.inner.kv.key.capacity() + tree.inner.kv.value.capacity();
+
+                for &left in [true, false].iter() {
+                    if let Some(Link::Reference { key, .. }) = tree.link(left) {
+                        self.0 += key.capacity();
+                    }
+                }
+            }
+        }
+
+        let mut footprint = Footprint(size_of::<Tree>());
+        self.visit(&mut footprint);
+        footprint.0
+    }
+
+    /// Like `load`, but fetches the child from an asynchronous data source.
+    /// Requires the `async` feature.
+    #[cfg(feature = "async")]
+    pub async fn load_async<S: AsyncFetch>(&mut self, left: bool, source: &S) -> Result<()> {
+        let link = self.pruned_link(left)?;
+        let (child_heights, hash) = (link.child_heights(), *link.hash());
+
+        let tree = source.fetch(link).await?;
+        debug_assert_eq!(tree.key(), link.key());
+        *self.slot_mut(left) = Some(Link::from_stored_tree(tree, hash, child_heights));
+
+        Ok(())
+    }
+}
+
+impl<H: Hasher, A: Aggregate> Tree<H, A> {
+    /// Like `new`, but creates a tree hashed with the `Hasher` `H` rather than
+    /// `Blake2bHasher`, e.g. `Tree::<Blake2bV1Hasher>::with_hasher(key, value)`,
+    /// or with an `Aggregate` `A` other than `NoAggregate`.
+    pub fn with_hasher(key: Vec<u8>, value: Vec<u8>) -> Self {
+        Tree {
+            inner: Box::new(TreeInner::new(KV::new(key, value), None, None)),
+        }
+    }
+
+    /// Clones only the root node, with each of its children as a
+    /// `Link::Reference` to the original's child (with the same key, hash, child
+    /// heights, and descendant count). The clone can be modified, and its
+    /// children loaded from the same store, without affecting the original's
+    /// in-memory nodes.
+    ///
+    /// The children must be written to the store before they can be loaded
+    /// into the clone, so they should be committed first. Panics if either
+    /// child's link is of variant `Link::Modified`, since its hash is not
+    /// known.
+    pub fn clone_shallow(&self) -> Self {
+        let clone_link = |maybe_link: &Option<Link<H, A>>| {
+            maybe_link.as_ref().map(|link| {
+                if link.is_modified() {
+                    panic!("Cannot shallow clone tree with Modified child");
+                }
+                Link::Reference {
+                    hash: *link.hash(),
+                    child_heights: link.child_heights(),
+                    descendant_count: link.descendant_count(),
+                    aggregate: link.aggregate().clone(),
+                    key: link.key().to_vec(),
+                }
+            })
+        };
+
+        let mut kv = KV::from_fields(
+            self.key().to_vec(),
+            self.value().to_vec(),
+            self.inner.kv.hash,
+        );
+        kv.dirty = self.inner.kv.dirty;
+        kv.update_hash();
+
+        Tree {
+            inner: Box::new(TreeInner::new(
+                kv,
+                clone_link(&self.inner.left),
+                clone_link(&self.inner.right),
+            )),
         }
     }
 
+    /// Keeps only the entries for which `f` returns `true`, and rebuilds a
+    /// balanced tree from them in one pass (as in `from_sorted`) rather than
+    /// deleting the other entries one at a time. Returns `None` if no entries
+    /// are kept.
+    ///
+    /// All nodes of the rebuilt tree are left modified, so the tree must be
+    /// committed to compute their hashes. Panics if the tree contains pruned
+    /// nodes, since there is no source to fetch them from.
+    pub fn retain<F>(self, f: F) -> Option<Self>
+    where
+        F: Fn(&[u8], &[u8]) -> bool,
+    {
+        let mut entries = Vec::with_capacity(self.count() as usize);
+        retain_entries(self, &f, &mut entries);
+        build_sorted(entries.as_mut_slice())
+    }
+
     /// Returns the root node's key as a slice.
     #[inline]
     pub fn key(&self) -> &[u8] {
@@ -93,16 +331,32 @@ impl Tree {
         self.inner.kv.value()
     }
 
-    /// Returns the hash of the root node's key/value pair.
+    /// Returns a mutable reference to the root node's value, for modifying it
+    /// in place.
+    ///
+    /// The hash of the key/value pair is marked as stale, so `hash` computes it
+    /// from the modified value, and `compute_hashes` or `commit` update the
+    /// stored hash. Only the root node's own hash is affected: when modifying a
+    /// descendant reached through `child_mut`, the hashes held by its
+    /// ancestors' links are not updated, and neither are their aggregates (use
+    /// `walk` to relink them as `Link::Modified` instead).
+    #[inline]
+    pub fn value_mut(&mut self) -> &mut Vec<u8> {
+        self.inner.kv.value_mut()
+    }
+
+    /// Returns the hash of the root node's key/value pair. Panics in debug
+    /// builds if the value has been modified through `value_mut` since the
+    /// tree was last hashed (see `compute_hashes`).
     #[inline]
-    pub fn kv_hash(&self) -> &Hash {
+    pub fn kv_hash(&self) -> &H::Digest {
         self.inner.kv.hash()
     }
 
     /// Returns a reference to the root node's `Link` on the given side, if any.
     /// If there is no child, returns `None`.
     #[inline]
-    pub fn link(&self, left: bool) -> Option<&Link> {
+    pub fn link(&self, left: bool) -> Option<&Link<H, A>> {
         if left {
             self.inner.left.as_ref()
         } else {
@@ -113,7 +367,7 @@ impl Tree {
     /// Returns a mutable reference to the root node's `Link` on the given side,
     /// if any. If there is no child, returns `None`.
     #[inline]
-    pub fn link_mut(&mut self, left: bool) -> Option<&mut Link> {
+    pub fn link_mut(&mut self, left: bool) -> Option<&mut Link<H, A>> {
         if left {
             self.inner.left.as_mut()
         } else {
@@ -145,19 +399,29 @@ impl Tree {
     }
 
     /// Returns the hash of the root node's child on the given side, if any. If
-    /// there is no child, returns the null hash (zero-filled).
+    /// there is no child, returns the null hash (the default digest, which is
+    /// zero-filled).
     #[inline]
-    pub fn child_hash(&self, left: bool) -> &Hash {
-        self.link(left).map_or(&NULL_HASH, |link| link.hash())
+    pub fn child_hash(&self, left: bool) -> H::Digest {
+        self.link(left)
+            .map_or_else(Default::default, |link| *link.hash())
     }
 
     /// Computes and returns the hash of the root node.
     #[inline]
-    pub fn hash(&self) -> Hash {
-        node_hash(
-            self.inner.kv.hash(),
-            self.child_hash(true),
-            self.child_hash(false),
+    pub fn hash(&self) -> H::Digest {
+        // the key/value hash is stale if the value was modified by `value_mut`
+        let kv = &self.inner.kv;
+        let hash = if kv.is_dirty() {
+            H::hash_kv(kv.key(), kv.value())
+        } else {
+            *kv.hash()
+        };
+        H::hash_counted_node(
+            &hash,
+            &self.child_hash(true),
+            &self.child_hash(false),
+            (self.child_count(true), self.child_count(false)),
         )
     }
 
@@ -183,22 +447,350 @@ impl Tree {
         (self.child_height(true), self.child_height(false))
     }
 
+    /// Returns the number of nodes in the child's subtree on the given side, if
+    /// any. If there is no child, returns 0.
+    #[inline]
+    pub fn child_count(&self, left: bool) -> u64 {
+        self.link(left).map_or(0, |child| child.descendant_count())
+    }
+
+    /// Returns the number of nodes in the tree, including the root node and
+    /// any pruned descendants.
+    #[inline]
+    pub fn count(&self) -> u64 {
+        1 + self.child_count(true) + self.child_count(false)
+    }
+
+    /// Returns the key at in-order position `n` (the `n`th smallest key,
+    /// starting from 0), or `None` if `n` is not less than the tree's node
+    /// count.
+    ///
+    /// Returns an error if finding the key requires descending into a child
+    /// which is pruned.
+    pub fn select(&self, mut n: u64) -> Result<Option<&[u8]>> {
+        if n >= self.count() {
+            return Ok(None);
+        }
+
+        let mut cursor = self;
+        loop {
+            let left_count = cursor.child_count(true);
+            let left = if n < left_count {
+                true
+            } else if n == left_count {
+                return Ok(Some(cursor.key()));
+            } else {
+                n -= left_count + 1;
+                false
+            };
+
+            cursor = match cursor.child(left) {
+                Some(child) => child,
+                None => bail!("Cannot select from pruned {} child", side_to_str(left)),
+            };
+        }
+    }
+
+    /// Returns the smallest key in the tree, found by descending through left
+    /// children in `O(height)` time.
+    ///
+    /// Returns `None` if the leftmost node is pruned (since a tree always has
+    /// at least one node, the key is otherwise always found).
+    pub fn first_key(&self) -> Option<&[u8]> {
+        self.edge_key(true)
+    }
+
+    /// Returns the largest key in the tree, found by descending through right
+    /// children in `O(height)` time.
+    ///
+    /// Returns `None` if the rightmost node is pruned (since a tree always has
+    /// at least one node, the key is otherwise always found).
+    pub fn last_key(&self) -> Option<&[u8]> {
+        self.edge_key(false)
+    }
+
+    /// Implementation of `first_key` and `last_key`, descending through the
+    /// children on the given side.
+    fn edge_key(&self, left: bool) -> Option<&[u8]> {
+        let mut cursor = self;
+        loop {
+            cursor = match cursor.link(left) {
+                None => return Some(cursor.key()),
+                Some(Link::Reference { .. }) => return None,
+                Some(link) => link.tree().unwrap(),
+            };
+        }
+    }
+
+    /// Returns the value for the given key, or `None` if the key is not in the
+    /// tree.
+    ///
+    /// Returns an error if finding the key requires descending into a child
+    /// which is pruned.
+    pub fn get(&self, key: &[u8]) -> Result<Option<&[u8]>> {
+        let mut cursor = self;
+        loop {
+            let left = match key.cmp(cursor.key()) {
+                Ordering::Equal => return Ok(Some(cursor.value())),
+                Ordering::Less => true,
+                Ordering::Greater => false,
+            };
+
+            cursor = match cursor.link(left) {
+                None => return Ok(None),
+                Some(Link::Reference { .. }) => {
+                    bail!("Cannot get key from pruned {} child", side_to_str(left))
+                }
+                Some(link) => link.tree().unwrap(),
+            };
+        }
+    }
+
+    /// Returns `true` if the given key is in the tree.
+    ///
+    /// Returns an error if finding the key requires descending into a child
+    /// which is pruned.
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Returns the hash of the subtree rooted at the node with the given key,
+    /// or `None` if the key is not in the tree. The subtree is not cloned, so
+    /// this is useful for getting the roots of subtrees to hand off elsewhere,
+    /// e.g. when sharding (see `replace_subtree`).
+    ///
+    /// Returns an error if finding the key requires descending into a child
+    /// which is pruned, or if the node has a modified child (whose hash is not
+    /// known until `compute_hashes` or `commit` is called).
+    pub fn subtree_root_hash(&self, key: &[u8]) -> Result<Option<H::Digest>> {
+        let mut cursor = self;
+        loop {
+            let left = match key.cmp(cursor.key()) {
+                Ordering::Equal => break,
+                Ordering::Less => true,
+                Ordering::Greater => false,
+            };
+
+            cursor = match cursor.link(left) {
+                None => return Ok(None),
+                Some(Link::Reference { .. }) => {
+                    bail!("Cannot get key from pruned {} child", side_to_str(left))
+                }
+                Some(link) => link.tree().unwrap(),
+            };
+        }
+
+        for &left in [true, false].iter() {
+            if let Some(Link::Modified { .. }) = cursor.link(left) {
+                bail!(
+                    "Cannot hash subtree with modified {} child",
+                    side_to_str(left)
+                );
+            }
+        }
+        Ok(Some(cursor.hash()))
+    }
+
+    /// Calls `f` with each node on the search path from the root to the node
+    /// with the given key, in order. The descent stops at the node with the
+    /// key, or where the key would be if it is not in the tree, or before a
+    /// pruned child (since there is no source to fetch it from), so `f` can
+    /// check the last node's links to tell these apart. Useful for counting
+    /// the nodes a lookup touches, or for finding which children to load.
+    pub fn walk_path<F>(&self, key: &[u8], mut f: F)
+    where
+        F: FnMut(&Self),
+    {
+        let mut cursor = self;
+        loop {
+            f(cursor);
+
+            let left = match key.cmp(cursor.key()) {
+                Ordering::Equal => return,
+                Ordering::Less => true,
+                Ordering::Greater => false,
+            };
+
+            cursor = match cursor.link(left).and_then(Link::tree) {
+                None => return,
+                Some(child) => child,
+            };
+        }
+    }
+
+    /// Returns the value for each of the given keys, or `None` for keys which
+    /// are not in the tree, in the same order as `sorted_keys`.
+    ///
+    /// The keys must be sorted and unique, which lets them be looked up in a
+    /// single descent: the keys are partitioned around each node's key, so
+    /// each node on the paths to the keys is visited once rather than once per
+    /// key. Returns an error if the keys are not sorted and unique, or if
+    /// finding any of the keys requires descending into a child which is
+    /// pruned.
+    pub fn get_many(&self, sorted_keys: &[&[u8]]) -> Result<Vec<Option<&[u8]>>> {
+        for pair in sorted_keys.windows(2) {
+            if pair[0] >= pair[1] {
+                bail!("Keys must be sorted and unique");
+            }
+        }
+
+        let mut values = Vec::with_capacity(sorted_keys.len());
+        self.get_many_inner(sorted_keys, &mut values)?;
+        Ok(values)
+    }
+
+    /// Recursive implementation of `get_many`, appending the value (or `None`)
+    /// for each of `keys` to `values`.
+    fn get_many_inner<'a>(
+        &'a self,
+        keys: &[&[u8]],
+        values: &mut Vec<Option<&'a [u8]>>,
+    ) -> Result<()> {
+        let (left_keys, found, right_keys) = match keys.binary_search(&self.key()) {
+            Ok(index) => (&keys[..index], true, &keys[index + 1..]),
+            Err(index) => (&keys[..index], false, &keys[index..]),
+        };
+
+        self.get_many_child(true, left_keys, values)?;
+        if found {
+            values.push(Some(self.value()));
+        }
+        self.get_many_child(false, right_keys, values)
+    }
+
+    /// Looks up `keys` in the child on the given side for `get_many_inner`.
+    fn get_many_child<'a>(
+        &'a self,
+        left: bool,
+        keys: &[&[u8]],
+        values: &mut Vec<Option<&'a [u8]>>,
+    ) -> Result<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        match self.link(left) {
+            None => values.extend(keys.iter().map(|_| None)),
+            Some(Link::Reference { .. }) => {
+                bail!("Cannot get key from pruned {} child", side_to_str(left))
+            }
+            Some(link) => link.tree().unwrap().get_many_inner(keys, values)?,
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the in-memory nodes of the tree satisfy the AVL invariant:
+    /// every node's balance factor must be between -1 and 1, the child heights
+    /// stored in each link must match the heights computed from the linked
+    /// child, and no node may be taller than an AVL tree with at most
+    /// `u64::MAX` nodes can be (which catches links claiming impossible heights,
+    /// e.g. from `Tree::from_fields`). Pruned subtrees are not checked, other
+    /// than the heights claimed by their links.
+    ///
+    /// Returns an error describing the first offending node found.
+    pub fn verify_avl(&self) -> Result<()> {
+        let mut stack = vec![self];
+        while let Some(tree) = stack.pop() {
+            if tree.height() > MAX_HEIGHT {
+                bail!(
+                    "Node {:?} has impossible height {}",
+                    tree.key(),
+                    tree.height()
+                );
+            }
+
+            for &left in [true, false].iter() {
+                let (link, child) = match tree.link(left) {
+                    Some(link) => match link.tree() {
+                        Some(child) => (link, child),
+                        None => continue,
+                    },
+                    None => continue,
+                };
+
+                if link.child_heights() != child.child_heights() {
+                    bail!(
+                        "Node {:?} has incorrect {} child heights: expected {:?}, got {:?}",
+                        tree.key(),
+                        side_to_str(left),
+                        child.child_heights(),
+                        link.child_heights()
+                    );
+                }
+                stack.push(child);
+            }
+
+            if tree.balance_factor().abs() > 1 {
+                bail!(
+                    "Node {:?} is unbalanced: balance factor is {}",
+                    tree.key(),
+                    tree.balance_factor()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` if the two trees are logically equal: their root nodes
+    /// have the same key, value, and key/value hash, and their children have
+    /// the same hashes, regardless of whether each child is pruned or retained
+    /// in memory. Children which are in memory on both sides are compared
+    /// recursively.
+    ///
+    /// Unlike comparing encodings, this compares the keys and values of the
+    /// root nodes and of all descendants held in memory by both trees.
+    /// Modified children have no hash yet, so they are only equal to children
+    /// which are also in memory and are themselves structurally equal.
+    pub fn structurally_eq(&self, other: &Self) -> bool {
+        if self.key() != other.key()
+            || self.value() != other.value()
+            || self.kv_hash() != other.kv_hash()
+        {
+            return false;
+        }
+
+        [true, false].iter().all(|&left| {
+            let (link, other_link) = match (self.link(left), other.link(left)) {
+                (None, None) => return true,
+                (Some(link), Some(other_link)) => (link, other_link),
+                _ => return false,
+            };
+
+            if !link.is_modified() && !other_link.is_modified() && link.hash() != other_link.hash()
+            {
+                return false;
+            }
+
+            match (link.tree(), other_link.tree()) {
+                (Some(child), Some(other_child)) => child.structurally_eq(other_child),
+                _ => !link.is_modified() && !other_link.is_modified(),
+            }
+        })
+    }
+
     /// Returns the height of the tree (the number of levels). For example, a
     /// single node has height 1, a node with a single descendant has height 2,
     /// etc.
+    ///
+    /// The height is cached in the node, so this takes constant time.
     #[inline]
     pub fn height(&self) -> u8 {
-        1 + max(self.child_height(true), self.child_height(false))
+        self.inner.height
     }
 
     /// Returns the balance factor of the root node. This is the difference
     /// between the height of the right child (if any) and the height of the
     /// left child (if any). For example, a balance factor of 2 means the right
     /// subtree is 2 levels taller than the left subtree.
+    ///
+    /// The heights are widened before subtracting, so this can't overflow even
+    /// for links claiming impossible heights.
     #[inline]
-    pub fn balance_factor(&self) -> i8 {
-        let left_height = self.child_height(true) as i8;
-        let right_height = self.child_height(false) as i8;
+    pub fn balance_factor(&self) -> i16 {
+        let left_height = i16::from(self.child_height(true));
+        let right_height = i16::from(self.child_height(false));
         right_height - left_height
     }
 
@@ -223,10 +815,39 @@ impl Tree {
             );
         }
         *slot = Link::maybe_from_modified_tree(maybe_child);
+        self.inner.update_height();
 
         self
     }
 
+    /// Like `attach`, but returns an error rather than panicking if there is
+    /// already a child on the given side, or if the child has the same key as
+    /// the root node (e.g. when building trees from untrusted data).
+    ///
+    /// On error, the tree is returned unchanged along with the error so the
+    /// caller can recover it. The child is dropped.
+    pub fn try_attach(
+        self,
+        left: bool,
+        maybe_child: Option<Self>,
+    ) -> std::result::Result<Self, (Self, Error)> {
+        if self.link(left).is_some() {
+            let err = format_err!(
+                "Tried to attach to {} tree slot, but it is already Some",
+                side_to_str(left)
+            );
+            return Err((self, err));
+        }
+
+        if let Some(child) = maybe_child.as_ref() {
+            if child.key() == self.key() {
+                return Err((self, format_err!("Tried to attach tree with same key")));
+            }
+        }
+
+        Ok(self.attach(left, maybe_child))
+    }
+
     /// Detaches the child on the given side (if any) from the root node, and
     /// returns `(root_node, maybe_child)`.
     ///
@@ -234,7 +855,7 @@ impl Tree {
     /// side after applying some operation to the detached child.
     #[inline]
     pub fn detach(mut self, left: bool) -> (Self, Option<Self>) {
-        let maybe_child = match self.slot_mut(left).take() {
+        let maybe_child = match self.take_link(left) {
             None => None,
             Some(Link::Reference { .. }) => None,
             Some(Link::Modified { tree, .. }) => Some(tree),
@@ -293,9 +914,38 @@ impl Tree {
         tree.attach(left, f(child))
     }
 
+    /// Performs a single left rotation, making the right child the new root
+    /// node. The old root becomes the new root's left child, taking the new
+    /// root's former left child as its right child. Key order is preserved.
+    ///
+    /// Panics if there is no right child in memory. The right child's former
+    /// left child may be pruned, since its link is moved without loading it.
+    /// Unlike the rotations done when applying batches, no further rebalancing
+    /// is done, so the result may not be balanced.
+    pub fn rotate_left(self) -> Self {
+        self.rotate(false)
+    }
+
+    /// Performs a single right rotation, making the left child the new root
+    /// node. This is the inverse of `rotate_left`.
+    ///
+    /// Panics if there is no left child in memory.
+    pub fn rotate_right(self) -> Self {
+        self.rotate(true)
+    }
+
+    /// Promotes the child on the given side to be the root node.
+    fn rotate(self, left: bool) -> Self {
+        let (mut tree, mut child) = self.detach_expect(left);
+        // move the grandchild's link as is, since it may be pruned
+        *tree.slot_mut(left) = child.take_link(!left);
+        tree.inner.update_height();
+        child.attach(!left, Some(tree))
+    }
+
     /// Returns a mutable reference to the child slot for the given side.
     #[inline]
-    pub(crate) fn slot_mut(&mut self, left: bool) -> &mut Option<Link> {
+    pub(crate) fn slot_mut(&mut self, left: bool) -> &mut Option<Link<H, A>> {
         if left {
             &mut self.inner.left
         } else {
@@ -303,6 +953,16 @@ impl Tree {
         }
     }
 
+    /// Removes and returns the link on the given side, if any, updating the
+    /// cached height. Unlike `detach`, the link is returned even if it is a
+    /// `Link::Reference`.
+    #[inline]
+    pub(crate) fn take_link(&mut self, left: bool) -> Option<Link<H, A>> {
+        let link = self.slot_mut(left).take();
+        self.inner.update_height();
+        link
+    }
+
     /// Replaces the root node's value with the given value and returns the
     /// modified `Tree`.
     #[inline]
@@ -311,118 +971,489 @@ impl Tree {
         self
     }
 
-    // TODO: add compute_hashes method
+    /// Replaces the root node's key and value with the given key and value,
+    /// recomputing the key/value hash, and returns the modified `Tree`. Useful
+    /// for fixing up nodes in place, e.g. when canonicalizing keys during a
+    /// migration.
+    ///
+    /// The caller is responsible for keeping the keys of the tree in order: the
+    /// new key must still sort between the keys of the node's left and right
+    /// subtrees, and on the same side of each of the node's ancestors. Since
+    /// the node does not know its ancestors, only the greatest key of its left
+    /// subtree and the least key of its right subtree are checked, and only in
+    /// debug builds. If a subtree's edge is pruned, its root's key is checked
+    /// instead.
+    #[inline]
+    pub fn with_kv(mut self, key: Vec<u8>, value: Vec<u8>) -> Self {
+        // the key of the given subtree nearest to this node's key
+        let nearest_key = |left: bool| match self.child(left) {
+            Some(child) => child.edge_key(!left).or_else(|| Some(child.key())),
+            None => self.link(left).map(|link| link.key()),
+        };
+        debug_assert!(
+            nearest_key(true).map_or(true, |nearest| nearest < key.as_slice())
+                && nearest_key(false).map_or(true, |nearest| nearest > key.as_slice()),
+            "New key must be between the keys of the node's subtrees"
+        );
+        self.inner.kv = KV::new(key, value);
+        self
+    }
+
+    /// Computes the hashes of all modified descendants, replacing their
+    /// `Link::Modified` links with `Link::Uncommitted` links which store the
+    /// computed hash. Afterwards `hash` and `child_hash` can be called without
+    /// panicking, and take constant time.
+    ///
+    /// Only subtrees which have been modified since their hashes were last
+    /// computed are traversed. Changing a descendant (e.g. through `walk` or
+    /// `attach`) relinks it and each of its ancestors as `Link::Modified`, so
+    /// stale hashes are never kept. `commit` reuses the stored hashes rather
+    /// than computing them again. The key/value hash of each node traversed is
+    /// also updated if its value was modified through `value_mut`.
+    pub fn compute_hashes(&mut self) {
+        self.inner.kv.update_hash();
+        for &left in [true, false].iter() {
+            let slot = self.slot_mut(left);
+            *slot = match slot.take() {
+                Some(Link::Modified {
+                    mut tree,
+                    child_heights,
+                    descendant_count,
+                    aggregate,
+                    ..
+                }) => {
+                    tree.compute_hashes();
+                    Some(Link::Uncommitted {
+                        hash: tree.hash(),
+                        child_heights,
+                        descendant_count,
+                        aggregate,
+                        tree,
+                    })
+                }
+                other => other,
+            };
+        }
+    }
+
+    /// Like `compute_hashes`, but the two children of each modified node are
+    /// hashed concurrently on rayon's thread pool, joining before the node's
+    /// own hash is computed. Requires the `rayon` feature.
+    #[cfg(feature = "rayon")]
+    pub fn compute_hashes_parallel(&mut self) {
+        fn compute_link_hash<H: Hasher, A: Aggregate>(slot: &mut Option<Link<H, A>>) {
+            *slot = match slot.take() {
+                Some(Link::Modified {
+                    mut tree,
+                    child_heights,
+                    descendant_count,
+                    aggregate,
+                    ..
+                }) => {
+                    tree.compute_hashes_parallel();
+                    Some(Link::Uncommitted {
+                        hash: tree.hash(),
+                        child_heights,
+                        descendant_count,
+                        aggregate,
+                        tree,
+                    })
+                }
+                other => other,
+            };
+        }
+
+        let TreeInner {
+            kv, left, right, ..
+        } = &mut *self.inner;
+        kv.update_hash();
+        rayon::join(|| compute_link_hash(left), || compute_link_hash(right));
+    }
+
+    /// Like `commit`, but first computes the hashes of the modified subtrees in
+    /// parallel (see `compute_hashes_parallel`). Requires the `rayon` feature.
+    ///
+    /// Hashing is the CPU-bound part of committing, so only it is
+    /// parallelized: nodes are still written and pruned on the calling thread,
+    /// in the same order as `commit`, and the two produce the same writes. As a
+    /// result the `Commit` object does not need to be `Sync`.
+    #[cfg(feature = "rayon")]
+    pub fn commit_parallel<C: Commit<H, A>>(&mut self, c: &mut C) -> Result<()> {
+        self.compute_hashes_parallel();
+        self.commit(c)
+    }
 
     /// Called to finalize modifications to a tree, recompute its hashes, and
     /// write the updated nodes to a backing store.
     ///
-    /// Traverses through the tree, computing hashes for all modified links and
-    /// replacing them with `Link::Loaded` variants, writes out all changes to
+    /// Traverses through the tree, computing hashes for all modified links
+    /// (reusing the hashes of `Link::Uncommitted` links) and replacing them
+    /// with `Link::Loaded` variants, writes out all changes to
     /// the given `Commit` object's `write` method, and calls the its `prune`
     /// method to test whether or not to keep or prune nodes from memory.
-    #[inline]
-    pub fn commit<C: Commit>(&mut self, c: &mut C) -> Result<()> {
-        // TODO: make this method less ugly
-        // TODO: call write in-order for better performance in writing batch to db?
+    ///
+    /// Nodes are written in post-order (children before their parents), between
+    /// a single call to the `Commit` object's `begin_batch` method and a single
+    /// call to its `end_batch` method. The writing and pruning traversals use
+    /// an explicit stack rather than recursion, so committing tall trees will
+    /// not overflow the call stack.
+    ///
+    /// Links are only upgraded and pruned once all nodes have been written and
+    /// `end_batch` has succeeded. If any of the `Commit` object's methods
+    /// return an error, the links of the tree are restored to the state they
+    /// were in before the call to `commit`, so it can be retried.
+    pub fn commit<C: Commit<H, A>>(&mut self, c: &mut C) -> Result<()> {
+        c.begin_batch()?;
+
+        let mut hashed = vec![];
+        self.hash_modified(&mut hashed);
+        if let Err(err) = self.write_uncommitted(c).and_then(|_| c.end_batch()) {
+            self.unhash_modified(&mut hashed.into_iter());
+            return Err(err);
+        }
 
-        if let Some(Link::Modified { .. }) = self.inner.left {
-            if let Some(Link::Modified {
-                mut tree,
-                child_heights,
-                ..
-            }) = self.inner.left.take()
-            {
-                tree.commit(c)?;
-                self.inner.left = Some(Link::Loaded {
-                    hash: tree.hash(),
-                    tree,
+        self.commit_nodes(c);
+        Ok(())
+    }
+
+    /// Like `compute_hashes`, but also records the original state of each
+    /// child link it visits in pre-order: `Some(pending_writes)` for links
+    /// which were `Link::Modified`, or `None` for links which were already
+    /// `Link::Uncommitted`. Used by `unhash_modified` to undo the hashing.
+    fn hash_modified(&mut self, hashed: &mut Vec<Option<usize>>) {
+        self.inner.kv.update_hash();
+        for &left in [true, false].iter() {
+            let slot = self.slot_mut(left);
+            *slot = match slot.take() {
+                Some(Link::Modified {
+                    pending_writes,
+                    mut tree,
                     child_heights,
-                });
-            } else {
-                unreachable!()
+                    descendant_count,
+                    aggregate,
+                }) => {
+                    hashed.push(Some(pending_writes));
+                    tree.hash_modified(hashed);
+                    Some(Link::Uncommitted {
+                        hash: tree.hash(),
+                        child_heights,
+                        descendant_count,
+                        aggregate,
+                        tree,
+                    })
+                }
+                Some(link @ Link::Uncommitted { .. }) => {
+                    hashed.push(None);
+                    Some(link)
+                }
+                other => other,
+            };
+        }
+    }
+
+    /// Reverts the links hashed by `hash_modified` to `Link::Modified`, given
+    /// the states it recorded.
+    fn unhash_modified<I: Iterator<Item = Option<usize>>>(&mut self, hashed: &mut I) {
+        for &left in [true, false].iter() {
+            let slot = self.slot_mut(left);
+            *slot = match slot.take() {
+                Some(Link::Uncommitted {
+                    hash,
+                    mut tree,
+                    child_heights,
+                    descendant_count,
+                    aggregate,
+                }) => match hashed.next() {
+                    Some(Some(pending_writes)) => {
+                        tree.unhash_modified(hashed);
+                        Some(Link::Modified {
+                            pending_writes,
+                            child_heights,
+                            descendant_count,
+                            aggregate,
+                            tree,
+                        })
+                    }
+                    _ => Some(Link::Uncommitted {
+                        hash,
+                        child_heights,
+                        descendant_count,
+                        aggregate,
+                        tree,
+                    }),
+                },
+                other => other,
+            };
+        }
+    }
+
+    /// Writes the root node and all of its `Link::Uncommitted` descendants to
+    /// the given `Commit` object in post-order, without modifying the tree.
+    fn write_uncommitted<C: Commit<H, A>>(&self, c: &mut C) -> Result<()> {
+        // TODO: call write in-order for better performance in writing batch to db?
+
+        // each node is pushed twice: first to push its children, then (once
+        // its children have been written) to write the node itself
+        let mut stack = vec![(self, false)];
+
+        while let Some((tree, children_written)) = stack.pop() {
+            if children_written {
+                c.write(tree)?;
+                continue;
+            }
+
+            // the right child is pushed first so the left child is written first
+            stack.push((tree, true));
+            for &left in [false, true].iter() {
+                if let Some(Link::Uncommitted { tree: child, .. }) = tree.link(left) {
+                    stack.push((child, false));
+                }
             }
         }
 
-        if let Some(Link::Modified { .. }) = self.inner.right {
-            if let Some(Link::Modified {
-                mut tree,
-                child_heights,
-                ..
-            }) = self.inner.right.take()
-            {
-                tree.commit(c)?;
-                self.inner.right = Some(Link::Loaded {
-                    hash: tree.hash(),
+        Ok(())
+    }
+
+    /// Implementation of `commit`, upgrading the links of the written nodes to
+    /// `Link::Loaded` and pruning them.
+    fn commit_nodes<C: Commit<H, A>>(&mut self, c: &C) {
+        // uncommitted descendants are detached from their parents as they are
+        // visited, then reattached as `Link::Loaded` once all of their own
+        // children have been committed
+        let mut stack: Vec<(Self, (u8, u8), bool, H::Digest)> =
+            Vec::with_capacity(self.height() as usize);
+
+        loop {
+            let parent = match stack.last_mut() {
+                Some((tree, _, _, _)) => tree,
+                None => &mut *self,
+            };
+            if let Some(child) = parent.take_uncommitted_child() {
+                stack.push(child);
+                continue;
+            }
+
+            let (mut tree, child_heights, left, hash) = match stack.pop() {
+                Some(entry) => entry,
+                None => break,
+            };
+            let depth = stack.len() as u8 + 1;
+            tree.prune_children(c, depth);
+
+            let parent = match stack.last_mut() {
+                Some((tree, _, _, _)) => tree,
+                None => &mut *self,
+            };
+            *parent.slot_mut(left) = Some(Link::from_stored_tree(tree, hash, child_heights));
+        }
+
+        self.prune_children(c, 0)
+    }
+
+    /// Detaches the first child (checking the left side, then the right side)
+    /// which has a link of variant `Link::Uncommitted`. Returns the child, its
+    /// link's `child_heights`, which side it was detached from, and its hash,
+    /// or `None` if neither link needs to be committed.
+    fn take_uncommitted_child(&mut self) -> Option<(Self, (u8, u8), bool, H::Digest)> {
+        for &left in [true, false].iter() {
+            match self.link(left) {
+                Some(Link::Uncommitted { .. }) => {}
+                _ => continue,
+            }
+
+            return match self.slot_mut(left).take() {
+                Some(Link::Uncommitted {
                     tree,
                     child_heights,
-                });
-            } else {
-                unreachable!()
-            }
+                    hash,
+                    ..
+                }) => Some((tree, child_heights, left, hash)),
+                _ => unreachable!(),
+            };
         }
 
-        c.write(&self)?;
+        None
+    }
 
-        let (prune_left, prune_right) = c.prune(&self);
+    /// Prunes the root node's children as specified by the given `Commit`
+    /// object's `prune_at_depth` method (`depth` being the node's distance
+    /// from the root of the tree being committed). Expects the node and its
+    /// children to already be written.
+    fn prune_children<C: Commit<H, A>>(&mut self, c: &C, depth: u8) {
+        let (prune_left, prune_right) = c.prune_at_depth(&self, depth);
         if prune_left {
             self.inner.left = self.inner.left.take().map(|link| link.into_reference());
         }
         if prune_right {
             self.inner.right = self.inner.right.take().map(|link| link.into_reference());
         }
-
-        Ok(())
     }
 
     /// Fetches the child on the given side using the given data source, and
     /// places it in the child slot (upgrading the link from `Link::Reference` to
     /// `Link::Loaded`).
+    ///
+    /// Returns an error if there is no child on the given side, or if the child
+    /// is already in memory (its link is not a `Link::Reference`).
     #[inline]
-    pub fn load<S: Fetch>(&mut self, left: bool, source: &S) -> Result<()> {
-        // TODO: return Err instead of panic?
-        let link = self.link(left).expect("Expected link");
-        let (child_heights, hash) = match link {
-            Link::Reference {
-                child_heights,
-                hash,
-                ..
-            } => (child_heights, hash),
-            _ => panic!("Expected Some(Link::Reference)"),
-        };
+    pub fn load<S: Fetch<H, A>>(&mut self, left: bool, source: &S) -> Result<()> {
+        let link = self.pruned_link(left)?;
+        let (child_heights, hash) = (link.child_heights(), *link.hash());
 
         let tree = source.fetch(link)?;
         debug_assert_eq!(tree.key(), link.key());
-        *self.slot_mut(left) = Some(Link::Loaded {
-            tree,
-            hash: *hash,
-            child_heights: *child_heights,
-        });
+        *self.slot_mut(left) = Some(Link::from_stored_tree(tree, hash, child_heights));
 
         Ok(())
     }
-}
 
-pub fn side_to_str(left: bool) -> &'static str {
-    if left {
-        "left"
-    } else {
-        "right"
+    /// Loads every pruned node of the tree from the given data source, so the
+    /// whole tree is in memory (upgrading each `Link::Reference` to
+    /// `Link::Loaded`). See `load_all_bounded` to limit how many nodes are
+    /// loaded.
+    pub fn load_all<S: Fetch<H, A>>(&mut self, source: &S) -> Result<()> {
+        self.load_all_bounded(source, usize::MAX)
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::commit::NoopCommit;
-    use super::hash::NULL_HASH;
-    use super::Tree;
+    /// Like `load_all`, but returns an error rather than loading more than
+    /// `max_loads` nodes, to avoid exhausting memory when loading a large
+    /// tree. The nodes loaded before the limit is reached are kept in memory.
+    ///
+    /// The traversal uses an explicit stack rather than recursion, so loading
+    /// tall trees will not overflow the call stack.
+    pub fn load_all_bounded<S: Fetch<H, A>>(&mut self, source: &S, max_loads: usize) -> Result<()> {
+        fn child_tree<H: Hasher, A: Aggregate>(
+            slot: &mut Option<Link<H, A>>,
+        ) -> Option<&mut Tree<H, A>> {
+            match slot {
+                Some(Link::Modified { tree, .. })
+                | Some(Link::Uncommitted { tree, .. })
+                | Some(Link::Loaded { tree, .. }) => Some(tree),
+                _ => None,
+            }
+        }
 
-    #[test]
-    fn build_tree() {
-        let tree = Tree::new(vec![1], vec![101]);
-        assert_eq!(tree.key(), &[1]);
-        assert_eq!(tree.value(), &[101]);
-        assert!(tree.child(true).is_none());
-        assert!(tree.child(false).is_none());
+        let mut loads = 0;
+        let mut stack = vec![self];
+
+        while let Some(tree) = stack.pop() {
+            for &left in [true, false].iter() {
+                match tree.link(left) {
+                    Some(Link::Reference { .. }) => {}
+                    _ => continue,
+                }
+
+                if loads == max_loads {
+                    bail!("Cannot load more than {} nodes", max_loads);
+                }
+                tree.load(left, source)?;
+                loads += 1;
+            }
+
+            let inner = &mut *tree.inner;
+            stack.extend(child_tree(&mut inner.right));
+            stack.extend(child_tree(&mut inner.left));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the link on the given side, or an error if there is no link or
+    /// it is not a `Link::Reference` (so its child can't be loaded).
+    fn pruned_link(&self, left: bool) -> Result<&Link<H, A>> {
+        match self.link(left) {
+            None => bail!("Cannot load {} child, node has no link", side_to_str(left)),
+            Some(link @ Link::Reference { .. }) => Ok(link),
+            Some(_) => bail!(
+                "Cannot load {} child, link is already loaded",
+                side_to_str(left)
+            ),
+        }
+    }
+}
+
+/// Recursive implementation of `Tree::from_sorted`, taking the entries out of
+/// the slice as they are used.
+fn build_sorted<H: Hasher, A: Aggregate>(
+    entries: &mut [Option<(Vec<u8>, Vec<u8>)>],
+) -> Option<Tree<H, A>> {
+    if entries.is_empty() {
+        return None;
+    }
+
+    let mid = entries.len() / 2;
+    let (left, rest) = entries.split_at_mut(mid);
+    let (entry, right) = rest.split_first_mut().unwrap();
+    let (key, value) = entry.take().unwrap();
+
+    let tree = Tree::with_hasher(key, value)
+        .attach(true, build_sorted(left))
+        .attach(false, build_sorted(right));
+    Some(tree)
+}
+
+/// Moves the entries of `tree` for which `f` returns `true` into `entries`, in
+/// order. Panics if the tree contains pruned nodes.
+fn retain_entries<H: Hasher, A: Aggregate, F>(
+    tree: Tree<H, A>,
+    f: &F,
+    entries: &mut Vec<Option<(Vec<u8>, Vec<u8>)>>,
+) where
+    F: Fn(&[u8], &[u8]) -> bool,
+{
+    let has_pruned_child = [true, false]
+        .iter()
+        .any(|&left| tree.link(left).map_or(false, Link::is_reference));
+    if has_pruned_child {
+        panic!("Cannot retain entries of a tree with pruned nodes");
+    }
+
+    let (tree, maybe_left) = tree.detach(true);
+    let (mut tree, maybe_right) = tree.detach(false);
+
+    if let Some(left) = maybe_left {
+        retain_entries(left, f, entries);
+    }
+    if f(tree.key(), tree.value()) {
+        let value = std::mem::replace(tree.value_mut(), vec![]);
+        entries.push(Some((tree.take_key(), value)));
+    }
+    if let Some(right) = maybe_right {
+        retain_entries(right, f, entries);
+    }
+}
+
+pub fn side_to_str(left: bool) -> &'static str {
+    if left {
+        "left"
+    } else {
+        "right"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::commit::{Commit, DepthPrune, NoopCommit};
+    use super::hash::{node_hash, Hash, NULL_HASH};
+    use super::kv::KV;
+    use super::{Link, MemStore, NoAggregate, Op, PanicSource, Tree, TreeInner, Walker};
+    use crate::error::Result;
+    use crate::test_utils::{
+        apply_memonly_unchecked, apply_to_memonly, assert_tree_invariants, make_batch_rand,
+        make_batch_seq, make_partially_pruned_tree, make_tree_rand, make_tree_seq, put_entry,
+        seq_key,
+    };
+    use failure::bail;
+    use rand::prelude::*;
+    use std::mem::size_of;
+
+    #[test]
+    fn build_tree() {
+        let tree = Tree::new(vec![1], vec![101]);
+        assert_eq!(tree.key(), &[1]);
+        assert_eq!(tree.value(), &[101]);
+        assert!(tree.child(true).is_none());
+        assert!(tree.child(false).is_none());
 
         let tree = tree.attach(true, None);
         assert!(tree.child(true).is_none());
@@ -447,6 +1478,99 @@ mod test {
             .attach(true, Some(Tree::new(vec![4], vec![5])));
     }
 
+    #[test]
+    fn try_attach() {
+        let tree = Tree::new(vec![1], vec![1])
+            .try_attach(true, Some(Tree::new(vec![0], vec![2])))
+            .ok()
+            .expect("attach failed");
+        assert_eq!(tree.child(true).unwrap().key(), &[0]);
+        assert_eq!(tree.height(), 2);
+
+        let (tree, err) = tree
+            .try_attach(true, Some(Tree::new(vec![2], vec![3])))
+            .err()
+            .expect("expected error");
+        assert_eq!(
+            err.to_string(),
+            "Tried to attach to left tree slot, but it is already Some"
+        );
+        assert_eq!(tree.child(true).unwrap().key(), &[0]);
+
+        let (tree, err) = tree
+            .try_attach(false, Some(Tree::new(vec![1], vec![3])))
+            .err()
+            .expect("expected error");
+        assert_eq!(err.to_string(), "Tried to attach tree with same key");
+        assert!(tree.link(false).is_none());
+
+        let tree = tree.try_attach(false, None).ok().expect("attach failed");
+        assert!(tree.link(false).is_none());
+    }
+
+    #[test]
+    fn rotate() {
+        let make_tree = || Tree::from_sorted((0..7u8).map(|i| (vec![i], vec![i]))).unwrap();
+        let mut expected = make_tree();
+        expected.compute_hashes();
+
+        let tree = make_tree().rotate_left();
+        assert_eq!(tree.key(), &[5]);
+        assert_eq!(tree.child(true).unwrap().key(), &[3]);
+        assert_eq!(tree.child(true).unwrap().child(false).unwrap().key(), &[4]);
+        assert_eq!(tree.child_heights(), (3, 1));
+        assert_eq!(tree.height(), 4);
+        let keys: Vec<_> = tree.iter().map(|(key, _)| key.to_vec()).collect();
+        assert_eq!(keys, (0..7u8).map(|i| vec![i]).collect::<Vec<_>>());
+
+        let mut tree = tree.rotate_right();
+        assert_eq!(tree.key(), &[3]);
+        assert_eq!(tree.child_heights(), (2, 2));
+        tree.compute_hashes();
+        assert!(tree.structurally_eq(&expected));
+        assert_eq!(tree.hash(), expected.hash());
+        assert!(tree.verify_avl().is_ok());
+
+        let mut tree = make_tree().rotate_right().rotate_left();
+        tree.compute_hashes();
+        assert_eq!(tree.hash(), expected.hash());
+    }
+
+    #[test]
+    fn rotate_pruned() {
+        let make_tree = || Tree::from_sorted((0..7u8).map(|i| (vec![i], vec![i]))).unwrap();
+        let mut expected = make_tree().rotate_left();
+        expected.compute_hashes();
+
+        let mut store = MemStore::new();
+        let mut tree = make_tree();
+        tree.commit(&mut store).expect("commit failed");
+        tree.load(false, &&store).expect("load failed");
+        assert!(tree.child(false).unwrap().link(true).unwrap().is_reference());
+
+        let mut tree = tree.rotate_left();
+        assert_eq!(tree.key(), &[5]);
+        let old_root = tree.child(true).unwrap();
+        assert_eq!(old_root.key(), &[3]);
+        assert_eq!(old_root.link(false).unwrap().key(), &[4]);
+        assert!(old_root.link(false).unwrap().is_reference());
+        assert_eq!(tree.child_heights(), (3, 1));
+
+        tree.compute_hashes();
+        assert_eq!(tree.hash(), expected.hash());
+        tree.load_all(&&store).expect("load failed");
+        let keys: Vec<_> = tree.iter().map(|(key, _)| key.to_vec()).collect();
+        assert_eq!(keys, (0..7u8).map(|i| vec![i]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected tree to have right child, but got None")]
+    fn rotate_missing_child() {
+        Tree::new(vec![1], vec![1])
+            .attach(true, Some(Tree::new(vec![0], vec![0])))
+            .rotate_left();
+    }
+
     #[test]
     fn modify() {
         let tree = Tree::new(vec![0], vec![1])
@@ -501,12 +1625,12 @@ mod test {
         tree.commit(&mut NoopCommit {}).expect("commit failed");
         assert_eq!(
             tree.child_hash(true),
-            &[
-                23, 66, 77, 65, 141, 140, 245, 11, 53, 36, 157, 248, 208, 6, 160, 222, 213, 143,
-                249, 85
+            [
+                1, 156, 207, 130, 73, 208, 56, 245, 16, 102, 68, 218, 234, 151, 165, 103, 129, 239,
+                217, 254
             ]
         );
-        assert_eq!(tree.child_hash(false), &NULL_HASH);
+        assert_eq!(tree.child_hash(false), NULL_HASH);
     }
 
     #[test]
@@ -515,8 +1639,8 @@ mod test {
         assert_eq!(
             tree.hash(),
             [
-                9, 242, 41, 142, 47, 227, 251, 242, 27, 29, 140, 24, 184, 111, 118, 188, 20, 58,
-                223, 197
+                36, 238, 108, 200, 27, 184, 179, 117, 23, 148, 177, 253, 237, 32, 28, 30, 56, 252,
+                6, 165
             ]
         );
     }
@@ -554,6 +1678,52 @@ mod test {
         assert_eq!(tree.balance_factor(), 1);
     }
 
+    /// Asserts that the cached height of every in-memory node matches the
+    /// height recomputed from its subtree, returning the recomputed height.
+    fn assert_cached_heights(tree: &Tree) -> u8 {
+        let mut child_heights = [0, 0];
+        for (i, &left) in [true, false].iter().enumerate() {
+            child_heights[i] = match tree.child(left) {
+                Some(child) => assert_cached_heights(child),
+                None => tree.child_height(left),
+            };
+            assert_eq!(tree.child_height(left), child_heights[i]);
+        }
+
+        let height = 1 + child_heights[0].max(child_heights[1]);
+        assert_eq!(tree.height(), height);
+        height
+    }
+
+    #[test]
+    fn cached_height() {
+        let mut rng: SmallRng = SeedableRng::seed_from_u64(0);
+        let mut tree = make_tree_rand(100, 10, 0);
+        assert_cached_heights(&tree);
+
+        for i in 0..200 {
+            let left = rng.gen();
+            tree = match rng.gen_range(0..3) {
+                // detach and reattach a child
+                0 => {
+                    let (tree, maybe_child) = tree.detach(left);
+                    assert_cached_heights(&tree);
+                    tree.attach(left, maybe_child)
+                }
+                // drop a grandchild's subtree
+                1 => {
+                    let grandchild_left = rng.gen();
+                    tree.walk(left, |maybe_child| {
+                        maybe_child.map(|child| child.detach(grandchild_left).0)
+                    })
+                }
+                // walk through the tree, rebalancing as entries are inserted
+                _ => apply_memonly_unchecked(tree, &make_batch_rand(10, i)),
+            };
+            assert_cached_heights(&tree);
+        }
+    }
+
     #[test]
     fn commit() {
         let mut tree = Tree::new(vec![0], vec![1]).attach(false, Some(Tree::new(vec![2], vec![3])));
@@ -561,4 +1731,936 @@ mod test {
 
         assert!(tree.link(false).expect("expected link").is_stored());
     }
+
+    /// A `Commit` which records the keys of written nodes, in order.
+    struct RecordCommit {
+        writes: Vec<Vec<u8>>,
+    }
+
+    impl Commit for RecordCommit {
+        fn write(&mut self, tree: &Tree) -> Result<()> {
+            self.writes.push(tree.key().to_vec());
+            Ok(())
+        }
+
+        fn prune(&self, _tree: &Tree) -> (bool, bool) {
+            (false, false)
+        }
+    }
+
+    /// A `Commit` which records the order of calls to its batch hooks and
+    /// `write`.
+    struct BatchCommit {
+        calls: Vec<&'static str>,
+    }
+
+    impl Commit for BatchCommit {
+        fn begin_batch(&mut self) -> Result<()> {
+            self.calls.push("begin");
+            Ok(())
+        }
+
+        fn end_batch(&mut self) -> Result<()> {
+            self.calls.push("end");
+            Ok(())
+        }
+
+        fn write(&mut self, _tree: &Tree) -> Result<()> {
+            self.calls.push("write");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn commit_batch_hooks() {
+        let batch = make_batch_seq(0..100);
+        let mut tree = Walker::<PanicSource>::apply_to(None, &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+
+        let mut commit = BatchCommit { calls: vec![] };
+        tree.commit(&mut commit).expect("commit failed");
+
+        assert_eq!(commit.calls.len(), 102);
+        assert_eq!(commit.calls.first(), Some(&"begin"));
+        assert_eq!(commit.calls.last(), Some(&"end"));
+        assert!(commit.calls[1..101].iter().all(|call| *call == "write"));
+    }
+
+    fn modified_post_order(tree: &Tree, keys: &mut Vec<Vec<u8>>) {
+        for &left in [true, false].iter() {
+            if let Some(link) = tree.link(left) {
+                if link.is_modified() {
+                    modified_post_order(link.tree().unwrap(), keys);
+                }
+            }
+        }
+        keys.push(tree.key().to_vec());
+    }
+
+    fn recursive_hash(tree: &Tree) -> Hash {
+        let child_hash = |left| tree.child(left).map_or(NULL_HASH, recursive_hash);
+        node_hash(tree.kv_hash(), &child_hash(true), &child_hash(false))
+    }
+
+    fn assert_commit_matches_recursive(mut tree: Tree) {
+        let mut expected_writes = vec![];
+        modified_post_order(&tree, &mut expected_writes);
+        let expected_hash = recursive_hash(&tree);
+
+        let mut commit = RecordCommit { writes: vec![] };
+        tree.commit(&mut commit).expect("commit failed");
+
+        assert_eq!(commit.writes, expected_writes);
+        assert_eq!(tree.hash(), expected_hash);
+    }
+
+    #[test]
+    fn compute_hashes() {
+        let batch = make_batch_seq(0..1_000);
+        let mut tree = Walker::<PanicSource>::apply_to(None, &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        let expected_hash = recursive_hash(&tree);
+
+        tree.compute_hashes();
+        assert!(tree.link(true).unwrap().is_uncommitted());
+        assert!(tree.link(false).unwrap().is_uncommitted());
+        assert_eq!(tree.hash(), expected_hash);
+
+        // modifying a descendant invalidates the hashes on its path
+        let walker = Walker::new(tree, PanicSource {});
+        let batch = vec![(seq_key(10), Op::Put(vec![1, 2, 3]))];
+        let mut tree = Walker::apply_to(Some(walker), &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        assert!(tree.link(true).unwrap().is_modified());
+        assert!(tree.link(false).unwrap().is_uncommitted());
+        let expected_hash = recursive_hash(&tree);
+
+        tree.compute_hashes();
+        assert_eq!(tree.hash(), expected_hash);
+
+        // committing reuses the computed hashes, and still writes every
+        // uncommitted node
+        let mut commit = RecordCommit { writes: vec![] };
+        tree.commit(&mut commit).expect("commit failed");
+        assert_eq!(commit.writes.len(), 1_000);
+        assert_eq!(tree.hash(), expected_hash);
+        assert!(tree.link(true).unwrap().is_stored());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn commit_parallel() {
+        struct EncodeCommit {
+            writes: Vec<(Vec<u8>, Vec<u8>)>,
+        }
+
+        impl Commit for EncodeCommit {
+            fn write(&mut self, tree: &Tree) -> Result<()> {
+                self.writes.push((tree.key().to_vec(), tree.encode()));
+                Ok(())
+            }
+        }
+
+        let batch = make_batch_rand(10_000, 0);
+        let build = || {
+            Walker::<PanicSource>::apply_to(None, &batch)
+                .expect("apply failed")
+                .0
+                .expect("expected tree")
+        };
+
+        let mut tree = build();
+        let mut serial = EncodeCommit { writes: vec![] };
+        tree.commit(&mut serial).expect("commit failed");
+
+        let mut parallel_tree = build();
+        let mut parallel = EncodeCommit { writes: vec![] };
+        parallel_tree
+            .commit_parallel(&mut parallel)
+            .expect("commit failed");
+
+        assert_eq!(parallel_tree.hash(), tree.hash());
+        assert_eq!(parallel.writes.len(), 10_000);
+        assert_eq!(parallel.writes, serial.writes);
+    }
+
+    #[test]
+    fn commit_fully_modified() {
+        let batch = make_batch_seq(0..100);
+        let tree = Walker::<PanicSource>::apply_to(None, &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        assert_commit_matches_recursive(tree);
+    }
+
+    #[test]
+    fn commit_partially_modified() {
+        let tree = make_tree_seq(100);
+        let walker = Walker::new(tree, PanicSource {});
+        let batch = make_batch_seq(40..45);
+        let tree = Walker::apply_to(Some(walker), &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        assert_commit_matches_recursive(tree);
+    }
+
+    #[test]
+    fn commit_tall_chain() {
+        let mut tree = Tree::new(vec![0], vec![0]);
+        for i in 1..=250 {
+            tree = Tree::new(vec![i], vec![i]).attach(true, Some(tree));
+        }
+        assert_eq!(tree.height(), 251);
+
+        let mut commit = RecordCommit { writes: vec![] };
+        tree.commit(&mut commit).expect("commit failed");
+
+        let expected_writes: Vec<_> = (0..=250u8).map(|i| vec![i]).collect();
+        assert_eq!(commit.writes, expected_writes);
+    }
+
+    /// A `Commit` which fails on the write after `writes_left` successful
+    /// writes.
+    struct FailingCommit {
+        writes_left: usize,
+    }
+
+    impl Commit for FailingCommit {
+        fn write(&mut self, _tree: &Tree) -> Result<()> {
+            if self.writes_left == 0 {
+                bail!("Write failed");
+            }
+            self.writes_left -= 1;
+            Ok(())
+        }
+    }
+
+    /// Returns the key, side, variant, and pending writes of each in-memory
+    /// link, in pre-order.
+    fn link_states(tree: &Tree, states: &mut Vec<(Vec<u8>, bool, &'static str, usize)>) {
+        for &left in [true, false].iter() {
+            let link = match tree.link(left) {
+                Some(link) => link,
+                None => continue,
+            };
+            let variant = match link {
+                Link::Reference { .. } => "reference",
+                Link::Modified { .. } => "modified",
+                Link::Uncommitted { .. } => "uncommitted",
+                Link::Loaded { .. } => "loaded",
+            };
+            states.push((
+                tree.key().to_vec(),
+                left,
+                variant,
+                tree.child_pending_writes(left),
+            ));
+            if let Some(child) = link.tree() {
+                link_states(child, states);
+            }
+        }
+    }
+
+    #[test]
+    fn commit_failure_rollback() {
+        // a mix of modified and uncommitted links
+        let mut tree = Walker::<PanicSource>::apply_to(None, &make_batch_seq(0..100))
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        tree.compute_hashes();
+        let walker = Walker::new(tree, PanicSource {});
+        let batch = vec![(seq_key(10), Op::Put(vec![1, 2, 3])), put_entry(200)];
+        let mut tree = Walker::apply_to(Some(walker), &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        let expected_hash = recursive_hash(&tree);
+
+        let mut before = vec![];
+        link_states(&tree, &mut before);
+        assert!(before.iter().any(|state| state.2 == "modified"));
+        assert!(before.iter().any(|state| state.2 == "uncommitted"));
+
+        for &writes_left in [0, 1, 5, 50].iter() {
+            let mut commit = FailingCommit { writes_left };
+            assert!(tree.commit(&mut commit).is_err());
+
+            let mut after = vec![];
+            link_states(&tree, &mut after);
+            assert_eq!(after, before);
+        }
+
+        // the commit can be retried once writes succeed
+        tree.commit(&mut MemStore::new()).expect("commit failed");
+        assert_eq!(tree.hash(), expected_hash);
+        assert!(tree.link(true).unwrap().is_reference());
+    }
+
+    fn pre_order_keys(tree: &Tree, keys: &mut Vec<Vec<u8>>) {
+        keys.push(tree.key().to_vec());
+        for &left in [true, false].iter() {
+            if let Some(child) = tree.child(left) {
+                pre_order_keys(child, keys);
+            }
+        }
+    }
+
+    #[test]
+    fn load_all() {
+        let make_tree = || {
+            Walker::<PanicSource>::apply_to(None, &make_batch_seq(0..100))
+                .expect("apply failed")
+                .0
+                .expect("expected tree")
+        };
+        let mut expected = make_tree();
+        expected.commit(&mut NoopCommit {}).expect("commit failed");
+        let mut expected_keys = vec![];
+        pre_order_keys(&expected, &mut expected_keys);
+
+        let mut store = MemStore::new();
+        let mut tree = make_tree();
+        tree.commit(&mut store).expect("commit failed");
+        assert_eq!(tree.iter().count(), 1);
+
+        tree.load_all(&&store).expect("load failed");
+        let mut keys = vec![];
+        pre_order_keys(&tree, &mut keys);
+        assert_eq!(keys, expected_keys);
+        assert_eq!(tree.hash(), expected.hash());
+        assert_eq!(tree.height(), expected.height());
+        assert_tree_invariants(&tree);
+
+        // loading an already-loaded tree does nothing
+        tree.load_all(&PanicSource {}).expect("load failed");
+    }
+
+    #[test]
+    fn load_all_bounded() {
+        let mut store = MemStore::new();
+        let mut tree = Walker::<PanicSource>::apply_to(None, &make_batch_seq(0..100))
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        tree.commit(&mut store).expect("commit failed");
+
+        // every node but the root must be loaded
+        let err = tree
+            .load_all_bounded(&&store, 98)
+            .err()
+            .expect("expected error");
+        assert_eq!(err.to_string(), "Cannot load more than 98 nodes");
+        assert_eq!(tree.iter().count(), 99);
+
+        tree.load_all_bounded(&&store, 1).expect("load failed");
+        assert_eq!(tree.iter().count(), 100);
+    }
+
+    #[test]
+    fn commit_100k() {
+        let batch = make_batch_seq(0..100_000);
+        let mut tree = Walker::<PanicSource>::apply_to(None, &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+
+        let mut commit = RecordCommit { writes: vec![] };
+        tree.commit(&mut commit).expect("commit failed");
+        assert_eq!(commit.writes.len(), 100_000);
+    }
+
+    #[test]
+    fn count_and_select() {
+        let tree = make_tree_rand(100, 10, 3);
+        assert_eq!(tree.count(), 101);
+
+        let keys: Vec<_> = tree.iter().map(|(key, _)| key).collect();
+        for (i, key) in keys.iter().enumerate() {
+            let selected = tree.select(i as u64).expect("select failed");
+            assert_eq!(selected, Some(*key));
+        }
+        assert_eq!(tree.select(101).expect("select failed"), None);
+    }
+
+    #[test]
+    fn count_survives_encoding() {
+        let tree = make_tree_seq(100);
+        let count = tree.count();
+        let left_count = tree.child_count(true);
+
+        let decoded = Tree::decode(tree.key().to_vec(), tree.encode().as_slice());
+        assert!(decoded.link(true).expect("expected link").is_reference());
+        assert_eq!(decoded.count(), count);
+        assert_eq!(decoded.child_count(true), left_count);
+
+        // the root can be selected without loading, but other keys are pruned
+        let root = decoded.select(left_count).expect("select failed");
+        assert_eq!(root, Some(tree.key()));
+        assert!(decoded.select(0).is_err());
+        assert_eq!(decoded.select(count).expect("select failed"), None);
+    }
+
+    #[test]
+    fn get() {
+        let tree = make_tree_seq(100);
+        for (key, value) in tree.iter() {
+            assert_eq!(tree.get(key).expect("get failed"), Some(value));
+            assert!(tree.contains_key(key).expect("contains_key failed"));
+        }
+
+        assert_eq!(tree.get(&[1, 2, 3]).expect("get failed"), None);
+        assert_eq!(tree.get(&[]).expect("get failed"), None);
+        assert!(!tree.contains_key(&[255; 10]).expect("contains_key failed"));
+    }
+
+    #[test]
+    fn get_pruned() {
+        let tree = make_partially_pruned_tree(vec![55], vec![77]);
+
+        assert_eq!(tree.get(&[5]).expect("get failed"), Some(&[55u8][..]));
+        assert_eq!(tree.get(&[7]).expect("get failed"), Some(&[77u8][..]));
+        assert_eq!(tree.get(&[8]).expect("get failed"), None);
+        assert!(tree.get(&[3]).is_err());
+        assert!(tree.get(&[1]).is_err());
+        assert!(tree.contains_key(&[3]).is_err());
+    }
+
+    #[test]
+    fn clone_shallow() {
+        let mut store = MemStore::without_pruning();
+        let mut tree = Walker::<PanicSource>::apply_to(None, &make_batch_seq(0..100))
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        tree.commit(&mut store).expect("commit failed");
+        let hash = tree.hash();
+
+        let mut clone = tree.clone_shallow();
+        assert_eq!(clone.hash(), hash);
+        assert_eq!(clone.key(), tree.key());
+        assert!(clone.link(true).unwrap().is_reference());
+        assert!(clone.link(false).unwrap().is_reference());
+        assert!(tree.link(true).unwrap().is_stored());
+
+        // the clone's children are loaded from the store, not the original
+        clone.load(true, &&store).expect("load failed");
+        assert_eq!(
+            clone.child(true).unwrap().hash(),
+            tree.child(true).unwrap().hash()
+        );
+
+        let walker = Walker::new(clone, &store);
+        let batch = make_batch_seq(0..10)
+            .into_iter()
+            .map(|(key, _)| (key, Op::Put(vec![1])))
+            .collect::<Vec<_>>();
+        let mut clone = Walker::apply_to(Some(walker), &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        clone.commit(&mut store).expect("commit failed");
+
+        assert_ne!(clone.hash(), hash);
+        assert_eq!(clone.get(&seq_key(5)).unwrap(), Some(&[1u8][..]));
+        assert_eq!(tree.hash(), hash);
+        assert_eq!(tree.get(&seq_key(5)).unwrap(), Some(&[123u8; 60][..]));
+    }
+
+    #[test]
+    fn get_many() {
+        let tree = make_tree_rand(1_000, 100, 0);
+
+        // a mix of present keys, absent keys between them, and absent keys
+        // outside of the tree's range
+        let mut keys: Vec<Vec<u8>> = tree.keys().step_by(3).map(|key| key.to_vec()).collect();
+        keys.extend(make_batch_rand(100, 50).into_iter().map(|(key, _)| key));
+        keys.push(vec![]);
+        keys.push(vec![255; 40]);
+        keys.sort();
+        keys.dedup();
+
+        let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_slice()).collect();
+        let values = tree.get_many(&keys).expect("get_many failed");
+        let expected: Vec<_> = keys
+            .iter()
+            .map(|key| tree.get(key).expect("get failed"))
+            .collect();
+        assert_eq!(values, expected);
+        assert_eq!(values.iter().filter(|value| value.is_some()).count(), 334);
+
+        assert_eq!(tree.get_many(&[]).expect("get_many failed"), vec![]);
+        assert!(tree.get_many(&[&[2], &[1]]).is_err());
+        assert!(tree.get_many(&[&[1], &[1]]).is_err());
+    }
+
+    #[test]
+    fn get_many_pruned() {
+        let tree = make_partially_pruned_tree(vec![55], vec![77]);
+
+        let values = tree
+            .get_many(&[&[5], &[6], &[7], &[8]])
+            .expect("get_many failed");
+        assert_eq!(
+            values,
+            vec![Some(&[55u8][..]), None, Some(&[77u8][..]), None]
+        );
+        assert!(tree.get_many(&[&[1], &[5]]).is_err());
+    }
+
+    #[test]
+    fn first_and_last_key() {
+        let trees = vec![
+            Tree::new(vec![5], vec![5]),
+            make_tree_seq(100),
+            make_tree_rand(100, 10, 0),
+            make_tree_rand(1_000, 100, 1),
+            Tree::new(vec![1], vec![1]).attach(
+                false,
+                Some(Tree::new(vec![2], vec![2]).attach(false, Some(Tree::new(vec![3], vec![3])))),
+            ),
+        ];
+
+        for tree in trees {
+            let keys: Vec<_> = tree.iter().map(|(key, _)| key).collect();
+            assert_eq!(tree.first_key(), keys.first().copied());
+            assert_eq!(tree.last_key(), keys.last().copied());
+        }
+    }
+
+    #[test]
+    fn first_and_last_key_pruned() {
+        let tree = make_partially_pruned_tree(vec![55], vec![77]);
+
+        assert_eq!(tree.first_key(), None);
+        assert_eq!(tree.last_key(), Some(&[7u8][..]));
+    }
+
+    #[test]
+    fn load_without_link() {
+        let mut tree = Tree::new(vec![5], vec![5]);
+        let err = tree.load(true, &PanicSource {}).unwrap_err();
+        assert!(err.to_string().contains("no link"));
+        assert!(tree.link(true).is_none());
+    }
+
+    #[test]
+    fn load_already_loaded() {
+        let mut tree = make_tree_seq(10);
+        assert!(tree.link(false).unwrap().is_stored());
+        let err = tree.load(false, &PanicSource {}).unwrap_err();
+        assert!(err.to_string().contains("already loaded"));
+        assert!(tree.link(false).unwrap().is_stored());
+    }
+
+    fn assert_pruned_below(tree: &Tree, depth: u8, keep_levels: u8) {
+        for &left in [true, false].iter() {
+            let link = match tree.link(left) {
+                Some(link) => link,
+                None => continue,
+            };
+            if depth + 1 < keep_levels {
+                assert!(link.is_stored());
+                assert_pruned_below(link.tree().unwrap(), depth + 1, keep_levels);
+            } else {
+                assert!(link.is_reference());
+            }
+        }
+    }
+
+    #[test]
+    fn commit_depth_prune() {
+        for &keep_levels in [0, 1, 3, 5, 20].iter() {
+            let batch = make_batch_seq(0..1_000);
+            let mut tree = Walker::<PanicSource>::apply_to(None, &batch)
+                .expect("apply failed")
+                .0
+                .expect("expected tree");
+            let expected_hash = apply_to_memonly(None, &batch)
+                .expect("expected tree")
+                .hash();
+
+            tree.commit(&mut DepthPrune { keep_levels })
+                .expect("commit failed");
+            assert_pruned_below(&tree, 0, keep_levels);
+            assert_eq!(tree.hash(), expected_hash);
+        }
+    }
+
+    #[test]
+    fn verify_avl_balanced() {
+        make_tree_seq(1_000)
+            .verify_avl()
+            .expect("expected balanced tree");
+    }
+
+    #[test]
+    fn verify_avl_unbalanced() {
+        let tree = Tree::new(vec![1], vec![1]).attach(
+            false,
+            Some(Tree::new(vec![2], vec![2]).attach(false, Some(Tree::new(vec![3], vec![3])))),
+        );
+        let err = tree.verify_avl().unwrap_err();
+        assert!(err.to_string().contains("unbalanced"));
+        assert!(err.to_string().contains("[1]"));
+    }
+
+    #[test]
+    fn verify_avl_incorrect_heights() {
+        let tree = Tree::from_fields(
+            vec![2],
+            vec![2],
+            Default::default(),
+            Some(Link::Loaded {
+                hash: Default::default(),
+                child_heights: (3, 0),
+                descendant_count: 1,
+                aggregate: NoAggregate,
+                tree: Tree::new(vec![1], vec![1]),
+            }),
+            None,
+        );
+        let err = tree.verify_avl().unwrap_err();
+        assert!(err.to_string().contains("incorrect left child heights"));
+    }
+
+    #[test]
+    fn subtree_root_hash() {
+        let tree = make_tree_seq(100);
+        assert_eq!(
+            tree.subtree_root_hash(tree.key()).expect("lookup failed"),
+            Some(tree.hash())
+        );
+        assert_eq!(tree.subtree_root_hash(&[1]).expect("lookup failed"), None);
+
+        let left_key = tree.link(true).expect("expected link").key().to_vec();
+        let left_hash = tree.subtree_root_hash(&left_key).expect("lookup failed");
+        let (_, maybe_left) = tree.detach(true);
+        let left = maybe_left.expect("expected child");
+        assert_eq!(left_hash, Some(left.hash()));
+
+        let inner_key = left.link(false).expect("expected link").key().to_vec();
+        let inner_hash = left.subtree_root_hash(&inner_key).expect("lookup failed");
+        let (_, maybe_inner) = left.detach(false);
+        assert_eq!(
+            inner_hash,
+            Some(maybe_inner.expect("expected child").hash())
+        );
+    }
+
+    #[test]
+    fn subtree_root_hash_pruned_or_modified() {
+        let mut tree = make_tree_seq(100);
+        let left_key = tree.link(true).expect("expected link").key().to_vec();
+        tree.commit(&mut MemStore::new()).expect("commit failed");
+        assert!(tree.subtree_root_hash(&left_key).is_err());
+        assert!(tree.subtree_root_hash(tree.key()).is_ok());
+
+        let tree = Tree::new(vec![2], vec![2]).attach(true, Some(Tree::new(vec![1], vec![1])));
+        assert!(tree.subtree_root_hash(&[2]).is_err());
+        assert!(tree
+            .subtree_root_hash(&[1])
+            .expect("lookup failed")
+            .is_some());
+    }
+
+    #[test]
+    fn walk_path() {
+        let tree = make_tree_seq(100);
+        let key = seq_key(37);
+
+        let mut expected = vec![];
+        let mut cursor = &tree;
+        while cursor.key() != key.as_slice() {
+            expected.push(cursor.key().to_vec());
+            let left = key.as_slice() < cursor.key();
+            cursor = cursor.child(left).expect("expected child");
+        }
+        expected.push(key.clone());
+
+        let mut visited = vec![];
+        tree.walk_path(&key, |node| visited.push(node.key().to_vec()));
+        assert_eq!(visited, expected);
+
+        // stops at the leaf where an absent key would be
+        let mut visited = vec![];
+        tree.walk_path(&[1], |node| visited.push(node.key().to_vec()));
+        assert!(visited.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(visited.last().unwrap().as_slice(), seq_key(99).as_slice());
+
+        // stops before pruned children
+        let mut tree = tree;
+        tree.commit(&mut MemStore::new()).expect("commit failed");
+        let mut count = 0;
+        tree.walk_path(&key, |_| count += 1);
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn with_kv() {
+        let mut tree = Tree::new(vec![2], vec![2])
+            .attach(true, Some(Tree::new(vec![1], vec![1])))
+            .attach(false, Some(Tree::new(vec![4], vec![4])));
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        let hash = tree.hash();
+
+        let tree = tree.with_kv(vec![3], vec![5]);
+        assert_eq!(tree.key(), &[3]);
+        assert_eq!(tree.value(), &[5]);
+        assert_eq!(tree.kv_hash(), Tree::new(vec![3], vec![5]).kv_hash());
+        assert_ne!(tree.hash(), hash);
+
+        let mut expected = Tree::new(vec![3], vec![5])
+            .attach(true, Some(Tree::new(vec![1], vec![1])))
+            .attach(false, Some(Tree::new(vec![4], vec![4])));
+        expected.commit(&mut NoopCommit {}).expect("commit failed");
+        assert_eq!(tree.hash(), expected.hash());
+
+        // changing the key back restores the original hash
+        let tree = tree.with_kv(vec![2], vec![2]);
+        assert_eq!(tree.hash(), hash);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "New key must be between the keys of the node's subtrees")]
+    fn with_kv_unordered() {
+        Tree::new(vec![2], vec![2])
+            .attach(false, Some(Tree::new(vec![4], vec![4])))
+            .with_kv(vec![5], vec![5]);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "New key must be between the keys of the node's subtrees")]
+    fn with_kv_unordered_grandchild() {
+        // the new key is between the children's keys, but not greater than
+        // every key in the left subtree
+        let left = Tree::new(vec![1], vec![1]).attach(false, Some(Tree::new(vec![3], vec![3])));
+        Tree::new(vec![4], vec![4])
+            .attach(true, Some(left))
+            .attach(false, Some(Tree::new(vec![6], vec![6])))
+            .with_kv(vec![2], vec![2]);
+    }
+
+    #[test]
+    fn impossible_heights() {
+        let link = Link::Reference {
+            hash: Default::default(),
+            child_heights: (199, 198),
+            descendant_count: 1,
+            aggregate: NoAggregate,
+            key: vec![1],
+        };
+        assert_eq!(link.height(), 200);
+        assert_eq!(link.balance_factor(), -1);
+
+        let tree = Tree::from_fields(vec![2], vec![2], Default::default(), Some(link), None);
+        assert_eq!(tree.height(), 201);
+        assert_eq!(tree.balance_factor(), -200);
+        let err = tree.verify_avl().unwrap_err();
+        assert!(err.to_string().contains("impossible height 201"));
+
+        // heights saturate rather than overflowing
+        let link = Link::Reference {
+            hash: Default::default(),
+            child_heights: (255, 0),
+            descendant_count: 1,
+            aggregate: NoAggregate,
+            key: vec![1],
+        };
+        assert_eq!(link.height(), 255);
+        assert_eq!(link.balance_factor(), -255);
+        let tree = Tree::from_fields(vec![2], vec![2], Default::default(), None, Some(link));
+        assert_eq!(tree.height(), 255);
+        assert_eq!(tree.balance_factor(), 255);
+        assert!(tree.verify_avl().is_err());
+    }
+
+    // sizes on 64-bit targets, which change if fields are added to nodes,
+    // links, or key/value pairs
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn node_size() {
+        assert_eq!(size_of::<Tree>(), 8);
+        assert_eq!(size_of::<Option<Link>>(), 56);
+        assert_eq!(size_of::<KV>(), 72);
+        assert_eq!(Tree::node_size(), 192);
+    }
+
+    #[test]
+    fn memory_footprint() {
+        let single = Tree::new(vec![0; 20], vec![123; 60]);
+        assert_eq!(Tree::node_size(), size_of::<TreeInner>());
+        assert_eq!(
+            single.memory_footprint(),
+            size_of::<Tree>() + Tree::node_size() + 80
+        );
+
+        let tree = make_tree_seq(9);
+        assert_eq!(tree.iter().count(), 10);
+        let data_len: usize = tree
+            .iter()
+            .map(|(key, value)| key.len() + value.len())
+            .sum();
+        assert!(
+            tree.memory_footprint() >= size_of::<Tree>() + 10 * size_of::<TreeInner>() + data_len
+        );
+        assert!(tree.memory_footprint() > single.memory_footprint() * 5);
+    }
+
+    #[test]
+    fn from_sorted() {
+        assert!(Tree::from_sorted(vec![]).is_none());
+
+        let entries = (0..1000).map(|n| (seq_key(n), vec![n as u8]));
+        let mut tree = Tree::from_sorted(entries).expect("expected tree");
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+
+        // floor(log2(1000)) + 1
+        assert_eq!(tree.height(), 10);
+        assert_eq!(tree.count(), 1000);
+        tree.verify_avl().expect("expected balanced tree");
+        assert_tree_invariants(&tree);
+
+        let keys: Vec<_> = tree.iter().map(|(key, _)| key.to_vec()).collect();
+        assert_eq!(keys, (0..1000).map(seq_key).collect::<Vec<_>>());
+
+        let batch = make_batch_seq(0..1000);
+        let expected = Walker::<PanicSource>::apply_to(None, &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        assert_eq!(tree.height(), expected.height());
+    }
+
+    #[test]
+    fn retain() {
+        let tree = make_tree_rand(1000, 100, 0);
+        let keep = |key: &[u8], _: &[u8]| key[0] % 3 != 0;
+        let expected_entries: Vec<_> = tree
+            .iter()
+            .filter(|(key, value)| keep(key, value))
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+            .collect();
+
+        let mut retained = tree.retain(keep).expect("expected tree");
+        retained.commit(&mut NoopCommit {}).expect("commit failed");
+        retained.verify_avl().expect("expected balanced tree");
+        assert_tree_invariants(&retained);
+        assert!(retained
+            .iter()
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+            .eq(expected_entries.iter().cloned()));
+
+        let mut expected = Tree::from_sorted(expected_entries).expect("expected tree");
+        expected.commit(&mut NoopCommit {}).expect("commit failed");
+        assert_eq!(retained.hash(), expected.hash());
+
+        assert!(retained.retain(|_, _| false).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot retain entries of a tree with pruned nodes")]
+    fn retain_pruned() {
+        let mut tree = make_tree_seq(100);
+        tree.commit(&mut MemStore::new()).expect("commit failed");
+        tree.retain(|_, _| true);
+    }
+
+    #[test]
+    fn value_mut() {
+        let mut tree = make_tree_seq(10);
+        let hash = tree.hash();
+
+        tree.value_mut().push(1);
+        let mut expected_value = vec![123; 60];
+        expected_value.push(1);
+        assert_eq!(tree.value(), expected_value.as_slice());
+        let modified_hash = tree.hash();
+        assert_ne!(modified_hash, hash);
+
+        let expected = make_tree_seq(10).with_value(expected_value);
+        assert_eq!(modified_hash, expected.hash());
+
+        // committing updates the stored key/value hash
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        assert_eq!(tree.kv_hash(), expected.kv_hash());
+        assert_eq!(tree.hash(), modified_hash);
+    }
+
+    #[test]
+    fn canonical_root_hash() {
+        assert_eq!(Tree::canonical_root_hash(vec![]), NULL_HASH);
+
+        let mut keys: Vec<_> = (0..100).map(seq_key).collect();
+        let ascending = keys.clone();
+        keys.reverse();
+        let descending = keys.clone();
+        keys.shuffle(&mut SmallRng::seed_from_u64(0));
+        let shuffled = keys;
+
+        let hashes: Vec<_> = [ascending, descending, shuffled]
+            .iter()
+            .map(|keys| {
+                let mut tree = Tree::new(keys[0].clone(), vec![123; 60]);
+                for key in &keys[1..] {
+                    tree = tree
+                        .insert(key.clone(), vec![123; 60])
+                        .ok()
+                        .expect("insert failed");
+                }
+                let entries = tree
+                    .iter()
+                    .map(|(key, value)| (key.to_vec(), value.to_vec()));
+                Tree::canonical_root_hash(entries)
+            })
+            .collect();
+        assert_eq!(hashes[0], hashes[1]);
+        assert_eq!(hashes[0], hashes[2]);
+
+        // the canonical tree is the one built by `from_sorted`
+        let entries = (0..100).map(|n| (seq_key(n), vec![123; 60]));
+        let mut tree = Tree::from_sorted(entries).expect("expected tree");
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        assert_eq!(tree.hash(), hashes[0]);
+
+        // later values for the same key take precedence
+        let entries = vec![(vec![1], vec![1]), (vec![0], vec![0]), (vec![1], vec![2])];
+        let expected = Tree::canonical_root_hash(vec![(vec![0], vec![0]), (vec![1], vec![2])]);
+        assert_eq!(Tree::canonical_root_hash(entries), expected);
+    }
+
+    #[test]
+    fn structurally_eq() {
+        let tree = make_tree_seq(10);
+        let mut pruned = make_tree_seq(10);
+        pruned.commit(&mut MemStore::new()).expect("commit failed");
+        assert!(pruned.link(true).unwrap().is_reference());
+        assert!(tree.structurally_eq(&pruned));
+        assert!(pruned.structurally_eq(&tree));
+        assert!(tree.structurally_eq(&make_tree_seq(10)));
+
+        let batch = [(seq_key(3), Op::Put(vec![1]))];
+        let walker = Walker::new(make_tree_seq(10), PanicSource {});
+        let modified = Walker::apply_to(Some(walker), &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        assert!(!modified.structurally_eq(&tree));
+        assert!(!modified.structurally_eq(&pruned));
+
+        let mut changed = modified;
+        changed.commit(&mut NoopCommit {}).expect("commit failed");
+        assert!(!changed.structurally_eq(&tree));
+        assert!(!changed.structurally_eq(&pruned));
+    }
 }
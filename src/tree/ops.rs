@@ -1,14 +1,31 @@
-use super::{Fetch, Link, Tree, Walker};
-use crate::error::Result;
-use failure::bail;
+use super::{Aggregate, Fetch, Hasher, Link, Tree, Walker};
+use crate::error::{Error, ErrorKind, Result};
+use failure::{bail, format_err};
+use std::cmp::Ordering;
 use std::collections::LinkedList;
 use std::fmt;
+use std::iter::repeat;
 use Op::*;
 
 /// An operation to be applied to a key in the store.
+#[derive(Clone)]
 pub enum Op {
+    /// Inserts the value, overwriting the existing value if the key exists.
     Put(Vec<u8>),
+    /// Inserts the value only if the key does not exist, otherwise leaves the
+    /// existing value untouched.
+    PutIfAbsent(Vec<u8>),
+    /// Overwrites the value of an existing key. Applying the batch fails if
+    /// the key does not exist.
+    Update(Vec<u8>),
+    /// Removes the key, if it exists.
     Delete,
+    /// Combines the existing value of the key with the given operand, using
+    /// the merge function given when applying the batch (see
+    /// `Walker::apply_to_with_merge`), or inserts the operand as the value if
+    /// the key does not exist. Unlike other operations, several merges for the
+    /// same key may be in a batch, and are applied in batch order.
+    Merge(Vec<u8>),
 }
 
 impl fmt::Debug for Op {
@@ -18,7 +35,10 @@ impl fmt::Debug for Op {
             "{}",
             match self {
                 Put(value) => format!("Put({:?})", value),
+                PutIfAbsent(value) => format!("PutIfAbsent({:?})", value),
+                Update(value) => format!("Update({:?})", value),
                 Delete => "Delete".to_string(),
+                Merge(operand) => format!("Merge({:?})", operand),
             }
         )
     }
@@ -27,142 +47,315 @@ impl fmt::Debug for Op {
 /// A single `(key, operation)` pair.
 pub type BatchEntry = (Vec<u8>, Op);
 
-/// A mapping of keys and operations. Keys should be sorted and unique.
+/// A mapping of keys and operations. Keys should be sorted and unique, other
+/// than for consecutive `Op::Merge` entries.
 pub type Batch = [BatchEntry];
 
+/// A function which combines the existing value of a key with the operand of
+/// an `Op::Merge`, called as `merge(key, existing_value, operand)` and
+/// returning the new value. It must be deterministic, so that every replica
+/// which applies the same batch computes the same values (and root hash).
+pub type MergeFn<'a> = dyn Fn(&[u8], &[u8], &[u8]) -> Result<Vec<u8>> + 'a;
+
+/// Limits on the lengths of the keys and values in a batch, to defend against
+/// memory exhaustion from untrusted writes. Checked by `EntryLimits::check`
+/// (and by `Merk::apply_with_limits`) before a batch is applied. The default
+/// limits are unlimited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EntryLimits {
+    /// The maximum length of a key, in bytes.
+    pub max_key_length: usize,
+
+    /// The maximum length of a value, in bytes. `Delete` operations have no
+    /// value, so they are only checked against `max_key_length`.
+    pub max_value_length: usize,
+}
+
+impl Default for EntryLimits {
+    fn default() -> Self {
+        EntryLimits {
+            max_key_length: usize::MAX,
+            max_value_length: usize::MAX,
+        }
+    }
+}
+
+impl EntryLimits {
+    /// Returns an error naming the index of the first entry in the batch whose
+    /// key or value is longer than the limits, along with its length and the
+    /// limit it exceeds.
+    pub fn check(&self, batch: &Batch) -> Result<()> {
+        for (index, (key, op)) in batch.iter().enumerate() {
+            if key.len() > self.max_key_length {
+                bail!(
+                    "Entry at index {} is too large: key length {} exceeds limit of {}",
+                    index,
+                    key.len(),
+                    self.max_key_length
+                );
+            }
+
+            let value = match op {
+                Put(value) | PutIfAbsent(value) | Update(value) | Merge(value) => value,
+                Delete => continue,
+            };
+            if value.len() > self.max_value_length {
+                bail!(
+                    "Entry at index {} is too large: value length {} exceeds limit of {}",
+                    index,
+                    value.len(),
+                    self.max_value_length
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
 /// A source of data which panics when called. Useful when creating a store
-/// which always keeps the state in memory.
+/// which always keeps the state in memory. See `ErrSource` for a source which
+/// returns an error instead.
 #[derive(Clone)]
 pub struct PanicSource {}
-impl Fetch for PanicSource {
-    fn fetch(&self, _link: &Link) -> Result<Tree> {
+impl<H: Hasher, A: Aggregate> Fetch<H, A> for PanicSource {
+    fn fetch(&self, _link: &Link<H, A>) -> Result<Tree<H, A>> {
         unreachable!("'fetch' should not have been called")
     }
 }
 
-impl<S> Walker<S>
+/// A source of data which returns an `ErrorKind::NoSource` error when called.
+/// Like `PanicSource`, but an unexpected fetch (e.g. caused by a bug, or by
+/// fuzzed input) can be handled by the caller rather than unwinding.
+#[derive(Clone)]
+pub struct ErrSource {}
+impl<H: Hasher, A: Aggregate> Fetch<H, A> for ErrSource {
+    fn fetch(&self, _link: &Link<H, A>) -> Result<Tree<H, A>> {
+        Err(ErrorKind::NoSource.into())
+    }
+}
+
+impl<S, H, A> Walker<S, H, A>
 where
-    S: Fetch + Sized + Send + Clone,
+    S: Fetch<H, A> + Sized + Send + Clone,
+    H: Hasher,
+    A: Aggregate,
 {
     /// Applies a batch of operations, possibly creating a new tree if
     /// `maybe_tree` is `None`. This is similar to `Walker<S>::apply`, but does
     /// not require a non-empty tree.
     ///
-    /// Keys in batch must be sorted and unique.
+    /// Keys in batch must be sorted and unique. Returns an error if the batch
+    /// contains an empty key, since empty keys are not allowed in trees (empty
+    /// values are allowed).
     pub fn apply_to(
         maybe_tree: Option<Self>,
         batch: &Batch,
-    ) -> Result<(Option<Tree>, LinkedList<Vec<u8>>)> {
-        let (maybe_walker, deleted_keys) = if batch.is_empty() {
-            (maybe_tree, LinkedList::default())
+    ) -> Result<(Option<Tree<H, A>>, LinkedList<Vec<u8>>)> {
+        let (maybe_tree, deleted_keys, _) = Self::apply_to_with_effects(maybe_tree, batch)?;
+        Ok((maybe_tree, deleted_keys))
+    }
+
+    /// Like `apply_to`, but also returns whether each entry in the batch took
+    /// effect, in the same order as the batch. An entry has no effect if it is
+    /// a `PutIfAbsent` for a key which already exists, or a `Delete` for a key
+    /// which does not exist.
+    ///
+    /// Returns an error if the batch contains an `Update` for a key which does
+    /// not exist, or contains an `Op::Merge` (see `apply_to_with_merge`).
+    ///
+    /// Keys in batch must be sorted and unique.
+    pub fn apply_to_with_effects(
+        maybe_tree: Option<Self>,
+        batch: &Batch,
+    ) -> Result<(Option<Tree<H, A>>, LinkedList<Vec<u8>>, Vec<bool>)> {
+        if let Some((key, _)) = batch.iter().find(|(_, op)| matches!(op, Merge(_))) {
+            bail!(
+                "Cannot apply Merge for key {:?} without a merge function",
+                key
+            );
+        }
+
+        Self::apply_to_inner(maybe_tree, batch, &no_merge)
+    }
+
+    /// Like `apply_to`, but also applies `Op::Merge` entries, combining the
+    /// existing value of each merged key with the operands of its merges (in
+    /// batch order) by calling `merge`. If the key does not exist, the first
+    /// operand is inserted as its value, and any later operands are merged
+    /// into it. Merging into values as the tree is traversed saves reading
+    /// them before writing the batch.
+    ///
+    /// Keys in batch must be sorted, and must be unique other than for
+    /// consecutive merges. Returns an error if `merge` does.
+    pub fn apply_to_with_merge<F>(
+        maybe_tree: Option<Self>,
+        batch: &Batch,
+        merge: F,
+    ) -> Result<(Option<Tree<H, A>>, LinkedList<Vec<u8>>)>
+    where
+        F: Fn(&[u8], &[u8], &[u8]) -> Result<Vec<u8>>,
+    {
+        let (maybe_tree, deleted_keys, _) = Self::apply_to_inner(maybe_tree, batch, &merge)?;
+        Ok((maybe_tree, deleted_keys))
+    }
+
+    /// Implementation of `apply_to_with_effects` and `apply_to_with_merge`,
+    /// applying `Op::Merge` entries with `merge`.
+    fn apply_to_inner(
+        maybe_tree: Option<Self>,
+        batch: &Batch,
+        merge: &MergeFn,
+    ) -> Result<(Option<Tree<H, A>>, LinkedList<Vec<u8>>, Vec<bool>)> {
+        // the batch is sorted, so an empty key can only be first
+        if let Some((key, _)) = batch.first() {
+            if key.is_empty() {
+                bail!("Keys in batch must not be empty");
+            }
+        }
+
+        let (maybe_walker, deleted_keys, effects) = if batch.is_empty() {
+            (maybe_tree, LinkedList::default(), vec![])
         } else {
             match maybe_tree {
-                None => return Ok((Self::build(batch)?, LinkedList::default())),
-                Some(tree) => tree.apply(batch)?,
+                None => {
+                    let (maybe_tree, effects) = Self::build(batch, merge)?;
+                    return Ok((maybe_tree, LinkedList::default(), effects));
+                }
+                Some(tree) => tree.apply(batch, merge)?,
             }
         };
 
         let maybe_tree = maybe_walker.map(|walker| walker.into_inner());
-        Ok((maybe_tree, deleted_keys))
+        Ok((maybe_tree, deleted_keys, effects))
     }
 
-    /// Builds a `Tree` from a batch of inserts. Fails if the batch contains a
-    /// `Delete` operation.
+    /// Builds a `Tree` from a batch of inserts. Any `Delete` operations in the
+    /// batch refer to keys which do not exist in the tree, so they are skipped,
+    /// and any `Update` operations result in an error. Returns the tree and
+    /// whether each entry took effect.
     ///
-    /// Keys in batch must be sorted and unique.
-    fn build(batch: &Batch) -> Result<Option<Tree>> {
+    /// Keys in batch must be sorted and unique (other than for merges).
+    fn build(batch: &Batch, merge: &MergeFn) -> Result<(Option<Tree<H, A>>, Vec<bool>)> {
         if batch.is_empty() {
-            return Ok(None);
+            return Ok((None, vec![]));
         }
 
-        let mid_index = batch.len() / 2;
-        let (mid_key, mid_op) = &batch[mid_index];
+        let (start, end) = key_run(batch, batch.len() / 2);
+        let (mid_key, mid_op) = &batch[start];
         let mid_value = match mid_op {
-            Delete => bail!("Tried to delete non-existent key {:?}", mid_key),
-            Put(value) => value,
+            Delete => {
+                // deleting a non-existent key is a no-op, so build the tree out
+                // of the remaining entries
+                let (maybe_left, mut effects) = Self::build(&batch[..start], merge)?;
+                let maybe_left = maybe_left.map(|tree| Walker::new(tree, PanicSource {}));
+                let right_batch = &batch[end..];
+                let (maybe_tree, _, mut right_effects) =
+                    Walker::<PanicSource, H, A>::apply_to_inner(maybe_left, right_batch, merge)?;
+                effects.push(false);
+                effects.append(&mut right_effects);
+                return Ok((maybe_tree, effects));
+            }
+            Update(_) => bail!("Cannot update key {:?} which does not exist", mid_key),
+            // TODO: take from batch so we don't have to clone
+            Put(value) | PutIfAbsent(value) => value.to_vec(),
+            Merge(_) => merge_values(mid_key, None, &batch[start..end], merge)?,
         };
 
-        // TODO: take from batch so we don't have to clone
-        let mid_tree = Tree::new(mid_key.to_vec(), mid_value.to_vec());
+        let mid_tree = Tree::with_hasher(mid_key.to_vec(), mid_value);
         let mid_walker = Walker::new(mid_tree, PanicSource {});
-        Ok(mid_walker
-            .recurse(batch, mid_index, true)?
-            .0 // use walker, ignore deleted_keys since it should be empty
-            .map(|w| w.into_inner()))
+        // ignore deleted_keys since it should be empty
+        let (maybe_walker, _, mut effects) = mid_walker.recurse(batch, start, end, merge)?;
+        effects.splice(start..start, repeat(true).take(end - start));
+        Ok((maybe_walker.map(|w| w.into_inner()), effects))
     }
 
     /// Applies a batch of operations to an existing tree. This is similar to
     /// `Walker<S>::apply`_to, but requires a populated tree.
     ///
-    /// Keys in batch must be sorted and unique.
-    fn apply(self, batch: &Batch) -> Result<(Option<Self>, LinkedList<Vec<u8>>)> {
+    /// Keys in batch must be sorted and unique (other than for merges).
+    fn apply(
+        self,
+        batch: &Batch,
+        merge: &MergeFn,
+    ) -> Result<(Option<Self>, LinkedList<Vec<u8>>, Vec<bool>)> {
         // binary search to see if this node's key is in the batch, and to split
         // into left and right batches
         let search = batch.binary_search_by(|(key, _op)| key.as_slice().cmp(self.tree().key()));
-        let tree = if let Ok(index) = search {
-            // a key matches this node's key, apply op to this node
-            match &batch[index].1 {
+        let (tree, start, end, mid_effect) = if let Ok(index) = search {
+            // a key matches this node's key, apply op (or merges) to this node
+            let (start, end) = key_run(batch, index);
+            match &batch[start].1 {
                 // TODO: take vec from batch so we don't need to clone
-                Put(value) => self.with_value(value.to_vec()),
+                Put(value) | Update(value) => (self.with_value(value.to_vec()), start, end, true),
+                PutIfAbsent(_) => (self, start, end, false),
+                Merge(_) => {
+                    let tree = self.tree();
+                    let value =
+                        merge_values(tree.key(), Some(tree.value()), &batch[start..end], merge)?;
+                    (self.with_value(value), start, end, true)
+                }
                 Delete => {
                     // TODO: we shouldn't have to do this as 2 different calls to apply
                     let source = self.clone_source();
-                    let wrap = |maybe_tree: Option<Tree>| {
+                    let wrap = |maybe_tree: Option<Tree<H, A>>| {
                         maybe_tree.map(|tree| Self::new(tree, source.clone()))
                     };
                     let key = self.tree().key().to_vec();
                     let maybe_tree = self.remove()?;
 
-                    let (maybe_tree, mut deleted_keys) =
-                        Self::apply_to(maybe_tree, &batch[..index])?;
+                    let (maybe_tree, mut deleted_keys, mut effects) =
+                        Self::apply_to_inner(maybe_tree, &batch[..start], merge)?;
                     let maybe_walker = wrap(maybe_tree);
 
-                    let (maybe_tree, mut deleted_keys_right) =
-                        Self::apply_to(maybe_walker, &batch[index + 1..])?;
+                    let (maybe_tree, mut deleted_keys_right, mut effects_right) =
+                        Self::apply_to_inner(maybe_walker, &batch[end..], merge)?;
                     let maybe_walker = wrap(maybe_tree);
 
                     deleted_keys.append(&mut deleted_keys_right);
                     deleted_keys.push_back(key);
+                    effects.push(true);
+                    effects.append(&mut effects_right);
 
-                    return Ok((maybe_walker, deleted_keys));
+                    return Ok((maybe_walker, deleted_keys, effects));
                 }
             }
         } else {
-            self
+            let index = search.unwrap_err();
+            (self, index, index, false)
         };
 
-        let (mid, exclusive) = match search {
-            Ok(index) => (index, true),
-            Err(index) => (index, false),
-        };
-
-        tree.recurse(batch, mid, exclusive)
+        let (maybe_walker, deleted_keys, mut effects) = tree.recurse(batch, start, end, merge)?;
+        effects.splice(start..start, repeat(mid_effect).take(end - start));
+        Ok((maybe_walker, deleted_keys, effects))
     }
 
     /// Recursively applies operations to the tree's children (if there are any
-    /// operations for them).
+    /// operations for them): the entries before `start` to the left child, and
+    /// the entries from `end` onwards to the right child. Returns whether each
+    /// entry took effect, for all entries in the batch other than those in
+    /// `start..end` (which were applied to this node).
     ///
     /// This recursion executes serially in the same thread, but in the future
     /// will be dispatched to workers in other threads.
     fn recurse(
         self,
         batch: &Batch,
-        mid: usize,
-        exclusive: bool,
-    ) -> Result<(Option<Self>, LinkedList<Vec<u8>>)> {
-        let left_batch = &batch[..mid];
-        let right_batch = if exclusive {
-            &batch[mid + 1..]
-        } else {
-            &batch[mid..]
-        };
+        start: usize,
+        end: usize,
+        merge: &MergeFn,
+    ) -> Result<(Option<Self>, LinkedList<Vec<u8>>, Vec<bool>)> {
+        let left_batch = &batch[..start];
+        let right_batch = &batch[end..];
 
         let mut deleted_keys = LinkedList::default();
+        let mut effects = Vec::with_capacity(batch.len());
 
         let tree = if !left_batch.is_empty() {
             self.walk(true, |maybe_left| {
-                let (maybe_left, mut deleted_keys_left) = Self::apply_to(maybe_left, left_batch)?;
+                let (maybe_left, mut deleted_keys_left, mut effects_left) =
+                    Self::apply_to_inner(maybe_left, left_batch, merge)?;
                 deleted_keys.append(&mut deleted_keys_left);
+                effects.append(&mut effects_left);
                 Ok(maybe_left)
             })?
         } else {
@@ -171,9 +364,10 @@ where
 
         let tree = if !right_batch.is_empty() {
             tree.walk(false, |maybe_right| {
-                let (maybe_right, mut deleted_keys_right) =
-                    Self::apply_to(maybe_right, right_batch)?;
+                let (maybe_right, mut deleted_keys_right, mut effects_right) =
+                    Self::apply_to_inner(maybe_right, right_batch, merge)?;
                 deleted_keys.append(&mut deleted_keys_right);
+                effects.append(&mut effects_right);
                 Ok(maybe_right)
             })?
         } else {
@@ -182,12 +376,12 @@ where
 
         let tree = tree.maybe_balance()?;
 
-        Ok((Some(tree), deleted_keys))
+        Ok((Some(tree), deleted_keys, effects))
     }
 
     /// Gets the wrapped tree's balance factor.
     #[inline]
-    fn balance_factor(&self) -> i8 {
+    fn balance_factor(&self) -> i16 {
         self.tree().balance_factor()
     }
 
@@ -215,16 +409,14 @@ where
     /// Applies an AVL tree rotation, a constant-time operation which only needs
     /// to swap pointers in order to rebalance a tree.
     fn rotate(self, left: bool) -> Result<Self> {
-        unsafe {
-            let (tree, child) = self.detach_expect(left)?;
-            let (child, maybe_grandchild) = child.detach(!left)?;
+        let (tree, child) = self.detach_expect(left)?;
+        let (child, maybe_grandchild) = child.detach(!left)?;
 
-            // attach grandchild to self
-            let tree = tree.attach(left, maybe_grandchild).maybe_balance()?;
+        // attach grandchild to self
+        let tree = tree.attach(left, maybe_grandchild).maybe_balance()?;
 
-            // attach self to child, return child
-            child.attach(!left, Some(tree)).maybe_balance()
-        }
+        // attach self to child, return child
+        child.attach(!left, Some(tree)).maybe_balance()
     }
 
     /// Removes the root node from the tree. Rearranges and rebalances
@@ -235,19 +427,17 @@ where
         let has_right = tree.link(false).is_some();
         let left = tree.child_height(true) > tree.child_height(false);
 
-        let maybe_tree = unsafe {
-            if has_left && has_right {
-                // two children, promote edge of taller child
-                let (tree, tall_child) = self.detach_expect(left)?;
-                let (_, short_child) = tree.detach_expect(!left)?;
-                Some(tall_child.promote_edge(!left, short_child)?)
-            } else if has_left || has_right {
-                // single child, promote it
-                Some(self.detach_expect(left)?.1)
-            } else {
-                // no child
-                None
-            }
+        let maybe_tree = if has_left && has_right {
+            // two children, promote edge of taller child
+            let (tree, tall_child) = self.detach_expect(left)?;
+            let (_, short_child) = tree.detach_expect(!left)?;
+            Some(tall_child.promote_edge(!left, short_child)?)
+        } else if has_left || has_right {
+            // single child, promote it
+            Some(self.detach_expect(left)?.1)
+        } else {
+            // no child
+            None
         };
 
         Ok(maybe_tree)
@@ -270,31 +460,477 @@ where
     fn remove_edge(self, left: bool) -> Result<(Self, Option<Self>)> {
         if self.tree().link(left).is_some() {
             // this node is not the edge, recurse
-            let (tree, child) = unsafe { self.detach_expect(left)? };
+            let (tree, child) = self.detach_expect(left)?;
             let (edge, maybe_child) = child.remove_edge(left)?;
             let tree = tree.attach(left, maybe_child).maybe_balance()?;
             Ok((edge, Some(tree)))
         } else {
             // this node is the edge, detach its child if present
-            unsafe { self.detach(!left) }
+            self.detach(!left)
+        }
+    }
+
+    /// Restores the AVL invariant throughout the whole tree (e.g. for trees
+    /// constructed manually with `Tree::from_fields`), rebalancing each subtree
+    /// bottom-up with rotations. Every node is traversed (fetching pruned nodes
+    /// from the source) and reattached, so all links are recomputed and the
+    /// tree must be committed afterwards.
+    pub fn rebalance(self) -> Result<Self> {
+        self.walk(true, |maybe_left| {
+            maybe_left.map(Self::rebalance).transpose()
+        })?
+        .walk(false, |maybe_right| {
+            maybe_right.map(Self::rebalance).transpose()
+        })?
+        .maybe_balance()
+    }
+
+    /// Adds `tree` as the leftmost descendant if `left` is `true`, otherwise as
+    /// the rightmost descendant, rebalancing the nodes on the way back up. Only
+    /// the edge of the tree is traversed, so no keys are compared. The caller
+    /// must ensure the key of `tree` is less than (or greater than,
+    /// respectively) every key in the tree.
+    fn push_edge(self, left: bool, tree: Tree<H, A>) -> Result<Self> {
+        self.walk(left, |maybe_child| match maybe_child {
+            None => Ok(Some(tree)),
+            Some(child) => child
+                .push_edge(left, tree)
+                .map(|child| Some(child.into_inner())),
+        })?
+        .maybe_balance()
+    }
+
+    /// Splits the tree into a tree of the entries with keys less than `key`,
+    /// and a tree of the entries with keys greater than or equal to `key`
+    /// (either of which may be empty). Both trees are balanced.
+    ///
+    /// Only the nodes on the path to `key` are detached and rejoined, so this
+    /// takes `O(height)` time. The rejoined nodes are linked as modified, so
+    /// both trees must be committed afterwards.
+    pub fn split_at(self, key: &[u8]) -> Result<(Option<Self>, Option<Self>)> {
+        let (tree, maybe_left) = self.detach(true)?;
+        let (mid, maybe_right) = tree.detach(false)?;
+
+        if key <= mid.tree().key() {
+            let (lower, upper) = match maybe_left {
+                Some(left) => left.split_at(key)?,
+                None => (None, None),
+            };
+            Ok((lower, Some(Self::join(upper, mid, maybe_right)?)))
+        } else {
+            let (lower, upper) = match maybe_right {
+                Some(right) => right.split_at(key)?,
+                None => (None, None),
+            };
+            Ok((Some(Self::join(maybe_left, mid, lower)?), upper))
+        }
+    }
+
+    /// Replaces the subtree rooted at the node with key `key` with `subtree`,
+    /// e.g. to graft in a subtree received during state sync without
+    /// reinserting each of its entries.
+    ///
+    /// The keys of `subtree` must be between the keys of the replaced node's
+    /// nearest ancestors on either side, so the ordering of the tree is kept.
+    /// The nodes on the path to `key` are rejoined around the new subtree (as
+    /// in `split_at`), so the tree stays balanced even if `subtree` has a
+    /// different height than the subtree it replaces. The rejoined nodes are
+    /// linked as modified, so the tree must be committed afterwards.
+    ///
+    /// Returns an error if `key` is not in the tree, if `subtree` has keys
+    /// outside of the allowed range, or if the edges of `subtree` are pruned
+    /// (so its range can't be checked). These are checked before any node is
+    /// detached, fetching the pruned nodes on the path to `key` in place, but
+    /// the walker is still consumed; `Tree::replace_subtree` returns the tree
+    /// along with the error.
+    pub fn replace_subtree(mut self, key: &[u8], subtree: Tree<H, A>) -> Result<Self> {
+        let source = self.clone_source();
+        self.tree_mut()
+            .check_replace_subtree(key, &subtree, &source)?;
+        self.replace_subtree_inner(key, subtree)
+    }
+
+    /// Recursive implementation of `replace_subtree`, once the replacement has
+    /// been checked with `Tree::check_replace_subtree`.
+    fn replace_subtree_inner(self, key: &[u8], subtree: Tree<H, A>) -> Result<Self> {
+        let left = match key.cmp(self.tree().key()) {
+            Ordering::Equal => return Ok(Walker::new(subtree, self.clone_source())),
+            Ordering::Less => true,
+            Ordering::Greater => false,
+        };
+
+        let (tree, maybe_left) = self.detach(true)?;
+        let (mid, maybe_right) = tree.detach(false)?;
+
+        if left {
+            let left = match maybe_left {
+                Some(left) => left.replace_subtree_inner(key, subtree)?,
+                None => return Err(ErrorKind::KeyNotFound(key.to_vec()).into()),
+            };
+            Self::join(Some(left), mid, maybe_right)
+        } else {
+            let right = match maybe_right {
+                Some(right) => right.replace_subtree_inner(key, subtree)?,
+                None => return Err(ErrorKind::KeyNotFound(key.to_vec()).into()),
+            };
+            Self::join(maybe_left, mid, Some(right))
+        }
+    }
+
+    /// Joins two trees through `mid`, a single node with no children whose key
+    /// is greater than all keys in `maybe_left` and less than all keys in
+    /// `maybe_right`, returning a balanced tree. Descends along the edge of the
+    /// taller tree until reaching a subtree of similar height to the shorter
+    /// tree, so this takes time proportional to the difference in heights.
+    fn join(maybe_left: Option<Self>, mid: Self, maybe_right: Option<Self>) -> Result<Self> {
+        let height =
+            |maybe_tree: &Option<Self>| maybe_tree.as_ref().map_or(0, |t| t.tree().height());
+        let (left_height, right_height) = (height(&maybe_left), height(&maybe_right));
+
+        if left_height > right_height.saturating_add(1) {
+            let (left, maybe_inner) = maybe_left.unwrap().detach(false)?;
+            let joined = Self::join(maybe_inner, mid, maybe_right)?;
+            left.attach(false, Some(joined)).maybe_balance()
+        } else if right_height > left_height.saturating_add(1) {
+            let (right, maybe_inner) = maybe_right.unwrap().detach(true)?;
+            let joined = Self::join(maybe_left, mid, maybe_inner)?;
+            right.attach(true, Some(joined)).maybe_balance()
+        } else {
+            Ok(mid.attach(true, maybe_left).attach(false, maybe_right))
+        }
+    }
+}
+
+impl<H: Hasher, A: Aggregate> Tree<H, A> {
+    /// Restores the AVL invariant throughout the whole tree. See
+    /// `Walker::rebalance`.
+    ///
+    /// Panics if the tree contains pruned nodes, since there is no source to
+    /// fetch them from.
+    pub fn rebalance(self) -> Result<Self> {
+        Walker::new(self, PanicSource {})
+            .rebalance()
+            .map(Walker::into_inner)
+    }
+
+    /// Splits the tree into a tree of the entries with keys less than `key`,
+    /// and a tree of the entries with keys greater than or equal to `key`. See
+    /// `Walker::split_at`.
+    ///
+    /// Panics if the path to `key` contains pruned nodes, since there is no
+    /// source to fetch them from.
+    pub fn split_at(self, key: &[u8]) -> Result<(Option<Self>, Option<Self>)> {
+        let (lower, upper) = Walker::new(self, PanicSource {}).split_at(key)?;
+        Ok((lower.map(Walker::into_inner), upper.map(Walker::into_inner)))
+    }
+
+    /// Replaces the subtree rooted at the node with key `key` with `subtree`.
+    /// See `Walker::replace_subtree`.
+    ///
+    /// The replacement is checked before any node is detached, so on error the
+    /// tree is returned unchanged along with the error. Returns an error if the
+    /// path to `key` contains pruned nodes, since there is no source to fetch
+    /// them from, and panics if other nodes needed to rejoin the path are
+    /// pruned.
+    pub fn replace_subtree(
+        mut self,
+        key: &[u8],
+        subtree: Tree<H, A>,
+    ) -> std::result::Result<Self, (Self, Error)> {
+        if let Err(err) = self.check_replace_subtree(key, &subtree, &ErrSource {}) {
+            return Err((self, err));
         }
+
+        Ok(Walker::new(self, PanicSource {})
+            .replace_subtree_inner(key, subtree)
+            .map(Walker::into_inner)
+            // fetching from `PanicSource` panics rather than returning an error
+            .expect("replace failed"))
+    }
+
+    /// Checks that the node with key `key` can be replaced with `subtree` (see
+    /// `Walker::replace_subtree`), loading the pruned nodes on the path to
+    /// `key` from `source`. No node is detached, so the tree keeps all of its
+    /// nodes on error.
+    fn check_replace_subtree<S: Fetch<H, A>>(
+        &mut self,
+        key: &[u8],
+        subtree: &Tree<H, A>,
+        source: &S,
+    ) -> Result<()> {
+        let (first_key, last_key) = match (subtree.first_key(), subtree.last_key()) {
+            (Some(first_key), Some(last_key)) => (first_key, last_key),
+            _ => bail!("Cannot check range of subtree with pruned edges"),
+        };
+
+        let (lower, upper) = self.replace_subtree_bounds(key, source)?;
+        if lower.map_or(false, |lower| first_key <= lower.as_slice())
+            || upper.map_or(false, |upper| last_key >= upper.as_slice())
+        {
+            bail!("Subtree keys are out of range for key {:?}", key);
+        }
+        Ok(())
+    }
+
+    /// Returns the keys of the nearest ancestors on either side of the node
+    /// with key `key`, which are the exclusive bounds on the keys of a subtree
+    /// replacing it. Returns an error if `key` is not in the tree.
+    fn replace_subtree_bounds<S: Fetch<H, A>>(
+        &mut self,
+        key: &[u8],
+        source: &S,
+    ) -> Result<(Option<Vec<u8>>, Option<Vec<u8>>)> {
+        let left = match key.cmp(self.key()) {
+            Ordering::Equal => return Ok((None, None)),
+            Ordering::Less => true,
+            Ordering::Greater => false,
+        };
+
+        if let Some(Link::Reference { .. }) = self.link(left) {
+            self.load(left, source)?;
+        }
+        let (lower, upper) = match self.child_mut(left) {
+            Some(child) => child.replace_subtree_bounds(key, source)?,
+            None => return Err(ErrorKind::KeyNotFound(key.to_vec()).into()),
+        };
+
+        let bound = Some(self.key().to_vec());
+        Ok(if left {
+            (lower, upper.or(bound))
+        } else {
+            (lower.or(bound), upper)
+        })
+    }
+
+    /// Inserts an entry with a key greater than every key in the tree, e.g.
+    /// when appending keys in increasing order, and returns the new root. The
+    /// result is the same as `insert`, but only the right edge of the tree is
+    /// traversed, without comparing keys.
+    ///
+    /// The ordering of the key is only checked in debug builds, where this
+    /// panics if the key is not greater than the tree's last key. Panics if the
+    /// right edge of the tree contains pruned nodes, since there is no source
+    /// to fetch them from.
+    pub fn push_max(self, key: Vec<u8>, value: Vec<u8>) -> Self {
+        debug_assert!(
+            self.last_key()
+                .map_or(true, |last_key| key.as_slice() > last_key),
+            "Key pushed with push_max must be greater than all keys in the tree"
+        );
+        self.push_edge(false, key, value)
+    }
+
+    /// Like `push_max`, but inserts an entry with a key less than every key in
+    /// the tree, traversing only the left edge of the tree.
+    pub fn push_min(self, key: Vec<u8>, value: Vec<u8>) -> Self {
+        debug_assert!(
+            !key.is_empty()
+                && self
+                    .first_key()
+                    .map_or(true, |first_key| key.as_slice() < first_key),
+            "Key pushed with push_min must be non-empty and less than all keys in the tree"
+        );
+        self.push_edge(true, key, value)
+    }
+
+    /// Implementation of `push_max` and `push_min`. See `Walker::push_edge`.
+    fn push_edge(self, left: bool, key: Vec<u8>, value: Vec<u8>) -> Self {
+        Walker::new(self, PanicSource {})
+            .push_edge(left, Tree::with_hasher(key, value))
+            .map(Walker::into_inner)
+            // fetching from `PanicSource` panics rather than returning an error
+            .expect("push failed")
+    }
+
+    /// Inserts a single entry into the tree (or updates its value if the key
+    /// is already in the tree), rebalancing as needed, and returns the new
+    /// root. Equivalent to applying a batch containing only `(key,
+    /// Op::Put(value))`.
+    ///
+    /// Returns the tree unchanged along with an error if the key is empty.
+    /// Panics if the path to `key` contains pruned nodes, since there is no
+    /// source to fetch them from.
+    pub fn insert(self, key: Vec<u8>, value: Vec<u8>) -> std::result::Result<Self, (Self, Error)> {
+        if key.is_empty() {
+            return Err((self, format_err!("Keys in batch must not be empty")));
+        }
+
+        let batch = [(key, Op::Put(value))];
+        let walker = Walker::new(self, PanicSource {});
+        let (maybe_tree, _) = Walker::<PanicSource, H, A>::apply_to(Some(walker), &batch)
+            // the key was checked, and fetching from `PanicSource` panics
+            // rather than returning an error
+            .expect("insert failed");
+        Ok(maybe_tree.expect("expected tree after put"))
     }
 }
 
+impl Tree {
+    /// Joins two trees through a separator entry, returning a balanced tree
+    /// containing the entries of `maybe_left`, the separator, and the entries
+    /// of `maybe_right`. The inverse of `split_at`.
+    ///
+    /// The separator is hung from the edge of the taller tree at the height of
+    /// the shorter one, so this takes time proportional to the difference in
+    /// the trees' heights. The rejoined nodes are linked as modified, so the
+    /// tree must be committed afterwards.
+    ///
+    /// Returns an error if any key in `maybe_left` is not less than the
+    /// separator's key, or any key in `maybe_right` is not greater than it.
+    /// Panics if the edge of the taller tree contains pruned nodes.
+    pub fn join(
+        maybe_left: Option<Tree>,
+        separator: (Vec<u8>, Vec<u8>),
+        maybe_right: Option<Tree>,
+    ) -> Result<Tree> {
+        let (key, value) = separator;
+        if let Some(last_key) = maybe_left.as_ref().and_then(|left| left.last_key()) {
+            if last_key >= key.as_slice() {
+                bail!(
+                    "Cannot join tree with keys not less than separator key {:?}",
+                    key
+                );
+            }
+        }
+        if let Some(first_key) = maybe_right.as_ref().and_then(|right| right.first_key()) {
+            if first_key <= key.as_slice() {
+                bail!(
+                    "Cannot join tree with keys not greater than separator key {:?}",
+                    key
+                );
+            }
+        }
+
+        let wrap = |tree: Tree| Walker::new(tree, PanicSource {});
+        Walker::join(
+            maybe_left.map(wrap),
+            wrap(Tree::new(key, value)),
+            maybe_right.map(wrap),
+        )
+        .map(Walker::into_inner)
+    }
+
+    /// Applies a batch of operations to `maybe_tree`, or builds a new tree from
+    /// the batch if `maybe_tree` is `None`. Returns the resulting tree, which is
+    /// `None` if all of its entries were deleted.
+    ///
+    /// Unlike `Walker::apply_to`, the batch is checked before it is applied:
+    /// returns an error naming the index of the first key which is less than
+    /// or equal to the key before it, rather than producing a corrupt tree.
+    /// Panics if the tree contains pruned nodes on the paths to the batch's
+    /// keys.
+    pub fn apply_sorted_batch(maybe_tree: Option<Tree>, batch: &Batch) -> Result<Option<Tree>> {
+        check_batch(batch)?;
+
+        let maybe_walker = maybe_tree.map(|tree| Walker::new(tree, PanicSource {}));
+        Walker::<PanicSource>::apply_to(maybe_walker, batch).map(|(maybe_tree, _)| maybe_tree)
+    }
+}
+
+/// Calls `validate` on each key in the batch, in order, for checking
+/// application-specific invariants of keys (e.g. a fixed length) before a
+/// batch is applied (see `Merk::apply_with_validator`). Returns an error naming
+/// the index of the first key which `validate` rejects, along with its error.
+pub fn check_keys<F>(batch: &Batch, validate: F) -> Result<()>
+where
+    F: Fn(&[u8]) -> Result<()>,
+{
+    for (index, (key, _)) in batch.iter().enumerate() {
+        if let Err(err) = validate(key) {
+            bail!("Key at index {} is invalid: {}", index, err);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the range of indexes of the entries in the batch with the same key
+/// as the entry at `index`. Only consecutive merges may share a key, so for
+/// other operations this is just `index..index + 1`.
+fn key_run(batch: &Batch, index: usize) -> (usize, usize) {
+    let key = &batch[index].0;
+    let start = batch[..index]
+        .iter()
+        .rposition(|(other, _)| other != key)
+        .map_or(0, |i| i + 1);
+    let end = batch[index..]
+        .iter()
+        .position(|(other, _)| other != key)
+        .map_or(batch.len(), |i| index + i);
+    (start, end)
+}
+
+/// Returns the value of `key` after applying `merges`, a run of `Op::Merge`
+/// entries for the key, in order: each operand is combined with the value so
+/// far using `merge`, starting from `existing` if the key exists, or from the
+/// first operand if it does not.
+pub(crate) fn merge_values(
+    key: &[u8],
+    existing: Option<&[u8]>,
+    merges: &Batch,
+    merge: &MergeFn,
+) -> Result<Vec<u8>> {
+    let mut value: Option<Vec<u8>> = None;
+    for (_, op) in merges {
+        let operand = match op {
+            Merge(operand) => operand,
+            _ => bail!("Keys in batch must be unique, other than for merges"),
+        };
+        value = Some(match (value, existing) {
+            (Some(value), _) => merge(key, &value, operand)?,
+            (None, Some(existing)) => merge(key, existing, operand)?,
+            (None, None) => operand.clone(),
+        });
+    }
+    value.ok_or_else(|| format_err!("Expected merge for key {:?}", key))
+}
+
+/// The merge function used when applying batches without one, which never
+/// merges since batches with merges are rejected beforehand.
+fn no_merge(key: &[u8], _existing: &[u8], _operand: &[u8]) -> Result<Vec<u8>> {
+    bail!(
+        "Cannot apply Merge for key {:?} without a merge function",
+        key
+    )
+}
+
+/// Returns an error if the keys in the batch are not sorted and unique,
+/// including the index of the first offending key in the message. Consecutive
+/// `Op::Merge` entries may share a key.
+pub(crate) fn check_batch(batch: &Batch) -> Result<()> {
+    for (i, pair) in batch.windows(2).enumerate() {
+        match pair[0].0.cmp(&pair[1].0) {
+            Ordering::Less => {}
+            Ordering::Equal => match (&pair[0].1, &pair[1].1) {
+                (Merge(_), Merge(_)) => {}
+                _ => bail!(
+                    "Keys in batch must be unique (duplicate at index {})",
+                    i + 1
+                ),
+            },
+            Ordering::Greater => {
+                bail!("Keys in batch must be sorted (unsorted at index {})", i + 1)
+            }
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::test_utils::{
-        apply_memonly, assert_tree_invariants, del_entry, make_tree_seq, seq_key,
+        apply_memonly, apply_memonly_unchecked, apply_to_memonly, assert_tree_invariants,
+        del_entry, make_batch_seq, make_tree_rand, make_tree_seq, put_entry, seq_key,
     };
     use crate::tree::*;
+    use rand::prelude::*;
 
     #[test]
     fn simple_insert() {
         let batch = [(b"foo2".to_vec(), Op::Put(b"bar2".to_vec()))];
         let tree = Tree::new(b"foo".to_vec(), b"bar".to_vec());
-        let (maybe_walker, deleted_keys) = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+        let (maybe_walker, deleted_keys, _) = Walker::new(tree, PanicSource {})
+            .apply(&batch, &no_merge)
             .expect("apply errored");
         let walker = maybe_walker.expect("should be Some");
         assert_eq!(walker.tree().key(), b"foo");
@@ -306,8 +942,8 @@ mod test {
     fn simple_update() {
         let batch = [(b"foo".to_vec(), Op::Put(b"bar2".to_vec()))];
         let tree = Tree::new(b"foo".to_vec(), b"bar".to_vec());
-        let (maybe_walker, deleted_keys) = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+        let (maybe_walker, deleted_keys, _) = Walker::new(tree, PanicSource {})
+            .apply(&batch, &no_merge)
             .expect("apply errored");
         let walker = maybe_walker.expect("should be Some");
         assert_eq!(walker.tree().key(), b"foo");
@@ -328,11 +964,13 @@ mod test {
             Some(Link::Loaded {
                 hash: [123; 20],
                 child_heights: (0, 0),
+                descendant_count: 1,
+                aggregate: NoAggregate,
                 tree: Tree::new(b"foo2".to_vec(), b"bar2".to_vec()),
             }),
         );
-        let (maybe_walker, deleted_keys) = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+        let (maybe_walker, deleted_keys, _) = Walker::new(tree, PanicSource {})
+            .apply(&batch, &no_merge)
             .expect("apply errored");
         let walker = maybe_walker.expect("should be Some");
         assert_eq!(walker.tree().key(), b"foo");
@@ -344,19 +982,88 @@ mod test {
     }
 
     #[test]
-    #[should_panic]
     fn delete_non_existent() {
         let batch = [(b"foo2".to_vec(), Op::Delete)];
         let tree = Tree::new(b"foo".to_vec(), b"bar".to_vec());
-        Walker::new(tree, PanicSource {}).apply(&batch).unwrap();
+        let (maybe_walker, deleted_keys, _) = Walker::new(tree, PanicSource {})
+            .apply(&batch, &no_merge)
+            .expect("apply errored");
+        let walker = maybe_walker.expect("should be Some");
+        assert_eq!(walker.tree().key(), b"foo");
+        assert!(walker.tree().link(true).is_none());
+        assert!(walker.tree().link(false).is_none());
+        assert!(deleted_keys.is_empty());
+    }
+
+    #[test]
+    fn build_with_non_existent_deletes() {
+        let batch = vec![
+            (vec![1], Op::Put(vec![1])),
+            (vec![2], Op::Delete),
+            (vec![3], Op::Put(vec![3])),
+            (vec![4], Op::Delete),
+        ];
+        let tree = apply_to_memonly(None, &batch).expect("expected tree");
+        let entries: Vec<_> = tree.iter().collect();
+        assert_eq!(
+            entries,
+            vec![(&[1u8][..], &[1u8][..]), (&[3u8][..], &[3u8][..])]
+        );
+    }
+
+    fn assert_delete_matches_build(keys: &[u8], deletes: &[u8]) {
+        let batch: Vec<_> = keys.iter().map(|k| (vec![*k], Op::Put(vec![*k]))).collect();
+        let tree = apply_to_memonly(None, &batch).expect("expected tree");
+
+        let batch: Vec<_> = deletes.iter().map(|k| (vec![*k], Op::Delete)).collect();
+        let tree = apply_memonly(tree, &batch);
+
+        let batch: Vec<_> = keys
+            .iter()
+            .filter(|k| !deletes.contains(*k))
+            .map(|k| (vec![*k], Op::Put(vec![*k])))
+            .collect();
+        let expected = apply_to_memonly(None, &batch).expect("expected tree");
+
+        assert_eq!(tree.hash(), expected.hash());
+    }
+
+    #[test]
+    fn delete_leaf() {
+        assert_delete_matches_build(&[1, 2, 3], &[3]);
+    }
+
+    #[test]
+    fn delete_node_with_one_child() {
+        assert_delete_matches_build(&[1, 2, 3, 4], &[2]);
+    }
+
+    #[test]
+    fn delete_node_with_two_children() {
+        assert_delete_matches_build(&[1, 2, 3, 4], &[3]);
+    }
+
+    #[test]
+    fn delete_marks_path_modified() {
+        let batch: Vec<_> = (1..5u8).map(|k| (vec![k], Op::Put(vec![k]))).collect();
+        let tree = apply_to_memonly(None, &batch).expect("expected tree");
+        assert_eq!(tree.key(), &[3]);
+
+        let batch = [(vec![2], Op::Delete)];
+        let (maybe_walker, _, _) = Walker::new(tree, PanicSource {})
+            .apply(&batch, &no_merge)
+            .expect("apply errored");
+        let tree = maybe_walker.expect("should be Some").into_inner();
+        assert!(tree.link(true).expect("expected link").is_modified());
+        assert!(tree.link(false).expect("expected link").is_stored());
     }
 
     #[test]
     fn delete_only_node() {
         let batch = [(b"foo".to_vec(), Op::Delete)];
         let tree = Tree::new(b"foo".to_vec(), b"bar".to_vec());
-        let (maybe_walker, deleted_keys) = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+        let (maybe_walker, deleted_keys, _) = Walker::new(tree, PanicSource {})
+            .apply(&batch, &no_merge)
             .expect("apply errored");
         assert!(maybe_walker.is_none());
         assert_eq!(deleted_keys.len(), 1);
@@ -367,8 +1074,8 @@ mod test {
     fn delete_deep() {
         let tree = make_tree_seq(50);
         let batch = [del_entry(5)];
-        let (maybe_walker, deleted_keys) = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+        let (maybe_walker, deleted_keys, _) = Walker::new(tree, PanicSource {})
+            .apply(&batch, &no_merge)
             .expect("apply errored");
         maybe_walker.expect("should be Some");
         assert_eq!(deleted_keys.len(), 1);
@@ -379,8 +1086,8 @@ mod test {
     fn delete_recursive() {
         let tree = make_tree_seq(50);
         let batch = [del_entry(29), del_entry(34)];
-        let (maybe_walker, mut deleted_keys) = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+        let (maybe_walker, mut deleted_keys, _) = Walker::new(tree, PanicSource {})
+            .apply(&batch, &no_merge)
             .expect("apply errored");
         maybe_walker.expect("should be Some");
         assert_eq!(deleted_keys.len(), 2);
@@ -392,8 +1099,8 @@ mod test {
     fn delete_recursive_2() {
         let tree = make_tree_seq(10);
         let batch = [del_entry(7), del_entry(9)];
-        let (maybe_walker, deleted_keys) = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+        let (maybe_walker, deleted_keys, _) = Walker::new(tree, PanicSource {})
+            .apply(&batch, &no_merge)
             .expect("apply errored");
         maybe_walker.expect("should be Some");
         let mut deleted_keys: Vec<&Vec<u8>> = deleted_keys.iter().collect();
@@ -469,4 +1176,589 @@ mod test {
         assert_eq!(tree.child(true).expect("expected child").key(), &[31]);
         assert_eq!(tree.child(false).expect("expected child").key(), &[79]);
     }
+
+    #[test]
+    fn rebalance_chain() {
+        let mut tree = Tree::new(vec![49], vec![49]);
+        for i in (0..49u8).rev() {
+            tree = Tree::new(vec![i], vec![i]).attach(false, Some(tree));
+        }
+        assert_eq!(tree.height(), 50);
+        assert!(tree.verify_avl().is_err());
+
+        let mut tree = tree.rebalance().expect("rebalance failed");
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        tree.verify_avl().expect("expected balanced tree");
+        assert_tree_invariants(&tree);
+        assert!(tree.height() <= 7);
+        assert_eq!(tree.count(), 50);
+
+        let keys: Vec<_> = tree.iter().map(|(key, _)| key.to_vec()).collect();
+        let expected: Vec<_> = (0..50u8).map(|i| vec![i]).collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn rebalance_incorrect_heights() {
+        let tree = Tree::from_fields(
+            vec![2],
+            vec![2],
+            Default::default(),
+            Some(Link::Loaded {
+                hash: Default::default(),
+                child_heights: (3, 0),
+                descendant_count: 5,
+                aggregate: NoAggregate,
+                tree: Tree::new(vec![1], vec![1]),
+            }),
+            None,
+        );
+        assert!(tree.verify_avl().is_err());
+
+        let mut tree = tree.rebalance().expect("rebalance failed");
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        tree.verify_avl().expect("expected balanced tree");
+        assert_eq!(tree.child_heights(), (1, 0));
+        assert_eq!(tree.count(), 2);
+    }
+
+    fn assert_split(tree: Tree, key: &[u8]) {
+        let keys: Vec<_> = tree.iter().map(|(key, _)| key.to_vec()).collect();
+        let (lower, upper) = tree.split_at(key).expect("split failed");
+
+        let mut split_keys = vec![];
+        for (maybe_tree, below) in vec![(lower, true), (upper, false)] {
+            if let Some(mut tree) = maybe_tree {
+                tree.commit(&mut NoopCommit {}).expect("commit failed");
+                tree.verify_avl().expect("expected balanced tree");
+                assert_tree_invariants(&tree);
+                for (k, _) in tree.iter() {
+                    assert_eq!(k < key, below);
+                    split_keys.push(k.to_vec());
+                }
+            }
+        }
+
+        assert_eq!(split_keys, keys);
+    }
+
+    fn build_tree(keys: std::ops::Range<u64>) -> Option<Tree> {
+        Tree::from_sorted(keys.map(|n| (seq_key(n), vec![n as u8])))
+    }
+
+    #[test]
+    fn join() {
+        let cases = vec![
+            (0..0, 1..1),
+            (0..1, 2..3),
+            (0..1_000, 1_001..1_002),
+            (0..3, 4..2_000),
+            (0..500, 501..1_000),
+            (0..0, 1..100),
+            (0..100, 101..101),
+        ];
+        for (left_keys, right_keys) in cases {
+            let separator = left_keys.end;
+            let mut tree = Tree::join(
+                build_tree(left_keys.clone()),
+                (seq_key(separator), vec![separator as u8]),
+                build_tree(right_keys.clone()),
+            )
+            .expect("join failed");
+
+            tree.commit(&mut NoopCommit {}).expect("commit failed");
+            tree.verify_avl().expect("expected balanced tree");
+            assert_tree_invariants(&tree);
+
+            let keys: Vec<_> = tree.iter().map(|(key, _)| key.to_vec()).collect();
+            let expected: Vec<_> = left_keys
+                .chain(separator..=separator)
+                .chain(right_keys)
+                .map(seq_key)
+                .collect();
+            assert_eq!(keys, expected);
+        }
+    }
+
+    #[test]
+    fn join_unordered() {
+        let separator = (seq_key(10), vec![10]);
+        assert!(Tree::join(build_tree(0..11), separator.clone(), None).is_err());
+        assert!(Tree::join(None, separator.clone(), build_tree(10..20)).is_err());
+        assert!(Tree::join(build_tree(0..10), separator, build_tree(11..20)).is_ok());
+    }
+
+    #[test]
+    fn split_and_join() {
+        let tree = make_tree_seq(100);
+        let expected: Vec<_> = tree.iter().map(|(key, _)| key.to_vec()).collect();
+
+        let (lower, upper) = tree.split_at(&seq_key(40)).expect("split failed");
+        let upper = apply_memonly(upper.expect("expected tree"), &[del_entry(40)]);
+        let mut tree =
+            Tree::join(lower, (seq_key(40), vec![123; 60]), Some(upper)).expect("join failed");
+
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        tree.verify_avl().expect("expected balanced tree");
+        let keys: Vec<_> = tree.iter().map(|(key, _)| key.to_vec()).collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn split_at() {
+        for &n in [0u64, 1, 5, 99, 100, 150].iter() {
+            assert_split(make_tree_seq(100), &seq_key(n));
+        }
+        assert_split(make_tree_seq(100), &[]);
+        assert_split(make_tree_seq(100), &[255; 20]);
+        assert_split(Tree::new(vec![5], vec![5]), &[5]);
+        assert_split(Tree::new(vec![5], vec![5]), &[6]);
+
+        let tree = make_tree_rand(1_000, 100, 7);
+        let keys: Vec<_> = tree.iter().map(|(key, _)| key.to_vec()).collect();
+        for key in keys.iter().step_by(97) {
+            assert_split(make_tree_rand(1_000, 100, 7), key);
+        }
+    }
+
+    fn apply_with_effects(tree: Tree, batch: &Batch) -> Result<(Tree, Vec<bool>)> {
+        let walker = Walker::new(tree, PanicSource {});
+        let (maybe_tree, _, effects) = Walker::apply_to_with_effects(Some(walker), batch)?;
+        Ok((maybe_tree.expect("expected tree"), effects))
+    }
+
+    #[test]
+    fn put_if_absent() {
+        let tree = make_tree_seq(10);
+        let batch = [
+            (seq_key(3), Op::PutIfAbsent(vec![1])),
+            (seq_key(20), Op::PutIfAbsent(vec![2])),
+        ];
+        let (tree, effects) = apply_with_effects(tree, &batch).expect("apply errored");
+        assert_eq!(effects, vec![false, true]);
+        assert_eq!(tree.get(&seq_key(3)).unwrap(), Some(&[123; 60][..]));
+        assert_eq!(tree.get(&seq_key(20)).unwrap(), Some(&[2][..]));
+    }
+
+    #[test]
+    fn update() {
+        let tree = make_tree_seq(10);
+        let batch = [(seq_key(3), Op::Update(vec![1]))];
+        let (tree, effects) = apply_with_effects(tree, &batch).expect("apply errored");
+        assert_eq!(effects, vec![true]);
+        assert_eq!(tree.get(&seq_key(3)).unwrap(), Some(&[1][..]));
+
+        let batch = [(seq_key(20), Op::Update(vec![2]))];
+        assert!(apply_with_effects(tree, &batch).is_err());
+    }
+
+    #[test]
+    fn update_empty() {
+        let batch = [(vec![1], Op::Put(vec![1])), (vec![2], Op::Update(vec![2]))];
+        assert!(Walker::<PanicSource>::apply_to(None, &batch).is_err());
+    }
+
+    #[test]
+    fn mixed_ops_effects() {
+        let tree = make_tree_seq(10);
+        let batch = [
+            (seq_key(1), Op::Put(vec![1])),
+            (seq_key(2), Op::PutIfAbsent(vec![2])),
+            (seq_key(3), Op::Update(vec![3])),
+            (seq_key(4), Op::Delete),
+            (seq_key(15), Op::Delete),
+            (seq_key(16), Op::PutIfAbsent(vec![16])),
+            (seq_key(17), Op::Put(vec![17])),
+        ];
+        let walker = Walker::new(tree, PanicSource {});
+        let (maybe_tree, deleted_keys, effects) =
+            Walker::apply_to_with_effects(Some(walker), &batch).expect("apply errored");
+        let mut tree = maybe_tree.expect("expected tree");
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        assert_tree_invariants(&tree);
+
+        assert_eq!(effects, vec![true, false, true, true, false, true, true]);
+        assert_eq!(
+            deleted_keys.into_iter().collect::<Vec<_>>(),
+            vec![seq_key(4)]
+        );
+        assert_eq!(tree.get(&seq_key(1)).unwrap(), Some(&[1][..]));
+        assert_eq!(tree.get(&seq_key(2)).unwrap(), Some(&[123; 60][..]));
+        assert_eq!(tree.get(&seq_key(3)).unwrap(), Some(&[3][..]));
+        assert_eq!(tree.get(&seq_key(4)).unwrap(), None);
+        assert_eq!(tree.get(&seq_key(15)).unwrap(), None);
+        assert_eq!(tree.get(&seq_key(16)).unwrap(), Some(&[16][..]));
+        assert_eq!(tree.get(&seq_key(17)).unwrap(), Some(&[17][..]));
+    }
+
+    #[test]
+    fn build_effects() {
+        let batch = [
+            (vec![1], Op::Delete),
+            (vec![2], Op::PutIfAbsent(vec![2])),
+            (vec![3], Op::Delete),
+            (vec![4], Op::Put(vec![4])),
+        ];
+        let (maybe_tree, _, effects) =
+            Walker::<PanicSource>::apply_to_with_effects(None, &batch).expect("apply errored");
+        assert_eq!(effects, vec![false, true, false, true]);
+        assert_eq!(maybe_tree.expect("expected tree").iter().count(), 2);
+    }
+
+    #[test]
+    fn apply_sorted_batch() {
+        let tree = make_tree_seq(10);
+
+        let unsorted = make_batch_seq(20..23)
+            .into_iter()
+            .chain(make_batch_seq(15..16))
+            .collect::<Vec<_>>();
+        let err = Tree::apply_sorted_batch(None, &unsorted)
+            .err()
+            .expect("expected error");
+        assert!(err.to_string().contains("sorted (unsorted at index 3)"));
+
+        let duplicate = vec![put_entry(20), put_entry(21), put_entry(21)];
+        let err = Tree::apply_sorted_batch(None, &duplicate)
+            .err()
+            .expect("expected error");
+        assert!(err.to_string().contains("unique (duplicate at index 2)"));
+
+        let batch = vec![put_entry(3), del_entry(5), put_entry(20)];
+        let mut tree = Tree::apply_sorted_batch(Some(tree), &batch)
+            .expect("apply failed")
+            .expect("expected tree");
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        assert_tree_invariants(&tree);
+        assert_eq!(tree.iter().count(), 11);
+        assert!(tree.get(&seq_key(5)).unwrap().is_none());
+        assert!(tree.get(&seq_key(20)).unwrap().is_some());
+    }
+
+    #[test]
+    fn empty_key_and_value() {
+        let batch = vec![(vec![], Op::Put(vec![1])), put_entry(1)];
+        let err = Walker::<PanicSource>::apply_to(None, &batch)
+            .err()
+            .expect("expected error");
+        assert_eq!(err.to_string(), "Keys in batch must not be empty");
+
+        let tree = make_tree_seq(10);
+        let walker = Walker::new(tree, PanicSource {});
+        assert!(Walker::apply_to(Some(walker), &batch).is_err());
+
+        // empty values are allowed, and are distinct from other values
+        let batch = vec![
+            (seq_key(1), Op::Put(vec![])),
+            (seq_key(2), Op::Put(vec![0])),
+        ];
+        let mut tree = apply_to_memonly(None, &batch).expect("expected tree");
+        assert_eq!(tree.get(&seq_key(1)).unwrap(), Some(&[][..]));
+        assert_eq!(tree.get(&seq_key(2)).unwrap(), Some(&[0][..]));
+        let empty_kv_hash = kv_hash(&seq_key(1), &[]);
+        assert_ne!(empty_kv_hash, kv_hash(&seq_key(1), &[0]));
+
+        let batch = vec![(seq_key(2), Op::Put(vec![]))];
+        let walker = Walker::new(tree, PanicSource {});
+        tree = Walker::apply_to(Some(walker), &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        assert_eq!(tree.get(&seq_key(2)).unwrap(), Some(&[][..]));
+    }
+
+    fn make_tree_sorted(keys: std::ops::Range<u64>, value: u8) -> Tree {
+        Tree::from_sorted(keys.map(|n| (seq_key(n), vec![value]))).expect("expected tree")
+    }
+
+    #[test]
+    fn replace_subtree() {
+        // the left subtree of the root (keys 0..15) is rooted at key 7
+        let tree = make_tree_sorted(0..31, 1);
+        let mut tree = tree
+            .replace_subtree(&seq_key(7), make_tree_sorted(0..15, 2))
+            .ok()
+            .expect("replace failed");
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+
+        let mut expected =
+            Tree::from_sorted((0..31).map(|n| (seq_key(n), vec![if n < 15 { 2 } else { 1 }])))
+                .expect("expected tree");
+        expected.commit(&mut NoopCommit {}).expect("commit failed");
+        assert_eq!(tree.hash(), expected.hash());
+    }
+
+    #[test]
+    fn replace_subtree_rebalances() {
+        let tree = make_tree_sorted(0..31, 1);
+        let mut tree = tree
+            .replace_subtree(&seq_key(7), make_tree_sorted(3..4, 2))
+            .ok()
+            .expect("replace failed");
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        assert_tree_invariants(&tree);
+
+        let keys: Vec<_> = tree.iter().map(|(key, _)| key.to_vec()).collect();
+        let expected_keys: Vec<_> = (3..4).chain(15..31).map(seq_key).collect();
+        assert_eq!(keys, expected_keys);
+    }
+
+    #[test]
+    fn tree_insert() {
+        let mut keys: Vec<u64> = (0..100).collect();
+        keys.shuffle(&mut SmallRng::seed_from_u64(0));
+
+        // inserting one at a time matches applying one-element batches
+        let mut tree = Tree::new(seq_key(keys[0]), vec![123; 60]);
+        let mut expected = Tree::new(seq_key(keys[0]), vec![123; 60]);
+        for &n in keys[1..].iter() {
+            tree = tree
+                .insert(seq_key(n), vec![123; 60])
+                .ok()
+                .expect("insert failed");
+            expected = apply_memonly_unchecked(expected, &[put_entry(n)]);
+        }
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        assert_tree_invariants(&tree);
+        assert_eq!(tree.hash(), expected.hash());
+
+        // and contains the same entries as one sorted batch
+        let sorted = apply_to_memonly(None, &make_batch_seq(0..100)).expect("expected tree");
+        assert!(tree.iter().eq(sorted.iter()));
+
+        // inserting an existing key updates its value
+        let mut tree = tree
+            .insert(seq_key(50), vec![1])
+            .ok()
+            .expect("insert failed");
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        assert_eq!(tree.get(&seq_key(50)).unwrap(), Some(&[1][..]));
+        assert_eq!(tree.count(), 100);
+
+        // inserting an empty key returns the tree along with the error
+        let expected_hash = tree.hash();
+        let (tree, err) = match tree.insert(vec![], vec![1]) {
+            Err(res) => res,
+            Ok(_) => panic!("expected error"),
+        };
+        assert_eq!(err.to_string(), "Keys in batch must not be empty");
+        assert_eq!(tree.hash(), expected_hash);
+        assert_eq!(tree.count(), 100);
+    }
+
+    #[test]
+    fn entry_limits() {
+        let limits = EntryLimits {
+            max_key_length: 8,
+            max_value_length: 60,
+        };
+        let mut batch = make_batch_seq(0..10);
+        batch.push((vec![255; 8], Op::Delete));
+        assert!(limits.check(&batch).is_ok());
+        assert!(EntryLimits::default().check(&batch).is_ok());
+
+        batch[3].1 = Op::Update(vec![0; 61]);
+        let err = limits.check(&batch).err().expect("expected error");
+        assert_eq!(
+            err.to_string(),
+            "Entry at index 3 is too large: value length 61 exceeds limit of 60"
+        );
+
+        batch[3].1 = Op::Put(vec![0; 60]);
+        batch[10].0 = vec![255; 9];
+        let err = limits.check(&batch).err().expect("expected error");
+        assert_eq!(
+            err.to_string(),
+            "Entry at index 10 is too large: key length 9 exceeds limit of 8"
+        );
+    }
+
+    #[test]
+    fn push_max_and_min() {
+        let mut pushed = Tree::new(seq_key(500), vec![123; 60]);
+        let mut inserted = Tree::new(seq_key(500), vec![123; 60]);
+        for i in 501..1000 {
+            pushed = pushed.push_max(seq_key(i), vec![123; 60]);
+            inserted = inserted.insert(seq_key(i), vec![123; 60]).ok().unwrap();
+        }
+        for i in (0..500).rev() {
+            pushed = pushed.push_min(seq_key(i), vec![123; 60]);
+            inserted = inserted.insert(seq_key(i), vec![123; 60]).ok().unwrap();
+        }
+        pushed.commit(&mut NoopCommit {}).expect("commit failed");
+        inserted.commit(&mut NoopCommit {}).expect("commit failed");
+        assert_tree_invariants(&pushed);
+
+        // pushing gives the same tree as inserting, and the same entries as a
+        // sorted build
+        assert_eq!(pushed.hash(), inserted.hash());
+
+        let entries = (0..1000).map(|i| (seq_key(i), vec![123; 60]));
+        let sorted = Tree::from_sorted(entries).expect("expected tree");
+        assert!(pushed.iter().eq(sorted.iter()));
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "Key pushed with push_max must be greater than all keys in the tree")]
+    fn push_max_unordered() {
+        make_tree_seq(10).push_max(seq_key(5), vec![1]);
+    }
+
+    fn add_u64(_key: &[u8], existing: &[u8], operand: &[u8]) -> Result<Vec<u8>> {
+        let mut a = [0; 8];
+        let mut b = [0; 8];
+        a.copy_from_slice(existing);
+        b.copy_from_slice(operand);
+        Ok((u64::from_be_bytes(a) + u64::from_be_bytes(b))
+            .to_be_bytes()
+            .to_vec())
+    }
+
+    #[test]
+    fn apply_merge_counter() {
+        let one = 1u64.to_be_bytes().to_vec();
+        let batch: Vec<_> = (0..100).map(|i| (seq_key(i), Merge(one.clone()))).collect();
+        let tree = Walker::<PanicSource>::apply_to_with_merge(None, &batch, add_u64)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+
+        let batch = vec![
+            (seq_key(10), Merge(one.clone())),
+            (seq_key(10), Merge(one.clone())),
+            (seq_key(10), Merge(one.clone())),
+            (seq_key(20), Op::Put(7u64.to_be_bytes().to_vec())),
+            (seq_key(30), Op::Delete),
+            (seq_key(150), Merge(5u64.to_be_bytes().to_vec())),
+            (seq_key(150), Merge(one.clone())),
+        ];
+        let (tree, deleted_keys) = Walker::<PanicSource>::apply_to_with_merge(
+            Some(Walker::new(tree, PanicSource {})),
+            &batch,
+            add_u64,
+        )
+        .expect("apply failed");
+        let mut tree = tree.expect("expected tree");
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        assert_tree_invariants(&tree);
+        assert_eq!(deleted_keys.len(), 1);
+
+        let get = |i| tree.get(&seq_key(i)).unwrap().map(|v| v.to_vec());
+        assert_eq!(get(0), Some(one.clone()));
+        assert_eq!(get(10), Some(4u64.to_be_bytes().to_vec()));
+        assert_eq!(get(20), Some(7u64.to_be_bytes().to_vec()));
+        assert_eq!(get(30), None);
+        assert_eq!(get(150), Some(6u64.to_be_bytes().to_vec()));
+        assert_eq!(tree.count(), 100);
+
+        // merges need a merge function
+        assert!(Walker::<PanicSource>::apply_to(None, &batch).is_err());
+
+        assert!(check_batch(&batch).is_ok());
+        let mut duplicate = batch.clone();
+        duplicate[1].1 = Op::Put(one);
+        assert!(check_batch(&duplicate).is_err());
+    }
+
+    #[test]
+    fn check_keys_fixed_length() {
+        let validate = |key: &[u8]| {
+            if key.len() != 8 {
+                bail!("expected 8-byte key, got {} bytes", key.len());
+            }
+            Ok(())
+        };
+
+        let mut batch = make_batch_seq(0..10);
+        assert!(check_keys(&batch, validate).is_ok());
+        assert!(check_keys(&[], validate).is_ok());
+
+        batch[4].0.push(0);
+        let err = check_keys(&batch, validate).err().expect("expected error");
+        assert_eq!(
+            err.to_string(),
+            "Key at index 4 is invalid: expected 8-byte key, got 9 bytes"
+        );
+    }
+
+    #[test]
+    fn err_source() {
+        let mut tree = make_tree_seq(100);
+        tree.commit(&mut MemStore::new()).expect("commit failed");
+        assert!(tree.link(true).unwrap().is_reference());
+
+        let walker = Walker::new(tree, ErrSource {});
+        let err = walker
+            .walk(true, |_| -> Result<Option<Tree>> { Ok(None) })
+            .err()
+            .expect("expected error");
+        assert_eq!(err.downcast_ref::<ErrorKind>(), Some(&ErrorKind::NoSource));
+    }
+
+    #[test]
+    fn replace_subtree_invalid() {
+        let replace =
+            |key, subtree| make_tree_sorted(0..31, 1).replace_subtree(&seq_key(key), subtree);
+        assert!(replace(100, make_tree_sorted(0..15, 2)).is_err());
+        assert!(replace(7, make_tree_sorted(10..16, 2)).is_err());
+        assert!(replace(23, make_tree_sorted(14..20, 2)).is_err());
+        assert!(replace(23, make_tree_sorted(16..31, 2)).is_ok());
+    }
+
+    #[test]
+    fn replace_subtree_returns_tree_on_error() {
+        let mut tree = make_tree_sorted(0..31, 1);
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        let expected_hash = tree.hash();
+
+        let (tree, err) = match tree.replace_subtree(&seq_key(100), make_tree_sorted(0..15, 2)) {
+            Err(res) => res,
+            Ok(_) => panic!("expected error"),
+        };
+        assert_eq!(
+            err.downcast_ref::<ErrorKind>(),
+            Some(&ErrorKind::KeyNotFound(seq_key(100)))
+        );
+        assert_eq!(tree.hash(), expected_hash);
+
+        let (mut tree, _) = match tree.replace_subtree(&seq_key(23), make_tree_sorted(14..20, 2)) {
+            Err(res) => res,
+            Ok(_) => panic!("expected error"),
+        };
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        assert_eq!(tree.hash(), expected_hash);
+        assert_eq!(tree.iter().count(), 31);
+    }
+
+    #[test]
+    fn replace_subtree_pruned() {
+        let mut tree = make_tree_sorted(0..31, 1);
+        let mut store = MemStore::new();
+        tree.commit(&mut store).expect("commit failed");
+        let expected_hash = tree.hash();
+
+        // the tree has no source, so the pruned path is an error
+        let (tree, err) = match tree.replace_subtree(&seq_key(7), make_tree_sorted(0..15, 2)) {
+            Err(res) => res,
+            Ok(_) => panic!("expected error"),
+        };
+        assert_eq!(err.downcast_ref::<ErrorKind>(), Some(&ErrorKind::NoSource));
+        assert_eq!(tree.hash(), expected_hash);
+        assert!(tree.link(true).unwrap().is_reference());
+
+        // a walker fetches the pruned path from its source
+        let mut tree = Walker::new(tree, &store)
+            .replace_subtree(&seq_key(7), make_tree_sorted(0..15, 2))
+            .expect("replace failed")
+            .into_inner();
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+
+        let mut expected = make_tree_sorted(0..31, 1)
+            .replace_subtree(&seq_key(7), make_tree_sorted(0..15, 2))
+            .ok()
+            .expect("replace failed");
+        expected.commit(&mut NoopCommit {}).expect("commit failed");
+        assert_eq!(tree.hash(), expected.hash());
+    }
 }
@@ -0,0 +1,147 @@
+//! Optional `serde` support for `Tree`, `Link`, and `KV` (requires the `serde`
+//! feature), mainly useful for inspecting trees with JSON or other formats.
+//!
+//! Trees are serialized as their root node's key/value pair and the links to
+//! its children, rather than the children themselves. Links are serialized by
+//! their child's key, hash, child heights, and descendant count, so
+//! deserialized trees only contain `Link::Reference` children, which can later
+//! be fetched with `Tree::load`.
+
+use super::hash::Hash;
+use super::kv::KV;
+use super::{Link, Tree, TreeInner};
+use serde::de::{Deserialize, Deserializer};
+use serde::ser::{Error, Serialize, SerializeStruct, Serializer};
+
+impl Serialize for KV {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("KV", 2)?;
+        state.serialize_field("key", self.key())?;
+        state.serialize_field("value", self.value())?;
+        state.end()
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename = "KV")]
+struct KVFields {
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl<'de> Deserialize<'de> for KV {
+    /// Deserializes the key/value pair and computes its hash.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let KVFields { key, value } = KVFields::deserialize(deserializer)?;
+        Ok(KV::new(key, value))
+    }
+}
+
+impl Serialize for Link {
+    /// Serializes the link's key, hash, child heights, and descendant count.
+    /// Returns an error for `Link::Modified` since its hash is not known.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.is_modified() {
+            return Err(S::Error::custom("Cannot serialize Link::Modified"));
+        }
+
+        let mut state = serializer.serialize_struct("Link", 4)?;
+        state.serialize_field("key", self.key())?;
+        state.serialize_field("hash", self.hash())?;
+        state.serialize_field("child_heights", &self.child_heights())?;
+        state.serialize_field("descendant_count", &self.descendant_count())?;
+        state.end()
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename = "Link")]
+struct LinkFields {
+    key: Vec<u8>,
+    hash: Hash,
+    child_heights: (u8, u8),
+    descendant_count: u64,
+}
+
+impl<'de> Deserialize<'de> for Link {
+    /// Deserializes the link as a `Link::Reference`.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let LinkFields {
+            key,
+            hash,
+            child_heights,
+            descendant_count,
+        } = LinkFields::deserialize(deserializer)?;
+
+        Ok(Link::Reference {
+            hash,
+            child_heights,
+            descendant_count,
+            aggregate: NoAggregate,
+            key,
+        })
+    }
+}
+
+impl Serialize for Tree {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Tree", 3)?;
+        state.serialize_field("kv", &self.inner.kv)?;
+        state.serialize_field("left", &self.inner.left)?;
+        state.serialize_field("right", &self.inner.right)?;
+        state.end()
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename = "Tree")]
+struct TreeFields {
+    kv: KV,
+    left: Option<Link>,
+    right: Option<Link>,
+}
+
+impl<'de> Deserialize<'de> for Tree {
+    /// Deserializes the root node, with any children as `Link::Reference`.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let TreeFields { kv, left, right } = TreeFields::deserialize(deserializer)?;
+        Ok(Tree {
+            inner: Box::new(TreeInner::new(kv, left, right)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Tree;
+    use crate::test_utils::make_tree_seq;
+
+    #[test]
+    fn json_round_trip() {
+        let tree = make_tree_seq(100);
+
+        let json = serde_json::to_string(&tree).expect("serialize failed");
+        let deserialized: Tree = serde_json::from_str(&json).expect("deserialize failed");
+
+        assert_eq!(deserialized.key(), tree.key());
+        assert_eq!(deserialized.value(), tree.value());
+        assert_eq!(deserialized.kv_hash(), tree.kv_hash());
+        assert_eq!(deserialized.hash(), tree.hash());
+        assert_eq!(deserialized.count(), tree.count());
+
+        for &left in [true, false].iter() {
+            let link = deserialized.link(left).expect("expected link");
+            let original = tree.link(left).expect("expected link");
+            assert!(link.is_reference());
+            assert_eq!(link.key(), original.key());
+            assert_eq!(link.hash(), original.hash());
+            assert_eq!(link.height(), original.height());
+        }
+    }
+
+    #[test]
+    fn serialize_modified() {
+        let tree = Tree::new(vec![1], vec![2]).attach(true, Some(Tree::new(vec![0], vec![1])));
+        assert!(serde_json::to_string(&tree).is_err());
+    }
+}
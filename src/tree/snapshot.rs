@@ -0,0 +1,211 @@
+use super::hash::Hash;
+use super::walk::Fetch;
+use super::Tree;
+use crate::error::Result;
+use crate::proofs::query::{is_above_end, is_below_start};
+use failure::bail;
+use std::cmp::Ordering;
+use std::ops::Bound;
+
+/// An immutable view of a committed version of a tree, which can be read while
+/// the tree it was taken from continues to be modified.
+///
+/// A snapshot holds its own copy of the root node, and loads all other nodes
+/// from its source as they are needed, so changes made to the original tree in
+/// memory (which `commit` makes in place) are never visible to it. The source
+/// is the only state it shares with the tree, so the source must keep every
+/// node written to it, rather than overwriting nodes in later commits (e.g.
+/// `MemStore`, which stores nodes by their hash). Sources which store nodes by
+/// key must instead be read at a fixed version, as `Merk::snapshot` does by
+/// reading from a RocksDB snapshot of the store's database.
+pub struct Snapshot<S: Fetch> {
+    root: Tree,
+    source: S,
+}
+
+impl Tree {
+    /// Takes a snapshot of the tree, loading nodes from `source` when it is
+    /// read. See `Snapshot`.
+    ///
+    /// Returns an error if the tree has changes which have not been committed,
+    /// since they will not have been written to the source.
+    pub fn snapshot<S: Fetch>(&self, source: S) -> Result<Snapshot<S>> {
+        for &left in [true, false].iter() {
+            if let Some(link) = self.link(left) {
+                if link.is_modified() || link.is_uncommitted() {
+                    bail!("Cannot snapshot tree with uncommitted changes");
+                }
+            }
+        }
+
+        // decoding turns all links into references, so the copy doesn't retain
+        // any of the tree's children
+        let root = Tree::decode(self.key().to_vec(), self.encode().as_slice());
+        Ok(Snapshot { root, source })
+    }
+}
+
+impl<S: Fetch> Snapshot<S> {
+    /// Returns the root hash of the tree at the time the snapshot was taken.
+    pub fn hash(&self) -> Hash {
+        self.root.hash()
+    }
+
+    /// Gets the value for the given key, or `None` if the key is not in the
+    /// tree.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut maybe_node: Option<Tree> = None;
+        loop {
+            let cursor = maybe_node.as_ref().unwrap_or(&self.root);
+            let left = match key.cmp(cursor.key()) {
+                Ordering::Equal => return Ok(Some(cursor.value().to_vec())),
+                Ordering::Less => true,
+                Ordering::Greater => false,
+            };
+
+            let child = match cursor.link(left) {
+                None => return Ok(None),
+                Some(link) => self.source.fetch(link)?,
+            };
+            maybe_node = Some(child);
+        }
+    }
+
+    /// Returns the entries with keys in the range between `start` and `end`, in
+    /// key order.
+    pub fn range(
+        &self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut entries = vec![];
+        self.range_inner(&self.root, &start, &end, &mut entries)?;
+        Ok(entries)
+    }
+
+    /// Recursive implementation of `range`, appending the entries of `tree`
+    /// which are in the range to `entries`.
+    fn range_inner(
+        &self,
+        tree: &Tree,
+        start: &Bound<Vec<u8>>,
+        end: &Bound<Vec<u8>>,
+        entries: &mut Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<()> {
+        let below_start = is_below_start(start, tree.key());
+        let above_end = is_above_end(end, tree.key());
+
+        if !below_start {
+            if let Some(link) = tree.link(true) {
+                let child = self.source.fetch(link)?;
+                self.range_inner(&child, start, end, entries)?;
+            }
+        }
+
+        if !below_start && !above_end {
+            entries.push((tree.key().to_vec(), tree.value().to_vec()));
+        }
+
+        if !above_end {
+            if let Some(link) = tree.link(false) {
+                let child = self.source.fetch(link)?;
+                self.range_inner(&child, start, end, entries)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{Commit, Link, MemStore, Op, PanicSource, Walker};
+    use super::*;
+    use crate::test_utils::{make_batch_seq, seq_key};
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+
+    /// A `MemStore` shared between a writer and concurrent readers.
+    #[derive(Clone)]
+    struct SharedStore(Arc<RwLock<MemStore>>);
+
+    impl Fetch for SharedStore {
+        fn fetch(&self, link: &Link) -> Result<Tree> {
+            (&*self.0.read().unwrap()).fetch(link)
+        }
+    }
+
+    impl Commit for SharedStore {
+        fn write(&mut self, tree: &Tree) -> Result<()> {
+            self.0.write().unwrap().write(tree)
+        }
+    }
+
+    fn apply(tree: Option<Tree>, batch: &[(Vec<u8>, Op)], store: &mut SharedStore) -> Tree {
+        let walker = tree.map(|tree| Walker::new(tree, store.clone()));
+        let mut tree = Walker::apply_to(walker, batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        tree.commit(store).expect("commit failed");
+        tree
+    }
+
+    #[test]
+    fn snapshot_uncommitted() {
+        let batch = make_batch_seq(0..10);
+        let tree = Walker::<PanicSource>::apply_to(None, &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        assert!(tree.snapshot(PanicSource {}).is_err());
+    }
+
+    #[test]
+    fn snapshot_concurrent_reads() {
+        let mut store = SharedStore(Arc::new(RwLock::new(MemStore::new())));
+        let mut tree = apply(None, &make_batch_seq(0..100), &mut store);
+        let snapshot = tree.snapshot(store.clone()).expect("snapshot failed");
+        let hash = snapshot.hash();
+        assert_eq!(hash, tree.hash());
+
+        let reader = thread::spawn(move || {
+            for _ in 0..20 {
+                for i in 0..100 {
+                    assert_eq!(snapshot.get(&seq_key(i)).unwrap(), Some(vec![123; 60]));
+                }
+                assert_eq!(snapshot.get(&seq_key(150)).unwrap(), None);
+
+                let entries = snapshot
+                    .range(Bound::Included(seq_key(90)), Bound::Unbounded)
+                    .unwrap();
+                let keys: Vec<_> = entries.into_iter().map(|(key, _)| key).collect();
+                assert_eq!(keys, (90..100).map(seq_key).collect::<Vec<_>>());
+            }
+            snapshot
+        });
+
+        for round in 0..20u8 {
+            let batch: Vec<_> = (0..200)
+                .map(|i| (seq_key(i), Op::Put(vec![round])))
+                .collect();
+            tree = apply(Some(tree), &batch, &mut store);
+        }
+
+        let snapshot = reader.join().expect("reader panicked");
+        assert_eq!(snapshot.hash(), hash);
+        assert_ne!(tree.hash(), hash);
+        assert_eq!(snapshot.get(&seq_key(5)).unwrap(), Some(vec![123; 60]));
+
+        let latest = tree.snapshot(store).expect("snapshot failed");
+        assert_eq!(latest.get(&seq_key(5)).unwrap(), Some(vec![19]));
+        assert_eq!(latest.get(&seq_key(150)).unwrap(), Some(vec![19]));
+        let entries = latest
+            .range(Bound::Excluded(seq_key(10)), Bound::Excluded(seq_key(13)))
+            .unwrap();
+        assert_eq!(
+            entries,
+            vec![(seq_key(11), vec![19]), (seq_key(12), vec![19])]
+        );
+    }
+}
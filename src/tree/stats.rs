@@ -0,0 +1,83 @@
+use super::Tree;
+
+/// Statistics about the lengths of the keys and values in the in-memory nodes
+/// of a tree, as returned by `Tree::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TreeStats {
+    /// The number of in-memory nodes.
+    pub node_count: usize,
+    /// The length of the shortest key.
+    pub min_key_len: usize,
+    /// The length of the longest key.
+    pub max_key_len: usize,
+    /// The sum of the lengths of all keys.
+    pub total_key_len: usize,
+    /// The length of the shortest value.
+    pub min_value_len: usize,
+    /// The length of the longest value.
+    pub max_value_len: usize,
+    /// The sum of the lengths of all values.
+    pub total_value_len: usize,
+}
+
+impl Tree {
+    /// Computes statistics about the key and value lengths of the nodes which
+    /// are currently in memory, in a single in-order pass. Pruned subtrees are
+    /// not counted.
+    pub fn stats(&self) -> TreeStats {
+        let mut stats = TreeStats {
+            min_key_len: usize::MAX,
+            min_value_len: usize::MAX,
+            ..Default::default()
+        };
+
+        for (key, value) in self.iter() {
+            stats.node_count += 1;
+            stats.min_key_len = stats.min_key_len.min(key.len());
+            stats.max_key_len = stats.max_key_len.max(key.len());
+            stats.total_key_len += key.len();
+            stats.min_value_len = stats.min_value_len.min(value.len());
+            stats.max_value_len = stats.max_value_len.max(value.len());
+            stats.total_value_len += value.len();
+        }
+
+        stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tree::MemStore;
+
+    #[test]
+    fn stats() {
+        // keys of length 1 to 100, with values 3 times as long
+        let entries = (1..=100u8).map(|len| (vec![len; len as usize], vec![0; 3 * len as usize]));
+        let mut tree = Tree::from_sorted(entries).expect("expected tree");
+
+        let stats = tree.stats();
+        assert_eq!(
+            stats,
+            TreeStats {
+                node_count: 100,
+                min_key_len: 1,
+                max_key_len: 100,
+                total_key_len: 5050,
+                min_value_len: 3,
+                max_value_len: 300,
+                total_value_len: 15150,
+            }
+        );
+
+        // only the root is left in memory after pruning
+        let mut store = MemStore::new();
+        tree.commit(&mut store).expect("commit failed");
+        let len = tree.key().len();
+        let stats = tree.stats();
+        assert_eq!(stats.node_count, 1);
+        assert_eq!(stats.min_key_len, len);
+        assert_eq!(stats.max_key_len, len);
+        assert_eq!(stats.total_value_len, 3 * len);
+    }
+}
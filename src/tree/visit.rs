@@ -0,0 +1,168 @@
+use super::Tree;
+
+/// The order in which `Tree::visit` calls `NodeVisitor::enter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VisitOrder {
+    /// Each node is entered before its children are visited.
+    PreOrder,
+
+    /// Each node is entered after its left subtree is visited and before its
+    /// right subtree is visited, so nodes are entered in key order.
+    InOrder,
+}
+
+/// A read-only operation over the in-memory nodes of a tree, driven by
+/// `Tree::visit`. All methods have default no-op implementations, so visitors
+/// only need to implement the methods they use.
+pub trait NodeVisitor {
+    /// The order in which nodes are entered. Defaults to
+    /// `VisitOrder::InOrder`.
+    fn order(&self) -> VisitOrder {
+        VisitOrder::InOrder
+    }
+
+    /// Called once for each in-memory node, at the point given by `order`.
+    fn enter(&mut self, _tree: &Tree) {}
+
+    /// Called once for each in-memory node, after both of its subtrees have
+    /// been visited.
+    fn leave(&mut self, _tree: &Tree) {}
+}
+
+/// A step of the traversal in `Tree::visit`.
+enum Step<'a> {
+    Descend(&'a Tree),
+    Enter(&'a Tree),
+    Leave(&'a Tree),
+}
+
+impl Tree {
+    /// Visits every node of the tree which is in memory, calling the visitor's
+    /// `enter` and `leave` methods for each. Pruned subtrees are skipped.
+    ///
+    /// Traversal uses an explicit stack rather than recursion, so visiting very
+    /// tall trees will not overflow the call stack.
+    pub fn visit<V: NodeVisitor>(&self, visitor: &mut V) {
+        let order = visitor.order();
+        let mut stack = vec![Step::Descend(self)];
+
+        while let Some(step) = stack.pop() {
+            let tree = match step {
+                Step::Enter(tree) => {
+                    visitor.enter(tree);
+                    continue;
+                }
+                Step::Leave(tree) => {
+                    visitor.leave(tree);
+                    continue;
+                }
+                Step::Descend(tree) => tree,
+            };
+
+            // steps are pushed in reverse, since the last one pushed is taken
+            // first
+            stack.push(Step::Leave(tree));
+            if let Some(right) = tree.child(false) {
+                stack.push(Step::Descend(right));
+            }
+            if order == VisitOrder::InOrder {
+                stack.push(Step::Enter(tree));
+            }
+            if let Some(left) = tree.child(true) {
+                stack.push(Step::Descend(left));
+            }
+            if order == VisitOrder::PreOrder {
+                stack.push(Step::Enter(tree));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tree::MemStore;
+
+    /// Records the keys of the nodes entered and left, in order.
+    struct Recorder {
+        order: VisitOrder,
+        events: Vec<(&'static str, u8)>,
+    }
+
+    impl NodeVisitor for Recorder {
+        fn order(&self) -> VisitOrder {
+            self.order
+        }
+
+        fn enter(&mut self, tree: &Tree) {
+            self.events.push(("enter", tree.key()[0]));
+        }
+
+        fn leave(&mut self, tree: &Tree) {
+            self.events.push(("leave", tree.key()[0]));
+        }
+    }
+
+    fn record(tree: &Tree, order: VisitOrder) -> Vec<(&'static str, u8)> {
+        let mut recorder = Recorder {
+            order,
+            events: vec![],
+        };
+        tree.visit(&mut recorder);
+        recorder.events
+    }
+
+    fn make_tree() -> Tree {
+        // 2 is the root, with children 1 and 4, which have left children 0 and 3
+        Tree::from_sorted((0..5u8).map(|i| (vec![i], vec![i]))).expect("expected tree")
+    }
+
+    #[test]
+    fn visit_in_order() {
+        let events = record(&make_tree(), VisitOrder::InOrder);
+        assert_eq!(
+            events,
+            vec![
+                ("enter", 0),
+                ("leave", 0),
+                ("enter", 1),
+                ("leave", 1),
+                ("enter", 2),
+                ("enter", 3),
+                ("leave", 3),
+                ("enter", 4),
+                ("leave", 4),
+                ("leave", 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn visit_pre_order() {
+        let events = record(&make_tree(), VisitOrder::PreOrder);
+        assert_eq!(
+            events,
+            vec![
+                ("enter", 2),
+                ("enter", 1),
+                ("enter", 0),
+                ("leave", 0),
+                ("leave", 1),
+                ("enter", 4),
+                ("enter", 3),
+                ("leave", 3),
+                ("leave", 4),
+                ("leave", 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn visit_skips_pruned() {
+        let mut tree = make_tree();
+        tree.commit(&mut MemStore::new()).expect("commit failed");
+
+        let events = record(&tree, VisitOrder::InOrder);
+        assert_eq!(events, vec![("enter", 2), ("leave", 2)]);
+    }
+}
@@ -1,11 +1,24 @@
-use super::super::{Link, Tree};
+use super::super::{Aggregate, Blake2bHasher, Hasher, Link, NoAggregate, Tree};
 use crate::error::Result;
+#[cfg(feature = "async")]
+use async_trait::async_trait;
 
 /// A source of data to be used by the tree when encountering a pruned node.
 /// This typcially means fetching the tree node from a backing store by its key,
 /// but could also implement an in-memory cache for example.
-pub trait Fetch {
+pub trait Fetch<H: Hasher = Blake2bHasher, A: Aggregate = NoAggregate> {
     /// Called when the tree needs to fetch a node with the given `Link`. The
     /// `link` value will always be a `Link::Reference` variant.
-    fn fetch(&self, link: &Link) -> Result<Tree>;
+    fn fetch(&self, link: &Link<H, A>) -> Result<Tree<H, A>>;
+}
+
+/// An asynchronous counterpart to `Fetch`, for sources which would otherwise
+/// block when loading pruned nodes (e.g. a key/value store accessed over the
+/// network). Requires the `async` feature.
+#[cfg(feature = "async")]
+#[async_trait]
+pub trait AsyncFetch {
+    /// Called when the tree needs to fetch a node with the given `Link`. The
+    /// `link` value will always be a `Link::Reference` variant.
+    async fn fetch(&self, link: &Link) -> Result<Tree>;
 }
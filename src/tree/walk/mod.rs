@@ -1,28 +1,35 @@
 mod fetch;
 mod ref_walker;
 
-use super::{Link, Tree};
+use super::{side_to_str, Aggregate, Blake2bHasher, Hasher, Link, NoAggregate, Tree};
 use crate::error::Result;
 use crate::owner::Owner;
+use failure::bail;
+#[cfg(feature = "async")]
+pub use fetch::AsyncFetch;
 pub use fetch::Fetch;
-pub use ref_walker::RefWalker;
+pub use ref_walker::{Bfs, RefWalker};
 
 /// Allows traversal of a `Tree`, fetching from the given source when traversing
 /// to a pruned node, detaching children as they are traversed.
-pub struct Walker<S>
+pub struct Walker<S, H = Blake2bHasher, A = NoAggregate>
 where
-    S: Fetch + Sized + Clone + Send,
+    S: Fetch<H, A> + Sized + Clone + Send,
+    H: Hasher,
+    A: Aggregate,
 {
-    tree: Owner<Tree>,
+    tree: Owner<Tree<H, A>>,
     source: S,
 }
 
-impl<S> Walker<S>
+impl<S, H, A> Walker<S, H, A>
 where
-    S: Fetch + Sized + Clone + Send,
+    S: Fetch<H, A> + Sized + Clone + Send,
+    H: Hasher,
+    A: Aggregate,
 {
     /// Creates a `Walker` with the given tree and source.
-    pub fn new(tree: Tree, source: S) -> Self {
+    pub fn new(tree: Tree<H, A>, source: S) -> Self {
         Walker {
             tree: Owner::new(tree),
             source,
@@ -30,8 +37,13 @@ where
     }
 
     /// Similar to `Tree#detach`, but yields a `Walker` which fetches from the
-    /// same source as `self`. Returned tuple is `(updated_self, maybe_child_walker)`.
-    pub unsafe fn detach(mut self, left: bool) -> Result<(Self, Option<Self>)> {
+    /// same source as `self`, fetching the child if it is pruned. Returned
+    /// tuple is `(updated_self, maybe_child_walker)`.
+    ///
+    /// The child's link is removed from `updated_self`, so the child (or a new
+    /// child to take its place) must be reattached with `attach` for the tree to
+    /// keep all of its nodes. `walk` enforces this.
+    pub fn detach(mut self, left: bool) -> Result<(Self, Option<Self>)> {
         let link = match self.tree.link(left) {
             None => return Ok((self, None)),
             Some(link) => link,
@@ -43,7 +55,7 @@ where
                 _ => unreachable!("Expected Some"),
             }
         } else {
-            let link = self.tree.slot_mut(left).take();
+            let link = self.tree.take_link(left);
             match link {
                 Some(Link::Reference { .. }) => (),
                 _ => unreachable!("Expected Some(Link::Reference)"),
@@ -57,15 +69,14 @@ where
 
     /// Similar to `Tree#detach_expect`, but yields a `Walker` which fetches
     /// from the same source as `self`. Returned tuple is `(updated_self, child_walker)`.
-    pub unsafe fn detach_expect(self, left: bool) -> Result<(Self, Self)> {
+    ///
+    /// Returns an error, rather than panicking, if there is no child on the
+    /// given side.
+    pub fn detach_expect(self, left: bool) -> Result<(Self, Self)> {
         let (walker, maybe_child) = self.detach(left)?;
-        if let Some(child) = maybe_child {
-            Ok((walker, child))
-        } else {
-            panic!(
-                "Expected {} child, got None",
-                if left { "left" } else { "right" }
-            );
+        match maybe_child {
+            Some(child) => Ok((walker, child)),
+            None => bail!("Expected {} child, got None", side_to_str(left)),
         }
     }
 
@@ -74,9 +85,9 @@ where
     pub fn walk<F, T>(self, left: bool, f: F) -> Result<Self>
     where
         F: FnOnce(Option<Self>) -> Result<Option<T>>,
-        T: Into<Tree>,
+        T: Into<Tree<H, A>>,
     {
-        let (mut walker, maybe_child) = unsafe { self.detach(left)? };
+        let (mut walker, maybe_child) = self.detach(left)?;
         let new_child = f(maybe_child)?.map(|t| t.into());
         walker.tree.own(|t| t.attach(left, new_child));
         Ok(walker)
@@ -87,27 +98,33 @@ where
     pub fn walk_expect<F, T>(self, left: bool, f: F) -> Result<Self>
     where
         F: FnOnce(Self) -> Result<Option<T>>,
-        T: Into<Tree>,
+        T: Into<Tree<H, A>>,
     {
-        let (mut walker, child) = unsafe { self.detach_expect(left)? };
+        let (mut walker, child) = self.detach_expect(left)?;
         let new_child = f(child)?.map(|t| t.into());
         walker.tree.own(|t| t.attach(left, new_child));
         Ok(walker)
     }
 
     /// Returns an immutable reference to the `Tree` wrapped by this walker.
-    pub fn tree(&self) -> &Tree {
+    pub fn tree(&self) -> &Tree<H, A> {
         &self.tree
     }
 
+    /// Returns a mutable reference to the `Tree` wrapped by this walker, e.g.
+    /// for loading pruned nodes in place before detaching any.
+    pub(crate) fn tree_mut(&mut self) -> &mut Tree<H, A> {
+        &mut self.tree
+    }
+
     /// Consumes the `Walker` and returns the `Tree` it wraps.
-    pub fn into_inner(self) -> Tree {
+    pub fn into_inner(self) -> Tree<H, A> {
         self.tree.into_inner()
     }
 
     /// Takes a `Tree` and returns a `Walker` which fetches from the same source
     /// as `self`.
-    fn wrap(&self, tree: Tree) -> Self {
+    fn wrap(&self, tree: Tree<H, A>) -> Self {
         Walker::new(tree, self.source.clone())
     }
 
@@ -120,7 +137,7 @@ where
     /// implements `Into<Tree`>.
     pub fn attach<T>(mut self, left: bool, maybe_child: Option<T>) -> Self
     where
-        T: Into<Tree>,
+        T: Into<Tree<H, A>>,
     {
         self.tree
             .own(|t| t.attach(left, maybe_child.map(|t| t.into())));
@@ -132,22 +149,106 @@ where
         self.tree.own(|t| t.with_value(value));
         self
     }
+
+    /// Returns the hash and child heights of each pruned node which will need
+    /// to be fetched next in order to walk to the given keys, in key order, so
+    /// they can be fetched from a remote store in a single batch.
+    ///
+    /// Since a pruned node's children aren't known until it has been fetched,
+    /// only the first pruned node on each path is returned. Once they have
+    /// been loaded with `prefetch`, this can be called again to plan the next
+    /// batch, until it returns an empty list.
+    ///
+    /// Keys must be sorted and unique.
+    pub fn plan_fetch(&self, keys: &[Vec<u8>]) -> Vec<(H::Digest, (u8, u8))> {
+        let mut plan = vec![];
+        plan_fetch(self.tree(), keys, &mut plan);
+        plan
+    }
+
+    /// Loads the pruned nodes returned by `plan_fetch` for the same keys, using
+    /// `fetched` as the source (e.g. a cache filled by a batched request for
+    /// the planned nodes). Nodes past the first pruned node on each path are
+    /// not loaded.
+    ///
+    /// Keys must be sorted and unique.
+    pub fn prefetch<F: Fetch<H, A>>(mut self, keys: &[Vec<u8>], fetched: &F) -> Result<Self> {
+        prefetch(&mut self.tree, keys, fetched)?;
+        Ok(self)
+    }
+}
+
+/// Splits the sorted keys into those which are on the left and the right side
+/// of `tree`, excluding `tree`'s own key.
+fn split_keys<'a, H: Hasher, A: Aggregate>(
+    tree: &Tree<H, A>,
+    keys: &'a [Vec<u8>],
+) -> (&'a [Vec<u8>], &'a [Vec<u8>]) {
+    match keys.binary_search_by(|key| key.as_slice().cmp(tree.key())) {
+        Ok(index) => (&keys[..index], &keys[index + 1..]),
+        Err(index) => (&keys[..index], &keys[index..]),
+    }
 }
 
-impl<S> From<Walker<S>> for Tree
+/// Recursive implementation of `Walker::plan_fetch`.
+fn plan_fetch<H: Hasher, A: Aggregate>(
+    tree: &Tree<H, A>,
+    keys: &[Vec<u8>],
+    plan: &mut Vec<(H::Digest, (u8, u8))>,
+) {
+    let (left_keys, right_keys) = split_keys(tree, keys);
+    for &(left, keys) in [(true, left_keys), (false, right_keys)].iter() {
+        if keys.is_empty() {
+            continue;
+        }
+
+        match tree.link(left) {
+            None => {}
+            Some(link) if link.is_reference() => plan.push((*link.hash(), link.child_heights())),
+            Some(link) => plan_fetch(link.tree().unwrap(), keys, plan),
+        }
+    }
+}
+
+/// Recursive implementation of `Walker::prefetch`.
+fn prefetch<H: Hasher, A: Aggregate, F: Fetch<H, A>>(
+    tree: &mut Tree<H, A>,
+    keys: &[Vec<u8>],
+    fetched: &F,
+) -> Result<()> {
+    let (left_keys, right_keys) = split_keys(tree, keys);
+    for &(left, keys) in [(true, left_keys), (false, right_keys)].iter() {
+        if keys.is_empty() {
+            continue;
+        }
+
+        match tree.link(left) {
+            None => {}
+            Some(link) if link.is_reference() => tree.load(left, fetched)?,
+            Some(_) => prefetch(tree.child_mut(left).unwrap(), keys, fetched)?,
+        }
+    }
+    Ok(())
+}
+
+impl<S, H, A> From<Walker<S, H, A>> for Tree<H, A>
 where
-    S: Fetch + Sized + Clone + Send,
+    S: Fetch<H, A> + Sized + Clone + Send,
+    H: Hasher,
+    A: Aggregate,
 {
-    fn from(walker: Walker<S>) -> Tree {
+    fn from(walker: Walker<S, H, A>) -> Self {
         walker.into_inner()
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::super::NoopCommit;
+    use super::super::{DepthPrune, MemStore, NoopCommit, Op, PanicSource};
     use super::*;
-    use crate::tree::Tree;
+    use crate::test_utils::{make_batch_seq, seq_key};
+    use crate::tree::{Hash, Tree};
+    use std::sync::{Arc, Mutex};
 
     #[derive(Clone)]
     struct MockSource {}
@@ -158,6 +259,49 @@ mod test {
         }
     }
 
+    #[test]
+    fn bfs() {
+        // builds a perfect tree of keys 1..=7, rooted at 4
+        let batch: Vec<_> = (1..=7).map(|i| (vec![i], Op::Put(vec![i]))).collect();
+        let mut tree = Walker::<PanicSource>::apply_to(None, &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+
+        let walker = RefWalker::new(&mut tree, PanicSource {});
+        let keys: Vec<_> = walker
+            .bfs(u8::MAX)
+            .map(|(_, kv)| kv.expect("expected key/value").0[0])
+            .collect();
+        assert_eq!(keys, vec![4, 2, 6, 1, 3, 5, 7]);
+
+        let nodes: Vec<_> = walker.bfs(1).collect();
+        assert_eq!(nodes.len(), 3);
+        assert_eq!(nodes[0].0, walker.tree().hash());
+        assert_eq!(nodes[1].0, walker.tree().child_hash(true));
+        assert_eq!(nodes[2].1, Some((vec![6], vec![6])));
+
+        assert_eq!(walker.bfs(0).count(), 1);
+    }
+
+    #[test]
+    fn bfs_pruned() {
+        let batch: Vec<_> = (1..=7).map(|i| (vec![i], Op::Put(vec![i]))).collect();
+        let mut tree = Walker::<PanicSource>::apply_to(None, &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        tree.commit(&mut DepthPrune { keep_levels: 2 })
+            .expect("commit failed");
+
+        let walker = RefWalker::new(&mut tree, PanicSource {});
+        let nodes: Vec<_> = walker.bfs(u8::MAX).collect();
+        assert_eq!(nodes.len(), 7);
+        assert!(nodes[..3].iter().all(|(_, kv)| kv.is_some()));
+        assert!(nodes[3..].iter().all(|(_, kv)| kv.is_none()));
+    }
+
     #[test]
     fn walk_modified() {
         let tree = Tree::new(b"test".to_vec(), b"abc".to_vec())
@@ -203,6 +347,8 @@ mod test {
                 hash: Default::default(),
                 key: b"foo".to_vec(),
                 child_heights: (0, 0),
+                descendant_count: 1,
+                aggregate: NoAggregate,
             }),
             None,
         );
@@ -219,6 +365,30 @@ mod test {
         assert!(walker.into_inner().child(true).is_none());
     }
 
+    #[test]
+    fn detach() {
+        let mut tree = Tree::new(b"test".to_vec(), b"abc".to_vec())
+            .attach(true, Some(Tree::new(b"foo".to_vec(), b"bar".to_vec())));
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        let hash = tree.hash();
+
+        let walker = Walker::new(tree, MockSource {});
+        let (walker, child) = walker.detach_expect(true).expect("detach failed");
+        assert_eq!(child.tree().key(), b"foo");
+        assert!(walker.tree().link(true).is_none());
+
+        // detaching a missing child is an error rather than a panic
+        let (walker, maybe_child) = walker.detach(false).expect("detach failed");
+        assert!(maybe_child.is_none());
+        let leaf = Walker::new(Tree::new(vec![0], vec![1]), MockSource {});
+        assert!(leaf.detach_expect(true).is_err());
+
+        // reattaching the child restores the tree
+        let mut tree = walker.attach(true, Some(child)).into_inner();
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        assert_eq!(tree.hash(), hash);
+    }
+
     #[test]
     fn walk_none() {
         let tree = Tree::new(b"test".to_vec(), b"abc".to_vec());
@@ -233,4 +403,197 @@ mod test {
             })
             .expect("walk failed");
     }
+
+    /// Fetches from a `MemStore`, recording the hash of each fetched node.
+    #[derive(Clone)]
+    struct RecordingSource<'a> {
+        store: &'a MemStore,
+        fetched: Arc<Mutex<Vec<Hash>>>,
+    }
+
+    impl<'a> Fetch for RecordingSource<'a> {
+        fn fetch(&self, link: &Link) -> Result<Tree> {
+            self.fetched.lock().unwrap().push(*link.hash());
+            self.store.fetch(link)
+        }
+    }
+
+    #[test]
+    fn plan_fetch_matches_walk() {
+        let batch = make_batch_seq(0..100);
+        let mut tree = Walker::<PanicSource>::apply_to(None, &batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        let mut store = MemStore::new();
+        tree.commit(&mut store).expect("commit failed");
+        let encoded = tree.encode();
+        let keys = vec![seq_key(3), seq_key(40), seq_key(41), seq_key(99)];
+
+        // plan and load one level at a time
+        let mut walker = Walker::new(Tree::decode(tree.key().to_vec(), &encoded), PanicSource {});
+        let mut planned = vec![];
+        loop {
+            let plan = walker.plan_fetch(keys.as_slice());
+            if plan.is_empty() {
+                break;
+            }
+            planned.extend(plan.into_iter().map(|(hash, _)| hash));
+            walker = walker
+                .prefetch(keys.as_slice(), &&store)
+                .expect("prefetch failed");
+        }
+        for key in keys.iter() {
+            assert!(walker.tree().contains_key(key).unwrap());
+        }
+
+        // walk directly, fetching nodes as they are reached
+        let fetched = Arc::new(Mutex::new(vec![]));
+        let source = RecordingSource {
+            store: &store,
+            fetched: fetched.clone(),
+        };
+        let mut tree = Tree::decode(tree.key().to_vec(), &encoded);
+        RefWalker::new(&mut tree, source)
+            .create_proof(keys.as_slice())
+            .expect("create_proof failed");
+
+        let mut fetched = fetched.lock().unwrap().clone();
+        planned.sort();
+        fetched.sort();
+        assert!(!planned.is_empty());
+        assert_eq!(planned, fetched);
+    }
+
+    #[cfg(feature = "async")]
+    mod async_walk {
+        use super::super::{AsyncFetch, RefWalker};
+        use crate::error::Result;
+        use crate::test_utils::make_batch_seq;
+        use crate::tree::{Commit, Link, PanicSource, Tree, Walker};
+        use async_trait::async_trait;
+        use futures::executor::block_on;
+        use futures::future::{FutureExt, LocalBoxFuture};
+        use std::collections::HashMap;
+        use std::future::Future;
+        use std::pin::Pin;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use std::task::{Context, Poll};
+        use std::time::Duration;
+
+        /// A future which completes once the given duration has elapsed on
+        /// another thread.
+        struct Delay {
+            duration: Duration,
+            done: Arc<AtomicBool>,
+            started: bool,
+        }
+
+        impl Delay {
+            fn new(duration: Duration) -> Self {
+                Delay {
+                    duration,
+                    done: Arc::new(AtomicBool::new(false)),
+                    started: false,
+                }
+            }
+        }
+
+        impl Future for Delay {
+            type Output = ();
+
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+                if self.done.load(Ordering::SeqCst) {
+                    return Poll::Ready(());
+                }
+
+                if !self.started {
+                    self.started = true;
+                    let done = self.done.clone();
+                    let waker = cx.waker().clone();
+                    let duration = self.duration;
+                    std::thread::spawn(move || {
+                        std::thread::sleep(duration);
+                        done.store(true, Ordering::SeqCst);
+                        waker.wake();
+                    });
+                }
+
+                Poll::Pending
+            }
+        }
+
+        /// Records the encoding of every written node, and prunes everything.
+        struct MapCommit {
+            nodes: HashMap<Vec<u8>, Vec<u8>>,
+        }
+
+        impl Commit for MapCommit {
+            fn write(&mut self, tree: &Tree) -> Result<()> {
+                self.nodes.insert(tree.key().to_vec(), tree.encode());
+                Ok(())
+            }
+        }
+
+        #[derive(Clone)]
+        struct DelayedSource {
+            nodes: Arc<HashMap<Vec<u8>, Vec<u8>>>,
+        }
+
+        #[async_trait]
+        impl AsyncFetch for DelayedSource {
+            async fn fetch(&self, link: &Link) -> Result<Tree> {
+                Delay::new(Duration::from_millis(1)).await;
+                let bytes = &self.nodes[link.key()];
+                Ok(Tree::decode(link.key().to_vec(), bytes.as_slice()))
+            }
+        }
+
+        fn collect_keys<'a, 'b: 'a>(
+            mut walker: RefWalker<'b, DelayedSource>,
+            keys: &'a mut Vec<Vec<u8>>,
+        ) -> LocalBoxFuture<'a, Result<()>> {
+            async move {
+                if let Some(left) = walker.walk_async(true).await? {
+                    collect_keys(left, keys).await?;
+                }
+                keys.push(walker.tree().key().to_vec());
+                if let Some(right) = walker.walk_async(false).await? {
+                    collect_keys(right, keys).await?;
+                }
+                Ok(())
+            }
+            .boxed_local()
+        }
+
+        #[test]
+        fn walk_async_delayed_source() {
+            let batch = make_batch_seq(0..100);
+            let mut tree = Walker::<PanicSource>::apply_to(None, &batch)
+                .expect("apply failed")
+                .0
+                .expect("expected tree");
+            let expected_keys: Vec<_> = tree.iter().map(|(key, _)| key.to_vec()).collect();
+
+            let mut commit = MapCommit {
+                nodes: HashMap::new(),
+            };
+            tree.commit(&mut commit).expect("commit failed");
+            let expected_hash = tree.hash();
+            assert!(tree.link(true).expect("expected link").is_reference());
+            assert!(tree.link(false).expect("expected link").is_reference());
+
+            let source = DelayedSource {
+                nodes: Arc::new(commit.nodes),
+            };
+            let mut keys = vec![];
+            block_on(collect_keys(RefWalker::new(&mut tree, source), &mut keys))
+                .expect("walk failed");
+
+            assert_eq!(keys, expected_keys);
+            assert_eq!(tree.iter().count(), 100);
+            assert_eq!(tree.hash(), expected_hash);
+        }
+    }
 }
@@ -1,6 +1,9 @@
-use super::super::{Link, Tree};
+use super::super::{Aggregate, Blake2bHasher, Hasher, Link, NoAggregate, Tree};
+#[cfg(feature = "async")]
+use super::AsyncFetch;
 use super::Fetch;
 use crate::error::Result;
+use std::collections::VecDeque;
 
 /// Allows read-only traversal of a `Tree`, fetching from the given source when
 /// traversing to a pruned node. The fetched nodes are then retained in memory
@@ -8,33 +11,93 @@ use crate::error::Result;
 ///
 /// Only finalized trees may be walked (trees which have had `commit` called
 /// since the last update).
-pub struct RefWalker<'a, S>
+///
+/// The source is usually a `Fetch` implementation, but may instead be an
+/// `AsyncFetch` implementation when the `async` feature is enabled, in which
+/// case the walker is traversed with `walk_async`.
+pub struct RefWalker<'a, S, H = Blake2bHasher, A = NoAggregate>
 where
-    S: Fetch + Sized + Clone + Send,
+    S: Sized + Clone + Send,
+    H: Hasher,
+    A: Aggregate,
 {
-    tree: &'a mut Tree,
+    tree: &'a mut Tree<H, A>,
     source: S,
 }
 
-impl<'a, S> RefWalker<'a, S>
+impl<'a, S, H, A> RefWalker<'a, S, H, A>
 where
-    S: Fetch + Sized + Clone + Send,
+    S: Sized + Clone + Send,
+    H: Hasher,
+    A: Aggregate,
 {
     /// Creates a `RefWalker` with the given tree and source.
-    pub fn new(tree: &'a mut Tree, source: S) -> Self {
+    pub fn new(tree: &'a mut Tree<H, A>, source: S) -> Self {
         // TODO: check if tree has modified links, panic if so
         RefWalker { tree, source }
     }
 
     /// Gets an immutable reference to the `Tree` wrapped by this `RefWalker`.
-    pub fn tree(&self) -> &Tree {
+    pub fn tree(&self) -> &Tree<H, A> {
         self.tree
     }
 
+    /// Creates an iterator which yields the nodes of the tree in breadth-first
+    /// order (level by level, left to right), descending no further than
+    /// `max_depth` levels below the root (the root has depth 0). See `Bfs`.
+    pub fn bfs(&self, max_depth: u8) -> Bfs<H, A> {
+        let mut queue = VecDeque::new();
+        queue.push_back((0, self.tree.hash(), Some(&*self.tree)));
+        Bfs { queue, max_depth }
+    }
+}
+
+/// An iterator which yields the nodes of a tree in breadth-first order, created
+/// by `RefWalker::bfs`. Each node is yielded as its hash and, if the node is in
+/// memory, its key/value pair. Pruned nodes are yielded with only their hash
+/// (known from their parent's link) and are not descended into, since they are
+/// not fetched from the source.
+///
+/// Panics if the tree contains modified nodes whose hashes have not been
+/// computed (see `Tree::compute_hashes`).
+pub struct Bfs<'a, H: Hasher = Blake2bHasher, A: Aggregate = NoAggregate> {
+    queue: VecDeque<(u8, H::Digest, Option<&'a Tree<H, A>>)>,
+    max_depth: u8,
+}
+
+impl<'a, H: Hasher, A: Aggregate> Iterator for Bfs<'a, H, A> {
+    type Item = (H::Digest, Option<(Vec<u8>, Vec<u8>)>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (depth, hash, maybe_tree) = self.queue.pop_front()?;
+
+        let tree = match maybe_tree {
+            None => return Some((hash, None)),
+            Some(tree) => tree,
+        };
+
+        if depth < self.max_depth {
+            for &left in [true, false].iter() {
+                if let Some(link) = tree.link(left) {
+                    self.queue.push_back((depth + 1, *link.hash(), link.tree()));
+                }
+            }
+        }
+
+        Some((hash, Some((tree.key().to_vec(), tree.value().to_vec()))))
+    }
+}
+
+impl<'a, S, H, A> RefWalker<'a, S, H, A>
+where
+    S: Fetch<H, A> + Sized + Clone + Send,
+    H: Hasher,
+    A: Aggregate,
+{
     /// Traverses to the child on the given side (if any), fetching from the
     /// source if pruned. When fetching, the link is upgraded from
     /// `Link::Reference` to `Link::Loaded`.
-    pub fn walk<'b>(&'b mut self, left: bool) -> Result<Option<RefWalker<'b, S>>> {
+    pub fn walk<'b>(&'b mut self, left: bool) -> Result<Option<RefWalker<'b, S, H, A>>> {
         let link = match self.tree.link(left) {
             None => return Ok(None),
             Some(link) => link,
@@ -52,3 +115,29 @@ where
         Ok(Some(RefWalker::new(child, self.source.clone())))
     }
 }
+
+#[cfg(feature = "async")]
+impl<'a, S> RefWalker<'a, S>
+where
+    S: AsyncFetch + Sized + Clone + Send + Sync,
+{
+    /// Like `walk`, but awaits the source when fetching a pruned child rather
+    /// than blocking.
+    pub async fn walk_async<'b>(&'b mut self, left: bool) -> Result<Option<RefWalker<'b, S>>> {
+        let link = match self.tree.link(left) {
+            None => return Ok(None),
+            Some(link) => link,
+        };
+
+        match link {
+            Link::Reference { .. } => {
+                self.tree.load_async(left, &self.source).await?;
+            }
+            Link::Modified { .. } => panic!("Cannot traverse Link::Modified"),
+            Link::Uncommitted { .. } | Link::Loaded { .. } => {}
+        }
+
+        let child = self.tree.child_mut(left).unwrap();
+        Ok(Some(RefWalker::new(child, self.source.clone())))
+    }
+}